@@ -0,0 +1,36 @@
+use std::{path::Path, process::Command};
+
+/// Note the commit built and the features it was built with, for `/version` of the exporter;
+/// no commit outside of a git checkout.
+fn main() {
+    // Those of the features table enabled, so none is ever left out of the list; optional
+    // dependencies count as features to cargo too, but aren't any of tarssh.
+    let manifest = std::fs::read_to_string("Cargo.toml").unwrap_or_default();
+    let mut features: Vec<&str> = manifest
+        .lines()
+        .skip_while(|line| line.trim() != "[features]")
+        .skip(1)
+        .take_while(|line| !line.starts_with('['))
+        .filter_map(|line| line.split_once('=').map(|(feature, _)| feature.trim()))
+        .filter(|&feature| feature != "default")
+        .filter(|feature| std::env::var_os(format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"))).is_some())
+        .collect();
+    features.sort_unstable();
+    println!("cargo:rustc-env=TARSSH_FEATURES={}", features.join(","));
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=TARSSH_GIT_HASH={}", hash.trim());
+    }
+    for path in &[".git/HEAD", ".git/refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+}
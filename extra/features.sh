@@ -0,0 +1,29 @@
+#!/bin/sh
+#
+# Build, lint and test tarssh with every combination of features that matters: none, each
+# feature alone, the defaults, the defaults with each optional feature, and all of them.
+# `nightly` is left out, as it takes a nightly compiler.
+#
+# Run from the root of the repository; stops at the first combination failing.
+
+set -eu
+
+OPTIONAL="aggregate_only top control profiling tls"
+ALL="sandbox drop_privs exporters alerts pretty_logs $OPTIONAL"
+
+check() {
+	echo "== features: ${1:-none}"
+	cargo clippy --quiet --no-default-features --features "$1" --all-targets -- -D warnings
+	cargo test --quiet --no-default-features --features "$1"
+}
+
+check ""
+for feature in $ALL; do
+	check "$feature"
+done
+check "sandbox drop_privs exporters alerts pretty_logs"
+for feature in $OPTIONAL; do
+	check "sandbox drop_privs exporters alerts pretty_logs $feature"
+done
+check "$ALL"
+echo "== all combinations passed"
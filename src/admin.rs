@@ -0,0 +1,303 @@
+use log::{info, warn};
+use std::{
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use super::{
+    failure::Failure,
+    handover::Handover,
+    listeners::{Acceptors, Defaults},
+    metrics::{Kick, Metrics},
+    reload::Reloader,
+    runtime::Runtime,
+    summary::Stats,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    time::delay_for,
+};
+
+/// What the commands of `help` are.
+const HELP: &str = "\
+show stats                       uptime, clients, the time they were trapped and the record
+show sessions                    the clients connected now, trapped the longest first
+kick <id|address|network>        hang up on a connection or all clients of a network
+say <id> <line>                  write a line to a connection after its next line ending
+set delay <seconds> [all]        wait this long between chunks, for the connections to come,
+                                 or all connected now too, from their next chunk on
+set timeout <seconds>            give up on a chunk not written after this long
+set max-clients <clients>        hold this many clients at once at most
+pause                            hang up on new connections, the listeners kept bound
+resume                           accept them again
+reload                           re-read the config and message files, telling what became of
+                                 every listener opened or closed
+drain [deadline]                 stop accepting, and end once the clients left give up or
+                                 the deadline (e.g. 1h) passes
+help                             this
+";
+
+/// A running instance as the control socket sees it.
+pub(crate) struct Instance {
+    pub(crate) name:      Option<String>,
+    pub(crate) metrics:   Arc<Metrics>,
+    pub(crate) acceptors: Arc<Acceptors>,
+}
+
+/// A unix socket taking commands, a line each, and answering each with lines of text ending
+/// in an empty one, for scripts to talk to tarssh with `socat` or `nc -U`.
+pub(crate) struct ControlSocket {
+    listener: UnixListener,
+    path:     PathBuf,
+}
+
+impl ControlSocket {
+    /// Bind the socket, replacing any left at the path, for the owner only.
+    pub(crate) fn bind(
+        runtime: &mut Runtime,
+        path: PathBuf,
+    ) -> Self {
+        let fail = |err: std::io::Error| format!("control, path: {}, error: {}", path.display(), err);
+        if std::fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            let _ = std::fs::remove_file(&path);
+        }
+        let listener = runtime
+            .block_on(async { UnixListener::bind(&path) })
+            .unwrap_or_else(|err| Failure::Bind.exit(fail(err)));
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+            .unwrap_or_else(|err| Failure::Bind.exit(fail(err)));
+        info!("control, path: {}", path.display());
+        Self { listener, path }
+    }
+
+    /// Take commands until tarssh ends.
+    pub(crate) async fn serve(
+        mut self,
+        instances: Vec<Instance>,
+        handover: &'static Handover,
+        reloader: Arc<Reloader>,
+    ) {
+        let instances = Arc::new(instances);
+        loop {
+            match self.listener.accept().await {
+                Ok((sock, _)) => {
+                    tokio::spawn(session(sock, instances.clone(), handover, reloader.clone()));
+                },
+                Err(err) => {
+                    warn!("control, path: {}, error: {}", self.path.display(), err);
+                    delay_for(Duration::from_millis(100)).await;
+                },
+            }
+        }
+    }
+}
+
+/// Answer the commands of a connection until it hangs up.
+async fn session(
+    sock: UnixStream,
+    instances: Arc<Vec<Instance>>,
+    handover: &'static Handover,
+    reloader: Arc<Reloader>,
+) {
+    let (reader, mut writer) = tokio::io::split(sock);
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut answer = match command(line, &instances, handover, &reloader) {
+            Ok(answer) => answer,
+            Err(err) => format!("error: {}\n", err),
+        };
+        answer.push('\n');
+        if writer.write_all(answer.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Carry out a command, returning the lines to answer with.
+fn command(
+    line: &str,
+    instances: &[Instance],
+    handover: &'static Handover,
+    reloader: &Reloader,
+) -> Result<String, String> {
+    let words: Vec<_> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["show", "stats"] => Ok(Stats::of(instances.iter().map(|instance| &instance.metrics)).to_string()),
+        ["show", "sessions"] => Ok(sessions(instances)),
+        ["kick", target] => {
+            let kick = match target.parse::<usize>() {
+                Ok(id) => Kick::Id(id),
+                Err(_) => Kick::Peers(target.parse()?),
+            };
+            let kicked: usize = instances.iter().map(|instance| instance.metrics.kick(kick)).sum();
+            info!("kick, {}, clients: {}, control: true", kick, kicked);
+            Ok(format!("kicked: {}\n", kicked))
+        },
+        ["say", id, _, ..] => {
+            let id: usize = id.parse().map_err(|err| format!("invalid id \"{}\": {}", id, err))?;
+            // What follows the id, as written, spaces and all.
+            let text = line
+                .strip_prefix("say")
+                .map(str::trim_start)
+                .and_then(|rest| rest.strip_prefix(words[1]))
+                .unwrap_or_default()
+                .trim_start();
+            let said = said(text)?;
+            let told: Vec<bool> = instances.iter().filter_map(|instance| instance.metrics.say(id, &said)).collect();
+            info!("say, id: {}, line: {:?}, clients: {}, control: true", id, text, told.iter().filter(|&&room| room).count());
+            if told.is_empty() {
+                return Err(format!("no connection \"{}\"", id));
+            }
+            if !told.contains(&true) {
+                return Err(format!("connection \"{}\" has too much said waiting already", id));
+            }
+            Ok(format!("said: {}\n", told.iter().filter(|&&room| room).count()))
+        },
+        ["set", "delay", seconds] | ["set", "delay", seconds, "all"] => {
+            let delay = Duration::from_secs(
+                seconds.parse().map_err(|err| format!("invalid delay \"{}\": {}", seconds, err))?
+            );
+            reconfigure(instances, |defaults| defaults.config.delay = delay)?;
+            if words.len() == 4 {
+                let retuned: usize = instances.iter().map(|instance| instance.metrics.retune(delay)).sum();
+                info!("set, delay: {}s, clients: {}, control: true", delay.as_secs(), retuned);
+                return Ok(format!("delay: {}s, clients: {}\n", delay.as_secs(), retuned));
+            }
+            info!("set, delay: {}s, control: true", delay.as_secs());
+            Ok(format!("delay: {}s\n", delay.as_secs()))
+        },
+        ["set", "timeout", seconds] => {
+            let timeout = Duration::from_secs(
+                seconds.parse().map_err(|err| format!("invalid timeout \"{}\": {}", seconds, err))?
+            );
+            reconfigure(instances, |defaults| defaults.config.timeout = timeout)?;
+            info!("set, timeout: {}s, control: true", timeout.as_secs());
+            Ok(format!("timeout: {}s\n", timeout.as_secs()))
+        },
+        ["set", "max-clients", clients] => {
+            let clients: usize = clients.parse().map_err(|err| format!("invalid max-clients \"{}\": {}", clients, err))?;
+            for instance in instances {
+                instance.metrics.limit(clients);
+            }
+            info!("set, max_clients: {}, control: true", clients);
+            Ok(format!("max_clients: {}\n", clients))
+        },
+        ["pause"] | ["resume"] => {
+            handover.pause(words[0] == "pause");
+            Ok(format!("paused: {}\n", handover.paused()))
+        },
+        ["drain"] | ["drain", _] => {
+            let deadline = match words.get(1) {
+                Some(deadline) => Some(
+                    humantime::parse_duration(deadline).map_err(|err| format!("invalid deadline \"{}\": {}", deadline, err))?
+                ),
+                None => None,
+            };
+            handover.drain(deadline);
+            let clients: usize = instances.iter().map(|instance| instance.metrics.connections()).sum();
+            Ok(format!("draining, clients: {}\n", clients))
+        },
+        ["reload"] => Ok(reloader.reload().to_string()),
+        ["help"] => Ok(HELP.to_owned()),
+        _ => Err(format!("unknown command \"{}\", try help", line)),
+    }
+}
+
+/// A line to say to a client, ending in CRLF: one that doesn't start with `SSH-`, which would
+/// end the tarpit for the client, and with nothing a terminal would act on.
+fn said(
+    text: &str,
+) -> Result<Vec<u8>, String> {
+    if text.starts_with("SSH-") {
+        return Err("a line may not start with SSH-".to_owned());
+    }
+    if text.chars().any(char::is_control) {
+        return Err("a line may not hold control characters".to_owned());
+    }
+    // Clients read lines of 255 bytes at most, the line ending included.
+    if text.len() > 253 {
+        return Err(format!("a line may hold 253 bytes at most, not {}", text.len()));
+    }
+    Ok(format!("{}\r\n", text).into_bytes())
+}
+
+/// Change the defaults of every instance, for the connections to come; listeners with options
+/// of their own keep them.
+fn reconfigure(
+    instances: &[Instance],
+    change: impl Fn(&mut Defaults),
+) -> Result<(), String> {
+    for instance in instances {
+        let (max_clients, mut defaults) = instance.acceptors.defaults();
+        change(&mut defaults);
+        instance.acceptors
+            .reconfigure(max_clients, defaults)
+            .map_err(|err| format!("banner, error: {}", err))?;
+    }
+    Ok(())
+}
+
+/// Every client connected now, of all instances, in columns.
+fn sessions(
+    instances: &[Instance],
+) -> String {
+    let several = instances.len() > 1;
+    let mut sessions = String::from(if several {
+        "# instance id peer trapped_seconds chunks bytes listener\n"
+    } else {
+        "# id peer trapped_seconds chunks bytes listener\n"
+    });
+    let mut all: Vec<_> = instances
+        .iter()
+        .flat_map(|instance| {
+            let name = instance.name.as_deref().unwrap_or("default");
+            instance.metrics.sessions().into_iter().map(move |session| (name, session))
+        })
+        .collect();
+    all.sort_by_key(|(_, session)| std::cmp::Reverse(session.duration));
+    for (name, session) in all {
+        if several {
+            sessions.push_str(name);
+            sessions.push(' ');
+        }
+        sessions.push_str(&format!(
+            "{} {} {} {} {} {}\n",
+            session.id,
+            session.peer,
+            session.duration.as_secs(),
+            session.sent_chunks,
+            session.sent_bytes,
+            session.listener,
+        ));
+    }
+    sessions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn says_lines() {
+        assert_eq!(said("Maintenance at 03:00 UTC").unwrap(), b"Maintenance at 03:00 UTC\r\n");
+        assert_eq!(said("").unwrap(), b"\r\n");
+        assert_eq!(said("ssh-2.0 is fine in lower case, Grüße").unwrap(), "ssh-2.0 is fine in lower case, Grüße\r\n".as_bytes());
+        assert_eq!(said(&"a".repeat(253)).unwrap().len(), 255);
+    }
+
+    #[test]
+    fn refuses_lines() {
+        for text in &["SSH-2.0-OpenSSH_9.6", "SSH-", "bell\x07", "two\r\nlines", "tab\there", "\x1b[2Jclear", "del\x7f"] {
+            assert!(said(text).is_err(), "said {:?}", text);
+        }
+        assert_eq!(said(&"a".repeat(254)).unwrap_err(), "a line may hold 253 bytes at most, not 254");
+        // Bytes count, not characters.
+        assert!(said(&"ü".repeat(127)).is_err());
+    }
+}
@@ -0,0 +1,372 @@
+use hyper::{
+    client::HttpConnector,
+    header::CONTENT_TYPE,
+    Body,
+    Client,
+    Method,
+    Request,
+    Uri,
+};
+use log::{info, warn};
+use regex::bytes::Regex;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+use super::{failure::Failure, json::json, sync::lock};
+
+/// Bytes of the input of a connection kept to match against, the rest is only counted.
+const MAX_PAYLOAD: usize = 4096;
+
+/// Canaries remembered to recognise them when they come back, the oldest are forgotten first.
+const MAX_CANARIES: usize = 65536;
+
+/// Rates which are suspicious when exceeded, `None` for rates not to be watched.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Thresholds {
+    /// Accepted connections per minute.
+    pub(crate) connections: Option<usize>,
+    /// Rejected connections per minute.
+    pub(crate) rejects:     Option<usize>,
+    /// Distinct peers per hour.
+    pub(crate) peers:       Option<usize>,
+}
+
+/// Rules to match whatever clients send against, and where to report a match.
+pub(crate) struct Alerts {
+    version:    Vec<Regex>,
+    payload:    Vec<Regex>,
+    webhook:    Option<Webhook>,
+    canaries:   Mutex<Canaries>,
+    thresholds: Thresholds,
+    /// Connections accepted in the current minute.
+    accepted:   AtomicUsize,
+    /// Connections rejected in the current minute.
+    rejected:   AtomicUsize,
+    /// Peers seen in the current hour, up to one more than the threshold.
+    peers:      Mutex<HashSet<IpAddr>>,
+}
+
+/// To whom each of the recent canaries was sent.
+#[derive(Default)]
+struct Canaries {
+    issued:     HashMap<String, (SocketAddr, SystemTime)>,
+    order:      VecDeque<String>,
+}
+
+struct Webhook {
+    uri:        Uri,
+    client:     Client<HttpConnector>,
+}
+
+impl Alerts {
+    pub(crate) fn new(
+        version: Vec<Regex>,
+        payload: Vec<Regex>,
+        webhook: Option<Uri>,
+        thresholds: Thresholds,
+    ) -> Self {
+        if let Some(uri) = &webhook {
+            if uri.scheme_str() != Some("http") {
+                Failure::Config.exit(format!("webhook, uri: {}, error: only http is supported", uri));
+            }
+        }
+        info!(
+            "alerts, version: {}, payload: {}, webhook: {}, thresholds: {:?}",
+            version.len(),
+            payload.len(),
+            webhook.as_ref().map_or_else(|| "none".to_owned(), Uri::to_string),
+            thresholds,
+        );
+        Self {
+            version,
+            payload,
+            webhook: webhook.map(|uri| Webhook {
+                uri,
+                client: Client::new(),
+            }),
+            canaries: Mutex::default(),
+            thresholds,
+            accepted: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            peers:    Mutex::default(),
+        }
+    }
+
+    /// Whether any of the rates is watched at all.
+    pub(crate) fn watches_rates(&self) -> bool {
+        self.thresholds.connections.is_some()
+        || self.thresholds.rejects.is_some()
+        || self.thresholds.peers.is_some()
+    }
+
+    /// Count an accepted connection towards the rates.
+    pub(crate) fn accepted(
+        &self,
+        peer: IpAddr,
+    ) {
+        if let Some(threshold) = self.thresholds.connections {
+            let accepted = self.accepted.fetch_add(1, Ordering::Relaxed) + 1;
+            if accepted == threshold + 1 {
+                self.exceeded("connections", "minute", threshold);
+            }
+        }
+        if let Some(threshold) = self.thresholds.peers {
+            let mut peers = lock(&self.peers);
+            if peers.len() <= threshold && peers.insert(peer) && peers.len() == threshold + 1 {
+                drop(peers);
+                self.exceeded("peers", "hour", threshold);
+            }
+        }
+    }
+
+    /// Count a rejected connection towards the rates.
+    pub(crate) fn rejected(&self) {
+        if let Some(threshold) = self.thresholds.rejects {
+            let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+            if rejected == threshold + 1 {
+                self.exceeded("rejects", "minute", threshold);
+            }
+        }
+    }
+
+    fn exceeded(
+        &self,
+        rate:       &str,
+        per:        &str,
+        threshold:  usize,
+    ) {
+        warn!("alert, kind: rate, rate: {}, threshold: {}/{}", rate, threshold, per);
+        self.notify(&[
+            ("event",       "rate"),
+            ("rate",        rate),
+            ("threshold",   &threshold.to_string()),
+            ("per",         per),
+        ]);
+    }
+
+    /// Start the windows of the rates over, every minute and every hour, forever.
+    pub(crate) async fn watch_rates(
+        self: Arc<Self>,
+    ) {
+        let mut minutes = tokio::time::interval(Duration::from_secs(60));
+        // The first tick completes immediately.
+        minutes.tick().await;
+        for minute in 1usize.. {
+            minutes.tick().await;
+            self.accepted.store(0, Ordering::Relaxed);
+            self.rejected.store(0, Ordering::Relaxed);
+            if minute % 60 == 0 {
+                lock(&self.peers).clear();
+            }
+        }
+    }
+
+    /// Remember to whom a canary was sent.
+    pub(crate) fn issued(
+        &self,
+        canary: &str,
+        peer:   SocketAddr,
+    ) {
+        let mut canaries = lock(&self.canaries);
+        if canaries.order.len() >= MAX_CANARIES {
+            if let Some(oldest) = canaries.order.pop_front() {
+                canaries.issued.remove(&oldest);
+            }
+        }
+        canaries.order.push_back(canary.to_owned());
+        canaries.issued.insert(canary.to_owned(), (peer, SystemTime::now()));
+    }
+
+    /// To whom and when a canary was sent, if it is still remembered.
+    fn canary(
+        &self,
+        canary: &str,
+    ) -> Option<(SocketAddr, SystemTime)> {
+        let canaries = lock(&self.canaries);
+        canaries.issued.get(canary).copied()
+    }
+
+    fn has_canaries(&self) -> bool {
+        !lock(&self.canaries).order.is_empty()
+    }
+
+    /// Start watching the input of a new connection, its payload only if it is sampled.
+    pub(crate) fn inspect(
+        self: &Arc<Self>,
+        peer: SocketAddr,
+        sampled: bool,
+    ) -> Inspection {
+        Inspection {
+            alerts:     self.clone(),
+            peer,
+            sampled,
+            version:    None,
+            payload:    Vec::new(),
+            fired:      vec![false; self.payload.len()],
+            canaries:   Vec::new(),
+        }
+    }
+
+    /// Post an event to the webhook, if there is one, as a flat JSON object.
+    pub(crate) fn notify(
+        &self,
+        fields: &[(&str, &str)],
+    ) {
+        let webhook = match &self.webhook {
+            Some(webhook) => webhook,
+            None => return,
+        };
+        let body = format!(
+            "{{{}}}",
+            fields
+                .iter()
+                .map(|(key, value)| format!("{}:{}", json(key), json(value)))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(webhook.uri.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body));
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                warn!("webhook, error: {}", err);
+                return;
+            },
+        };
+        let response = webhook.client.request(request);
+        tokio::spawn(async move {
+            match response.await {
+                Ok(response) if response.status().is_success() => (),
+                Ok(response) => warn!("webhook, status: {}", response.status()),
+                Err(err) => warn!("webhook, error: {}", err),
+            }
+        });
+    }
+}
+
+/// What a single connection has sent so far, as far as the rules are concerned.
+pub(crate) struct Inspection {
+    alerts:     Arc<Alerts>,
+    peer:       SocketAddr,
+    /// Whether the payload is kept and searched at all.
+    sampled:    bool,
+    version:    Option<String>,
+    payload:    Vec<u8>,
+    /// Payload rules which already matched, so they fire only once per connection.
+    fired:      Vec<bool>,
+    /// Canaries this connection already sent back.
+    canaries:   Vec<String>,
+}
+
+impl Inspection {
+    /// Check the identification string of the client.
+    pub(crate) fn version(
+        &mut self,
+        version: &[u8],
+    ) {
+        self.version = Some(String::from_utf8_lossy(version).into_owned());
+        for rule in &self.alerts.version {
+            if let Some(found) = rule.find(version) {
+                self.alert("version", rule, found.as_bytes());
+            }
+        }
+    }
+
+    /// Check everything the client sent so far, including what just arrived.
+    pub(crate) fn payload(
+        &mut self,
+        input: &[u8],
+    ) {
+        if !self.sampled
+        || self.payload.len() >= MAX_PAYLOAD
+        || (self.alerts.payload.is_empty() && !self.alerts.has_canaries())
+        {
+            return;
+        }
+        let room = MAX_PAYLOAD - self.payload.len();
+        self.payload.extend_from_slice(&input[..input.len().min(room)]);
+        for (index, rule) in self.alerts.payload.iter().enumerate() {
+            if self.fired[index] {
+                continue;
+            }
+            if let Some(found) = rule.find(&self.payload) {
+                self.fired[index] = true;
+                self.alert("payload", rule, found.as_bytes());
+            }
+        }
+        self.canaries();
+    }
+
+    /// Look for canaries sent to anyone, this client included, in its payload.
+    fn canaries(
+        &mut self,
+    ) {
+        let words = self.payload
+            .split(|byte| !byte.is_ascii_hexdigit())
+            .filter(|word| word.len() == 16)
+            .map(|word| String::from_utf8_lossy(word).to_ascii_lowercase())
+            .collect::<Vec<_>>();
+        for word in words {
+            if self.canaries.contains(&word) {
+                continue;
+            }
+            if let Some((issued_to, issued_at)) = self.alerts.canary(&word) {
+                let peer = self.peer.to_string();
+                let issued_to = issued_to.to_string();
+                let issued_at = humantime::format_rfc3339_seconds(issued_at).to_string();
+                warn!(
+                    "alert, peer: {}, kind: canary, canary: {}, issued_to: {}, issued_at: {}",
+                    peer,
+                    word,
+                    issued_to,
+                    issued_at,
+                );
+                self.alerts.notify(&[
+                    ("event",       "canary"),
+                    ("peer",        &peer),
+                    ("canary",      &word),
+                    ("issued_to",   &issued_to),
+                    ("issued_at",   &issued_at),
+                ]);
+                self.canaries.push(word);
+            }
+        }
+    }
+
+    fn alert(
+        &self,
+        kind:   &str,
+        rule:   &Regex,
+        found:  &[u8],
+    ) {
+        let peer = self.peer.to_string();
+        let found = String::from_utf8_lossy(found);
+        let version = self.version.as_deref().unwrap_or_default();
+        warn!(
+            "alert, peer: {}, kind: {}, rule: {:?}, match: {:?}, version: {:?}",
+            peer,
+            kind,
+            rule.as_str(),
+            found,
+            version,
+        );
+        self.alerts.notify(&[
+            ("event",   "match"),
+            ("peer",    &peer),
+            ("kind",    kind),
+            ("rule",    rule.as_str()),
+            ("match",   &found),
+            ("version", version),
+        ]);
+    }
+}
@@ -0,0 +1,421 @@
+use log::info;
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    seq::SliceRandom,
+    thread_rng,
+    Rng,
+};
+use std::{
+    fs::File,
+    io::{
+        self,
+        BufReader,
+        SeekFrom,
+        prelude::*,
+    },
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use super::{sync::lock, template::Template};
+
+/// Message files larger than this are read as they are sent, instead of kept in memory.
+const MAX_LOADED: u64 = 1 << 20;
+
+/// Bytes of a streamed message read at once.
+const WINDOW: usize = 4096;
+
+/// A message file given on the command line, optionally with a weight, e.g. `file.txt:3`.
+#[derive(Clone, Debug)]
+pub(crate) struct MessageSpec {
+    path:   String,
+    weight: u32,
+}
+
+impl FromStr for MessageSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (path, weight) = match spec.rfind(':') {
+            Some(index) => match spec[index + 1..].parse::<u32>() {
+                Ok(weight) => (&spec[..index], weight),
+                Err(_) => (spec, 1),
+            },
+            None => (spec, 1),
+        };
+        if path.is_empty() {
+            Err(format!("empty filename in \"{}\"", spec))
+        } else if weight == 0 {
+            Err(format!("weight of \"{}\" must be at least 1", path))
+        } else {
+            Ok(Self {
+                path: path.to_owned(),
+                weight,
+            })
+        }
+    }
+}
+
+impl MessageSpec {
+    /// What the file holds, with its weight: all of it if it is small enough to be loaded,
+    /// only its size and modification time if it is streamed.
+    pub(crate) fn content(&self) -> io::Result<Vec<u8>> {
+        let metadata = std::fs::metadata(&self.path)?;
+        let mut content = format!("{}:{}\n", self.path, self.weight).into_bytes();
+        if metadata.len() > MAX_LOADED {
+            content.extend_from_slice(format!("{} {:?}", metadata.len(), metadata.modified()?).as_bytes());
+        } else {
+            content.extend_from_slice(&std::fs::read(&self.path)?);
+        }
+        Ok(content)
+    }
+}
+
+/// A banner of the pool.
+pub(crate) enum Message {
+    /// Expanded for every connection.
+    Template(Arc<Template>),
+    /// Sent as it is, straight from the file.
+    Stream(Arc<Stream>),
+}
+
+/// All banners a connection might get, weighted.
+pub(crate) struct BannerPool {
+    banners: Vec<Message>,
+    weights: WeightedIndex<u32>,
+}
+
+impl BannerPool {
+    pub(crate) fn load(
+        messages: &[MessageSpec],
+    ) -> std::io::Result<Self> {
+        if messages.is_empty() {
+            let banner = format!(
+                "{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n",
+                "My name is Yon Yonson",
+                "I live in Wisconsin.",
+                "There, the people I meet",
+                "As I walk down the street",
+                "Say “Hey, what’s your name?”",
+                "And I say:",
+            );
+            return Ok(Self::builtin("default", banner));
+        }
+
+        let mut banners = Vec::with_capacity(messages.len());
+        for message in messages {
+            let file = File::open(&message.path)
+                .map_err(|err| std::io::Error::new(err.kind(), format!("{}: {}", message.path, err)))?;
+            let bytes = file.metadata()?.len();
+            if bytes > MAX_LOADED {
+                info!(
+                    "banner, file: {}, weight: {}, bytes: {}, streamed: true",
+                    message.path,
+                    message.weight,
+                    bytes,
+                );
+                banners.push((
+                    Message::Stream(Arc::new(Stream {
+                        file: Mutex::new(file),
+                    })),
+                    message.weight,
+                ));
+                continue;
+            }
+            let banner = BufReader::new(file)
+                .lines()
+                .try_fold(
+                    String::new(),
+                    |mut result, line| if let Ok(line) = line {
+                        result.push_str(&line);
+                        result.push_str("\r\n");
+                        Ok(result)
+                    } else {
+                        line
+                    },
+                )?;
+            info!(
+                "banner, file: {}, weight: {}, banner:\n{}",
+                message.path,
+                message.weight,
+                banner,
+            );
+            banners.push((Message::Template(Arc::new(Template::parse(&banner))), message.weight));
+        }
+        Ok(Self::new(banners))
+    }
+
+    /// A single banner that comes with tarssh.
+    pub(crate) fn builtin(
+        name: &str,
+        banner: String,
+    ) -> Self {
+        info!("banner, builtin: {}, banner:\n{}", name, banner);
+        Self::new(vec![(Message::Template(Arc::new(Template::parse(&banner))), 1)])
+    }
+
+    fn new(
+        banners: Vec<(Message, u32)>,
+    ) -> Self {
+        let weights = WeightedIndex::new(banners.iter().map(|(_, weight)| *weight))
+            .expect("banner weights are validated to be positive");
+        Self {
+            banners: banners
+                .into_iter()
+                .map(|(banner, _)| banner)
+                .collect(),
+            weights,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.banners.len()
+    }
+
+    /// Pick a banner at random, according to the weights.
+    pub(crate) fn pick(&self) -> Message {
+        match &self.banners[self.weights.sample(&mut thread_rng())] {
+            Message::Template(template) => Message::Template(template.clone()),
+            Message::Stream(stream) => Message::Stream(stream.clone()),
+        }
+    }
+}
+
+/// A message file too large to keep in memory, shared by all connections sending it.
+pub(crate) struct Stream {
+    file: Mutex<File>,
+}
+
+impl Stream {
+    /// Read a window of the file from an offset, returning it with line breaks as CRLF and the
+    /// number of bytes of the file it took; an empty window at the end of the file.
+    ///
+    /// A window ends with a line, unless a line doesn't even fit, and never within a UTF-8
+    /// code point either way.
+    fn read(
+        &self,
+        offset: u64,
+    ) -> io::Result<(Vec<u8>, usize)> {
+        let mut buffer = vec![0u8; WINDOW];
+        let read = {
+            let mut file = lock(&self.file);
+            file.seek(SeekFrom::Start(offset))?;
+            let mut read = 0;
+            while read < WINDOW {
+                match file.read(&mut buffer[read..])? {
+                    0 => break,
+                    count => read += count,
+                }
+            }
+            read
+        };
+        buffer.truncate(read);
+        let taken = if read < WINDOW {
+            read
+        } else if let Some(end) = buffer.iter().rposition(|&byte| byte == b'\n') {
+            end + 1
+        } else {
+            // Leave a code point cut short by the window to the next one.
+            let start = (read.saturating_sub(4)..read)
+                .rev()
+                .find(|&index| buffer[index] & 0xc0 != 0x80)
+                .unwrap_or(read);
+            let length = match buffer.get(start) {
+                Some(byte) if byte & 0xe0 == 0xc0 => 2,
+                Some(byte) if byte & 0xf0 == 0xe0 => 3,
+                Some(byte) if byte & 0xf8 == 0xf0 => 4,
+                _ => 1,
+            };
+            if start > 0 && start + length > read { start } else { read }
+        };
+        buffer.truncate(taken);
+
+        let mut window = Vec::with_capacity(buffer.len() + buffer.len() / 16);
+        let mut lines = buffer.split(|&byte| byte == b'\n').peekable();
+        while let Some(line) = lines.next() {
+            let last = lines.peek().is_none();
+            if last && line.is_empty() {
+                break;
+            }
+            window.extend_from_slice(line.strip_suffix(b"\r").unwrap_or(line));
+            // A line cut short by the window goes on in the next one, but the last line of
+            // the file ends like every other.
+            if !last || read < WINDOW {
+                window.extend_from_slice(b"\r\n");
+            }
+        }
+        Ok((window, taken))
+    }
+}
+
+/// A banner made up for a connection.
+pub(crate) enum Banner {
+    /// Rendered in memory.
+    Text(Arc<Vec<u8>>),
+    /// Read from its file while it is sent.
+    Stream(Arc<Stream>),
+}
+
+impl Banner {
+    /// Go through the banner from the start, a window at a time.
+    pub(crate) fn windows(&self) -> Windows {
+        Windows {
+            banner: match self {
+                Self::Text(text) => Self::Text(text.clone()),
+                Self::Stream(stream) => Self::Stream(stream.clone()),
+            },
+            offset: 0,
+            done:   false,
+        }
+    }
+}
+
+/// Where a connection is in its banner.
+pub(crate) struct Windows {
+    banner: Banner,
+    offset: u64,
+    done:   bool,
+}
+
+impl Windows {
+    /// The next window of the banner, `None` once it was sent completely.
+    pub(crate) async fn next(
+        &mut self,
+    ) -> io::Result<Option<Arc<Vec<u8>>>> {
+        if self.done {
+            return Ok(None);
+        }
+        match &self.banner {
+            Banner::Text(text) => {
+                self.done = true;
+                Ok(Some(text.clone()))
+            },
+            Banner::Stream(stream) => {
+                let stream = stream.clone();
+                let offset = self.offset;
+                let (window, taken) = tokio::task::spawn_blocking(move || stream.read(offset))
+                    .await
+                    .map_err(io::Error::other)??;
+                if taken == 0 {
+                    self.done = true;
+                    if offset == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "streamed banner is empty"));
+                    }
+                    return Ok(None);
+                }
+                self.offset += taken as u64;
+                Ok(Some(Arc::new(window)))
+            },
+        }
+    }
+}
+
+/// Filler lines slipped between the lines of a disguised banner.
+static FILLERS: &[&str] = &["", " ", "#", "# ", "--", "."];
+
+/// Shuffle the lines of a rendered banner, pad them with trailing whitespace and slip
+/// in some filler lines, so no two connections get quite the same first few bytes.
+pub(crate) fn disguise(
+    banner: &[u8],
+) -> Vec<u8> {
+    let mut rng = thread_rng();
+    let mut lines: Vec<&[u8]> = banner
+        .split(|&byte| byte == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .collect();
+    if let Some([]) = lines.last() {
+        lines.pop();
+    }
+    lines.shuffle(&mut rng);
+
+    let mut disguised = Vec::with_capacity(banner.len() * 2);
+    for line in lines {
+        if rng.gen_ratio(1, 8) {
+            if let Some(filler) = FILLERS.choose(&mut rng) {
+                disguised.extend_from_slice(filler.as_bytes());
+                disguised.extend_from_slice(b"\r\n");
+            }
+        }
+        disguised.extend_from_slice(line);
+        disguised.resize(disguised.len() + rng.gen_range(0, 4), b' ');
+        disguised.extend_from_slice(b"\r\n");
+    }
+    disguised
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::template::Context;
+
+    /// A message file of its own for every test.
+    fn message(
+        name: &str,
+        content: &str,
+    ) -> String {
+        let path = std::env::temp_dir().join(format!("tarssh-banner-{}-{}.txt", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn text(
+        message: Message,
+    ) -> String {
+        match message {
+            Message::Template(template) => String::from_utf8(template.render(&Context {
+                peer:          "192.0.2.1:50022".parse().unwrap(),
+                connection_id: 1,
+                uptime:        std::time::Duration::from_secs(0),
+                canary:        None,
+            }).to_vec()).unwrap(),
+            Message::Stream(_) => panic!("a small message got streamed"),
+        }
+    }
+
+    #[test]
+    fn parses_message_specs() {
+        let spec: MessageSpec = "motd.txt".parse().unwrap();
+        assert_eq!((spec.path.as_str(), spec.weight), ("motd.txt", 1));
+        let spec: MessageSpec = "motd.txt:3".parse().unwrap();
+        assert_eq!((spec.path.as_str(), spec.weight), ("motd.txt", 3));
+        // Whatever does not end in a weight is part of the path.
+        let spec: MessageSpec = "/srv/a:b.txt".parse().unwrap();
+        assert_eq!((spec.path.as_str(), spec.weight), ("/srv/a:b.txt", 1));
+        let spec: MessageSpec = "motd.txt:-1".parse().unwrap();
+        assert_eq!((spec.path.as_str(), spec.weight), ("motd.txt:-1", 1));
+    }
+
+    #[test]
+    fn rejects_message_specs() {
+        for spec in &["", ":3", "motd.txt:0"] {
+            assert!(spec.parse::<MessageSpec>().is_err(), "accepted \"{}\"", spec);
+        }
+    }
+
+    #[test]
+    fn loads_banners() {
+        let first = message("first", "one\ntwo\r\n");
+        let second = message("second", "three");
+        let specs: Vec<MessageSpec> = vec![first.parse().unwrap(), format!("{}:4000000000", second).parse().unwrap()];
+        let pool = BannerPool::load(&specs).unwrap();
+        assert_eq!(pool.len(), 2);
+        assert_eq!(text(BannerPool::load(&specs[..1]).unwrap().pick()), "one\r\ntwo\r\n");
+        // All but certainly the one weighing four billion times as much.
+        assert_eq!(text(pool.pick()), "three\r\n");
+        let _ = std::fs::remove_file(first);
+        let _ = std::fs::remove_file(second);
+    }
+
+    #[test]
+    fn falls_back_to_the_builtin_banner() {
+        let pool = BannerPool::load(&[]).unwrap();
+        assert_eq!(pool.len(), 1);
+        assert!(text(pool.pick()).starts_with("My name is Yon Yonson\r\n"));
+    }
+
+    #[test]
+    fn rejects_missing_files() {
+        let spec: MessageSpec = "/nonexistent/tarssh/motd.txt".parse().unwrap();
+        let err = BannerPool::load(&[spec]).err().expect("loaded a missing file");
+        assert!(err.to_string().starts_with("/nonexistent/tarssh/motd.txt: "), "{}", err);
+    }
+}
@@ -0,0 +1,126 @@
+use log::info;
+use std::{
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Mutex,
+};
+use super::{filter::Prefix, sync::lock};
+
+/// Networks whose connections are hung up on as soon as they are accepted, changed at runtime
+/// through the exporter and kept in a file, if there is one, one prefix per line.
+pub(crate) struct Bans {
+    prefixes: Mutex<Vec<Prefix>>,
+    path:     Option<PathBuf>,
+}
+
+/// A change of the bans.
+#[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+#[derive(Clone, Copy)]
+pub(crate) enum Change {
+    Add(Prefix),
+    Remove(Prefix),
+}
+
+impl Bans {
+    /// The bans kept in a file, none if it doesn't exist yet; blank lines and those starting
+    /// with `#` don't count.
+    pub(crate) fn load(
+        path: Option<PathBuf>,
+    ) -> Result<Self, String> {
+        let prefixes = match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(bans) => bans
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()
+                    .map_err(|err| format!("bans, path: {}, error: {}", path.display(), err))?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(err) => return Err(format!("bans, path: {}, error: {}", path.display(), err)),
+            },
+            None => Vec::new(),
+        };
+        if let Some(path) = &path {
+            info!("bans, path: {}, prefixes: {}", path.display(), prefixes.len());
+        }
+        Ok(Self {
+            prefixes: Mutex::new(prefixes),
+            path,
+        })
+    }
+
+    /// The first ban an address falls under.
+    pub(crate) fn find(
+        &self,
+        ip: IpAddr,
+    ) -> Option<Prefix> {
+        lock(&self.prefixes).iter().copied().find(|prefix| prefix.contains(ip))
+    }
+
+    /// Every ban, in the order added.
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    pub(crate) fn list(&self) -> Vec<Prefix> {
+        lock(&self.prefixes).clone()
+    }
+
+    /// Add or remove a ban, saved to the file before it takes effect; returns whether anything
+    /// changed.
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    pub(crate) fn change(
+        &self,
+        change: Change,
+    ) -> io::Result<bool> {
+        let mut prefixes = lock(&self.prefixes);
+        let mut changed = prefixes.clone();
+        match change {
+            Change::Add(prefix) if !changed.contains(&prefix) => changed.push(prefix),
+            Change::Remove(prefix) => changed.retain(|banned| *banned != prefix),
+            Change::Add(_) => {},
+        }
+        if changed == *prefixes {
+            return Ok(false);
+        }
+        if let Some(path) = &self.path {
+            // Written aside and renamed, so a crash never leaves half a file.
+            let mut saving = path.clone().into_os_string();
+            saving.push(".new");
+            let bans: String = changed.iter().map(|prefix| format!("{:?}\n", prefix)).collect();
+            std::fs::write(&saving, bans)?;
+            std::fs::rename(&saving, path)?;
+        }
+        *prefixes = changed;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_bans_in_a_file() {
+        let path = std::env::temp_dir().join(format!("tarssh-bans-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let bans = Bans::load(Some(path.clone())).unwrap();
+        assert!(bans.list().is_empty());
+        let v4: Prefix = "203.0.113.0/24".parse().unwrap();
+        let v6: Prefix = "2001:db8::/32".parse().unwrap();
+        assert!(bans.change(Change::Add(v4)).unwrap());
+        assert!(bans.change(Change::Add(v6)).unwrap());
+        assert!(!bans.change(Change::Add(v4)).unwrap());
+        assert_eq!(Bans::load(Some(path.clone())).unwrap().list(), vec![v4, v6]);
+        assert!(bans.change(Change::Remove(v4)).unwrap());
+        assert!(!bans.change(Change::Remove(v4)).unwrap());
+        let loaded = Bans::load(Some(path.clone())).unwrap();
+        assert_eq!(loaded.list(), vec![v6]);
+        assert_eq!(loaded.find("2001:db8::7".parse().unwrap()), Some(v6));
+        assert_eq!(loaded.find("203.0.113.7".parse().unwrap()), None);
+        std::fs::write(&path, "# by hand\n\n  203.0.113.0/24 \n").unwrap();
+        assert_eq!(Bans::load(Some(path.clone())).unwrap().list(), vec![v4]);
+        std::fs::write(&path, "203.0.113.0/24\nnowhere\n").unwrap();
+        assert!(Bans::load(Some(path.clone())).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+use log::info;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+use super::sync::lock;
+
+/// How often per second the budget is refilled.
+const TICKS_PER_SECOND: usize = 10;
+
+/// Bytes per second all connections together may send, handed out in a round-robin fashion.
+///
+/// Connections which have not sent anything yet go first, so a new client gets its first
+/// chunk promptly even when thousands of older ones are waiting for their turn.
+pub(crate) struct Budget {
+    per_second: usize,
+    state:      Mutex<State>,
+}
+
+struct State {
+    available:  usize,
+    fresh:      VecDeque<Waiter>,
+    waiting:    VecDeque<Waiter>,
+}
+
+struct Waiter {
+    bytes:  usize,
+    wake:   oneshot::Sender<()>,
+}
+
+impl Budget {
+    /// The budget, shared by the connections and the task refilling it.
+    pub(crate) fn new(
+        per_second: usize,
+    ) -> Arc<Self> {
+        info!("budget, bytes_per_second: {}", per_second);
+        Arc::new(Self {
+            per_second,
+            state: Mutex::new(State {
+                available:  per_second,
+                fresh:      VecDeque::new(),
+                waiting:    VecDeque::new(),
+            }),
+        })
+    }
+
+    /// Wait until there is budget to send so many bytes, and spend it.
+    pub(crate) async fn spend(
+        &self,
+        bytes: usize,
+        fresh: bool,
+    ) {
+        // A chunk larger than the whole budget would never get its turn otherwise.
+        let bytes = bytes.min(self.per_second);
+        let receiver = {
+            let mut state = lock(&self.state);
+            if state.available >= bytes
+            && state.fresh.is_empty()
+            && (fresh || state.waiting.is_empty())
+            {
+                state.available -= bytes;
+                return;
+            }
+            let (wake, receiver) = oneshot::channel();
+            let waiter = Waiter { bytes, wake };
+            if fresh {
+                state.fresh.push_back(waiter);
+            } else {
+                state.waiting.push_back(waiter);
+            }
+            receiver
+        };
+        let _ = receiver.await;
+    }
+
+    /// Top up the budget and wake whoever's turn it is, forever.
+    pub(crate) async fn refill(
+        self: Arc<Self>,
+    ) {
+        let mut ticks = tokio::time::interval(Duration::from_secs(1) / TICKS_PER_SECOND as u32);
+        loop {
+            ticks.tick().await;
+            let mut state = lock(&self.state);
+            let state = &mut *state;
+            state.available = (state.available + (self.per_second / TICKS_PER_SECOND).max(1)).min(self.per_second);
+            loop {
+                let queue = if state.fresh.is_empty() {
+                    &mut state.waiting
+                } else {
+                    &mut state.fresh
+                };
+                match queue.front() {
+                    Some(waiter) if waiter.bytes <= state.available => {
+                        if let Some(waiter) = queue.pop_front() {
+                            // Clients which went away in the meantime don't spend anything.
+                            if waiter.wake.send(()).is_ok() {
+                                state.available -= waiter.bytes;
+                            }
+                        }
+                    },
+                    _ => break,
+                }
+            }
+        }
+    }
+}
+
+/// A number of bytes per second, which must be positive.
+pub(crate) fn parse_rate(
+    rate: &str,
+) -> Result<usize, String> {
+    match rate.parse::<usize>() {
+        Ok(0) => Err("bytes per second must be at least 1".to_owned()),
+        Ok(rate) => Ok(rate),
+        Err(err) => Err(format!("invalid bytes per second \"{}\": {}", rate, err)),
+    }
+}
+
+impl fmt::Debug for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Budget")
+            .field("per_second", &self.per_second)
+            .finish()
+    }
+}
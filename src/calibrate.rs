@@ -0,0 +1,357 @@
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+use super::{failure::Failure, instances, Config};
+
+/// Longest delay worth considering, as the default of --max-delay.
+const MAX_DELAY: u64 = 300;
+
+/// Longest delay before the first chunk worth considering.
+const MAX_FIRST: u64 = 60;
+
+/// Chunk sizes worth considering.
+const CHUNK_SIZES: &[usize] = &[1, 2, 4, 8, 16, 32, 64];
+
+/// Time to wait for the target to connect or send a chunk.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// When an SSH client gives up on a server which never sends its version string, by default.
+#[derive(Debug)]
+struct Client {
+    name:   &'static str,
+    /// Share of the clients out there, a rough guess to be overridden with --weight.
+    weight: u32,
+    /// Time the client waits for the first byte.
+    first:  Option<Duration>,
+    /// Time the client waits for the next chunk.
+    idle:   Option<Duration>,
+    /// Lines the client reads before the version string at most.
+    lines:  Option<usize>,
+    /// Bytes the client reads before the version string at most.
+    bytes:  Option<usize>,
+}
+
+static CLIENTS: &[Client] = &[
+    // Without ConnectTimeout, it waits forever, but for 1024 lines.
+    Client {
+        name:   "openssh",
+        weight: 40,
+        first:  None,
+        idle:   None,
+        lines:  Some(1024),
+        bytes:  None,
+    },
+    Client {
+        name:   "putty",
+        weight: 5,
+        first:  None,
+        idle:   None,
+        lines:  None,
+        bytes:  None,
+    },
+    // The timeout of a session applies to every single read.
+    Client {
+        name:   "libssh",
+        weight: 20,
+        first:  Some(Duration::from_secs(10)),
+        idle:   Some(Duration::from_secs(10)),
+        lines:  None,
+        bytes:  None,
+    },
+    // The banner timeout applies to the first line, two seconds to every other one of at
+    // most 100.
+    Client {
+        name:   "paramiko",
+        weight: 15,
+        first:  Some(Duration::from_secs(15)),
+        idle:   Some(Duration::from_secs(2)),
+        lines:  Some(100),
+        bytes:  None,
+    },
+    // golang.org/x/crypto/ssh counts every byte before the version string against its 255.
+    Client {
+        name:   "go",
+        weight: 20,
+        first:  None,
+        idle:   None,
+        lines:  None,
+        bytes:  Some(255),
+    },
+];
+
+/// What to calibrate the delays against.
+#[derive(Debug, StructOpt)]
+pub(crate) struct CalibrateConfig {
+    /// Address of a running tarpit to probe, to see how long it holds each client.
+    #[structopt(long = "target")]
+    target: Option<SocketAddr>,
+    /// Chunks to wait for when probing.
+    #[structopt(long = "chunks", default_value = "3")]
+    chunks: usize,
+    /// Share of a client among all clients (e.g. "go=50"), instead of the built-in guess.
+    #[structopt(short = "w", long = "weight")]
+    weights: Vec<Weight>,
+    /// Average bytes per line of the message.
+    #[structopt(long = "line-length", default_value = "32")]
+    line_length: usize,
+    /// Time beyond which holding a client on is worth nothing more, e.g. --max-session.
+    #[structopt(long = "horizon", default_value = "1day", parse(try_from_str = humantime::parse_duration))]
+    horizon: Duration,
+    /// Name of the instance of the config file fragment recommended.
+    #[structopt(long = "instance", default_value = "calibrated", parse(try_from_str = instance))]
+    instance: String,
+}
+
+/// The name of an instance of a config file.
+fn instance(
+    name: &str,
+) -> Result<String, String> {
+    if instances::valid_name(name) {
+        Ok(name.to_owned())
+    } else {
+        Err(format!("invalid instance name \"{}\", expected letters, digits, -, _ or .", name))
+    }
+}
+
+/// The share of a client, e.g. `go=50`.
+#[derive(Debug)]
+struct Weight {
+    name:   &'static str,
+    weight: u32,
+}
+
+impl FromStr for Weight {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let index = spec
+            .find('=')
+            .ok_or_else(|| format!("invalid weight \"{}\", expected client=weight", spec))?;
+        let client = CLIENTS
+            .iter()
+            .find(|client| client.name == &spec[..index])
+            .ok_or_else(|| format!(
+                "unknown client \"{}\", expected one of: {}",
+                &spec[..index],
+                CLIENTS.iter().map(|client| client.name).collect::<Vec<_>>().join(", "),
+            ))?;
+        let weight = spec[index + 1..]
+            .parse()
+            .map_err(|err| format!("invalid weight \"{}\": {}", spec, err))?;
+        Ok(Self {
+            name: client.name,
+            weight,
+        })
+    }
+}
+
+/// How a tarpit paces a connection.
+#[derive(Clone, Copy, Debug)]
+struct Pacing {
+    /// Delay before the first chunk.
+    first:      Duration,
+    /// Delay between all further chunks.
+    delay:      Duration,
+    chunk_size: usize,
+}
+
+impl Pacing {
+    /// Time a client stays before giving up, at most `horizon`.
+    fn hold(
+        &self,
+        client: &Client,
+        line_length: usize,
+        horizon: Duration,
+    ) -> Duration {
+        if let Some(first) = client.first.filter(|&first| self.first >= first) {
+            return first.min(horizon);
+        }
+        if let Some(idle) = client.idle.filter(|&idle| self.delay >= idle) {
+            return (self.first + idle).min(horizon);
+        }
+        let limit = match (client.lines.map(|lines| lines * line_length), client.bytes) {
+            (Some(lines), Some(bytes)) => Some(lines.min(bytes)),
+            (lines, bytes) => lines.or(bytes),
+        };
+        match limit {
+            // The client gives up with the chunk holding the last byte it would read.
+            Some(limit) => {
+                let chunks = limit.div_ceil(self.chunk_size.max(1)).saturating_sub(1);
+                self.delay
+                    .checked_mul(chunks.min(u32::MAX as usize) as u32)
+                    .and_then(|rest| rest.checked_add(self.first))
+                    .map_or(horizon, |hold| hold.min(horizon))
+            },
+            None => horizon,
+        }
+    }
+
+    /// Bytes per second a connection costs.
+    fn rate(&self) -> f64 {
+        self.chunk_size as f64 / self.delay.as_secs_f64()
+    }
+
+    /// The command line to get this pacing.
+    fn args(&self) -> Vec<String> {
+        let mut args = if self.first == self.delay {
+            vec!["--delay".to_owned(), self.delay.as_secs().to_string()]
+        } else {
+            // Only the first chunk is sent before the connection is a second old.
+            vec![
+                "--tiers".to_owned(),
+                format!("1s={}s,{}s", self.first.as_secs(), self.delay.as_secs()),
+            ]
+        };
+        args.push("--chunk-size".to_owned());
+        args.push(self.chunk_size.to_string());
+        args
+    }
+
+    /// The section of a config file to get this pacing for an instance, of the same options
+    /// as the command line.
+    fn fragment(
+        &self,
+        instance: &str,
+    ) -> String {
+        let mut fragment = format!("[{}]\n", instance);
+        for option in self.args().chunks(2) {
+            fragment.push_str(&format!("{} = {}\n", option[0].trim_start_matches("--"), option[1]));
+        }
+        fragment
+    }
+}
+
+/// Expected time clients are held, weighted by their shares.
+fn expected(
+    pacing: &Pacing,
+    clients: &[(&Client, u32)],
+    config: &CalibrateConfig,
+) -> Duration {
+    let total: u32 = clients.iter().map(|(_, weight)| weight).sum();
+    let held: f64 = clients
+        .iter()
+        .map(|(client, weight)| {
+            pacing.hold(client, config.line_length, config.horizon).as_secs_f64() * f64::from(*weight)
+        })
+        .sum();
+    Duration::from_secs_f64(if total == 0 { 0.0 } else { held / f64::from(total) })
+}
+
+/// The pacing holding clients longest, the cheapest of them if several do.
+fn recommend(
+    clients: &[(&Client, u32)],
+    config: &CalibrateConfig,
+) -> (Pacing, Duration) {
+    let mut best: Option<(Pacing, Duration)> = None;
+    for first in 1..=MAX_FIRST {
+        for delay in 1..=MAX_DELAY {
+            for &chunk_size in CHUNK_SIZES {
+                let pacing = Pacing {
+                    first: Duration::from_secs(first),
+                    delay: Duration::from_secs(delay),
+                    chunk_size,
+                };
+                let held = expected(&pacing, clients, config);
+                let better = best.as_ref().is_none_or(|(best, best_held)| {
+                    held > *best_held || (held == *best_held && pacing.rate() < best.rate())
+                });
+                if better {
+                    best = Some((pacing, held));
+                }
+            }
+        }
+    }
+    best.expect("there is always a pacing to consider")
+}
+
+/// Connect to a running tarpit like a client would, and time its first few chunks.
+fn probe(
+    target: SocketAddr,
+    chunks: usize,
+) -> std::io::Result<Pacing> {
+    let mut sock = TcpStream::connect_timeout(&target, PROBE_TIMEOUT)?;
+    sock.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    let start = Instant::now();
+    sock.write_all(format!("SSH-2.0-tarssh_{}_calibrate\r\n", env!("CARGO_PKG_VERSION")).as_bytes())?;
+    let mut buffer = [0u8; 4096];
+    let mut arrivals = Vec::with_capacity(chunks);
+    let mut chunk_size = 0;
+    while arrivals.len() < chunks.max(2) {
+        let read = sock.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        arrivals.push(start.elapsed());
+        chunk_size = chunk_size.max(read);
+        println!("probe, target: {}, after: {:.1?}, bytes: {}", target, arrivals[arrivals.len() - 1], read);
+    }
+    match arrivals.as_slice() {
+        [first, .., last] => Ok(Pacing {
+            first:  *first,
+            delay:  (*last - *first) / (arrivals.len() - 1) as u32,
+            chunk_size,
+        }),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "closed by the target")),
+    }
+}
+
+fn print(
+    label: &str,
+    pacing: &Pacing,
+    clients: &[(&Client, u32)],
+    config: &CalibrateConfig,
+) {
+    println!(
+        "{}: first chunk after {:.1?}, then {} bytes every {:.1?}, {:.2} bytes/s per connection",
+        label,
+        pacing.first,
+        pacing.chunk_size,
+        pacing.delay,
+        pacing.rate(),
+    );
+    for (client, weight) in clients {
+        let held = Duration::from_secs(pacing.hold(client, config.line_length, config.horizon).as_secs());
+        println!("  {:<10} weight {:>3}  held {}", client.name, weight, humantime::format_duration(held));
+    }
+    let held = Duration::from_secs(expected(pacing, clients, config).as_secs());
+    println!("  expected hold {}", humantime::format_duration(held));
+}
+
+/// Recommend the delays holding the clients longest, and how long a running tarpit holds them.
+pub(crate) fn run(
+    config: CalibrateConfig,
+) {
+    let clients: Vec<(&Client, u32)> = CLIENTS
+        .iter()
+        .map(|client| {
+            let weight = config.weights
+                .iter()
+                .rev()
+                .find(|weight| weight.name == client.name)
+                .map_or(client.weight, |weight| weight.weight);
+            (client, weight)
+        })
+        .collect();
+
+    if let Some(target) = config.target {
+        match probe(target, config.chunks) {
+            Ok(pacing) => print(&format!("probed {}", target), &pacing, &clients, &config),
+            Err(err) => Failure::Runtime.exit(format!("calibrate, target: {}, error: {}", target, err)),
+        }
+    }
+
+    let (pacing, _) = recommend(&clients, &config);
+    print("recommended", &pacing, &clients, &config);
+    let args = pacing.args();
+    // Whatever is recommended has to make it through the command line of the tarpit.
+    if let Err(err) = Config::from_iter_safe(std::iter::once("tarssh".to_owned()).chain(args.iter().cloned())) {
+        Failure::Config.exit(format!("calibrate, args: {}, error: {}", args.join(" "), err.message));
+    }
+    println!("  tarssh {}", args.join(" "));
+    println!("or in a --config file:");
+    print!("{}", pacing.fragment(&config.instance));
+}
@@ -0,0 +1,270 @@
+use std::{
+    io::Write,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "alerts")]
+use super::alerts::{Alerts, Thresholds};
+use super::{
+    banner::{self, Banner, BannerPool, Message},
+    bans::Bans,
+    listeners::Defaults,
+    metrics::{History, Metrics},
+    profiles::{self, PROFILES},
+    runtime::Runtime,
+    shared::Shared,
+    surprises::{Surprises, SURPRISES},
+    tarpit::{ChunkSize, Disconnect, Echo, TarpitConfig},
+    template::{Context, Template},
+    watermark::Watermark,
+};
+
+/// Longest line allowed, CRLF included; RFC 4253 only caps the version string like this,
+/// but clients tend to apply the same limit to the lines before it.
+const MAX_LINE: usize = 255;
+
+/// Rounds of anything random, to get to see most of what it can come up with.
+const ROUNDS: usize = 100;
+
+/// Chunk sizes to cut content with, as used by the profiles and then some.
+const CHUNK_SIZES: &[(usize, usize)] = &[(1, 1), (2, 2), (3, 7), (4, 16), (8, 24), (16, 32)];
+
+fn peers() -> Vec<SocketAddr> {
+    vec![
+        "192.0.2.1:50022".parse().unwrap(),
+        "[2001:db8::1]:50022".parse().unwrap(),
+        "[2001:db8:ffff:ffff:ffff:ffff:ffff:ffff]:65535".parse().unwrap(),
+    ]
+}
+
+/// Check content, as it goes over the wire before the version string, against RFC 4253:
+/// UTF-8, lines terminated by CRLF, none of them beginning with "SSH-" or longer than
+/// `MAX_LINE`, and no chunk ever cutting a code point or a line break in two.
+fn assert_compliant(
+    what: &str,
+    content: &[u8],
+) {
+    assert!(std::str::from_utf8(content).is_ok(), "{}: not UTF-8: {:?}", what, String::from_utf8_lossy(content));
+    assert!(content.is_empty() || content.ends_with(b"\r\n"), "{}: not terminated by CRLF", what);
+    for line in content.split_inclusive(|&byte| byte == b'\n') {
+        let text = String::from_utf8_lossy(line);
+        assert!(line.ends_with(b"\r\n"), "{}: line not terminated by CRLF: {:?}", what, text);
+        assert!(!line[..line.len() - 2].contains(&b'\r'), "{}: bare CR in line: {:?}", what, text);
+        assert!(!line.starts_with(b"SSH-"), "{}: line begins with \"SSH-\": {:?}", what, text);
+        assert!(line.len() <= MAX_LINE, "{}: line of {} bytes: {:?}", what, line.len(), text);
+    }
+
+    for &(min, max) in CHUNK_SIZES {
+        let mut sent = Vec::with_capacity(content.len());
+        for chunk in ChunkSize::new(min, max).unwrap().chunks(content) {
+            assert!(!chunk.is_empty(), "{}: empty chunk of {}-{}", what, min, max);
+            if !sent.is_empty() {
+                let cut = sent.len();
+                assert!(content[cut] & 0xc0 != 0x80, "{}: chunk of {}-{} cuts a code point at {}", what, min, max, cut);
+                assert!(content[cut - 1..=cut] != *b"\r\n", "{}: chunk of {}-{} cuts a CRLF at {}", what, min, max, cut);
+            }
+            sent.extend_from_slice(chunk);
+        }
+        assert_eq!(sent, content, "{}: chunks of {}-{} don't add up", what, min, max);
+    }
+}
+
+/// Every way a pool of banners can come out for a connection.
+fn assert_pool(
+    what: &str,
+    pool: &BannerPool,
+) {
+    for peer in peers() {
+        for _ in 0..ROUNDS {
+            let template = match pool.pick() {
+                Message::Template(template) => template,
+                Message::Stream(_) => panic!("{}: streamed banner in a builtin pool", what),
+            };
+            let banner = template.render(&Context {
+                peer,
+                connection_id:  usize::MAX,
+                uptime:         Duration::from_secs(400 * 24 * 3600),
+                canary:         Some(super::template::canary()),
+            });
+            assert_compliant(what, &banner);
+            assert_compliant(&format!("{}, disguised", what), &banner::disguise(&banner));
+        }
+    }
+}
+
+#[test]
+fn default_banner() {
+    let pool = BannerPool::load(&[]).unwrap();
+    assert_pool("default banner", &pool);
+}
+
+#[test]
+fn profiles() {
+    for profile in PROFILES {
+        assert_compliant(&format!("profile {}, preamble", profile.name), &profile.preamble());
+        assert_pool(&format!("profile {}, banner", profile.name), &profile.banners());
+    }
+}
+
+#[test]
+fn decoys() {
+    // Asking for more than there are gets all of them.
+    assert_compliant("decoys", &profiles::decoys(usize::MAX));
+    for count in 0..8 {
+        assert_compliant("decoys", &profiles::decoys(count));
+    }
+}
+
+#[test]
+fn surprises() {
+    for surprise in SURPRISES {
+        assert_compliant(&format!("surprise {}", surprise.name), surprise.content);
+    }
+}
+
+#[test]
+fn templates() {
+    let template = Template::parse(concat!(
+        "Client {peer}, connection {connection_id}, at {timestamp}\r\n",
+        "Up for {uptime}, canary {canary}\r\n",
+        "Unknown {placeholders} and {unbalanced braces stay\r\n",
+    ));
+    for peer in peers() {
+        let banner = template.render(&Context {
+            peer,
+            connection_id:  usize::MAX,
+            uptime:         Duration::from_secs(400 * 24 * 3600 + 3661),
+            canary:         Some(super::template::canary()),
+        });
+        assert_compliant("template", &banner);
+    }
+}
+
+#[test]
+fn echo() {
+    let inputs: &[&[u8]] = &[
+        b"SSH-2.0-OpenSSH_9.6\r\n",
+        b"SSH-2.0-Go\n",
+        b"no line break at all",
+        b"\r\r\r\n\n\n",
+        b"\x00\x01\x02\xff\xfe\x1b[2J\x7f",
+        "Say “Hey, what’s your name?”\r\n".as_bytes(),
+        &[b'x'; 4096],
+    ];
+    for &(_, max) in CHUNK_SIZES {
+        for input in inputs {
+            let echo = Echo::default();
+            echo.push(input);
+            while let Some(line) = echo.line(max) {
+                assert_compliant("echo", &line);
+            }
+        }
+    }
+}
+
+#[test]
+fn scripts() {
+    let runtime = Runtime::new(None, None);
+    let shared = Arc::new(Shared { geoip: None, asn_db: None, bans: Bans::load(None).unwrap() });
+    let metrics = Metrics::new(&runtime, History { peers: 16, sessions: 16 }, "", shared, Duration::default());
+    #[cfg(feature = "alerts")]
+    let alerts = Alerts::new(Vec::new(), Vec::new(), None, Thresholds {
+        connections:    None,
+        rejects:        None,
+        peers:          None,
+    });
+    let watermark: Arc<Watermark> = Arc::new("secret".parse().unwrap());
+    let defaults = Defaults {
+        config:     TarpitConfig {
+            delay:          Duration::from_secs(10),
+            timeout:        Duration::from_secs(30),
+            write_retries:  0,
+            max_session:    None,
+            backoff:        None,
+            tiers:          None,
+            chunk_size:     ChunkSize::new(1, 1).unwrap(),
+            budget:         None,
+            bandwidth:      None,
+            disconnect:     Disconnect::Fin,
+            strict:         None,
+            surprises:      Surprises::builtin(),
+            keepalive:      None,
+        },
+        banners:    Arc::new(BannerPool::load(&[]).unwrap()),
+        profile:    None,
+        decoys:     8,
+        disguise:   true,
+        echo:       true,
+        watermark:  Some(watermark),
+        shed:       None,
+        sample:     "100%".parse().unwrap(),
+        #[cfg(feature = "exporters")]
+        monitors:   Arc::default(),
+    };
+    for peer in peers() {
+        for profile in PROFILES {
+            // Panics on a chunk size range of a profile that isn't one.
+            profile.config(defaults.config.clone());
+            let preamble = Arc::new(profile.preamble());
+            let (_, token) = metrics.connect(peer.ip(), peer, Instant::now()).unwrap();
+            let script = defaults.script(
+                &preamble,
+                peer,
+                &token,
+                &metrics,
+                #[cfg(feature = "alerts")]
+                &alerts,
+            );
+            assert_compliant("script, preamble", &script.preamble);
+            match script.banner {
+                Banner::Text(banner) => assert_compliant("script, banner", &banner),
+                Banner::Stream(_) => panic!("script, banner: streamed builtin banner"),
+            }
+            let _ = metrics.disconnect(token);
+        }
+    }
+}
+
+#[test]
+fn streamed() {
+    let path = std::env::temp_dir().join(format!("tarssh-compliance-{}.txt", std::process::id()));
+    {
+        let mut file = std::fs::File::create(&path).unwrap();
+        let mut written = 0;
+        for line in 0u64.. {
+            // Mixed line breaks, lines of all lengths, and code points of all widths.
+            let text = format!(
+                "{} Say “Hey, what’s your name?” 🐑{}{}",
+                line,
+                "x".repeat((line % 200) as usize),
+                if line % 3 == 0 { "\r\n" } else { "\n" },
+            );
+            file.write_all(text.as_bytes()).unwrap();
+            written += text.len();
+            if written > 3 << 20 {
+                break;
+            }
+        }
+        // Not terminated at all.
+        file.write_all(b"the end").unwrap();
+    }
+
+    let pool = BannerPool::load(&[path.to_str().unwrap().parse().unwrap()]).unwrap();
+    let stream = match pool.pick() {
+        Message::Stream(stream) => stream,
+        Message::Template(_) => panic!("streamed: message file loaded into memory"),
+    };
+    let mut runtime = Runtime::new(None, None);
+    let total = runtime.block_on(async {
+        let mut windows = Banner::Stream(stream).windows();
+        let mut total = 0;
+        while let Some(window) = windows.next().await.unwrap() {
+            assert_compliant("streamed window", &window);
+            total += window.len();
+        }
+        total
+    });
+    std::fs::remove_file(&path).unwrap();
+    assert!(total > 3 << 20, "streamed: only {} bytes", total);
+}
@@ -0,0 +1,205 @@
+use hyper::{body, header::AUTHORIZATION, Body, Client, Method, Request, Uri};
+#[cfg(unix)]
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+use std::time::Duration;
+use structopt::StructOpt;
+use super::{
+    exporters,
+    failure::Failure,
+    filter::Prefix,
+    summary::Stats,
+};
+
+/// How to reach the exporter of a running tarssh.
+#[derive(Debug, StructOpt)]
+pub(crate) struct Remote {
+    /// Exporter of the running tarssh.
+    #[structopt(long = "url", default_value = "http://127.0.0.1:8080")]
+    url:  Uri,
+    /// Credentials the exporter asks for, as "bearer:<token>" or "basic:<user>:<password>".
+    #[structopt(long = "auth", parse(try_from_str = exporters::authorization))]
+    auth: Option<String>,
+}
+
+impl Remote {
+    /// Send a request to a path of the exporter, returning the body of the response if it
+    /// succeeded, and exiting otherwise.
+    fn request(
+        &self,
+        method: Method,
+        path: &str,
+    ) -> String {
+        let uri = format!("{}{}", self.url.to_string().trim_end_matches('/'), path);
+        let mut request = Request::builder().method(method).uri(uri.as_str());
+        if let Some(auth) = &self.auth {
+            request = request.header(AUTHORIZATION, auth.as_str());
+        }
+        let request = request
+            .body(Body::empty())
+            .unwrap_or_else(|err| Failure::Config.exit(format!("control, uri: {}, error: {}", uri, err)));
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap_or_else(|err| Failure::Runtime.exit(format!("runtime, error: {}", err)));
+        let response = runtime.block_on(async {
+            let response = Client::new().request(request).await?;
+            let status = response.status();
+            body::to_bytes(response.into_body()).await.map(|body| (status, body))
+        });
+        match response {
+            Ok((status, body)) if status.is_success() => String::from_utf8_lossy(&body).into_owned(),
+            Ok((status, body)) => Failure::Remote.exit(format!(
+                "control, uri: {}, status: {}, error: {}",
+                uri,
+                status,
+                String::from_utf8_lossy(&body).trim(),
+            )),
+            Err(err) => Failure::Remote.exit(format!("control, uri: {}, error: {}", uri, err)),
+        }
+    }
+}
+
+/// Which clients of a running tarssh to hang up on.
+#[derive(Debug, StructOpt)]
+pub(crate) struct KickConfig {
+    #[structopt(flatten)]
+    remote: Remote,
+    /// Id of a connection, as logged when it was accepted, or an address or network (e.g.
+    /// "203.0.113.0/24") to hang up on all clients of.
+    target: String,
+}
+
+/// Hang up on clients of a running tarssh and tell how many.
+pub(crate) fn kick(
+    config: KickConfig,
+) {
+    let query = match config.target.parse::<usize>() {
+        Ok(id) => format!("id={}", id),
+        Err(_) => match config.target.parse::<Prefix>() {
+            Ok(_) => format!("peer={}", config.target),
+            Err(err) => Failure::Config.exit(format!("kick, target: {}, error: {}", config.target, err)),
+        },
+    };
+    let kicked = config.remote.request(Method::POST, &format!("/kick?{}", query));
+    println!("kicked: {}", kicked.trim());
+}
+
+/// The control socket to list the clients of a running tarssh through.
+#[cfg(unix)]
+#[derive(Debug, StructOpt)]
+pub(crate) struct SessionsConfig {
+    /// Control socket of the running tarssh.
+    #[structopt(long = "socket", parse(from_os_str))]
+    socket: PathBuf,
+}
+
+/// Print the clients connected to a running tarssh in a table, trapped the longest first.
+#[cfg(unix)]
+pub(crate) fn sessions(
+    config: SessionsConfig,
+) {
+    let answer = ask(&config.socket, "show sessions");
+    let mut lines = answer.lines();
+    let header: Vec<_> = lines
+        .next()
+        .and_then(|header| header.strip_prefix("# "))
+        .unwrap_or_default()
+        .split(' ')
+        .collect();
+    let column = |name: &str| header.iter().position(|known| *known == name);
+    let columns = [
+        ("INSTANCE", column("instance")),
+        ("PEER", column("peer")),
+        ("TRAPPED", column("trapped_seconds")),
+        ("CHUNKS", column("chunks")),
+        ("LISTENER", column("listener")),
+    ];
+    let columns: Vec<_> = columns.iter().filter_map(|(title, index)| index.map(|index| (*title, index))).collect();
+    let mut rows = vec![columns.iter().map(|(title, _)| title.to_string()).collect::<Vec<_>>()];
+    for line in lines {
+        let fields: Vec<_> = line.split(' ').collect();
+        rows.push(columns.iter().map(|(title, index)| {
+            let field = fields.get(*index).copied().unwrap_or_default();
+            match (*title, field.parse()) {
+                ("TRAPPED", Ok(seconds)) => humantime::format_duration(Duration::from_secs(seconds)).to_string(),
+                _ => field.to_owned(),
+            }
+        }).collect());
+    }
+    let widths: Vec<_> = (0..columns.len())
+        .map(|index| rows.iter().map(|row| row[index].chars().count()).max().unwrap_or(0))
+        .collect();
+    for row in rows {
+        let cells: Vec<_> = row.iter().zip(&widths).map(|(cell, width)| format!("{:<1$}", cell, width)).collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+/// Where to ask a running tarssh how it is doing.
+#[derive(Debug, StructOpt)]
+pub(crate) struct StatsConfig {
+    #[structopt(flatten)]
+    remote: Remote,
+    /// Control socket of the running tarssh, asked rather than its exporter.
+    #[cfg(unix)]
+    #[structopt(long = "socket", parse(from_os_str))]
+    socket: Option<PathBuf>,
+}
+
+/// Print the uptime, clients, time trapped and record of a running tarssh.
+pub(crate) fn stats(
+    config: StatsConfig,
+) {
+    #[cfg(unix)]
+    {
+        if let Some(path) = &config.socket {
+            return print!("{}", ask(path, "show stats"));
+        }
+    }
+    let export = config.remote.request(Method::GET, "/metrics");
+    let mut stats = Stats::default();
+    for family in exporters::families(&export) {
+        let values = family.samples.iter().map(|(_, _, value)| *value);
+        let seconds = |value: f64| Duration::from_secs(value.max(0.0) as u64);
+        match family.name {
+            "uptime_seconds" => stats.uptime = seconds(values.fold(0.0, f64::max)),
+            "connections_count" => stats.clients = values.sum::<f64>() as usize,
+            "wasted_seconds" => stats.trapped = seconds(values.sum()),
+            "total_maximum_connection_time_seconds" => stats.record = seconds(values.fold(0.0, f64::max)),
+            _ => {},
+        }
+    }
+    print!("{}", stats);
+}
+
+/// Send a command to a control socket, returning its answer, and exiting if it failed.
+#[cfg(unix)]
+fn ask(
+    path: &std::path::Path,
+    command: &str,
+) -> String {
+    let fail = |err: std::io::Error| -> ! {
+        Failure::Remote.exit(format!("control, path: {}, error: {}", path.display(), err))
+    };
+    let mut sock = UnixStream::connect(path).unwrap_or_else(|err| fail(err));
+    sock.write_all(format!("{}\n", command).as_bytes()).unwrap_or_else(|err| fail(err));
+    // An answer ends with an empty line, and the socket stays open for more.
+    let mut answer = Vec::new();
+    let mut buf = [0; 4096];
+    while !answer.ends_with(b"\n\n") {
+        match sock.read(&mut buf).unwrap_or_else(|err| fail(err)) {
+            0 => break,
+            read => answer.extend_from_slice(&buf[..read]),
+        }
+    }
+    let answer = String::from_utf8_lossy(&answer).trim_end().to_owned() + "\n";
+    match answer.strip_prefix("error: ") {
+        Some(err) => Failure::Remote.exit(format!("control, path: {}, error: {}", path.display(), err.trim())),
+        None => answer,
+    }
+}
@@ -1,83 +1,1348 @@
-use log::info;
+use log::{debug, info, warn};
 
 use hyper::{
-    Body, Request, Response, Server,
+    header::{ACCEPT, ALLOW, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    Body, Method, Request, Response, Server, StatusCode,
     server::{
+        accept::{self, Accept},
         Builder,
-        conn::AddrIncoming,
+        conn::{AddrIncoming, Http},
     },
     service::{make_service_fn, service_fn},
 };
 
+#[cfg(not(feature = "aggregate_only"))]
+use std::{collections::HashMap, net::IpAddr, time::{SystemTime, UNIX_EPOCH}};
 use std::{
     convert::Infallible,
-    net::SocketAddr,
+    fmt,
+    io,
+    net::{AddrParseError, SocketAddr},
+    str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
+#[cfg(not(feature = "aggregate_only"))]
 use super::{
+    filter::Prefix,
+    metrics::{Archived, Detail, Event, Kick, PeerHistory},
+};
+#[cfg(not(feature = "aggregate_only"))]
+use futures::{
+    future::{self, Either},
+    pin_mut,
+    stream::{self, Stream},
+};
+#[cfg(not(feature = "aggregate_only"))]
+use tokio::sync::broadcast::RecvError;
+use super::{
+    bans::{Bans, Change},
+    shared::Shared,
+    failure::Failure,
+    handover::Handover,
+    json::json,
     metrics::Metrics,
+    openmetrics,
+    reload::Reloader,
     runtime::Runtime,
+    watermark::sha256,
 };
 
+#[cfg(feature = "profiling")]
+use super::profiling;
+#[cfg(feature = "tls")]
+use super::tls::{Acceptor, TlsStream};
+#[cfg(feature = "tls")]
+use tokio::{net::TcpListener, sync::mpsc};
+#[cfg(unix)]
+use std::{os::unix::fs::FileTypeExt, path::PathBuf};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time::{delay_for, timeout},
+};
+
+/// How long a monitor on a tarpit listener may take to send its request.
+const MONITOR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client of the exporter may take for its TLS handshake.
+#[cfg(feature = "tls")]
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections through their TLS handshakes that may wait for the exporter to take them.
+#[cfg(feature = "tls")]
+const HANDSHAKES: usize = 16;
+
+/// Peers `/top` lists unless asked for another number.
+#[cfg(not(feature = "aggregate_only"))]
+const TOP_PEERS: usize = 10;
+
+/// Lengths of the networks `/top?per=network` adds peers up by, as botnets come from whole
+/// subnets: a /24 of IPv4, and a /64 of IPv6, what a single site usually gets.
+#[cfg(not(feature = "aggregate_only"))]
+const NETWORK_V4: u8 = 24;
+#[cfg(not(feature = "aggregate_only"))]
+const NETWORK_V6: u8 = 64;
+
+/// A page of the most interesting metrics, live from `/stats.json` and `/top`.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Media type of the classic text format.
+const TEXT_FORMAT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// Built with `aggregate_only`, the exporter may only ever serve aggregates: anything
+// revealing single peers (their addresses as labels, per-peer endpoints, top talkers)
+// has to be left out at compile time with `#[cfg(not(feature = "aggregate_only"))]`.
+
+/// Credentials a scraper of the exporter has to send, as `bearer:<token>` or
+/// `basic:<user>:<password>`, kept as the SHA-256 of the `Authorization` header expected, to
+/// be compared in constant time.
+pub(crate) enum Auth {
+    Bearer([u8; 32]),
+    Basic([u8; 32]),
+}
+
+impl FromStr for Auth {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let expected = sha256(&[authorization(spec)?.as_bytes()]);
+        Ok(if spec.starts_with("bearer:") { Auth::Bearer(expected) } else { Auth::Basic(expected) })
+    }
+}
+
+/// The `Authorization` header of credentials as `bearer:<token>` or `basic:<user>:<password>`.
+pub(crate) fn authorization(
+    spec: &str,
+) -> Result<String, String> {
+    match spec.split_once(':') {
+        Some(("bearer", token)) if !token.is_empty() => Ok(format!("Bearer {}", token)),
+        Some(("basic", credentials)) if credentials.contains(':') => Ok(format!("Basic {}", base64(credentials.as_bytes()))),
+        _ => Err("expected bearer:<token> or basic:<user>:<password>".to_owned()),
+    }
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The credentials stay out of the logs.
+        f.write_str(self.scheme())
+    }
+}
+
+impl Auth {
+    fn scheme(&self) -> &'static str {
+        match self {
+            Auth::Bearer(_) => "bearer",
+            Auth::Basic(_) => "basic",
+        }
+    }
+
+    /// Whether a request comes with the credentials.
+    fn allows(
+        &self,
+        request: &Request<Body>,
+    ) -> bool {
+        let expected = match self {
+            Auth::Bearer(expected) | Auth::Basic(expected) => expected,
+        };
+        let sent = match request.headers().get(AUTHORIZATION) {
+            Some(sent) => sha256(&[sent.as_bytes()]),
+            None => return false,
+        };
+        sent.iter().zip(expected.iter()).fold(0, |differ, (sent, expected)| differ | (sent ^ expected)) == 0
+    }
+
+    /// What a request without the credentials is told.
+    fn challenge(&self) -> Response<Body> {
+        let challenge = match self {
+            Auth::Bearer(_) => "Bearer realm=\"tarssh\"",
+            Auth::Basic(_) => "Basic realm=\"tarssh\"",
+        };
+        Response::builder()
+            .status(401)
+            .header(WWW_AUTHENTICATE, challenge)
+            .body(Body::from("unauthorized\n"))
+            .unwrap_or_default()
+    }
+}
+
+/// Who may use the exporter once it asks for credentials: anyone with those to read and
+/// change, and anyone with those to read only, e.g. dashboards, who may not `POST`.
+pub(crate) struct Access {
+    full: Option<Auth>,
+    read: Vec<Auth>,
+}
+
+impl Access {
+    /// The access of these credentials, unless there are none and anyone may do anything.
+    pub(crate) fn new(
+        full: Option<Auth>,
+        read: Vec<Auth>,
+    ) -> Option<Self> {
+        if full.is_none() && read.is_empty() {
+            return None;
+        }
+        info!("exporter, auth: {}, read_only: {}", full.as_ref().map_or("none", Auth::scheme), read.len());
+        Some(Self { full, read })
+    }
+
+    /// What a request is told instead if its credentials don't allow it: a change is refused
+    /// outright with read-only credentials only, as none could allow it.
+    fn refusal(
+        &self,
+        request: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        let read = request.method() == Method::GET || request.method() == Method::HEAD;
+        if self.full.as_ref().is_some_and(|full| full.allows(request)) {
+            return None;
+        }
+        if !read && self.full.is_none() {
+            return Some(forbidden("changes need --exporter-auth"));
+        }
+        if self.read.iter().any(|auth| auth.allows(request)) {
+            return if read { None } else { Some(forbidden("read-only credentials")) };
+        }
+        self.full.as_ref().or(self.read.first()).map(Auth::challenge)
+    }
+}
+
+/// Base64 as per RFC 4648, with padding.
+fn base64(
+    bytes: &[u8],
+) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let word = chunk.iter().enumerate().fold(0u32, |word, (index, &byte)| word | (byte as u32) << (16 - 8 * index));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(ALPHABET[(word >> (18 - 6 * index) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Where the exporter listens: a TCP address, or with `unix:<path>`, a unix socket.
+#[derive(Clone, Debug)]
+pub(crate) enum Listen {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Listen {
+    /// The address, if listening on TCP.
+    pub(crate) fn tcp(&self) -> Option<SocketAddr> {
+        match self {
+            Listen::Tcp(address) => Some(*address),
+            #[cfg(unix)]
+            Listen::Unix(_) => None,
+        }
+    }
+}
+
 pub(crate) struct Exporter {
-    inner: Vec<Builder<AddrIncoming>>,
+    inner:    Vec<Builder<AddrIncoming>>,
+    /// The TCP listeners instead, with a certificate to serve them over TLS with.
+    #[cfg(feature = "tls")]
+    tls:      Vec<TcpListener>,
+    #[cfg(feature = "tls")]
+    acceptor: Option<Arc<Acceptor>>,
+    #[cfg(unix)]
+    unix:     Vec<UnixListener>,
+    auth:     Option<Arc<Access>>,
 }
 
 impl Exporter {
     pub(crate) fn new(
         runtime: &mut Runtime,
-        listen: Vec<SocketAddr>,
+        listen: Vec<Listen>,
+        auth: Option<Access>,
+        #[cfg(feature = "tls")]
+        acceptor: Option<Acceptor>,
+        handover: &Handover,
     ) -> Self {
+        let tcp: Vec<_> = listen.iter().filter_map(Listen::tcp).map(|address| {
+            let listener = handover
+                .take(address)
+                .map_or_else(|| std::net::TcpListener::bind(address), Ok)
+                .unwrap_or_else(|err| Failure::Bind.exit(format!("listen, addr: {}, error: {}", address, err)));
+            handover.listening(
+                address,
+                #[cfg(unix)]
+                &listener,
+            );
+            (address, listener)
+        }).collect();
+        #[cfg(feature = "tls")]
+        let (tcp, tls) = match acceptor {
+            Some(_) => (Vec::new(), tcp),
+            None => (tcp, Vec::new()),
+        };
         Self {
-            inner: listen.iter().map(|address| {
-                let listener = runtime.block_on(async { Server::bind(&address) });
-                info!("listen, addr: {}", address);
+            auth: auth.map(Arc::new),
+            inner: tcp.into_iter().map(|(address, listener)| {
+                let listener = runtime
+                    .block_on(async { Server::from_tcp(listener) })
+                    .unwrap_or_else(|err| Failure::Bind.exit(format!("listen, addr: {}, error: {}", address, err)));
+                info!("listen, addr: {}, aggregate_only: {}", address, cfg!(feature = "aggregate_only"));
                 listener
-            }).collect()
+            }).collect(),
+            #[cfg(feature = "tls")]
+            tls: tls.into_iter().map(|(address, listener)| {
+                let listener = runtime
+                    .block_on(async { TcpListener::from_std(listener) })
+                    .unwrap_or_else(|err| Failure::Bind.exit(format!("listen, addr: {}, error: {}", address, err)));
+                info!("listen, addr: {}, tls: true, aggregate_only: {}", address, cfg!(feature = "aggregate_only"));
+                listener
+            }).collect(),
+            #[cfg(feature = "tls")]
+            acceptor: acceptor.map(Arc::new),
+            #[cfg(unix)]
+            unix: listen.iter().filter_map(|listen| match listen {
+                Listen::Unix(path) => Some(path),
+                Listen::Tcp(_) => None,
+            }).map(|path| {
+                // A socket left behind by an earlier tarssh, or the one about to be restarted.
+                if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                    let _ = std::fs::remove_file(path);
+                }
+                let listener = runtime
+                    .block_on(async { UnixListener::bind(path) })
+                    .unwrap_or_else(|err| Failure::Bind.exit(format!("listen, path: {}, error: {}", path.display(), err)));
+                info!("listen, path: {}, aggregate_only: {}", path.display(), cfg!(feature = "aggregate_only"));
+                listener
+            }).collect(),
         }
     }
 
+    /// Serve the metrics of all instances, labelled by their names if they have any.
     pub(crate) fn spawn(
         self,
         runtime: &Runtime,
-    ) -> Arc<Metrics> {
-        let metrics = Arc::new(Metrics::new(runtime.start()));
-
+        instances: Vec<(Option<String>, Arc<Metrics>)>,
+        handover: &'static Handover,
+        shared: Arc<Shared>,
+        reloader: Arc<Reloader>,
+    ) {
+        let instances = Arc::new(instances);
+        let control = Arc::new(Control { handover, shared, reloader });
         for exporter in self.inner {
-            let metrics = metrics.clone();
-            runtime.spawn(
-                exporter.serve(
-                    make_service_fn(
-                        move |_connection| {
-                            let metrics = metrics.clone();
-                            async move {
-                                Ok::<_, Infallible>(
-                                    service_fn(
-                                        move |req: Request<Body>| {
-                                            let metrics = metrics.clone();
-                                            async move {
-                                                metrics.handle(req).await
-                                            }
-                                        }
-                                    )
-                                )
-                            }
-                        }
-                    )
-                )
-            );
+            serve(runtime, exporter, &instances, &self.auth, &control);
+        }
+        #[cfg(feature = "tls")]
+        for listener in self.tls {
+            let acceptor = self.acceptor.clone().expect("TLS listeners come with a certificate");
+            let incoming = handshaken(runtime, listener, acceptor, handover);
+            serve(runtime, Server::builder(accept::from_stream(incoming)), &instances, &self.auth, &control);
+        }
+        #[cfg(unix)]
+        for listener in self.unix {
+            let incoming = futures::stream::unfold(listener, |mut listener| async {
+                let sock = listener.accept().await.map(|(sock, _)| sock);
+                Some((sock, listener))
+            });
+            serve(runtime, Server::builder(accept::from_stream(incoming)), &instances, &self.auth, &control);
+        }
+    }
+}
+
+/// The connections of a TLS listener once through their handshakes, each of which gets a task
+/// and a deadline of its own, so a client stalling its handshake holds up no other; until this
+/// process stopped accepting.
+#[cfg(feature = "tls")]
+fn handshaken(
+    runtime: &Runtime,
+    mut listener: TcpListener,
+    acceptor: Arc<Acceptor>,
+    handover: &'static Handover,
+) -> mpsc::Receiver<io::Result<TlsStream>> {
+    let (sender, receiver) = mpsc::channel(HANDSHAKES);
+    runtime.spawn(async move {
+        let accepting = async {
+            loop {
+                let (sock, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("accept, err: {}, tls: true", err);
+                        delay_for(Duration::from_millis(100)).await;
+                        continue;
+                    },
+                };
+                let acceptor = acceptor.clone();
+                let mut sender = sender.clone();
+                tokio::spawn(async move {
+                    match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(sock)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send(Ok(stream)).await;
+                        },
+                        Ok(Err(err)) => debug!("exporter, peer: {}, handshake: failed, error: {}", peer, err),
+                        Err(_) => debug!("exporter, peer: {}, handshake: timed out", peer),
+                    }
+                });
+            }
+        };
+        let stopped = handover.stopped();
+        futures::pin_mut!(accepting, stopped);
+        futures::future::select(accepting, stopped).await;
+    });
+    receiver
+}
+
+/// What the exporter, unlike a monitor, gets to control tarssh through.
+struct Control {
+    handover: &'static Handover,
+    shared:   Arc<Shared>,
+    reloader: Arc<Reloader>,
+}
+
+/// Serve the metrics of all instances on the connections a listener of whatever kind accepts.
+fn serve<I>(
+    runtime: &Runtime,
+    exporter: Builder<I>,
+    instances: &Arc<Vec<(Option<String>, Arc<Metrics>)>>,
+    auth: &Option<Arc<Access>>,
+    control: &Arc<Control>,
+)
+where
+    I: Accept + Send + 'static,
+    I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let metrics = instances.clone();
+    let auth = auth.clone();
+    let handover = control.handover;
+    let control = control.clone();
+    runtime.spawn(
+        exporter.serve(
+            make_service_fn(
+                move |_connection: &I::Conn| {
+                    let metrics = metrics.clone();
+                    let auth = auth.clone();
+                    let control = control.clone();
+                    async move {
+                        Ok::<_, Infallible>(
+                            service_fn(
+                                move |req: Request<Body>| {
+                                    let metrics = metrics.clone();
+                                    let auth = auth.clone();
+                                    let control = control.clone();
+                                    async move {
+                                        handle(&metrics, auth.as_deref(), Some(&control), req).await
+                                    }
+                                }
+                            )
+                        )
+                    }
+                }
+            )
+        )
+        .with_graceful_shutdown(handover.stopped())
+    );
+}
+
+/// A listen address of the exporter, `unix:` and the path of a unix socket, or none for
+/// `disabled`.
+pub(crate) fn parse_listen(
+    spec: &str,
+) -> Result<Option<Listen>, String> {
+    match (spec, spec.strip_prefix("unix:")) {
+        ("disabled", _) => Ok(None),
+        #[cfg(unix)]
+        (_, Some(path)) if !path.is_empty() => Ok(Some(Listen::Unix(path.into()))),
+        #[cfg(unix)]
+        (_, Some(_)) => Err("expected unix:<path>".to_owned()),
+        #[cfg(not(unix))]
+        (_, Some(_)) => Err("unix sockets are not supported here".to_owned()),
+        (spec, None) => spec.parse().map(|address| Some(Listen::Tcp(address))).map_err(|err: AddrParseError| err.to_string()),
+    }
+}
+
+/// Serve the metrics of an instance to a monitor connecting to one of its tarpit listeners,
+/// if it asks with an HTTP GET, and else just hang up; monitors are never tarpitted.
+pub(crate) async fn monitor(
+    mut sock: TcpStream,
+    peer: SocketAddr,
+    metrics: Arc<Metrics>,
+) {
+    let peeked = timeout(MONITOR_TIMEOUT, async {
+        let mut start = [0u8; 4];
+        loop {
+            match sock.peek(&mut start).await? {
+                0 => return Ok::<_, io::Error>(false),
+                4 => return Ok(&start == b"GET "),
+                // The rest of it is a packet away.
+                _ => delay_for(Duration::from_millis(10)).await,
+            }
+        }
+    }).await;
+    match peeked {
+        Ok(Ok(true)) => info!("monitor, peer: {}", peer),
+        Ok(Ok(false)) => return info!("monitor, peer: {}, error: not a GET", peer),
+        Ok(Err(err)) => return info!("monitor, peer: {}, error: {}", peer, err),
+        Err(_) => return info!("monitor, peer: {}, error: timeout", peer),
+    }
+    let instances = Arc::new(vec![(None, metrics)]);
+    let service = service_fn(move |req: Request<Body>| {
+        let instances = instances.clone();
+        async move {
+            // Allowed by their addresses, monitors aren't asked for credentials, but may only
+            // watch.
+            handle(&instances, None, None, req).await
         }
+    });
+    if let Err(err) = Http::new().serve_connection(sock, service).await {
+        debug!("monitor, peer: {}, error: {}", peer, err);
+    }
+}
 
-        metrics
+/// Answer a request of the exporter, or without `control`, of a monitor, which may neither
+/// control tarssh nor stream its events.  Nothing is changed without credentials to ask for,
+/// lest anyone reaching the exporter could.
+#[cfg_attr(feature = "aggregate_only", allow(unused_variables))]
+async fn handle(
+    instances: &[(Option<String>, Arc<Metrics>)],
+    auth: Option<&Access>,
+    control: Option<&Control>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if let Some(refusal) = auth.and_then(|auth| auth.refusal(&request)) {
+        return Ok(refusal);
+    }
+    let read = request.method() == Method::GET || request.method() == Method::HEAD;
+    if !read && auth.is_none() && control.is_some() {
+        return Ok(forbidden("changes need --exporter-auth"));
     }
+    Ok(match request.uri().path() {
+        "/" if read => Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(DASHBOARD))
+            .unwrap_or_default(),
+        "/metrics" if read => metrics(instances, &request),
+        "/stats.json" if read => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(stats(instances)))
+            .unwrap_or_default(),
+        "/version" if read => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(version()))
+            .unwrap_or_default(),
+        #[cfg(not(feature = "aggregate_only"))]
+        "/top" if read => match top(instances, request.uri().query()) {
+            Ok(top) => Response::new(Body::from(top)),
+            Err(err) => bad_request(err),
+        },
+        #[cfg(not(feature = "aggregate_only"))]
+        "/archive.csv" if read => Response::builder()
+            .header(CONTENT_TYPE, "text/csv; charset=utf-8")
+            .body(Body::from(archive_csv(instances)))
+            .unwrap_or_default(),
+        #[cfg(not(feature = "aggregate_only"))]
+        "/archive.jsonl" if read => Response::builder()
+            .header(CONTENT_TYPE, "application/jsonl")
+            .body(Body::from(archive_jsonl(instances)))
+            .unwrap_or_default(),
+        #[cfg(not(feature = "aggregate_only"))]
+        "/connections" if read => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(connections(instances)))
+            .unwrap_or_default(),
+        #[cfg(not(feature = "aggregate_only"))]
+        path if read && path.starts_with("/connections/") => connection(instances, path),
+        #[cfg(not(feature = "aggregate_only"))]
+        "/events" if read && control.is_some() => Response::builder()
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header(hyper::header::CACHE_CONTROL, "no-cache")
+            .body(Body::wrap_stream(events(instances, control.map(|control| control.handover))))
+            .unwrap_or_default(),
+        #[cfg(not(feature = "aggregate_only"))]
+        "/kick" if control.is_some() && request.method() == Method::POST => match kick(instances, request.uri().query()) {
+            Ok(kicked) => Response::new(Body::from(kicked)),
+            Err(err) => bad_request(err),
+        },
+        "/bans" => match control {
+            Some(control) if read => Response::new(Body::from(bans(&control.shared.bans))),
+            Some(control) if request.method() == Method::POST => ban(&control.shared.bans, request.uri().query()),
+            Some(_) => not_allowed("GET, HEAD, POST"),
+            None => not_found("/bans"),
+        },
+        "/reload" => match control {
+            Some(control) if request.method() == Method::POST => {
+                let reloaded = control.reloader.reload();
+                match reloaded.error {
+                    None => Response::new(Body::from(reloaded.to_string())),
+                    Some(_) => server_error(reloaded.to_string().trim_end().to_owned()),
+                }
+            },
+            Some(_) => not_allowed("POST"),
+            None => not_found("/reload"),
+        },
+        "/max-clients" => match control {
+            Some(_) if request.method() == Method::POST => match limit(instances, request.uri().query()) {
+                Ok(limited) => Response::new(Body::from(limited)),
+                Err(err) => bad_request(err),
+            },
+            Some(_) => not_allowed("POST"),
+            None => not_found("/max-clients"),
+        },
+        "/pause" | "/resume" => match control {
+            Some(control) if request.method() == Method::POST => {
+                control.handover.pause(request.uri().path() == "/pause");
+                Response::new(Body::from(format!("paused: {}\n", control.handover.paused())))
+            },
+            Some(_) => not_allowed("POST"),
+            None => not_found(request.uri().path()),
+        },
+        #[cfg(unix)]
+        "/drain" => match control {
+            Some(control) if request.method() == Method::POST => match drain(instances, control.handover, request.uri().query()) {
+                Ok(draining) => Response::new(Body::from(draining)),
+                Err(err) => bad_request(err),
+            },
+            Some(_) => not_allowed("POST"),
+            None => not_found("/drain"),
+        },
+        #[cfg(feature = "profiling")]
+        "/debug/process" if read && control.is_some() => Response::new(Body::from(profiling::process())),
+        #[cfg(feature = "profiling")]
+        "/debug/cpu" if read && control.is_some() => match profiling::cpu(request.uri().query()).await {
+            Ok(cpu) => Response::new(Body::from(cpu)),
+            Err(err) => bad_request(err),
+        },
+        #[cfg(feature = "profiling")]
+        "/debug/tasks" if read && control.is_some() => Response::new(Body::from(profiling::tasks(instances))),
+        "/" | "/metrics" | "/stats.json" | "/version" => not_allowed("GET, HEAD"),
+        #[cfg(not(feature = "aggregate_only"))]
+        "/top" | "/archive.csv" | "/archive.jsonl" | "/connections" => not_allowed("GET, HEAD"),
+        #[cfg(not(feature = "aggregate_only"))]
+        path if path.starts_with("/connections/") => not_allowed("GET, HEAD"),
+        #[cfg(not(feature = "aggregate_only"))]
+        "/events" if control.is_some() => not_allowed("GET, HEAD"),
+        #[cfg(not(feature = "aggregate_only"))]
+        "/kick" if control.is_some() => not_allowed("POST"),
+        #[cfg(feature = "profiling")]
+        "/debug/process" | "/debug/cpu" | "/debug/tasks" if control.is_some() => not_allowed("GET, HEAD"),
+        path => not_found(path),
+    })
 }
 
-impl Metrics {
-    pub(crate) async fn handle(
+fn not_found(
+    path: &str,
+) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from(format!("no such path \"{}\", try /metrics\n", path)))
+        .unwrap_or_default()
+}
+
+fn forbidden(
+    reason: &str,
+) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::from(format!("{}\n", reason)))
+        .unwrap_or_default()
+}
+
+fn server_error(
+    err: String,
+) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(Body::from(err + "\n"))
+        .unwrap_or_default()
+}
+
+fn bad_request(
+    err: String,
+) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(err + "\n"))
+        .unwrap_or_default()
+}
+
+fn not_allowed(
+    allow: &'static str,
+) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(ALLOW, allow)
+        .body(Body::from(format!("only {} allowed\n", allow)))
+        .unwrap_or_default()
+}
+
+/// The metrics in OpenMetrics if asked for it, and else in the classic text format.
+fn metrics(
+    instances: &[(Option<String>, Arc<Metrics>)],
+    request: &Request<Body>,
+) -> Response<Body> {
+    let accept = request.headers().get(ACCEPT).and_then(|accept| accept.to_str().ok());
+    let (content_type, export) = match accept {
+        Some(accept) if openmetrics::accepted(accept) => (openmetrics::CONTENT_TYPE, openmetrics::encode(&export(instances))),
+        _ => (TEXT_FORMAT, export(instances)),
+    };
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(export))
+        .unwrap_or_default()
+}
+
+/// What was built: the version, the commit if built from a git checkout, and the features.
+fn version() -> String {
+    let features: Vec<_> = env!("TARSSH_FEATURES")
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .map(json)
+        .collect();
+    format!(
+        "{{\"version\":{},\"git_hash\":{},\"features\":[{}]}}\n",
+        json(env!("CARGO_PKG_VERSION")),
+        option_env!("TARSSH_GIT_HASH").map_or_else(|| "null".to_owned(), json),
+        features.join(","),
+    )
+}
+
+/// The metrics of all instances, labelled by their names if there are several.
+pub(crate) fn export(
+    instances: &[(Option<String>, Arc<Metrics>)],
+) -> String {
+    match instances {
+        [(None, metrics)] => metrics.export(),
+        instances => merge(instances
+            .iter()
+            .map(|(name, metrics)| (name.as_deref().unwrap_or("default"), metrics.export()))
+            .collect()),
+    }
+}
+
+/// The connections of all instances kept in their archives, by when they started, each with
+/// the name of its instance.
+#[cfg(not(feature = "aggregate_only"))]
+fn archived(
+    instances: &[(Option<String>, Arc<Metrics>)],
+) -> Vec<(&str, Archived)> {
+    let mut archived: Vec<_> = instances
+        .iter()
+        .flat_map(|(name, metrics)| {
+            let name = name.as_deref().unwrap_or("default");
+            metrics.archive().into_iter().map(move |session| (name, session))
+        })
+        .collect();
+    archived.sort_by_key(|(_, session)| session.start);
+    archived
+}
+
+/// Seconds since the epoch, with milliseconds.
+#[cfg(not(feature = "aggregate_only"))]
+fn timestamp(
+    time: SystemTime,
+) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:03}", since.as_secs(), since.subsec_millis())
+}
+
+/// The archived connections as CSV, a header first.
+#[cfg(not(feature = "aggregate_only"))]
+fn archive_csv(
+    instances: &[(Option<String>, Arc<Metrics>)],
+) -> String {
+    let mut csv = String::from("instance,id,peer,listener,start,duration_seconds,chunks,bytes\n");
+    for (name, session) in archived(instances) {
+        // Only the name of an instance is free to hold a comma or a quote.
+        let name = if name.contains(&[',', '"', '\n'][..]) {
+            format!("\"{}\"", name.replace('"', "\"\""))
+        } else {
+            name.to_owned()
+        };
+        csv.push_str(&format!(
+            "{},{},{},{},{},{:.3},{},{}\n",
+            name,
+            session.id,
+            session.peer,
+            session.listener,
+            timestamp(session.start),
+            session.duration.as_secs_f64(),
+            session.sent_chunks,
+            session.sent_bytes,
+        ));
+    }
+    csv
+}
+
+/// The archived connections as JSON lines, an object each.
+#[cfg(not(feature = "aggregate_only"))]
+fn archive_jsonl(
+    instances: &[(Option<String>, Arc<Metrics>)],
+) -> String {
+    archived(instances)
+        .iter()
+        .map(|(name, session)| format!(
+            "{{\"instance\":{},\"id\":{},\"peer\":{},\"listener\":{},\"start\":{},\"duration_seconds\":{:.3},\"chunks\":{},\"bytes\":{}}}\n",
+            json(name),
+            session.id,
+            json(&session.peer.to_string()),
+            json(&session.listener.to_string()),
+            timestamp(session.start),
+            session.duration.as_secs_f64(),
+            session.sent_chunks,
+            session.sent_bytes,
+        ))
+        .collect()
+}
+
+/// A client connected now as a JSON object, with all there is to know about it.
+#[cfg(not(feature = "aggregate_only"))]
+fn detail(
+    name: &str,
+    detail: &Detail,
+) -> String {
+    let text = |value: Option<String>| value.map_or_else(|| "null".to_owned(), |value| json(&value));
+    format!(
+        "{{\"instance\":{},\"id\":{},\"peer\":{},\"listener\":{},\"start\":{},\"duration_seconds\":{:.3},\
+         \"sent_chunks\":{},\"sent_bytes\":{},\"sent_eastereggs\":{},\"sent_banners\":{},\"received_bytes\":{},\
+         \"delay_seconds\":{},\"version\":{},\"country\":{},\"asn\":{}}}",
+        json(name),
+        detail.id,
+        json(&detail.peer.to_string()),
+        json(&detail.listener.to_string()),
+        timestamp(detail.start),
+        detail.duration.as_secs_f64(),
+        detail.sent_chunks,
+        detail.sent_bytes,
+        detail.sent_eastereggs,
+        detail.sent_banners,
+        detail.received_bytes,
+        detail.delay.map_or_else(|| "null".to_owned(), |delay| delay.as_secs().to_string()),
+        text(detail.version.clone()),
+        text(detail.country.map(|country| country.to_string())),
+        text(detail.asn.clone()),
+    )
+}
+
+/// The clients of all instances connected now, trapped the longest first, as a JSON array.
+#[cfg(not(feature = "aggregate_only"))]
+fn connections(
+    instances: &[(Option<String>, Arc<Metrics>)],
+) -> String {
+    let mut all: Vec<_> = instances
+        .iter()
+        .flat_map(|(name, metrics)| {
+            let name = name.as_deref().unwrap_or("default");
+            metrics.details().into_iter().map(move |detail| (name, detail))
+        })
+        .collect();
+    all.sort_by_key(|(_, detail)| std::cmp::Reverse(detail.duration));
+    let all: Vec<_> = all.iter().map(|(name, connection)| detail(name, connection)).collect();
+    format!("[{}]\n", all.join(","))
+}
+
+/// The client of a connection, by its id in the path, if it is still connected.
+#[cfg(not(feature = "aggregate_only"))]
+fn connection(
+    instances: &[(Option<String>, Arc<Metrics>)],
+    path: &str,
+) -> Response<Body> {
+    let found = path
+        .strip_prefix("/connections/")
+        .and_then(|id| id.parse().ok())
+        .and_then(|id| instances.iter().find_map(|(name, metrics)| {
+            Some(detail(name.as_deref().unwrap_or("default"), &metrics.detail(id)?))
+        }));
+    match found {
+        Some(found) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(found + "\n"))
+            .unwrap_or_default(),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from(format!("no connection \"{}\", try /connections\n", path.trim_start_matches("/connections/"))))
+            .unwrap_or_default(),
+    }
+}
+
+/// The peers of all instances trapped the most connections of, or the longest with
+/// `?by=trapped`, `?n=10` of them, one per line: address, connections, seconds trapped and
+/// seconds since last seen; or with `?per=network`, their /24 and /64 networks, each with the
+/// number of its peers too.
+#[cfg(not(feature = "aggregate_only"))]
+fn top(
+    instances: &[(Option<String>, Arc<Metrics>)],
+    query: Option<&str>,
+) -> Result<String, String> {
+    let mut count = TOP_PEERS;
+    let mut by_trapped = false;
+    let mut per_network = false;
+    for pair in query.unwrap_or_default().split('&').filter(|pair| !pair.is_empty()) {
+        match pair.find('=').map(|index| (&pair[..index], &pair[index + 1..])) {
+            Some(("n", value)) => count = value
+                .parse()
+                .map_err(|err| format!("invalid n \"{}\": {}", value, err))?,
+            Some(("by", "connections")) => by_trapped = false,
+            Some(("by", "trapped")) => by_trapped = true,
+            Some(("per", "peer")) => per_network = false,
+            Some(("per", "network")) => per_network = true,
+            _ => return Err(format!("unknown parameter \"{}\", expected n, by=connections|trapped or per=peer|network", pair)),
+        }
+    }
+    let mut peers: HashMap<IpAddr, (PeerHistory, Duration)> = HashMap::new();
+    for (_, metrics) in instances {
+        for (peer, (history, last_seen)) in metrics.peers() {
+            let (total, seen) = peers.entry(peer).or_insert((PeerHistory::default(), last_seen));
+            total.seen += history.seen;
+            total.trapped += history.trapped;
+            *seen = (*seen).min(last_seen);
+        }
+    }
+    let rank = |history: &PeerHistory| std::cmp::Reverse(if by_trapped {
+        (history.trapped, history.seen)
+    } else {
+        (history.seen, history.trapped)
+    });
+    if per_network {
+        let mut networks: HashMap<Prefix, (usize, PeerHistory, Duration)> = HashMap::new();
+        for (peer, (history, last_seen)) in peers {
+            let (peers, total, seen) = networks
+                .entry(Prefix::network(peer, NETWORK_V4, NETWORK_V6))
+                .or_insert((0, PeerHistory::default(), last_seen));
+            *peers += 1;
+            total.seen += history.seen;
+            total.trapped += history.trapped;
+            *seen = (*seen).min(last_seen);
+        }
+        let mut networks: Vec<_> = networks.into_iter().collect();
+        networks.sort_by_key(|&(network, (_, history, _))| (rank(&history), network));
+        let mut top = String::from("# network peers connections trapped_seconds last_seen_seconds_ago\n");
+        for (network, (peers, history, last_seen)) in networks.into_iter().take(count) {
+            top.push_str(&format!("{:?} {} {} {} {}\n", network, peers, history.seen, history.trapped, last_seen.as_secs()));
+        }
+        return Ok(top);
+    }
+    let mut peers: Vec<_> = peers.into_iter().collect();
+    peers.sort_by_key(|&(peer, (history, _))| (rank(&history), peer));
+    let mut top = String::from("# peer connections trapped_seconds last_seen_seconds_ago\n");
+    for (peer, (history, last_seen)) in peers.into_iter().take(count) {
+        top.push_str(&format!("{} {} {} {}\n", peer, history.seen, history.trapped, last_seen.as_secs()));
+    }
+    Ok(top)
+}
+
+/// The events of all instances as server-sent events, each a JSON object with the kind of
+/// event and the instance, if there are several, until the exporter is stopped for a
+/// handover; missed events are told about in a comment.
+#[cfg(not(feature = "aggregate_only"))]
+fn events(
+    instances: &[(Option<String>, Arc<Metrics>)],
+    handover: Option<&'static Handover>,
+) -> impl Stream<Item = Result<String, Infallible>> {
+    let several = instances.len() > 1;
+    let streams = instances.iter().map(|(name, metrics)| {
+        let instance = name.as_deref().filter(|_| several).map(json);
+        Box::pin(stream::unfold(metrics.events(), move |mut events| {
+            let instance = instance.clone();
+            async move {
+                let received = {
+                    let received = events.recv();
+                    let stopped = async {
+                        match handover {
+                            Some(handover) => handover.stopped().await,
+                            None => future::pending().await,
+                        }
+                    };
+                    pin_mut!(received, stopped);
+                    match future::select(received, stopped).await {
+                        Either::Left((received, _)) => received,
+                        Either::Right(_) => return None,
+                    }
+                };
+                let message = match received {
+                    Ok(event) => event_message(&event, instance.as_deref()),
+                    Err(RecvError::Lagged(missed)) => format!(": missed {} events\n\n", missed),
+                    Err(RecvError::Closed) => return None,
+                };
+                Some((Ok(message), events))
+            }
+        }))
+    });
+    stream::select_all(streams)
+}
+
+/// An event as a message of server-sent events.
+#[cfg(not(feature = "aggregate_only"))]
+fn event_message(
+    event: &Event,
+    instance: Option<&str>,
+) -> String {
+    let (kind, fields) = match event {
+        Event::Connect { id, peer, clients } => (
+            "connect",
+            format!("\"id\":{},\"peer\":{},\"clients\":{}", id, json(&peer.to_string()), clients),
+        ),
+        Event::Disconnect { id, peer, duration, reason } => (
+            "disconnect",
+            format!(
+                "\"id\":{},\"peer\":{},\"duration\":{},\"reason\":{}",
+                id,
+                json(&peer.to_string()),
+                duration.as_secs_f64(),
+                json(reason),
+            ),
+        ),
+        Event::Easteregg { id, peer } => (
+            "easteregg",
+            format!("\"id\":{},\"peer\":{}", id, json(&peer.to_string())),
+        ),
+    };
+    let instance = instance.map_or_else(String::new, |instance| format!(",\"instance\":{}", instance));
+    format!("event: {}\ndata: {{\"event\":\"{}\",{}{}}}\n\n", kind, kind, fields, instance)
+}
+
+/// Hang up on a client by `?id=<n>` of its connection, or on all of a network by
+/// `?peer=<address or prefix>`, in all instances; returns how many.
+#[cfg(not(feature = "aggregate_only"))]
+fn kick(
+    instances: &[(Option<String>, Arc<Metrics>)],
+    query: Option<&str>,
+) -> Result<String, String> {
+    let kick = match query.unwrap_or_default().split_once('=') {
+        Some(("id", id)) => Kick::Id(id.parse().map_err(|err| format!("invalid id \"{}\": {}", id, err))?),
+        Some(("peer", peer)) => Kick::Peers(peer.parse()?),
+        _ => return Err("expected id=<connection> or peer=<address or prefix>".to_owned()),
+    };
+    let kicked: usize = instances.iter().map(|(_, metrics)| metrics.kick(kick)).sum();
+    info!("kick, {}, clients: {}", kick, kicked);
+    Ok(format!("{}\n", kicked))
+}
+
+/// Drain, with a deadline by `?deadline=<duration>` if the clients left are not to stay until
+/// they give up; answers with how many there are.
+#[cfg(unix)]
+fn drain(
+    instances: &[(Option<String>, Arc<Metrics>)],
+    handover: &Handover,
+    query: Option<&str>,
+) -> Result<String, String> {
+    let deadline = match query.filter(|query| !query.is_empty()).map(|query| query.split_once('=')) {
+        None => None,
+        Some(Some(("deadline", deadline))) => Some(
+            humantime::parse_duration(deadline).map_err(|err| format!("invalid deadline \"{}\": {}", deadline, err))?
+        ),
+        Some(_) => return Err("expected nothing or deadline=<duration>".to_owned()),
+    };
+    handover.drain(deadline);
+    let clients: usize = instances.iter().map(|(_, metrics)| metrics.connections()).sum();
+    Ok(format!("draining, clients: {}\n", clients))
+}
+
+/// Hold `?clients=<number>` clients at once at most in every instance from now on.
+fn limit(
+    instances: &[(Option<String>, Arc<Metrics>)],
+    query: Option<&str>,
+) -> Result<String, String> {
+    let clients: usize = match query.unwrap_or_default().split_once('=') {
+        Some(("clients", clients)) => clients.parse().map_err(|err| format!("invalid clients \"{}\": {}", clients, err))?,
+        _ => return Err("expected clients=<number>".to_owned()),
+    };
+    for (_, metrics) in instances {
+        metrics.limit(clients);
+    }
+    info!("set, max_clients: {}", clients);
+    Ok(format!("max_clients: {}\n", clients))
+}
+
+/// Every ban, one per line.
+fn bans(
+    bans: &Bans,
+) -> String {
+    bans.list().iter().map(|prefix| format!("{:?}\n", prefix)).collect()
+}
+
+/// Ban a network by `?add=<address or prefix>`, or lift a ban by `?remove=`, saved before it
+/// takes effect; answers with every ban.
+fn ban(
+    bans: &Bans,
+    query: Option<&str>,
+) -> Response<Body> {
+    let change = match query.unwrap_or_default().split_once('=') {
+        Some(("add", prefix)) => prefix.parse().map(Change::Add),
+        Some(("remove", prefix)) => prefix.parse().map(Change::Remove),
+        _ => Err("expected add=<address or prefix> or remove=<address or prefix>".to_owned()),
+    };
+    let change = match change {
+        Ok(change) => change,
+        Err(err) => return bad_request(err),
+    };
+    match bans.change(change) {
+        Ok(changed) => {
+            match change {
+                Change::Add(prefix) => info!("ban, add: {:?}, changed: {}", prefix, changed),
+                Change::Remove(prefix) => info!("ban, remove: {:?}, changed: {}", prefix, changed),
+            }
+            Response::new(Body::from(self::bans(bans)))
+        },
+        Err(err) => {
+            warn!("ban, error: {}", err);
+            server_error(format!("saving the bans failed: {}", err))
+        },
+    }
+}
+
+/// The labels of a sample, by name.
+pub(crate) type Labels<'a> = Vec<(&'a str, String)>;
+
+/// A sample of the text format: name, labels and value.
+pub(crate) type Sample<'a> = (&'a str, Labels<'a>, f64);
+
+/// A metric of the text format, with its samples.
+pub(crate) struct Family<'a> {
+    pub(crate) name:        &'a str,
+    pub(crate) description: &'a str,
+    pub(crate) kind:        &'a str,
+    pub(crate) samples:     Vec<Sample<'a>>,
+}
+
+impl<'a> Family<'a> {
+    /// The samples by their labels, but `le`, in order of appearance.
+    pub(crate) fn series(
         &self,
-        _request: Request<Body>,
-    ) -> Result<Response<Body>, Infallible> {
-        Ok(Response::new(Body::from(self.export())))
+    ) -> Vec<(Labels<'a>, Vec<&Sample<'a>>)> {
+        let mut series: Vec<(Vec<_>, Vec<_>)> = Vec::new();
+        for sample in &self.samples {
+            let labels: Vec<_> = sample.1.iter().filter(|(key, _)| *key != "le").cloned().collect();
+            match series.iter_mut().find(|(known, _)| *known == labels) {
+                Some((_, samples)) => samples.push(sample),
+                None => series.push((labels, vec![sample])),
+            }
+        }
+        series
+    }
+}
+
+/// The metrics of an export of the text format, in order.
+pub(crate) fn families(
+    export: &str,
+) -> Vec<Family<'_>> {
+    let mut families: Vec<Family<'_>> = Vec::new();
+    for line in export.lines().filter(|line| !line.is_empty()) {
+        if let Some(header) = line.strip_prefix("# HELP ") {
+            let (name, description) = header.split_once(' ').unwrap_or((header, ""));
+            families.push(Family {
+                name,
+                description,
+                kind:    "untyped",
+                samples: Vec::new(),
+            });
+        } else if let Some(header) = line.strip_prefix("# TYPE ") {
+            if let (Some(family), Some((_, kind))) = (families.last_mut(), header.split_once(' ')) {
+                family.kind = kind;
+            }
+        } else if let (Some(family), Some(sample)) = (families.last_mut(), sample(line)) {
+            family.samples.push(sample);
+        }
+    }
+    families
+}
+
+/// A sample line of the text format, unless it doesn't parse.
+fn sample(
+    line: &str,
+) -> Option<Sample<'_>> {
+    let (series, value) = line.rsplit_once(' ')?;
+    let value = value.parse().ok()?;
+    let (name, mut rest) = match series.find('{') {
+        Some(index) => (&series[..index], series[index + 1..].strip_suffix('}')?),
+        None => (series, ""),
+    };
+    let mut labels = Vec::new();
+    while !rest.is_empty() {
+        let equals = rest.find("=\"")?;
+        let mut label = String::new();
+        let mut end = None;
+        let mut characters = rest[equals + 2..].char_indices();
+        while let Some((index, character)) = characters.next() {
+            match character {
+                '\\' => match characters.next()?.1 {
+                    'n' => label.push('\n'),
+                    character => label.push(character),
+                },
+                '"' => {
+                    end = Some(equals + 2 + index + 1);
+                    break;
+                },
+                character => label.push(character),
+            }
+        }
+        labels.push((&rest[..equals], label));
+        rest = rest[end?..].trim_start_matches(',');
+    }
+    Some((name, labels, value))
+}
+
+/// The metrics of all instances as a JSON object by name, with their type, description and
+/// samples, each with its labels and value, or its buckets by `le`, sum and count of a histogram;
+/// values which aren't finite are null.
+fn stats(
+    instances: &[(Option<String>, Arc<Metrics>)],
+) -> String {
+    let export = export(instances);
+    let number = |value: f64| if value.is_finite() { value.to_string() } else { "null".to_owned() };
+    let labels = |labels: &[(&str, String)]| labels
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json(key), json(value)))
+        .collect::<Vec<_>>()
+        .join(",");
+    let metrics: Vec<_> = families(&export)
+        .iter()
+        .map(|family| {
+            let samples: Vec<_> = match family.kind {
+                "histogram" => family
+                    .series()
+                    .into_iter()
+                    .map(|(series, samples)| {
+                        let buckets: Vec<_> = samples
+                            .iter()
+                            .filter_map(|(_, labels, value)| {
+                                let (_, le) = labels.iter().find(|(key, _)| *key == "le")?;
+                                Some(format!("{}:{}", json(le), number(*value)))
+                            })
+                            .collect();
+                        let value = |suffix: &str| samples
+                            .iter()
+                            .find(|(name, ..)| name.ends_with(suffix))
+                            .map_or_else(|| "null".to_owned(), |(_, _, value)| number(*value));
+                        format!(
+                            "{{\"labels\":{{{}}},\"buckets\":{{{}}},\"sum\":{},\"count\":{}}}",
+                            labels(&series),
+                            buckets.join(","),
+                            value("_sum"),
+                            value("_count"),
+                        )
+                    })
+                    .collect(),
+                _ => family
+                    .samples
+                    .iter()
+                    .map(|(name, sample, value)| format!(
+                        "{{\"name\":{},\"labels\":{{{}}},\"value\":{}}}",
+                        json(name),
+                        labels(sample),
+                        number(*value),
+                    ))
+                    .collect(),
+            };
+            format!(
+                "{}:{{\"type\":{},\"help\":{},\"samples\":[{}]}}",
+                json(family.name),
+                json(family.kind),
+                json(family.description),
+                samples.join(","),
+            )
+        })
+        .collect();
+    format!("{{{}}}\n", metrics.join(","))
+}
+
+/// The exports of several instances as one, every sample labelled with its instance and
+/// every metric described just once, ahead of the samples of all instances.
+fn merge(
+    exports: Vec<(&str, String)>,
+) -> String {
+    // Name, description and samples of every metric, in order of appearance.
+    let mut families: Vec<(String, String, String)> = Vec::new();
+    for (instance, export) in &exports {
+        let mut family = None;
+        for line in export.lines().filter(|line| !line.is_empty()) {
+            if let Some(header) = line.strip_prefix("# HELP ") {
+                let name = header.split(' ').next().unwrap_or_default();
+                family = Some(match families.iter().position(|(known, ..)| known == name) {
+                    Some(index) => index,
+                    None => {
+                        families.push((name.to_owned(), String::new(), String::new()));
+                        families.len() - 1
+                    },
+                });
+            }
+            let (_, headers, samples) = match family {
+                Some(index) => &mut families[index],
+                None => continue,
+            };
+            if line.starts_with('#') {
+                if !headers.contains(line) {
+                    headers.push_str(line);
+                    headers.push('\n');
+                }
+            } else if let Some(index) = line.find(['{', ' ']) {
+                let (name, rest) = line.split_at(index);
+                match rest.strip_prefix('{') {
+                    Some(labels) => samples.push_str(&format!("{}{{instance=\"{}\",{}\n", name, instance, labels)),
+                    None => samples.push_str(&format!("{}{{instance=\"{}\"}}{}\n", name, instance, rest)),
+                }
+            }
+        }
+    }
+    families
+        .into_iter()
+        .map(|(_, headers, samples)| headers + &samples + "\n")
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(
+        method: Method,
+        token: Option<&str>,
+    ) -> Request<Body> {
+        let mut request = Request::builder().method(method).uri("/reload");
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn status(
+        access: &Access,
+        request: Request<Body>,
+    ) -> Option<StatusCode> {
+        access.refusal(&request).map(|refusal| refusal.status())
+    }
+
+    #[test]
+    fn refuses_by_credentials() {
+        let access = Access::new(Some("bearer:full".parse().unwrap()), vec!["bearer:read".parse().unwrap()]).unwrap();
+        assert_eq!(status(&access, request(Method::POST, Some("full"))), None);
+        assert_eq!(status(&access, request(Method::GET, Some("full"))), None);
+        assert_eq!(status(&access, request(Method::GET, Some("read"))), None);
+        assert_eq!(status(&access, request(Method::POST, Some("read"))), Some(StatusCode::FORBIDDEN));
+        assert_eq!(status(&access, request(Method::POST, None)), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(&access, request(Method::GET, None)), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(&access, request(Method::GET, Some("other"))), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn refuses_changes_without_full_credentials() {
+        let access = Access::new(None, vec!["bearer:read".parse().unwrap()]).unwrap();
+        assert_eq!(status(&access, request(Method::GET, Some("read"))), None);
+        assert_eq!(status(&access, request(Method::GET, None)), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(&access, request(Method::POST, Some("read"))), Some(StatusCode::FORBIDDEN));
+        assert_eq!(status(&access, request(Method::POST, None)), Some(StatusCode::FORBIDDEN));
+        assert!(Access::new(None, Vec::new()).is_none());
+    }
+
+    #[test]
+    fn lists_features_built_with() {
+        let version = version();
+        assert!(version.contains("\"exporters\""), "{}", version);
+        assert_eq!(version.contains("\"tls\""), cfg!(feature = "tls"), "{}", version);
+        assert_eq!(version.contains("\"control\""), cfg!(feature = "control"), "{}", version);
+        assert!(!version.contains("\"default\""), "{}", version);
+        assert!(!version.contains("\"hyper\""), "{}", version);
     }
 }
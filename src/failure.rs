@@ -0,0 +1,58 @@
+use log::{error, log_enabled, Level};
+use std::fmt;
+
+/// Everything tarssh may give up over, each with an exit code of its own.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Failure {
+    /// The command line or a file it refers to is unusable, e.g. a missing message file.
+    Config,
+    /// Not all listen addresses could be bound.
+    Bind,
+    /// Privileges could not be dropped.
+    #[cfg_attr(not(all(unix, feature = "drop_privs")), allow(dead_code))]
+    Privdrop,
+    /// The runtime could not be set up, e.g. no threads or signal handlers.
+    Runtime,
+    /// A running tarssh could not be reached, or refused a command.
+    #[cfg_attr(not(feature = "control"), allow(dead_code))]
+    Remote,
+}
+
+impl Failure {
+    /// The exit code, as per sysexits.h.
+    pub(crate) fn code(self) -> i32 {
+        match self {
+            Self::Config    => exitcode::CONFIG,
+            Self::Bind      => exitcode::OSERR,
+            Self::Privdrop  => exitcode::NOPERM,
+            Self::Runtime   => exitcode::UNAVAILABLE,
+            Self::Remote    => exitcode::PROTOCOL,
+        }
+    }
+
+    /// Log why and what tarssh gives up over in a single event, then exit; to stderr if there
+    /// is no logging, as for the commands besides the tarpit.
+    pub(crate) fn exit<M: AsRef<str>>(
+        self,
+        message: M,
+    ) -> ! {
+        if log_enabled!(Level::Error) {
+            error!("exit, failure: {}, code: {}, error: {}", self, self.code(), message.as_ref());
+        } else {
+            eprintln!("exit, failure: {}, code: {}, error: {}", self, self.code(), message.as_ref());
+        }
+        std::process::exit(self.code());
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Config    => "config",
+            Self::Bind      => "bind",
+            Self::Privdrop  => "privdrop",
+            Self::Runtime   => "runtime",
+            Self::Remote    => "remote",
+        })
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    fmt,
+    io,
+    net::IpAddr,
+    str::FromStr,
+};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+#[cfg(target_os = "linux")]
+use super::sockopt;
+
+/// Instructions a socket filter may have at most, as Linux has it.
+#[cfg(target_os = "linux")]
+const MAX_INSTRUCTIONS: usize = 4096;
+
+/// A network to drop the packets of, e.g. `203.0.113.0/24` or `2001:db8::/32`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct Prefix {
+    ip:     IpAddr,
+    length: u8,
+}
+
+impl FromStr for Prefix {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (ip, length) = match spec.find('/') {
+            Some(index) => (&spec[..index], Some(&spec[index + 1..])),
+            None => (spec, None),
+        };
+        let ip = ip
+            .parse::<IpAddr>()
+            .map_err(|err| format!("invalid address in \"{}\": {}", spec, err))?;
+        let bits = if ip.is_ipv6() { 128 } else { 32 };
+        let length = match length {
+            Some(length) => match length.parse::<u8>() {
+                Ok(length) if length <= bits => length,
+                _ => return Err(format!("invalid prefix length in \"{}\"", spec)),
+            },
+            None => bits,
+        };
+        Ok(Self { ip, length })
+    }
+}
+
+impl fmt::Debug for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.length)
+    }
+}
+
+impl Prefix {
+    /// The network of an address, as long as given for its family.
+    #[cfg(all(feature = "exporters", not(feature = "aggregate_only")))]
+    pub(crate) fn network(
+        ip: IpAddr,
+        v4: u8,
+        v6: u8,
+    ) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Self {
+                ip:     IpAddr::V4((u32::from(ip) & u32::MAX.checked_shl(32 - v4.min(32) as u32).unwrap_or(0)).into()),
+                length: v4.min(32),
+            },
+            IpAddr::V6(ip) => Self {
+                ip:     IpAddr::V6((u128::from(ip) & u128::MAX.checked_shl(128 - v6.min(128) as u32).unwrap_or(0)).into()),
+                length: v6.min(128),
+            },
+        }
+    }
+
+    /// Whether an address is within the prefix, which it never is of the other family.
+    pub(crate) fn contains(
+        &self,
+        ip: IpAddr,
+    ) -> bool {
+        let (prefix, ip) = match (self.ip, ip) {
+            (IpAddr::V4(prefix), IpAddr::V4(ip)) => (u128::from(u32::from(prefix)) << 96, u128::from(u32::from(ip)) << 96),
+            (IpAddr::V6(prefix), IpAddr::V6(ip)) => (u128::from(prefix), u128::from(ip)),
+            _ => return false,
+        };
+        let mask = u128::MAX.checked_shl(128 - self.length as u32).unwrap_or(0);
+        prefix & mask == ip & mask
+    }
+
+    /// The words of the prefix as a filter loads them, each with the mask of the bits that
+    /// count; none for a prefix of length zero.
+    #[cfg(target_os = "linux")]
+    fn words(&self) -> Vec<(u32, u32)> {
+        let octets = match self.ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        octets
+            .chunks(4)
+            .enumerate()
+            .map(|(index, word)| {
+                let bits = (self.length as usize).saturating_sub(index * 32).min(32);
+                let mask = if bits == 0 { 0 } else { u32::MAX << (32 - bits) };
+                (u32::from_be_bytes([word[0], word[1], word[2], word[3]]) & mask, mask)
+            })
+            .take_while(|&(_, mask)| mask != 0)
+            .collect()
+    }
+}
+
+/// A classic BPF program dropping every packet from any of the prefixes, looking at the IP
+/// header, so IPv4 clients of a dual-stack listener are matched by IPv4 prefixes.
+#[cfg(target_os = "linux")]
+fn program(
+    prefixes: &[Prefix],
+) -> Vec<libc::sock_filter> {
+    // Classic BPF, as in linux/filter.h.
+    const LD_W_ABS: u16 = 0x20;
+    const LD_B_ABS: u16 = 0x30;
+    const ALU_AND_K: u16 = 0x54;
+    const ALU_RSH_K: u16 = 0x74;
+    const JMP_JA: u16 = 0x05;
+    const JMP_JEQ_K: u16 = 0x15;
+    const RET_K: u16 = 0x06;
+    let op = |code, jt, jf, k| libc::sock_filter { code, jt, jf, k };
+    let net = |offset: i32| (libc::SKF_NET_OFF + offset) as u32;
+
+    // Every prefix of a family: for every word, drop to the next prefix unless it matches,
+    // then drop the packet; accept it once none matched.
+    let block = |prefixes: Vec<&Prefix>, source: i32| {
+        let mut block = Vec::new();
+        for prefix in prefixes {
+            let words = prefix.words();
+            for (index, &(word, mask)) in words.iter().enumerate() {
+                let rest = 3 * (words.len() - 1 - index) as u8 + 1;
+                block.push(op(LD_W_ABS, 0, 0, net(source + 4 * index as i32)));
+                block.push(op(ALU_AND_K, 0, 0, mask));
+                block.push(op(JMP_JEQ_K, 0, rest, word));
+            }
+            block.push(op(RET_K, 0, 0, 0));
+        }
+        block.push(op(RET_K, 0, 0, u32::MAX));
+        block
+    };
+    let v4 = block(prefixes.iter().filter(|prefix| prefix.ip.is_ipv4()).collect(), 12);
+    let v6 = block(prefixes.iter().filter(|prefix| prefix.ip.is_ipv6()).collect(), 8);
+
+    // IPv4 packets on to their block, all others past it.
+    let mut program = vec![
+        op(LD_B_ABS, 0, 0, net(0)),
+        op(ALU_RSH_K, 0, 0, 4),
+        op(JMP_JEQ_K, 1, 0, 4),
+        op(JMP_JA, 0, 0, v4.len() as u32),
+    ];
+    program.extend(v4);
+    program.extend(v6);
+    program
+}
+
+/// Have the kernel drop the packets of the prefixes before they reach a listening socket,
+/// SYNs included, so they never get a connection.
+#[cfg(target_os = "linux")]
+pub(crate) fn attach(
+    fd: RawFd,
+    prefixes: &[Prefix],
+) -> io::Result<()> {
+    let mut program = program(prefixes);
+    if program.len() > MAX_INSTRUCTIONS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many prefixes to drop"));
+    }
+    let filter = libc::sock_fprog {
+        len:    program.len() as libc::c_ushort,
+        filter: program.as_mut_ptr(),
+    };
+    // SAFETY: the option is a sock_fprog, and the program outlives the call, which copies it.
+    unsafe { sockopt::set_option(fd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &filter) }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub(crate) fn attach(
+    _fd: RawFd,
+    _prefixes: &[Prefix],
+) -> io::Result<()> {
+    Err(io::Error::other("dropping prefixes takes Linux"))
+}
+
+/// Packets the kernel dropped for a socket so far, by its filter or for lack of room.
+#[cfg(target_os = "linux")]
+pub(crate) fn drops(
+    fd: RawFd,
+) -> io::Result<u32> {
+    let mut meminfo = [0u32; libc::SK_MEMINFO_DROPS as usize + 1];
+    let mut length = std::mem::size_of_val(&meminfo) as libc::socklen_t;
+    // SAFETY: the kernel writes at most length bytes, and tells how many.
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_MEMINFO,
+            meminfo.as_mut_ptr() as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        Err(io::Error::last_os_error())
+    } else if (length as usize) < std::mem::size_of_val(&meminfo) {
+        Err(io::Error::other("no drops in SO_MEMINFO"))
+    } else {
+        Ok(meminfo[libc::SK_MEMINFO_DROPS as usize])
+    }
+}
@@ -0,0 +1,45 @@
+use std::fmt;
+use super::{banner::MessageSpec, watermark::sha256};
+
+/// Options changing nothing about the tarpit, left out so that checking or debugging a sensor
+/// doesn't make it look different.
+const IGNORED: &[&str] = &["verbose", "disable_log_timestamps", "disable_log_ident", "disable_log_level", "check"];
+
+/// A digest of the options of every instance and the content of every message file, to tell
+/// at a glance which sensors of a fleet run alike.
+#[derive(Default)]
+pub(crate) struct Fingerprint {
+    parts: Vec<u8>,
+}
+
+impl Fingerprint {
+    /// Add the options of an instance as parsed, defaults included, and the files they name.
+    pub(crate) fn add<'a>(
+        &mut self,
+        name: &str,
+        options: &impl fmt::Debug,
+        messages: impl IntoIterator<Item = &'a MessageSpec>,
+    ) {
+        self.parts.extend_from_slice(format!("[{}]\n", name).as_bytes());
+        // One line per option, the ones of the options themselves indented further.
+        for line in format!("{:#?}", options).lines() {
+            let option = line.strip_prefix("    ").and_then(|line| line.split(':').next());
+            if !option.is_some_and(|option| IGNORED.contains(&option)) {
+                self.parts.extend_from_slice(line.as_bytes());
+                self.parts.push(b'\n');
+            }
+        }
+        for message in messages {
+            let content = message.content().unwrap_or_else(|err| err.to_string().into_bytes());
+            self.parts.extend_from_slice(&(content.len() as u64).to_be_bytes());
+            self.parts.extend_from_slice(&content);
+        }
+    }
+
+    /// The first 16 hex digits of the SHA-256 of everything added so far.
+    pub(crate) fn finish(
+        &self,
+    ) -> String {
+        sha256(&[&self.parts])[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
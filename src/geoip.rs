@@ -0,0 +1,489 @@
+use std::{
+    convert::TryFrom,
+    fmt,
+    fs,
+    net::IpAddr,
+    path::Path,
+};
+
+/// What starts the metadata at the end of a database.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// Zeroes between the search tree and the data section.
+const DATA_SEPARATOR: usize = 16;
+
+/// How deep maps, arrays and pointers of a record may nest, so a broken database can't
+/// recurse forever.
+const MAX_DEPTH: usize = 32;
+
+/// A country, by its ISO 3166-1 code, e.g. `DE`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Country([u8; 2]);
+
+impl fmt::Display for Country {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.0[0] as char, self.0[1] as char)
+    }
+}
+
+/// A value in the data section of a database, as far as lookups care.
+enum Value<'a> {
+    String(&'a str),
+    Uint(u64),
+    Map(Vec<(&'a str, Value<'a>)>),
+    /// Arrays, doubles, floats, bytes, signed ints and booleans, which no lookup asks for.
+    Other,
+}
+
+impl<'a> Value<'a> {
+    /// The value at a path of keys into maps.
+    fn get(
+        self,
+        path: &[&str],
+    ) -> Option<Value<'a>> {
+        match (path, self) {
+            ([], value) => Some(value),
+            ([key, rest @ ..], Value::Map(entries)) => entries
+                .into_iter()
+                .find(|(name, _)| name == key)
+                .and_then(|(_, value)| value.get(rest)),
+            _ => None,
+        }
+    }
+}
+
+/// A MaxMind database, like GeoLite2-Country or GeoLite2-City, read into memory at once so
+/// it's there after a chroot, and looked up by hand along the MaxMind DB format.
+pub(crate) struct GeoIp {
+    file:        Vec<u8>,
+    node_count:  usize,
+    /// Bits of a record, two records to a node.
+    record_size: usize,
+    /// Where the data section starts.
+    data_start:  usize,
+    /// The node IPv4 addresses start at, after 96 zero bits in an IPv6 tree; none in a tree of
+    /// IPv4 addresses only.
+    ipv4_start:  usize,
+    ipv6:        bool,
+}
+
+impl GeoIp {
+    pub(crate) fn open(
+        path: &Path,
+    ) -> Result<Self, String> {
+        let file = fs::read(path).map_err(|err| format!("geoip, path: {}, error: {}", path.display(), err))?;
+        let invalid = |what: &str| format!("geoip, path: {}, error: {}", path.display(), what);
+        let metadata = file
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or_else(|| invalid("no metadata"))?;
+        let metadata = &file[metadata + METADATA_MARKER.len()..];
+        let (metadata, _) = decode(metadata, 0, 0).ok_or_else(|| invalid("broken metadata"))?;
+        let field = |name: &str| match metadata_field(&metadata, name) {
+            Some(Value::Uint(value)) => Ok(*value as usize),
+            _ => Err(invalid(&format!("no {} in the metadata", name))),
+        };
+        let node_count = field("node_count")?;
+        let record_size = field("record_size")?;
+        let ipv6 = match field("ip_version")? {
+            4 => false,
+            6 => true,
+            _ => return Err(invalid("unknown ip_version")),
+        };
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(invalid("unknown record_size"));
+        }
+        let data_start = node_count * record_size / 4 + DATA_SEPARATOR;
+        if data_start > file.len() {
+            return Err(invalid("truncated search tree"));
+        }
+        let mut geoip = Self {
+            file,
+            node_count,
+            record_size,
+            data_start,
+            ipv4_start: 0,
+            ipv6,
+        };
+        if ipv6 {
+            for _ in 0..96 {
+                if geoip.ipv4_start >= node_count {
+                    break;
+                }
+                geoip.ipv4_start = geoip.record(geoip.ipv4_start, 0).ok_or_else(|| invalid("truncated search tree"))?;
+            }
+        }
+        Ok(geoip)
+    }
+
+    /// The country an address is in.
+    pub(crate) fn country(
+        &self,
+        ip: IpAddr,
+    ) -> Option<Country> {
+        match self.lookup(ip, &["country", "iso_code"])? {
+            Value::String(code) => match code.as_bytes() {
+                &[first, second] => Some(Country([first, second])),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The autonomous system an address is in, by its number, with the organization it
+    /// belongs to if a GeoLite2-ASN database knows.
+    pub(crate) fn asn(
+        &self,
+        ip: IpAddr,
+    ) -> Option<(u32, Option<&str>)> {
+        let number = match self.lookup(ip, &["autonomous_system_number"])? {
+            Value::Uint(number) => u32::try_from(number).ok()?,
+            _ => return None,
+        };
+        let organization = match self.lookup(ip, &["autonomous_system_organization"]) {
+            Some(Value::String(organization)) => Some(organization),
+            _ => None,
+        };
+        Some((number, organization))
+    }
+
+    /// The value at a path of keys into the record of an address.
+    fn lookup(
+        &self,
+        ip: IpAddr,
+        path: &[&str],
+    ) -> Option<Value<'_>> {
+        let (octets, mut node) = match ip {
+            IpAddr::V4(ip) => (ip.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(_) if !self.ipv6 => return None,
+            IpAddr::V6(ip) => (ip.octets().to_vec(), 0),
+        };
+        for index in 0..octets.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (octets[index / 8] >> (7 - index % 8)) & 1;
+            node = self.record(node, bit as usize)?;
+        }
+        // Equal to the node count, a record says the address isn't in the database.
+        let offset = node.checked_sub(self.node_count + DATA_SEPARATOR)?;
+        let (value, _) = decode(&self.file[self.data_start..], offset, 0)?;
+        value.get(path)
+    }
+
+    /// The left (0) or right (1) record of a node.
+    fn record(
+        &self,
+        node: usize,
+        right: usize,
+    ) -> Option<usize> {
+        let length = self.record_size / 4;
+        let bytes = self.file.get(node * length..(node + 1) * length)?;
+        Some(match (self.record_size, right) {
+            (28, 0) => (bytes[3] as usize & 0xf0) << 20 | number(&bytes[..3]),
+            (28, _) => (bytes[3] as usize & 0x0f) << 24 | number(&bytes[4..]),
+            (_, 0) => number(&bytes[..length / 2]),
+            (_, _) => number(&bytes[length / 2..]),
+        })
+    }
+}
+
+/// A field of the metadata map.
+fn metadata_field<'a>(
+    metadata: &'a Value<'a>,
+    name: &str,
+) -> Option<&'a Value<'a>> {
+    match metadata {
+        Value::Map(entries) => entries.iter().find(|(key, _)| *key == name).map(|(_, value)| value),
+        _ => None,
+    }
+}
+
+/// A big-endian unsigned number.
+fn number(
+    bytes: &[u8],
+) -> usize {
+    bytes.iter().fold(0, |number, &byte| number << 8 | byte as usize)
+}
+
+/// The value at an offset of a section, with pointers relative to its start, and the offset of
+/// whatever follows it.
+fn decode(
+    section: &[u8],
+    offset: usize,
+    depth: usize,
+) -> Option<(Value<'_>, usize)> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let control = *section.get(offset)?;
+    let mut at = offset + 1;
+    let mut kind = control >> 5;
+    let mut bytes = |count: usize| {
+        let bytes = section.get(at..at + count)?;
+        at += count;
+        Some(bytes)
+    };
+    if kind == 1 {
+        let high = (control & 0x07) as usize;
+        let pointer = match (control >> 3) & 0x03 {
+            0 => high << 8 | number(bytes(1)?),
+            1 => (high << 16 | number(bytes(2)?)) + 2048,
+            2 => (high << 24 | number(bytes(3)?)) + 526_336,
+            _ => number(bytes(4)?),
+        };
+        let (value, _) = decode(section, pointer, depth + 1)?;
+        return Some((value, at));
+    }
+    if kind == 0 {
+        kind = 7 + bytes(1)?[0];
+    }
+    let size = match control & 0x1f {
+        29 => 29 + number(bytes(1)?),
+        30 => 285 + number(bytes(2)?),
+        31 => 65_821 + number(bytes(3)?),
+        size => size as usize,
+    };
+    let value = match kind {
+        2 => Value::String(std::str::from_utf8(bytes(size)?).ok()?),
+        5 | 6 | 9 | 10 if size <= 8 => Value::Uint(number(bytes(size)?) as u64),
+        3 => bytes(8).map(|_| Value::Other)?,
+        15 => bytes(4).map(|_| Value::Other)?,
+        4 | 8 | 10 => bytes(size).map(|_| Value::Other)?,
+        14 => Value::Other,
+        7 => {
+            let mut entries = Vec::with_capacity(size.min(64));
+            for _ in 0..size {
+                let (key, next) = decode(section, at, depth + 1)?;
+                let (value, next) = decode(section, next, depth + 1)?;
+                match key {
+                    Value::String(key) => entries.push((key, value)),
+                    _ => return None,
+                }
+                at = next;
+            }
+            Value::Map(entries)
+        },
+        11 => {
+            for _ in 0..size {
+                let (_, next) = decode(section, at, depth + 1)?;
+                at = next;
+            }
+            Value::Other
+        },
+        _ => return None,
+    };
+    Some((value, at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    /// A string of the data section, its size in a byte of its own from 29 bytes on.
+    fn string(
+        text: &str,
+    ) -> Vec<u8> {
+        let size = match text.len() {
+            size if size < 29 => vec![2 << 5 | size as u8],
+            size => vec![2 << 5 | 29, (size - 29) as u8],
+        };
+        [&size[..], text.as_bytes()].concat()
+    }
+
+    /// An unsigned 32-bit int of the data section.
+    fn uint(
+        value: u32,
+    ) -> Vec<u8> {
+        let bytes = value.to_be_bytes();
+        let start = bytes.iter().position(|&byte| byte != 0).unwrap_or(4);
+        [&[6 << 5 | (4 - start) as u8][..], &bytes[start..]].concat()
+    }
+
+    /// A map of the data section.
+    fn map(
+        entries: &[(&str, Vec<u8>)],
+    ) -> Vec<u8> {
+        let mut map = vec![7 << 5 | entries.len() as u8];
+        for (key, value) in entries {
+            map.extend(string(key));
+            map.extend_from_slice(value);
+        }
+        map
+    }
+
+    /// A record of the search tree: another node, data at an offset, or nothing.
+    #[derive(Clone, Copy)]
+    enum Record {
+        Node(usize),
+        Data(usize),
+        Empty,
+    }
+
+    /// A database with the prefixes, as bits of the address, pointing to the data at their
+    /// offsets.
+    fn database(
+        ip_version: u32,
+        record_size: usize,
+        prefixes: &[(Vec<u8>, usize)],
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut nodes = vec![[Record::Empty; 2]];
+        for (bits, offset) in prefixes {
+            let mut node = 0;
+            for (index, &bit) in bits.iter().enumerate() {
+                let bit = bit as usize;
+                if index + 1 == bits.len() {
+                    nodes[node][bit] = Record::Data(*offset);
+                } else if let Record::Node(next) = nodes[node][bit] {
+                    node = next;
+                } else {
+                    nodes.push([Record::Empty; 2]);
+                    nodes[node][bit] = Record::Node(nodes.len() - 1);
+                    node = nodes.len() - 1;
+                }
+            }
+        }
+        let node_count = nodes.len();
+        let value = |record: Record| match record {
+            Record::Node(node) => node,
+            Record::Data(offset) => node_count + DATA_SEPARATOR + offset,
+            Record::Empty => node_count,
+        } as u32;
+        let mut file = Vec::new();
+        for [left, right] in nodes {
+            let (left, right) = (value(left), value(right));
+            match record_size {
+                24 => {
+                    file.extend_from_slice(&left.to_be_bytes()[1..]);
+                    file.extend_from_slice(&right.to_be_bytes()[1..]);
+                },
+                28 => {
+                    file.extend_from_slice(&left.to_be_bytes()[1..]);
+                    file.push(((left >> 20) & 0xf0) as u8 | ((right >> 24) & 0x0f) as u8);
+                    file.extend_from_slice(&right.to_be_bytes()[1..]);
+                },
+                _ => {
+                    file.extend_from_slice(&left.to_be_bytes());
+                    file.extend_from_slice(&right.to_be_bytes());
+                },
+            }
+        }
+        file.extend_from_slice(&[0; DATA_SEPARATOR]);
+        file.extend_from_slice(data);
+        file.extend_from_slice(METADATA_MARKER);
+        file.extend(map(&[
+            ("node_count", uint(node_count as u32)),
+            ("record_size", uint(record_size as u32)),
+            ("ip_version", uint(ip_version)),
+            ("database_type", string("tarssh-test")),
+        ]));
+        file
+    }
+
+    /// The first bits of an address.
+    fn bits(
+        octets: &[u8],
+        length: usize,
+    ) -> Vec<u8> {
+        (0..length).map(|index| (octets[index / 8] >> (7 - index % 8)) & 1).collect()
+    }
+
+    /// Write a database to a file of its own, and open it.
+    fn open(
+        name: &str,
+        file: &[u8],
+    ) -> Result<GeoIp, String> {
+        let path = std::env::temp_dir().join(format!("tarssh-geoip-{}-{}.mmdb", name, std::process::id()));
+        std::fs::write(&path, file).unwrap();
+        let geoip = GeoIp::open(&path);
+        let _ = std::fs::remove_file(&path);
+        geoip
+    }
+
+    /// A country record at 0, an ASN record, and a country record pointing to the code of the
+    /// first one, with the offsets of the latter two.
+    fn data() -> (Vec<u8>, usize, usize) {
+        let mut data = map(&[("country", map(&[("iso_code", string("DE")), ("geoname_id", uint(2921044))]))]);
+        let asn = data.len();
+        data.extend(map(&[
+            ("autonomous_system_number", uint(64496)),
+            ("autonomous_system_organization", string("Example")),
+        ]));
+        let linked = data.len();
+        let code = data.windows(3).position(|window| window == string("DE").as_slice()).unwrap();
+        data.extend(map(&[("country", map(&[("iso_code", vec![1 << 5 | (code >> 8) as u8, code as u8])]))]));
+        (data, asn, linked)
+    }
+
+    #[test]
+    fn looks_up_ipv4_databases() {
+        let (data, asn, linked) = data();
+        for &record_size in &[24, 28, 32] {
+            let file = database(4, record_size, &[
+                (bits(&[192, 0, 2, 0], 24), 0),
+                (bits(&[198, 51, 100, 0], 24), asn),
+                (bits(&[203, 0, 113, 0], 24), linked),
+            ], &data);
+            let geoip = open("v4", &file).unwrap();
+            assert_eq!(geoip.country("192.0.2.1".parse().unwrap()).map(|country| country.to_string()), Some("DE".to_owned()));
+            assert_eq!(geoip.country("203.0.113.255".parse().unwrap()).map(|country| country.to_string()), Some("DE".to_owned()));
+            assert_eq!(geoip.asn("198.51.100.7".parse().unwrap()), Some((64496, Some("Example"))));
+            assert!(geoip.country("198.51.100.7".parse().unwrap()).is_none());
+            assert!(geoip.asn("192.0.2.1".parse().unwrap()).is_none());
+            assert!(geoip.country("192.0.3.1".parse().unwrap()).is_none());
+            assert!(geoip.country("2001:db8::1".parse().unwrap()).is_none());
+        }
+    }
+
+    #[test]
+    fn looks_up_ipv6_databases() {
+        let (data, asn, _) = data();
+        let file = database(6, 28, &[
+            (bits(&Ipv6Addr::from([0, 0, 0, 0, 0, 0, 0xc000, 0x0200]).octets(), 120), 0),
+            (bits(&"2001:db8::".parse::<Ipv6Addr>().unwrap().octets(), 32), asn),
+        ], &data);
+        let geoip = open("v6", &file).unwrap();
+        assert_eq!(geoip.country("192.0.2.1".parse().unwrap()).map(|country| country.to_string()), Some("DE".to_owned()));
+        assert_eq!(geoip.asn("2001:db8:1::1".parse().unwrap()), Some((64496, Some("Example"))));
+        assert!(geoip.asn("2001:db9::1".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn rejects_broken_databases() {
+        let (data, _, _) = data();
+        let good = database(4, 24, &[(bits(&[192, 0, 2, 0], 24), 0)], &data);
+        let marker = good.windows(METADATA_MARKER.len()).rposition(|window| window == METADATA_MARKER).unwrap();
+        let with_metadata = |metadata: &[u8]| [&good[..marker + METADATA_MARKER.len()], metadata].concat();
+        let cases: Vec<(&str, Vec<u8>)> = vec![
+            ("no metadata", good[..marker].to_vec()),
+            ("broken metadata", with_metadata(&[0xff])),
+            ("no node_count in the metadata", with_metadata(&map(&[("record_size", uint(24)), ("ip_version", uint(4))]))),
+            ("unknown ip_version", database(5, 24, &[], &data)),
+            ("unknown record_size", database(4, 20, &[], &data)),
+            ("truncated search tree", with_metadata(&map(&[
+                ("node_count", uint(1 << 20)),
+                ("record_size", uint(24)),
+                ("ip_version", uint(4)),
+            ]))),
+        ];
+        for (error, file) in cases {
+            let err = open("broken", &file).err().unwrap_or_else(|| panic!("opened a database with {}", error));
+            assert!(err.ends_with(error), "{}: {}", error, err);
+        }
+        assert!(GeoIp::open(Path::new("/nonexistent/tarssh.mmdb")).is_err());
+    }
+
+    #[test]
+    fn rejects_broken_records() {
+        // A map whose only value points back at the map itself.
+        let data = vec![7 << 5 | 1, 2 << 5 | 1, b'a', 1 << 5, 0];
+        assert!(decode(&data, 0, 0).is_none());
+        // A string running past the end.
+        assert!(decode(&[2 << 5 | 4, b'a'], 0, 0).is_none());
+        // A map with a number for a key.
+        assert!(decode(&[7 << 5 | 1, 6 << 5 | 1, 1, 6 << 5 | 1, 1], 0, 0).is_none());
+        assert!(decode(&[], 0, 0).is_none());
+    }
+}
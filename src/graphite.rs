@@ -0,0 +1,97 @@
+use log::{debug, info, warn};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use super::{
+    exporters::{self, families},
+    metrics::Metrics,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    time::delay_for,
+};
+
+/// Push the metrics of all instances to Graphite, in the plaintext protocol of carbon, instead
+/// of or besides having them scraped.
+pub(crate) struct Graphite {
+    /// Host and port of carbon, e.g. `graphite.example:2003`.
+    addr:     String,
+    interval: Duration,
+    /// What every path starts with.
+    prefix:   String,
+}
+
+impl Graphite {
+    pub(crate) fn new(
+        addr: String,
+        interval: Duration,
+        prefix: String,
+    ) -> Self {
+        info!("graphite, addr: {}, interval: {:?}, prefix: {}", addr, interval, prefix);
+        Self {
+            addr,
+            interval,
+            prefix,
+        }
+    }
+
+    /// Push the metrics every interval, over a connection of their own each time.
+    pub(crate) async fn push(
+        self,
+        instances: Vec<(Option<String>, Arc<Metrics>)>,
+    ) {
+        loop {
+            delay_for(self.interval).await;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let lines = encode(&exporters::export(&instances), &self.prefix, now);
+            let pushed = async {
+                let mut sock = TcpStream::connect(self.addr.as_str()).await?;
+                sock.write_all(lines.as_bytes()).await?;
+                sock.shutdown(std::net::Shutdown::Write)
+            };
+            match pushed.await {
+                Ok(()) => debug!("graphite, addr: {}, bytes: {}", self.addr, lines.len()),
+                Err(err) => warn!("graphite, addr: {}, error: {}", self.addr, err),
+            }
+        }
+    }
+}
+
+/// An export of the text format as lines of the plaintext protocol: every sample at the path
+/// of its name, followed by the names and values of its labels, but those which aren't finite.
+fn encode(
+    export: &str,
+    prefix: &str,
+    now: u64,
+) -> String {
+    let mut lines = String::new();
+    for family in families(export) {
+        for (name, labels, value) in family.samples.iter().filter(|(_, _, value)| value.is_finite()) {
+            let mut path = format!("{}.{}", prefix, name);
+            for (key, label) in labels {
+                path.push('.');
+                path.push_str(key);
+                path.push('.');
+                path.push_str(&node(label));
+            }
+            lines.push_str(&format!("{} {} {}\n", path, value, now));
+        }
+    }
+    lines
+}
+
+/// A label value as a node of a path: everything but letters, digits, `-` and `_` turned into
+/// `_`, dots and spaces of addresses included.
+fn node(
+    label: &str,
+) -> String {
+    label
+        .chars()
+        .map(|character| match character {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => character,
+            _ => '_',
+        })
+        .collect()
+}
@@ -0,0 +1,421 @@
+use log::info;
+#[cfg(unix)]
+use log::error;
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+#[cfg(unix)]
+use std::{
+    ffi::OsString,
+    io::{self, Read, Write},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::UnixStream,
+        process::CommandExt,
+    },
+    path::PathBuf,
+    process::Command,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use super::metrics::Metrics;
+use super::sync::lock;
+#[cfg(unix)]
+use tokio::sync::Notify;
+use tokio::sync::watch;
+
+/// Listeners passed on to a new process, e.g. `0.0.0.0:22=3,[::]:22=4`.
+#[cfg(unix)]
+const LISTEN_FDS: &str = "TARSSH_HANDOVER_FDS";
+
+/// Where a new process tells it took over, by writing a byte.
+#[cfg(unix)]
+const READY_FD: &str = "TARSSH_HANDOVER_READY";
+
+/// Time a new process may take to be ready before the handover is called off.
+#[cfg(unix)]
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Listening sockets passed from a running tarssh to a new one, on `SIGUSR2`, so its clients
+/// stay in the tarpit while the new one takes over accepting.
+pub(crate) struct Handover {
+    /// How this process was started, to start the next one alike.
+    #[cfg(unix)]
+    exe:        std::io::Result<PathBuf>,
+    #[cfg(unix)]
+    args:       Vec<OsString>,
+    /// Whether this process took over from a former one.
+    took_over:  bool,
+    /// Listeners taken over from the former process and not claimed yet, by address.
+    inherited:  Mutex<HashMap<SocketAddr, TcpListener>>,
+    /// To tell the former process once this one is ready.
+    #[cfg(unix)]
+    ready:      Mutex<Option<UnixStream>>,
+    /// Every listener of this process, to pass on.
+    #[cfg(unix)]
+    listening:  Mutex<Vec<(SocketAddr, RawFd)>>,
+    /// Set once the listeners are passed on and nothing is to be accepted anymore.
+    stop:       watch::Sender<bool>,
+    stopped:    watch::Receiver<bool>,
+    /// Set while connections are hung up on as soon as they are accepted, the listeners kept.
+    paused:     AtomicBool,
+    /// Woken to stop accepting without passing anything on.
+    #[cfg(unix)]
+    drain:      Notify,
+    /// How long the drain asked for waits for the clients left, if not until they gave up.
+    #[cfg(unix)]
+    deadline:   Mutex<Option<Duration>>,
+    /// Set once a new process took the listeners over.
+    #[cfg(unix)]
+    handed_over: AtomicBool,
+}
+
+impl Handover {
+    /// Take over the listeners a former process passed on, if any, living as long as the
+    /// tarpit does.
+    pub(crate) fn inherit() -> &'static Self {
+        let (stop, stopped) = watch::channel(false);
+        #[cfg_attr(not(unix), allow(unused_mut))]
+        let mut handover = Self {
+            #[cfg(unix)]
+            exe:        std::env::current_exe(),
+            #[cfg(unix)]
+            args:       std::env::args_os().skip(1).collect(),
+            took_over:  false,
+            inherited:  Mutex::default(),
+            #[cfg(unix)]
+            ready:      Mutex::default(),
+            #[cfg(unix)]
+            listening:  Mutex::default(),
+            stop,
+            stopped,
+            paused:     AtomicBool::new(false),
+            #[cfg(unix)]
+            drain:      Notify::new(),
+            #[cfg(unix)]
+            deadline:   Mutex::default(),
+            #[cfg(unix)]
+            handed_over: AtomicBool::new(false),
+        };
+
+        #[cfg(unix)]
+        if let Some(fds) = std::env::var_os(LISTEN_FDS) {
+            std::env::remove_var(LISTEN_FDS);
+            handover.took_over = true;
+            for listener in fds.to_string_lossy().split(',').filter(|listener| !listener.is_empty()) {
+                let parsed = listener.rfind('=').and_then(|index| Some((
+                    listener[..index].parse::<SocketAddr>().ok()?,
+                    listener[index + 1..].parse::<RawFd>().ok()?,
+                )));
+                match parsed {
+                    Some((addr, fd)) => {
+                        // SAFETY: the former process passed this fd on for just this.
+                        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+                        info!("handover, addr: {}, fd: {}", addr, fd);
+                        lock(&handover.inherited).insert(addr, listener);
+                    },
+                    None => error!("handover, listener: {}, error: invalid", listener),
+                }
+            }
+            let ready = std::env::var(READY_FD).ok().and_then(|fd| fd.parse::<RawFd>().ok());
+            std::env::remove_var(READY_FD);
+            // SAFETY: as above.
+            *lock(&handover.ready) = ready.map(|fd| unsafe { UnixStream::from_raw_fd(fd) });
+        }
+        Box::leak(Box::new(handover))
+    }
+
+    /// Take over a listening socket passed on by whoever started this process, e.g. a
+    /// supervisor binding privileged ports, telling its address.
+    #[cfg(unix)]
+    pub(crate) fn adopt(
+        &self,
+        fd: RawFd,
+    ) -> io::Result<SocketAddr> {
+        let mut listening: libc::c_int = 0;
+        let mut length = std::mem::size_of_val(&listening) as libc::socklen_t;
+        // SAFETY: the option is an int, written along with its size.
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_ACCEPTCONN,
+                &mut listening as *mut libc::c_int as *mut libc::c_void,
+                &mut length,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if listening == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a listening socket"));
+        }
+        // SAFETY: a listening socket, passed on for just this.
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        let addr = listener.local_addr()?;
+        info!("adopt, addr: {}, fd: {}", addr, fd);
+        lock(&self.inherited).insert(addr, listener);
+        Ok(addr)
+    }
+
+    /// Whether this process took over from a former one, and so runs with its privileges.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn took_over(&self) -> bool {
+        self.took_over
+    }
+
+    /// Whether there is a listener to take over on this address.
+    pub(crate) fn inherits(
+        &self,
+        addr: SocketAddr,
+    ) -> bool {
+        lock(&self.inherited).contains_key(&addr)
+    }
+
+    /// The listener to take over on this address, instead of binding it.
+    pub(crate) fn take(
+        &self,
+        addr: SocketAddr,
+    ) -> Option<TcpListener> {
+        lock(&self.inherited).remove(&addr)
+    }
+
+    /// Remember a listener to pass on.
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub(crate) fn listening(
+        &self,
+        addr: SocketAddr,
+        #[cfg(unix)]
+        listener: &impl AsRawFd,
+    ) {
+        #[cfg(unix)]
+        lock(&self.listening).push((addr, listener.as_raw_fd()));
+    }
+
+    /// Forget a listener about to be closed.
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub(crate) fn closing(
+        &self,
+        #[cfg(unix)]
+        listener: &impl AsRawFd,
+    ) {
+        #[cfg(unix)]
+        lock(&self.listening).retain(|&(_, fd)| fd != listener.as_raw_fd());
+    }
+
+    /// Stop accepting as for a handover, but with no new process to take over: the clients
+    /// stay until they give up, or the deadline passes, and then tarssh ends.
+    #[cfg(unix)]
+    pub(crate) fn drain(
+        &self,
+        deadline: Option<Duration>,
+    ) {
+        *lock(&self.deadline) = deadline;
+        self.drain.notify();
+    }
+
+    /// Pause or resume accepting, the listeners staying bound; returns whether it changed.
+    #[cfg_attr(not(any(unix, feature = "exporters")), allow(dead_code))]
+    pub(crate) fn pause(
+        &self,
+        paused: bool,
+    ) -> bool {
+        let changed = self.paused.swap(paused, Ordering::Relaxed) != paused;
+        if changed {
+            info!("pause, paused: {}", paused);
+        }
+        changed
+    }
+
+    /// Whether connections are to be hung up on as soon as they are accepted.
+    pub(crate) fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Pause on `SIGTSTP` and resume on `SIGCONT`, until this process stopped accepting.
+    #[cfg(unix)]
+    pub(crate) async fn pause_on_signals(&self) {
+        use futures::future::{select, Either};
+        use tokio::signal::unix::{signal, SignalKind};
+        let signals = signal(SignalKind::from_raw(libc::SIGTSTP))
+            .and_then(|pause| Ok((pause, signal(SignalKind::from_raw(libc::SIGCONT))?)));
+        let (mut pause, mut resume) = match signals {
+            Ok(signals) => signals,
+            Err(err) => return error!("pause, error: {}", err),
+        };
+        let pausing = async {
+            loop {
+                let paused = pause.recv();
+                let resumed = resume.recv();
+                futures::pin_mut!(paused, resumed);
+                match select(paused, resumed).await {
+                    Either::Left((Some(_), _)) => self.pause(true),
+                    Either::Right((Some(_), _)) => self.pause(false),
+                    _ => break,
+                };
+            }
+        };
+        let stopped = self.stopped();
+        futures::pin_mut!(pausing, stopped);
+        select(pausing, stopped).await;
+    }
+
+    /// Resolves once this process stopped accepting, after handing over its listeners or to
+    /// drain.
+    pub(crate) async fn stopped(&self) {
+        let mut stopped = self.stopped.clone();
+        while let Some(false) = stopped.recv().await {}
+    }
+
+    /// Whether the listeners were handed over to a new process, which runs the show from then on.
+    #[cfg(unix)]
+    pub(crate) fn handed_over(&self) -> bool {
+        self.handed_over.load(Ordering::Relaxed)
+    }
+
+    /// Tell the former process this one took over, closing whatever it didn't claim.
+    pub(crate) fn ready(&self) {
+        let unclaimed: Vec<_> = lock(&self.inherited).drain().collect();
+        for (addr, _) in unclaimed {
+            info!("handover, addr: {}, unclaimed: true", addr);
+        }
+        #[cfg(unix)]
+        {
+            let ready = lock(&self.ready).take();
+            if let Some(mut ready) = ready {
+                if let Err(err) = ready.write_all(b"!") {
+                    error!("handover, error: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Start a new process like this one with all the listeners, and wait for it to be
+    /// ready, returning its pid.
+    #[cfg(unix)]
+    fn pass_on(&self) -> io::Result<u32> {
+        let listening = lock(&self.listening).clone();
+        let exe = self.exe.as_ref().map_err(|err| io::Error::new(err.kind(), err.to_string()))?;
+        let (mut ready, theirs) = UnixStream::pair()?;
+        let theirs_fd = theirs.as_raw_fd();
+        let fds: Vec<_> = listening.iter().map(|&(_, fd)| fd).chain(Some(theirs_fd)).collect();
+        let mut command = Command::new(exe);
+        command
+            .args(&self.args)
+            .env(LISTEN_FDS, listening
+                .iter()
+                .map(|(addr, fd)| format!("{}={}", addr, fd))
+                .collect::<Vec<_>>()
+                .join(","))
+            .env(READY_FD, theirs_fd.to_string());
+        // SAFETY: only fcntl, which is async-signal-safe, runs between fork and exec.
+        unsafe {
+            command.pre_exec(move || {
+                for &fd in &fds {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        let mut child = command.spawn()?;
+        drop(theirs);
+        let pid = child.id();
+        ready.set_read_timeout(Some(READY_TIMEOUT))?;
+        let mut byte = [0u8];
+        let result = match ready.read(&mut byte) {
+            Ok(1) => Ok(pid),
+            Ok(_) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "new process gave up")),
+            Err(err) => {
+                let _ = child.kill();
+                Err(err)
+            },
+        };
+        // Reap it whenever it exits.
+        std::thread::spawn(move || child.wait());
+        result
+    }
+
+    /// Pass the listeners on whenever `SIGUSR2` asks to, or drain on `SIGUSR1`, with the
+    /// deadline given, or when asked to, then stop accepting and wait for the clients left to
+    /// give up; tells which it was.
+    #[cfg(unix)]
+    pub(crate) async fn serve(
+        &'static self,
+        metrics: &[Arc<Metrics>],
+        drain_deadline: Option<Duration>,
+    ) -> &'static str {
+        use futures::future::{select, Either};
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut restart = signal(SignalKind::user_defined2())
+            .map_err(|err| error!("handover, error: {}", err))
+            .ok();
+        let mut drain = signal(SignalKind::user_defined1())
+            .map_err(|err| error!("drain, error: {}", err))
+            .ok();
+        let mut deadline = None;
+        let ended = loop {
+            let restarting = async {
+                match restart.as_mut() {
+                    Some(restart) => restart.recv().await,
+                    None => futures::future::pending().await,
+                }
+            };
+            let signalled = async {
+                match drain.as_mut() {
+                    Some(drain) => drain.recv().await,
+                    None => futures::future::pending().await,
+                }
+            };
+            let asked = self.drain.notified();
+            futures::pin_mut!(restarting, signalled, asked);
+            if let Either::Right((draining, _)) = select(restarting, select(signalled, asked)).await {
+                deadline = match draining {
+                    Either::Left(_) => drain_deadline,
+                    Either::Right(_) => lock(&self.deadline).take(),
+                };
+                info!(
+                    "drain, listeners: {}, clients: {}, deadline: {}",
+                    lock(&self.listening).len(),
+                    clients(metrics),
+                    deadline.map_or_else(|| "none".to_owned(), |deadline| format!("{}s", deadline.as_secs())),
+                );
+                break "drained";
+            }
+            info!("handover, listeners: {}", lock(&self.listening).len());
+            match tokio::task::spawn_blocking(move || self.pass_on()).await {
+                Ok(Ok(pid)) => {
+                    info!("handover, pid: {}, clients: {}", pid, clients(metrics));
+                    self.handed_over.store(true, Ordering::Relaxed);
+                    break "handed over";
+                },
+                Ok(Err(err)) => error!("handover, error: {}", err),
+                Err(err) => error!("handover, error: {}", err),
+            }
+        };
+        let _ = self.stop.broadcast(true);
+        let deadline = deadline.map(|deadline| Instant::now() + deadline);
+        while clients(metrics) > 0 {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                info!("drain, deadline: passed, clients: {}", clients(metrics));
+                break;
+            }
+            tokio::time::delay_for(Duration::from_secs(1)).await;
+        }
+        ended
+    }
+}
+
+#[cfg(unix)]
+fn clients(
+    metrics: &[Arc<Metrics>],
+) -> usize {
+    metrics.iter().map(|metrics| metrics.connections()).sum()
+}
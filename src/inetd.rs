@@ -0,0 +1,73 @@
+use log::info;
+use std::{
+    os::unix::io::FromRawFd,
+    sync::Arc,
+    time::Instant,
+};
+#[cfg(feature = "alerts")]
+use super::alerts::Alerts;
+use super::{
+    failure::Failure,
+    listeners::{self, Defaults},
+    metrics::{Event, Metrics},
+    profiles::Profile,
+    runtime::Runtime,
+    tarpit::tarpit_connection,
+};
+
+/// Tarpit the single connection handed over on stdin, by inetd, tcpserver or a systemd
+/// service with `Accept=yes`, until the client gives up.
+pub(crate) fn serve(
+    runtime: &mut Runtime,
+    defaults: Defaults,
+    metrics: Arc<Metrics>,
+    #[cfg(feature = "alerts")]
+    alerts: Arc<Alerts>,
+) {
+    // SAFETY: stdin is the connection, and nothing else in the process touches it.
+    let sock = unsafe { std::net::TcpStream::from_raw_fd(0) };
+    let peer = sock
+        .peer_addr()
+        .map(listeners::unmap)
+        .unwrap_or_else(|err| Failure::Config.exit(format!("inetd, stdin, error: {}", err)));
+    let local = sock
+        .local_addr()
+        .map(listeners::unmap)
+        .unwrap_or_else(|err| Failure::Config.exit(format!("inetd, stdin, error: {}", err)));
+    if let Some(ban) = metrics.banned(peer.ip()) {
+        return info!("reject, peer: {}, banned: {:?}, inetd: true", peer, ban);
+    }
+    let preamble = Arc::new(defaults.profile.map(Profile::preamble).unwrap_or_default());
+    runtime.block_on(async {
+        let sock = tokio::net::TcpStream::from_std(sock)
+            .unwrap_or_else(|err| Failure::Runtime.exit(format!("inetd, stdin, error: {}", err)));
+        let token = match metrics.connect(peer.ip(), local, Instant::now()) {
+            Ok((_, token)) => token,
+            Err(error) => Failure::Runtime.exit(format!("inetd, peer: {}, error: {}", peer, error)),
+        };
+        info!("connect, peer: {}, inetd: true", peer);
+        metrics.event(|| Event::Connect { id: token.id(), peer, clients: 1 });
+        #[cfg(feature = "alerts")]
+        alerts.accepted(peer.ip());
+        let script = defaults.script(
+            &preamble,
+            peer,
+            &token,
+            &metrics,
+            #[cfg(feature = "alerts")]
+            &alerts,
+        );
+        #[cfg(feature = "alerts")]
+        let inspection = alerts.inspect(peer, script.sampled);
+        let _ = tarpit_connection(
+            sock,
+            peer,
+            defaults.config,
+            token,
+            metrics.clone(),
+            script,
+            #[cfg(feature = "alerts")]
+            inspection,
+        ).await;
+    });
+}
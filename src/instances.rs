@@ -0,0 +1,58 @@
+use std::{collections::HashSet, fs, path::Path};
+
+/// Named tarpits in a config file, each a `[name]` section of long options, one per line as
+/// `option = value`, or just `option` for a flag:
+///
+/// ```text
+/// # The DMZ gets the full show.
+/// [dmz]
+/// listen = 10.0.0.1:22
+/// max-clients = 1000
+/// profile = openssh-debian
+/// disguise
+/// ```
+///
+/// Returns the arguments of every instance, by name, in order.
+pub(crate) fn load(
+    path: &Path,
+) -> Result<Vec<(String, Vec<String>)>, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let mut instances: Vec<(String, Vec<String>)> = Vec::new();
+    let mut names = HashSet::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let at = || format!("{}:{}", path.display(), number + 1);
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            let name = name.trim();
+            if !valid_name(name) {
+                return Err(format!("{}: invalid instance name \"{}\"", at(), name));
+            }
+            if !names.insert(name.to_owned()) {
+                return Err(format!("{}: instance \"{}\" defined twice", at(), name));
+            }
+            instances.push((name.to_owned(), Vec::new()));
+            continue;
+        }
+        let (_, args) = instances
+            .last_mut()
+            .ok_or_else(|| format!("{}: option outside of an [instance]", at()))?;
+        match line.find('=') {
+            Some(index) => {
+                args.push(format!("--{}", line[..index].trim()));
+                args.push(line[index + 1..].trim().to_owned());
+            },
+            None => args.push(format!("--{}", line)),
+        }
+    }
+    Ok(instances)
+}
+
+/// Whether a name may name an instance: letters, digits, `-`, `_` and `.` only.
+pub(crate) fn valid_name(
+    name: &str,
+) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
@@ -0,0 +1,22 @@
+/// Quote a string for JSON.
+pub(crate) fn json(
+    value: &str,
+) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for character in value.chars() {
+        match character {
+            '"'     => quoted.push_str("\\\""),
+            '\\'    => quoted.push_str("\\\\"),
+            '\n'    => quoted.push_str("\\n"),
+            '\r'    => quoted.push_str("\\r"),
+            '\t'    => quoted.push_str("\\t"),
+            character if character.is_control() => {
+                quoted.push_str(&format!("\\u{:04x}", character as u32));
+            },
+            character => quoted.push(character),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
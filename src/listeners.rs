@@ -1,49 +1,887 @@
-use log::{info, warn};
+use futures::{
+    future::{select, Either},
+    pin_mut,
+};
+use log::{debug, error, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{
-    net::SocketAddr,
-    sync::Arc,
+    fmt,
+    io,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+use std::os::unix::io::AsRawFd;
+#[cfg(feature = "alerts")]
+use super::alerts::Alerts;
+#[cfg(feature = "exporters")]
+use super::exporters;
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+use super::sockopt;
 use super::{
-    errx,
-    tarpit::tarpit_connection,
-    metrics::Metrics,
+    banner::{self, Banner, BannerPool, Message, MessageSpec},
+    failure::Failure,
+    filter::{self, Prefix},
+    handover::Handover,
+    profiles::{self, Profile},
+    tarpit::{tarpit_connection, Script, TarpitConfig},
+    metrics::{AcceptError, Event, Metrics, MetricsError, Token},
     runtime::Runtime,
+    sync::lock,
+    template::{self, Context},
+    watermark::Watermark,
 };
 use tokio::{
-    net::TcpListener,
-    time::delay_for,
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    runtime::Handle,
+    sync::oneshot,
+    time::{delay_for, delay_until, timeout},
 };
 
+/// How often a listener on its fallback address tries to get its preferred one back.
+const PROMOTE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Backlog of the listening sockets, as tokio has it.
+const BACKLOG: i32 = 1024;
+
+/// How long accepting pauses once out of file descriptors, to let some connections go first.
+const FDS_PAUSE: Duration = Duration::from_secs(1);
+
+/// How often the packets the kernel dropped for a listener are counted.
+#[cfg(target_os = "linux")]
+const DROPS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long accepting may keep failing before the listener is bound anew.
+const REBIND_AFTER: Duration = Duration::from_secs(10);
+
+/// How long binding a listener anew waits after failing, at first and at most.
+const REBIND_BACKOFF: (Duration, Duration) = (Duration::from_secs(1), Duration::from_secs(60));
+
+/// What a client past the connection limit is told, with `OverLimit::Busy`.
+const BUSY_LINE: &[u8] = b"Server busy, try again later\r\n";
+
+/// How long telling a client past the limit that the server is busy may take.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a client past the connection limit gets, each wasting a different share of its time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OverLimit {
+    /// A clean close, as soon as it's accepted.
+    Drop,
+    /// An immediate reset.
+    Rst,
+    /// A line saying the server is busy, then a clean close.
+    Busy,
+    /// The connection held open for so long without a byte, then closed; at most as many
+    /// clients as the limit are held at once, any more are dropped.
+    Hold(Duration),
+}
+
+impl FromStr for OverLimit {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "drop"  => Ok(Self::Drop),
+            "rst"   => Ok(Self::Rst),
+            "busy"  => Ok(Self::Busy),
+            _ => match name.strip_prefix("hold:").map(str::parse::<u64>) {
+                Some(Ok(seconds)) => Ok(Self::Hold(Duration::from_secs(seconds))),
+                _ => Err(format!(
+                    "unknown over-limit mode \"{}\", expected drop, rst, busy or hold:<secs>",
+                    name,
+                )),
+            },
+        }
+    }
+}
+
+impl OverLimit {
+    /// The mode, as counted.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Drop    => "drop",
+            Self::Rst     => "rst",
+            Self::Busy    => "busy",
+            Self::Hold(_) => "hold",
+        }
+    }
+
+    /// Get rid of a client past the limit, as this mode says; `held` counts the clients held
+    /// open, up to `max_held`.
+    fn turn_away(
+        self,
+        sock: TcpStream,
+        tarpits: &Handle,
+        held: &Arc<AtomicUsize>,
+        max_held: usize,
+    ) {
+        match self {
+            Self::Drop => (),
+            Self::Rst => sock.set_linger(Some(Duration::from_secs(0)))
+                .unwrap_or_else(|err| debug!("set_linger(), error: {}", err)),
+            Self::Busy => {
+                tarpits.spawn(async move {
+                    let mut sock = sock;
+                    let _ = timeout(BUSY_TIMEOUT, sock.write_all(BUSY_LINE)).await;
+                });
+            },
+            Self::Hold(duration) => {
+                if held.fetch_add(1, Ordering::Relaxed) >= max_held {
+                    held.fetch_sub(1, Ordering::Relaxed);
+                    return;
+                }
+                let held = held.clone();
+                tarpits.spawn(async move {
+                    delay_for(duration).await;
+                    drop(sock);
+                    held.fetch_sub(1, Ordering::Relaxed);
+                });
+            },
+        }
+    }
+}
+
+/// An address to listen to, optionally with its own banner and pacing, and an address to
+/// fall back to while it can't be bound, e.g. `0.0.0.0:22,fallback=0.0.0.0:2222,delay=5`, or a
+/// range of ports to listen to alike, e.g. `0.0.0.0:2000-2100`.
+#[derive(Clone, Debug)]
+pub(crate) struct ListenSpec {
+    addr:        SocketAddr,
+    /// The last port of a range, from the port of `addr` on.
+    last_port:   Option<u16>,
+    fallback:    Option<SocketAddr>,
+    v6only:      Option<bool>,
+    /// Whether connections diverted by TPROXY are taken, their destination being the service.
+    transparent: bool,
+    /// Whether the address may be bound before the host has it.
+    freebind:    bool,
+    /// How long a client may take to send something before it is accepted.
+    defer:       Option<Duration>,
+    /// Networks whose packets the kernel drops before they reach the listener.
+    drop_from:   Arc<[Prefix]>,
+    /// What clients past the connection limit get.
+    over_limit:  Option<OverLimit>,
+    delay:       Option<Duration>,
+    timeout:     Option<Duration>,
+    messages:    Vec<MessageSpec>,
+    profile:     Option<&'static Profile>,
+}
+
+impl FromStr for ListenSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut options = spec.split(',');
+        let addr = options.next().unwrap_or_default();
+        let (addr, last_port) = match addr.rfind('-') {
+            Some(index) if addr[..index].parse::<SocketAddr>().is_ok() => (
+                &addr[..index],
+                Some(addr[index + 1..]
+                    .parse::<u16>()
+                    .map_err(|err| format!("invalid last port in \"{}\": {}", spec, err))?),
+            ),
+            _ => (addr, None),
+        };
+        let addr = addr
+            .parse::<SocketAddr>()
+            .map_err(|err| format!("invalid address in \"{}\": {}", spec, err))?;
+        if matches!(last_port, Some(last_port) if last_port < addr.port()) {
+            return Err(format!("empty port range in \"{}\"", spec));
+        }
+        let seconds = |value: &str| value
+            .parse::<u64>()
+            .map(Duration::from_secs)
+            .map_err(|err| format!("invalid seconds \"{}\": {}", value, err));
+
+        let mut listen = Self {
+            addr,
+            last_port,
+            fallback:    None,
+            v6only:      None,
+            transparent: false,
+            freebind:    false,
+            defer:       None,
+            drop_from:   Arc::new([]),
+            over_limit:  None,
+            delay:       None,
+            timeout:     None,
+            messages:    Vec::new(),
+            profile:     None,
+        };
+        for option in options {
+            match option.find('=').map(|index| (&option[..index], &option[index + 1..])) {
+                Some(("fallback", value))    => listen.fallback = Some(
+                    value
+                        .parse()
+                        .map_err(|err| format!("invalid fallback address \"{}\": {}", value, err))?
+                ),
+                Some(("v6only", value))      => listen.v6only = Some(
+                    value
+                        .parse()
+                        .map_err(|err| format!("invalid v6only \"{}\": {}", value, err))?
+                ),
+                Some(("transparent", value)) => listen.transparent =
+                    value
+                        .parse()
+                        .map_err(|err| format!("invalid transparent \"{}\": {}", value, err))?,
+                Some(("defer", value))       => listen.defer = Some(seconds(value)?),
+                Some(("overlimit", value))   => listen.over_limit = Some(value.parse()?),
+                Some(("delay", value))       => listen.delay = Some(seconds(value)?),
+                Some(("timeout", value))     => listen.timeout = Some(seconds(value)?),
+                Some(("message", value))     => listen.messages.push(value.parse()?),
+                Some(("profile", value))     => listen.profile = Some(profiles::find(value)?),
+                _ => return Err(format!("unknown listen option \"{}\"", option)),
+            }
+        }
+        if listen.last_port.is_some() && listen.fallback.is_some() {
+            return Err(format!("no fallback for a port range in \"{}\"", spec));
+        }
+        Ok(listen)
+    }
+}
+
+impl ListenSpec {
+    /// All addresses of a family on a port, IPv6 leaving IPv4 to a listener of its own.
+    pub(crate) fn everywhere(
+        ip: IpAddr,
+        port: u16,
+    ) -> Self {
+        Self {
+            v6only: if ip.is_ipv6() { Some(true) } else { None },
+            ..Self::addr(SocketAddr::new(ip, port))
+        }
+    }
+
+    /// Just an address, without any overrides.
+    pub(crate) fn addr(
+        addr: SocketAddr,
+    ) -> Self {
+        Self {
+            addr,
+            last_port:   None,
+            fallback:    None,
+            v6only:      None,
+            transparent: false,
+            freebind:    false,
+            defer:       None,
+            drop_from:   Arc::new([]),
+            over_limit:  None,
+            delay:       None,
+            timeout:     None,
+            messages:    Vec::new(),
+            profile:     None,
+        }
+    }
+
+    /// A listener for every port of a range, or just this one.
+    pub(crate) fn ports(self) -> Vec<Self> {
+        match self.last_port {
+            Some(last_port) => (self.addr.port()..=last_port)
+                .map(|port| Self {
+                    addr:       SocketAddr::new(self.addr.ip(), port),
+                    last_port:  None,
+                    ..self.clone()
+                })
+                .collect(),
+            None => vec![self],
+        }
+    }
+
+    /// How the address of this listener reads, with its range of ports if it has one.
+    fn range(&self) -> String {
+        match self.last_port {
+            Some(last_port) => format!("{}-{}", self.addr, last_port),
+            None => self.addr.to_string(),
+        }
+    }
+
+    /// The message files of this listener, if it has its own.
+    pub(crate) fn messages(&self) -> &[MessageSpec] {
+        &self.messages
+    }
+
+    pub(crate) fn bind_spec(&self) -> BindSpec {
+        BindSpec {
+            addr:       self.addr,
+            last_port:  self.last_port,
+            fallback:   self.fallback,
+            options:    SocketOptions {
+                v6only:         self.v6only,
+                transparent:    self.transparent,
+                freebind:       self.freebind,
+                defer_accept:   self.defer,
+                drop_from:      self.drop_from.clone(),
+            },
+        }
+    }
+
+    /// Bind the address even if the host doesn't have it (yet), if asked to.
+    pub(crate) fn or_freebind(
+        self,
+        freebind: bool,
+    ) -> Self {
+        Self {
+            freebind: self.freebind || freebind,
+            ..self
+        }
+    }
+
+    /// Turn clients past the limit away as given, unless this listener says otherwise.
+    pub(crate) fn or_over_limit(
+        self,
+        over_limit: OverLimit,
+    ) -> Self {
+        Self {
+            over_limit: self.over_limit.or(Some(over_limit)),
+            ..self
+        }
+    }
+
+    /// Drop the packets of these networks.
+    pub(crate) fn drop_from(
+        self,
+        drop_from: Arc<[Prefix]>,
+    ) -> Self {
+        Self {
+            drop_from,
+            ..self
+        }
+    }
+
+    /// Take IPv6 connections only as given, unless this listener says otherwise.
+    pub(crate) fn or_v6only(
+        self,
+        v6only: Option<bool>,
+    ) -> Self {
+        Self {
+            v6only: self.v6only.or(v6only),
+            ..self
+        }
+    }
+
+    /// The global pacing with the profile and overrides of this listener applied.
+    fn config(
+        &self,
+        config: TarpitConfig,
+    ) -> TarpitConfig {
+        let config = match self.profile {
+            Some(profile) => profile.config(config),
+            None => config,
+        };
+        TarpitConfig {
+            delay:      self.delay.unwrap_or(config.delay),
+            timeout:    self.timeout.unwrap_or(config.timeout),
+            ..config
+        }
+    }
+}
+
+/// What to bind for a listener: its address, the last port of its range if it has one, an
+/// address to fall back to, and how to set up the socket.
+#[derive(Clone, Debug)]
+pub(crate) struct BindSpec {
+    pub(crate) addr:      SocketAddr,
+    pub(crate) last_port: Option<u16>,
+    pub(crate) fallback:  Option<SocketAddr>,
+    pub(crate) options:   SocketOptions,
+}
+
+/// How to set up a listening socket, beyond its address.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SocketOptions {
+    /// Whether an IPv6 address takes IPv6 connections only (`None` for the default of the
+    /// system).
+    pub(crate) v6only:       Option<bool>,
+    /// Take connections to any address, as diverted by a TPROXY rule, keeping the address
+    /// they were meant for.
+    pub(crate) transparent:  bool,
+    /// Bind an address not (yet) configured on the host, like a floating one of VRRP.
+    pub(crate) freebind:     bool,
+    /// Accept connections only once the client sent something, or this long passed.
+    pub(crate) defer_accept: Option<Duration>,
+    /// Have the kernel drop the packets of these networks, SYNs included.
+    pub(crate) drop_from:    Arc<[Prefix]>,
+}
+
+#[cfg(feature = "exporters")]
+impl BindSpec {
+    /// Just an address, the way the system binds it by default.
+    pub(crate) fn addr(
+        addr: SocketAddr,
+    ) -> Self {
+        Self {
+            addr,
+            last_port:  None,
+            fallback:   None,
+            options:    SocketOptions::default(),
+        }
+    }
+}
+
+/// Bind a listening socket, setting `IPV6_V6ONLY`, `IP_TRANSPARENT`, `IP_FREEBIND` and
+/// deferred accepting if asked to.
+fn bind_std(
+    addr: SocketAddr,
+    options: &SocketOptions,
+) -> io::Result<std::net::TcpListener> {
+    let domain = if addr.is_ipv6() { Domain::ipv6() } else { Domain::ipv4() };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    if let (true, Some(v6only)) = (addr.is_ipv6(), options.v6only) {
+        socket.set_only_v6(v6only)?;
+    }
+    if options.transparent {
+        set_transparent(&socket, addr.is_ipv6())?;
+    }
+    if options.freebind {
+        set_freebind(&socket, addr.is_ipv6())?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    // An accept filter takes a listening socket.
+    if let Some(timeout) = options.defer_accept {
+        set_defer_accept(&socket, timeout)?;
+    }
+    Ok(socket.into_tcp_listener())
+}
+
+/// Accept connections to addresses of other hosts, as TPROXY diverts them; takes
+/// `CAP_NET_ADMIN`.
+#[cfg(target_os = "linux")]
+fn set_transparent(
+    socket: &Socket,
+    ipv6: bool,
+) -> io::Result<()> {
+    let (level, name) = if ipv6 {
+        (libc::SOL_IPV6, libc::IPV6_TRANSPARENT)
+    } else {
+        (libc::SOL_IP, libc::IP_TRANSPARENT)
+    };
+    sockopt::set_int_option(socket.as_raw_fd(), level, name, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_transparent(
+    _socket: &Socket,
+    _ipv6: bool,
+) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "transparent listeners take Linux"))
+}
+
+/// Bind addresses the host doesn't have: with `IP_FREEBIND`, which covers IPv6 as well, on
+/// Linux, with `IP_BINDANY` or `IPV6_BINDANY` on FreeBSD, which takes `PRIV_NETINET_BINDANY`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_freebind(
+    socket: &Socket,
+    _ipv6: bool,
+) -> io::Result<()> {
+    sockopt::set_int_option(socket.as_raw_fd(), libc::IPPROTO_IP, libc::IP_FREEBIND, 1)
+}
+
+#[cfg(target_os = "freebsd")]
+fn set_freebind(
+    socket: &Socket,
+    ipv6: bool,
+) -> io::Result<()> {
+    let (level, name) = if ipv6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_BINDANY)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_BINDANY)
+    };
+    sockopt::set_int_option(socket.as_raw_fd(), level, name, 1)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn set_freebind(
+    _socket: &Socket,
+    _ipv6: bool,
+) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "binding addresses the host lacks takes Linux or FreeBSD"))
+}
+
+/// Wake the acceptor only once a client sent something: with `TCP_DEFER_ACCEPT` for so long
+/// on Linux, with the `dataready` accept filter, which has no timeout, on FreeBSD.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_defer_accept(
+    socket: &Socket,
+    timeout: Duration,
+) -> io::Result<()> {
+    let seconds = timeout.as_secs().clamp(1, i32::MAX as u64) as libc::c_int;
+    sockopt::set_int_option(socket.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT, seconds)
+}
+
+#[cfg(target_os = "freebsd")]
+fn set_defer_accept(
+    socket: &Socket,
+    _timeout: Duration,
+) -> io::Result<()> {
+    // SAFETY: all zeroes is an empty filter name and argument.
+    let mut filter: libc::accept_filter_arg = unsafe { std::mem::zeroed() };
+    for (to, from) in filter.af_name.iter_mut().zip(b"dataready") {
+        *to = *from as libc::c_char;
+    }
+    // SAFETY: the option is an accept_filter_arg.
+    unsafe { sockopt::set_option(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_ACCEPTFILTER, &filter) }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn set_defer_accept(
+    _socket: &Socket,
+    _timeout: Duration,
+) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "deferred accepting takes Linux or FreeBSD"))
+}
+
+/// Bind all addresses (or their fallbacks) or none of them, with `claim`, telling for every
+/// one whether it is on its fallback, and reporting on every one: if any of them fails, the
+/// ones bound are closed again.
+fn bind_all(
+    listen: &[BindSpec],
+    mut claim: impl FnMut(SocketAddr, &SocketOptions) -> io::Result<TcpListener>,
+) -> (Option<Vec<(TcpListener, bool)>>, Vec<Report>) {
+    let results: Vec<_> = listen
+        .iter()
+        .map(|&BindSpec { addr, fallback, ref options, .. }| {
+            let result = match (claim(addr, options), fallback) {
+                (Err(err), Some(fallback)) => {
+                    warn!("listen, addr: {}, error: {}, fallback: {}", addr, err, fallback);
+                    claim(fallback, options).map(|listener| (listener, Some(fallback)))
+                },
+                (result, _) => result.map(|listener| (listener, None)),
+            };
+            (addr, result)
+        })
+        .collect();
+    let failed = results.iter().any(|(_, result)| result.is_err());
+    let mut bound = Vec::with_capacity(results.len());
+    let reports = results
+        .into_iter()
+        .map(|(addr, result)| Report {
+            addr,
+            outcome: match result {
+                Ok((listener, fallback)) if !failed => {
+                    bound.push((listener, fallback.is_some()));
+                    Outcome::Bound(fallback)
+                },
+                Ok(_) => Outcome::RolledBack,
+                Err(err) => Outcome::Failed(err),
+            },
+        })
+        .collect();
+    (if failed { None } else { Some(bound) }, reports)
+}
+
+/// What changing the listeners did to an address.
+#[derive(Debug)]
+pub(crate) enum Outcome {
+    /// Bound, on the fallback if there is one.
+    Bound(Option<SocketAddr>),
+    /// Bound, and closed again as another address couldn't be.
+    RolledBack,
+    /// Closed, as it is not to be listened on anymore.
+    Closed,
+    Failed(io::Error),
+}
+
+/// An address bound or closed along with others, and how that went.
+#[derive(Debug)]
+pub(crate) struct Report {
+    pub(crate) addr:    SocketAddr,
+    pub(crate) outcome: Outcome,
+}
+
+impl Report {
+    pub(crate) fn failed(&self) -> bool {
+        matches!(self.outcome, Outcome::Failed(_))
+    }
+
+    /// Log it as what happened to the address, as bad as it was.
+    pub(crate) fn log(
+        &self,
+        context: &str,
+    ) {
+        match self.outcome {
+            Outcome::Bound(_) | Outcome::Closed => info!("{}, {}", context, self),
+            Outcome::RolledBack => warn!("{}, {}", context, self),
+            Outcome::Failed(_) => error!("{}, {}", context, self),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Outcome::Bound(None)            => write!(f, "addr: {}", self.addr),
+            Outcome::Bound(Some(fallback))  => write!(f, "addr: {}, fallback: {}", self.addr, fallback),
+            Outcome::RolledBack             => write!(f, "addr: {}, rolled back", self.addr),
+            Outcome::Closed                 => write!(f, "addr: {}, close: true", self.addr),
+            Outcome::Failed(err)            => write!(f, "addr: {}, error: {}", self.addr, err),
+        }
+    }
+}
+
+/// Bind a listening socket for the accept loops; must run on their executor.
+fn bind(
+    addr: SocketAddr,
+    options: &SocketOptions,
+) -> io::Result<TcpListener> {
+    drop_from(TcpListener::from_std(bind_std(addr, options)?)?, options)
+}
+
+/// Attach the filter of the networks to drop to a listener, if there are any.
+fn drop_from(
+    listener: TcpListener,
+    options: &SocketOptions,
+) -> io::Result<TcpListener> {
+    if !options.drop_from.is_empty() {
+        #[cfg(unix)]
+        filter::attach(std::os::unix::io::AsRawFd::as_raw_fd(&listener), &options.drop_from)?;
+        #[cfg(not(unix))]
+        return Err(io::Error::new(io::ErrorKind::Other, "dropping prefixes takes Linux"));
+    }
+    Ok(listener)
+}
+
+/// Take over the listener a former process passed on for this address, or else bind it; must
+/// run on the executor of the accept loops.
+fn claim(
+    handover: &Handover,
+    addr: SocketAddr,
+    options: &SocketOptions,
+) -> io::Result<TcpListener> {
+    match handover.take(addr) {
+        // Networks to drop of this process replace those of the former one.
+        Some(listener) => drop_from(TcpListener::from_std(listener)?, options),
+        None => bind(addr, options),
+    }
+}
+
+/// Whether accepting failed for lack of file descriptors, of the process or the whole system.
+fn out_of_fds(
+    err: &io::Error,
+) -> bool {
+    #[cfg(unix)]
+    return matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE));
+    #[cfg(not(unix))]
+    return false;
+}
+
+/// The IPv4 address of a client connected to a dual-stack listener, instead of the IPv6 one
+/// it is mapped to.
+pub(crate) fn unmap(
+    peer: SocketAddr,
+) -> SocketAddr {
+    match peer.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), peer.port()),
+            None => peer,
+        },
+        IpAddr::V4(_) => peer,
+    }
+}
+
+/// Share of the connections getting the costly treatment, payload inspection and echo,
+/// e.g. `1%` or `0.01`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Sample(f64);
+
+impl Sample {
+    /// Whether a new connection is to be sampled.
+    fn pick(self) -> bool {
+        self.0 >= 1.0 || (self.0 > 0.0 && rand::random::<f64>() < self.0)
+    }
+}
+
+impl FromStr for Sample {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let share = match spec.strip_suffix('%') {
+            Some(percent) => percent.trim().parse::<f64>().map(|percent| percent / 100.0),
+            None => spec.parse::<f64>(),
+        }
+        .map_err(|err| format!("invalid sample \"{}\": {}", spec, err))?;
+        if !(0.0..=1.0).contains(&share) {
+            return Err(format!("invalid sample \"{}\", expected 0% to 100%", spec));
+        }
+        Ok(Self(share))
+    }
+}
+
+impl std::fmt::Display for Sample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}%", self.0 * 100.0)
+    }
+}
+
+/// What every listener goes with, unless it has overrides of its own.
+#[derive(Clone)]
+pub(crate) struct Defaults {
+    pub(crate) config:    TarpitConfig,
+    pub(crate) banners:   Arc<BannerPool>,
+    pub(crate) profile:   Option<&'static Profile>,
+    /// Number of decoy lines sent after the preamble.
+    pub(crate) decoys:    usize,
+    /// Shuffle and pad the banner for every connection.
+    pub(crate) disguise:  bool,
+    /// Send back whatever clients send, between the chunks of the banner.
+    pub(crate) echo:      bool,
+    /// Sign every connection with a line at the end of the preamble.
+    pub(crate) watermark: Option<Arc<Watermark>>,
+    /// Number of the oldest connections to drop whenever accepting runs out of file descriptors.
+    pub(crate) shed:      Option<usize>,
+    /// Share of the connections to inspect the payload of and echo.
+    pub(crate) sample:    Sample,
+    /// Addresses scraping the metrics on the tarpit listeners, instead of being tarpitted.
+    #[cfg(feature = "exporters")]
+    pub(crate) monitors:  Arc<[IpAddr]>,
+}
+
+impl Defaults {
+    /// Everything a new connection gets to read, made up for it.
+    pub(crate) fn script(
+        &self,
+        preamble: &Arc<Vec<u8>>,
+        peer: SocketAddr,
+        token: &Token,
+        metrics: &Metrics,
+        #[cfg(feature = "alerts")]
+        alerts: &Alerts,
+    ) -> Script {
+        let banner = match self.banners.pick() {
+            Message::Template(template) => {
+                let canary = if template.has_canary() {
+                    let canary = template::canary();
+                    info!("canary, peer: {}, canary: {}", peer, canary);
+                    #[cfg(feature = "alerts")]
+                    alerts.issued(&canary, peer);
+                    Some(canary)
+                } else {
+                    None
+                };
+                let banner = template.render(&Context {
+                    peer,
+                    connection_id:  token.id(),
+                    uptime:         metrics.uptime(),
+                    canary,
+                });
+                Banner::Text(if self.disguise {
+                    Arc::new(banner::disguise(&banner))
+                } else {
+                    banner
+                })
+            },
+            // Streamed as they are, too large to render or disguise.
+            Message::Stream(stream) => Banner::Stream(stream),
+        };
+        let preamble = match (self.decoys, &self.watermark) {
+            (0, None) => preamble.clone(),
+            (decoys, watermark) => {
+                let mut preamble = [&preamble[..], &profiles::decoys(decoys)].concat();
+                if let Some(watermark) = watermark {
+                    preamble.extend_from_slice(format!("Session-ID: {}\r\n", watermark.mark(peer, token.id())).as_bytes());
+                }
+                Arc::new(preamble)
+            },
+        };
+        let sampled = self.sample.pick();
+        if sampled {
+            debug!("sample, peer: {}", peer);
+            metrics.sampled();
+        }
+        Script {
+            preamble,
+            banner,
+            echo: if self.echo && sampled { Some(Arc::default()) } else { None },
+            sampled,
+        }
+    }
+}
+
 pub(crate) struct Listeners {
-    inner: Vec<TcpListener>,
+    /// Every listener with whether it is on its fallback address.
+    inner: Vec<(TcpListener, bool, ListenSpec)>,
 }
 
 impl Listeners {
     pub(crate) fn new(
         runtime: &mut Runtime,
-        listen: Vec<SocketAddr>,
+        listen: Vec<ListenSpec>,
+        handover: &Handover,
     ) -> Self {
-        Self {
-            inner:
-                listen
-                .iter()
-                .map(
-                    |addr| match runtime.block_on(async { TcpListener::bind(addr).await }) {
-                        Ok(listener) => {
-                            info!("listen, addr: {}", addr);
-                            listener
-                        }
-                        Err(err) => {
-                            errx(
-                                exitcode::OSERR,
-                                format!("listen, addr: {}, error: {}", addr, err),
-                            );
-                        }
-                    },
-                )
-                .collect()
+        let (ranges, listen): (Vec<_>, Vec<_>) = listen.into_iter().partition(|listen| listen.last_port.is_some());
+        let binds: Vec<_> = listen.iter().map(ListenSpec::bind_spec).collect();
+        let mut inner: Vec<_> = match Self::bind(runtime, &binds, handover) {
+            Ok(inner) => inner
+                .into_iter()
+                .zip(listen)
+                .map(|((listener, standby), listen)| (listener, standby, listen))
+                .collect(),
+            Err(failures) => Failure::Bind.exit(
+                format!("listen, addrs: {}, failures: {}", listen.len(), failures),
+            ),
+        };
+        for range in ranges {
+            inner.extend(Self::bind_range(runtime, range, handover));
+        }
+        Self { inner }
+    }
+
+    /// Bind every port of a range that can be, giving up only if none of them can.
+    ///
+    /// Ports already taken are common enough on a wide range, so they are merely counted and
+    /// told about with `-vv`.
+    fn bind_range(
+        runtime: &mut Runtime,
+        range: ListenSpec,
+        handover: &Handover,
+    ) -> Vec<(TcpListener, bool, ListenSpec)> {
+        let addrs = range.range();
+        let mut bound = Vec::new();
+        let mut failures = 0;
+        for listen in range.ports() {
+            let BindSpec { addr, options, .. } = listen.bind_spec();
+            match runtime.block_on_acceptor(async { claim(handover, addr, &options) }) {
+                Ok(listener) => bound.push((listener, false, listen)),
+                Err(err) => {
+                    debug!("listen, addr: {}, error: {}", addr, err);
+                    failures += 1;
+                },
+            }
         }
+        if bound.is_empty() {
+            Failure::Bind.exit(format!("listen, addrs: {}, failures: {}", addrs, failures));
+        }
+        info!("listen, addrs: {}, bound: {}, failures: {}", addrs, bound.len(), failures);
+        bound
+    }
+
+    /// Bind all addresses (or their fallbacks) or none of them, telling for every one
+    /// whether it is on its fallback.
+    ///
+    /// Every address is tried and reported; if any of them fails, the ones bound so far are
+    /// closed again and the number of failures is returned.  Listeners handed over by a former
+    /// process are taken over instead of bound.
+    pub(crate) fn bind(
+        runtime: &mut Runtime,
+        listen: &[BindSpec],
+        handover: &Handover,
+    ) -> Result<Vec<(TcpListener, bool)>, usize> {
+        let (bound, reports) = bind_all(listen, |addr, options| {
+            runtime.block_on_acceptor(async { claim(handover, addr, options) })
+        });
+        for report in &reports {
+            report.log("listen");
+        }
+        bound.ok_or_else(|| reports.iter().filter(|report| report.failed()).count())
     }
 
     pub(crate) fn len(
@@ -52,64 +890,713 @@ impl Listeners {
         self.inner.len()
     }
 
+    /// Start accepting on every listener, returning them as a set which can still change.
     pub(crate) fn spawn(
         self,
         runtime: &Runtime,
         max_clients: usize,
-        delay: Duration,
-        timeout: Duration,
+        defaults: Defaults,
         metrics: Arc<Metrics>,
-        banner: String,
-    ) {
+        #[cfg(feature = "alerts")]
+        alerts: Arc<Alerts>,
+        handover: &'static Handover,
+    ) -> std::io::Result<Arc<Acceptors>> {
         info!(
-            "start, servers: {}, max_clients: {}, delay: {}s, timeout: {}s, banner:\n{}",
+            "start, servers: {}, max_clients: {}, delay: {}s, timeout: {}s, write_retries: {}, max_session: {:?}, backoff: {:?}, banners: {}, decoys: {}, disguise: {}, echo: {}, watermark: {}, sample: {}",
             self.len(),
             max_clients,
-            delay.as_secs(),
-            timeout.as_secs(),
-            banner,
+            defaults.config.delay.as_secs(),
+            defaults.config.timeout.as_secs(),
+            defaults.config.write_retries,
+            defaults.config.max_session,
+            defaults.config.backoff,
+            defaults.banners.len(),
+            defaults.decoys,
+            defaults.disguise,
+            defaults.echo,
+            defaults.watermark.is_some(),
+            defaults.sample,
         );
-        let banner = Arc::new(banner.into_bytes());
-        for mut listener in self.inner {
-            let banner = banner.clone();
-            let metrics = metrics.clone();
-            let server = async move {
-                loop {
-                    match listener.accept().await {
-                        Ok((sock, peer)) => {
-                            let metrics = metrics.clone();
-                            match metrics.connect(max_clients, Instant::now()) {
-                                Ok((connected, token)) => {
-                                    info!("connect, peer: {}, clients: {}", peer, connected);
-                                    tokio::spawn(
-                                        tarpit_connection(
-                                            sock,
-                                            peer,
-                                            delay,
-                                            timeout,
-                                            token,
-                                            metrics.clone(),
-                                            banner.clone()
-                                        )
-                                    );
-                                },
-                                Err(connected) => info!("reject, peer: {}, clients: {}", peer, connected),
+        metrics.limit(max_clients);
+        runtime.spawn_acceptor(probe_acceptor(metrics.clone()));
+        let acceptors = Arc::new(Acceptors {
+            metrics,
+            #[cfg(feature = "alerts")]
+            alerts,
+            handover,
+            tarpits:    runtime.handle().clone(),
+            acceptor:   runtime.acceptor(),
+            held:       Arc::default(),
+            open:       Mutex::new(Open {
+                defaults,
+                listeners:  Vec::new(),
+            }),
+        });
+        for (listener, standby, listen) in self.inner {
+            acceptors.start(listener, standby, listen)?;
+        }
+        Ok(acceptors)
+    }
+}
+
+/// The listeners of a tarpit while it runs, and all it takes to open more of them.
+pub(crate) struct Acceptors {
+    metrics:     Arc<Metrics>,
+    #[cfg(feature = "alerts")]
+    alerts:      Arc<Alerts>,
+    handover:    &'static Handover,
+    tarpits:     Handle,
+    acceptor:    Handle,
+    /// Clients past the limit held open, of all listeners.
+    held:        Arc<AtomicUsize>,
+    open:        Mutex<Open>,
+}
+
+/// What the listeners of a tarpit accept with unless they override it, and the listeners,
+/// changed together.
+struct Open {
+    defaults:  Defaults,
+    /// Every listener accepting, or about to.
+    listeners: Vec<Listening>,
+}
+
+/// A listener accepting, with what closes it and what it accepts with.
+struct Listening {
+    listen:   ListenSpec,
+    close:    oneshot::Sender<()>,
+    settings: Arc<Mutex<Arc<Settings>>>,
+}
+
+/// What a listener accepts with, the defaults of its tarpit with its own overrides, for the
+/// connections to come.
+struct Settings {
+    defaults: Defaults,
+    preamble: Arc<Vec<u8>>,
+}
+
+impl Settings {
+    /// The defaults with the overrides of a listener, its message files read.
+    fn new(
+        defaults: &Defaults,
+        listen: &ListenSpec,
+    ) -> std::io::Result<Self> {
+        let banners = match (listen.messages.is_empty(), listen.profile) {
+            (false, _) => Arc::new(BannerPool::load(&listen.messages)?),
+            (true, Some(profile)) => Arc::new(profile.banners()),
+            (true, None) => defaults.banners.clone(),
+        };
+        let profile = listen.profile.or(defaults.profile);
+        Ok(Self {
+            defaults: Defaults {
+                config: listen.config(defaults.config.clone()),
+                banners,
+                profile,
+                ..defaults.clone()
+            },
+            preamble: Arc::new(profile.map(Profile::preamble).unwrap_or_default()),
+        })
+    }
+}
+
+impl Acceptors {
+    /// Start accepting on a listener.
+    fn start(
+        self: &Arc<Self>,
+        listener: TcpListener,
+        standby: bool,
+        listen: ListenSpec,
+    ) -> std::io::Result<()> {
+        // Made up along with the listener being added, so a reload can't slip in between.
+        let mut open = lock(&self.open);
+        let settings = Settings::new(&open.defaults, &listen)?;
+        self.accept(&mut open, listener, standby, listen, settings);
+        Ok(())
+    }
+
+    /// Accept on a listener with the settings made up for it, adding it to those open.
+    fn accept(
+        self: &Arc<Self>,
+        open: &mut Open,
+        mut listener: TcpListener,
+        standby: bool,
+        listen: ListenSpec,
+        settings: Settings,
+    ) {
+        if listen.delay.is_some()
+        || listen.timeout.is_some()
+        || !listen.messages.is_empty()
+        || listen.profile.is_some()
+        {
+            info!(
+                "override, addr: {}, profile: {}, delay: {}s, timeout: {}s, banners: {}",
+                listen.addr,
+                listen.profile.map_or("none", |profile| profile.name),
+                settings.defaults.config.delay.as_secs(),
+                settings.defaults.config.timeout.as_secs(),
+                settings.defaults.banners.len(),
+            );
+        }
+        let current = Arc::new(Mutex::new(Arc::new(settings)));
+        let metrics = self.metrics.clone();
+        let tarpits = self.tarpits.clone();
+        #[cfg(feature = "alerts")]
+        let alerts = self.alerts.clone();
+        let handover = self.handover;
+        let over_limit = listen.over_limit.unwrap_or(OverLimit::Drop);
+        let held = self.held.clone();
+        let rate = metrics.listener(listen.addr, over_limit.name());
+        let addr = listen.addr;
+        let options = listen.bind_spec().options;
+        let transparent = listen.transparent;
+        let mut promotion = match (standby, listen.fallback) {
+            (true, Some(fallback)) => {
+                let (promote, promoted) = oneshot::channel();
+                self.acceptor.spawn(until_stopped(handover, self::promote(listen.bind_spec(), fallback, promote)));
+                Some(promoted)
+            },
+            _ => None,
+        };
+        if let Ok(addr) = listener.local_addr() {
+            handover.listening(
+                addr,
+                #[cfg(unix)]
+                &listener,
+            );
+        }
+        let (close, closed) = oneshot::channel::<()>();
+        open.listeners.push(Listening {
+            listen,
+            close,
+            settings: current.clone(),
+        });
+        let server = async move {
+            // Closed on purpose only, not just as the set of listeners goes away.
+            let closed = async {
+                if closed.await.is_err() {
+                    futures::future::pending::<()>().await;
+                }
+            };
+            pin_mut!(closed);
+            // The address actually bound, which may be the fallback, and since when accepting
+            // fails.
+            let mut bound = listener.local_addr().unwrap_or(addr);
+            let mut failing: Option<Instant> = None;
+            #[cfg(target_os = "linux")]
+            let mut count_drops = Instant::now() + DROPS_INTERVAL;
+            loop {
+                // While on the fallback, the preferred address may become available.
+                let accepted = {
+                    let next = async {
+                        match promotion.as_mut() {
+                            None => Either::Left(listener.accept().await),
+                            Some(promoted) => {
+                                let accept = listener.accept();
+                                pin_mut!(accept);
+                                match select(accept, promoted).await {
+                                    Either::Left((accepted, _)) => Either::Left(accepted),
+                                    Either::Right((promoted, _)) => Either::Right(promoted),
+                                }
+                            },
+                        }
+                    };
+                    // Now and then, the packets the kernel dropped are counted in between.
+                    let counting = async {
+                        #[cfg(target_os = "linux")]
+                        delay_until(count_drops.into()).await;
+                        #[cfg(not(target_os = "linux"))]
+                        futures::future::pending::<()>().await;
+                    };
+                    pin_mut!(next, counting);
+                    match select(select(next, counting), closed.as_mut()).await {
+                        Either::Left((Either::Left((next, _)), _)) => Some(next),
+                        Either::Left((Either::Right(_), _)) => None,
+                        Either::Right(_) => break,
+                    }
+                };
+                let accepted = match accepted {
+                    Some(accepted) => accepted,
+                    None => {
+                        #[cfg(target_os = "linux")]
+                        {
+                            count_drops = Instant::now() + DROPS_INTERVAL;
+                            match filter::drops(std::os::unix::io::AsRawFd::as_raw_fd(&listener)) {
+                                Ok(drops) => rate.dropped(drops as usize),
+                                Err(err) => debug!("drops, addr: {}, error: {}", addr, err),
+                            }
+                        }
+                        continue;
+                    },
+                };
+                let accepted = match accepted {
+                    Either::Left(accepted) => accepted,
+                    Either::Right(promoted) => {
+                        promotion = None;
+                        if let Ok(promoted) = promoted {
+                            handover.closing(
+                                #[cfg(unix)]
+                                &listener,
+                            );
+                            if let Ok(addr) = promoted.local_addr() {
+                                handover.listening(
+                                    addr,
+                                    #[cfg(unix)]
+                                    &promoted,
+                                );
                             }
+                            bound = promoted.local_addr().unwrap_or(addr);
+                            listener = promoted;
                         }
-                        Err(err) => match err.kind() {
-                            std::io::ErrorKind::ConnectionRefused
-                            | std::io::ErrorKind::ConnectionAborted
-                            | std::io::ErrorKind::ConnectionReset => (),
-                            _ => {
-                                let wait = Duration::from_millis(100);
-                                warn!("accept, err: {}, wait: {:?}", err, wait);
-                                delay_for(wait).await;
+                        continue;
+                    },
+                };
+                if accepted.is_ok() {
+                    failing = None;
+                }
+                match accepted {
+                    Ok((sock, peer)) => {
+                        let peer = unmap(peer);
+                        let settings = lock(&current).clone();
+                        let Settings { defaults, preamble } = &*settings;
+                        #[cfg(feature = "exporters")]
+                        if defaults.monitors.contains(&peer.ip()) {
+                            tarpits.spawn(exporters::monitor(sock, peer, metrics.clone()));
+                            continue;
+                        }
+                        rate.accepted();
+                        if handover.paused() {
+                            info!("reject, peer: {}, paused: true", peer);
+                            metrics.paused();
+                            continue;
+                        }
+                        let connected = match admit(&metrics, peer, bound) {
+                            Some(connected) => connected,
+                            None => continue,
+                        };
+                        let metrics = metrics.clone();
+                        match connected {
+                            Ok((connected, token)) => {
+                                // Diverted by TPROXY, the listener got it for whatever address it was meant for.
+                                match sock.local_addr().ok().filter(|_| transparent).map(unmap) {
+                                    Some(service) => {
+                                        info!("connect, peer: {}, clients: {}, id: {}, service: {}", peer, connected, token.id(), service);
+                                        metrics.service(service);
+                                    },
+                                    None => info!("connect, peer: {}, clients: {}, id: {}", peer, connected, token.id()),
+                                }
+                                metrics.event(|| Event::Connect { id: token.id(), peer, clients: connected });
+                                if let Some((asn, organization)) = metrics.asn(peer.ip()) {
+                                    info!("asn, peer: {}, asn: {}, organization: {:?}", peer, asn, organization.unwrap_or_default());
+                                }
+                                #[cfg(feature = "alerts")]
+                                alerts.accepted(peer.ip());
+                                let script = defaults.script(
+                                    preamble,
+                                    peer,
+                                    &token,
+                                    &metrics,
+                                    #[cfg(feature = "alerts")]
+                                    &alerts,
+                                );
+                                #[cfg(feature = "alerts")]
+                                let inspection = alerts.inspect(peer, script.sampled);
+                                tarpits.spawn(
+                                    tarpit_connection(
+                                        sock,
+                                        peer,
+                                        defaults.config.clone(),
+                                        token,
+                                        metrics.clone(),
+                                        script,
+                                        #[cfg(feature = "alerts")]
+                                        inspection,
+                                    )
+                                );
+                            },
+                            Err(MetricsError::OverLimit { count }) => {
+                                info!("reject, peer: {}, clients: {}, over_limit: {}", peer, count, over_limit.name());
+                                rate.over_limit();
+                                #[cfg(feature = "alerts")]
+                                alerts.rejected();
+                                over_limit.turn_away(sock, &tarpits, &held, metrics.max_clients());
+                            },
+                            Err(error) => warn!("reject, peer: {}, error: {}", peer, error),
+                        }
+                    }
+                    Err(err) if out_of_fds(&err) => {
+                        rate.accept_error(AcceptError::Emfile);
+                        let shed = lock(&current).defaults.shed.map_or(0, |count| metrics.shed(count));
+                        warn!("accept, err: {}, pause: {:?}, shed: {}", err, FDS_PAUSE, shed);
+                        delay_for(FDS_PAUSE).await;
+                    },
+                    Err(err) => match err.kind() {
+                        std::io::ErrorKind::ConnectionRefused => rate.accept_error(AcceptError::Refused),
+                        std::io::ErrorKind::ConnectionAborted
+                        | std::io::ErrorKind::ConnectionReset => rate.accept_error(AcceptError::Reset),
+                        _ if matches!(failing, Some(since) if since.elapsed() >= REBIND_AFTER) => {
+                            warn!("rebind, addr: {}, error: {}", bound, err);
+                            rate.accept_error(AcceptError::Other);
+                            handover.closing(
+                                #[cfg(unix)]
+                                &listener,
+                            );
+                            drop(listener);
+                            let rebound = {
+                                let rebind = rebind(bound, options.clone());
+                                pin_mut!(rebind);
+                                match select(rebind, closed.as_mut()).await {
+                                    Either::Left((rebound, _)) => rebound,
+                                    Either::Right(_) => return info!("close, addr: {}", addr),
+                                }
+                            };
+                            if let Ok(addr) = rebound.local_addr() {
+                                handover.listening(
+                                    addr,
+                                    #[cfg(unix)]
+                                    &rebound,
+                                );
                             }
+                            listener = rebound;
+                            failing = None;
+                            rate.rebound();
                         },
+                        _ => {
+                            rate.accept_error(AcceptError::Other);
+                            failing.get_or_insert_with(Instant::now);
+                            let wait = Duration::from_millis(100);
+                            warn!("accept, err: {}, wait: {:?}", err, wait);
+                            delay_for(wait).await;
+                        }
+                    },
+                }
+            }
+            // Closed for good, the clients it accepted stay.
+            handover.closing(
+                #[cfg(unix)]
+                &listener,
+            );
+            info!("close, addr: {}", addr);
+        };
+        self.acceptor.spawn(until_stopped(handover, server));
+    }
+
+    /// What the listeners accept with unless they override it.
+    pub(crate) fn defaults(&self) -> (usize, Defaults) {
+        let open = lock(&self.open);
+        (self.metrics.max_clients(), open.defaults.clone())
+    }
+
+    /// Accept with these from now on, for the connections to come, the overrides of every
+    /// listener applied anew and its message files re-read; if any of them can't be, nothing
+    /// changes.
+    pub(crate) fn reconfigure(
+        &self,
+        max_clients: usize,
+        defaults: Defaults,
+    ) -> std::io::Result<()> {
+        let mut open = lock(&self.open);
+        let settings = open.listeners
+            .iter()
+            .map(|listening| Settings::new(&defaults, &listening.listen))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        for (listening, settings) in open.listeners.iter().zip(settings) {
+            *lock(&listening.settings) = Arc::new(settings);
+        }
+        self.metrics.limit(max_clients);
+        open.defaults = defaults;
+        Ok(())
+    }
+
+    /// Listen on exactly these addresses from now on, all or nothing: bind every one missing
+    /// first, and close those not among them only once all are bound; if any of them can't
+    /// be, or a message file of its overrides can't be read, the ones just bound are closed
+    /// again and the listeners stay as they were.  Reports on every address bound or closed,
+    /// as the error if anything failed.
+    ///
+    /// Listeners are told apart by their address; to change the options of one, it has to be
+    /// removed first.
+    pub(crate) fn update(
+        self: &Arc<Self>,
+        listen: Vec<ListenSpec>,
+    ) -> Result<Vec<Report>, Vec<Report>> {
+        let listen: Vec<_> = listen.into_iter().flat_map(ListenSpec::ports).collect();
+        // Held throughout, so neither a reload nor a listener being added can slip in between.
+        let mut open = lock(&self.open);
+        let (kept, closing): (Vec<_>, Vec<_>) = std::mem::take(&mut open.listeners)
+            .into_iter()
+            .partition(|open| listen.iter().any(|listen| listen.addr == open.listen.addr));
+        open.listeners = kept;
+        let added: Vec<_> = listen
+            .into_iter()
+            .filter(|listen| !open.listeners.iter().any(|open| open.listen.addr == listen.addr))
+            .collect();
+        let binds: Vec<_> = added.iter().map(ListenSpec::bind_spec).collect();
+        // Bound on the executor to accept on.
+        let (bound, mut reports) = bind_all(&binds, |addr, options| {
+            self.acceptor.enter(|| claim(self.handover, addr, options))
+        });
+        let settings: Vec<_> = added.iter().map(|listen| Settings::new(&open.defaults, listen)).collect();
+        let bound = match bound {
+            Some(bound) if settings.iter().all(Result::is_ok) => bound,
+            _ => {
+                for (report, settings) in reports.iter_mut().zip(settings) {
+                    match (&report.outcome, settings) {
+                        (Outcome::Bound(_), Err(err)) => report.outcome = Outcome::Failed(err),
+                        (Outcome::Bound(_), Ok(_)) => report.outcome = Outcome::RolledBack,
+                        _ => {},
                     }
                 }
-            };
-            runtime.spawn(server);
+                open.listeners.extend(closing);
+                return Err(reports);
+            },
+        };
+        for closing in closing {
+            let _ = closing.close.send(());
+            reports.push(Report { addr: closing.listen.addr, outcome: Outcome::Closed });
+        }
+        for (((listener, standby), listen), settings) in bound.into_iter().zip(added).zip(settings) {
+            if let Ok(settings) = settings {
+                self.accept(&mut open, listener, standby, listen, settings);
+            }
         }
+        Ok(reports)
+    }
+}
+
+/// Run a task of a listener until the listeners are handed over to a new process.
+/// Connect a client accepted on a listener, unless its peer is banned: hung up on then, it
+/// never counts as connected.
+fn admit(
+    metrics: &Metrics,
+    peer: SocketAddr,
+    bound: SocketAddr,
+) -> Option<Result<(usize, Token), MetricsError>> {
+    if let Some(ban) = metrics.banned(peer.ip()) {
+        info!("reject, peer: {}, banned: {:?}", peer, ban);
+        return None;
+    }
+    Some(metrics.connect(peer.ip(), bound, Instant::now()))
+}
+
+pub(crate) async fn until_stopped(
+    handover: &Handover,
+    task: impl std::future::Future<Output = ()>,
+) {
+    let stopped = handover.stopped();
+    pin_mut!(task, stopped);
+    select(task, stopped).await;
+}
+
+/// Try to bind every address (or else its fallback), all at the same time, and report each
+/// of them; returns the number of failures.
+///
+/// Nothing is kept bound, this merely tells early whether binding for real would work; the
+/// listeners to take over from a former process count as bound.  A range of ports fails only
+/// if none of its ports can be bound.
+pub(crate) fn preflight(
+    addrs: &[BindSpec],
+    handover: &Handover,
+) -> usize {
+    let bind_std = |addr, options| if handover.inherits(addr) {
+        Ok(None)
+    } else {
+        bind_std(addr, options).map(Some)
+    };
+    let (ranges, addrs): (Vec<_>, Vec<_>) = addrs.iter().partition(|bind| bind.last_port.is_some());
+    let range_failures = ranges
+        .iter()
+        .filter(|&&&BindSpec { addr, last_port, ref options, .. }| {
+            let last_port = last_port.unwrap_or_else(|| addr.port());
+            // Bound all at once, as for real.
+            let results: Vec<_> = (addr.port()..=last_port)
+                .map(|port| bind_std(SocketAddr::new(addr.ip(), port), options))
+                .collect();
+            let failures = results.iter().filter(|result| result.is_err()).count();
+            if failures == results.len() {
+                error!("check, addrs: {}-{}, failures: {}", addr, last_port, failures);
+                true
+            } else {
+                info!("check, addrs: {}-{}, ok: {}, failures: {}", addr, last_port, results.len() - failures, failures);
+                false
+            }
+        })
+        .count();
+    let results: Vec<_> = addrs
+        .iter()
+        .map(|&&BindSpec { addr, fallback, ref options, .. }| match (bind_std(addr, options), fallback) {
+            (Err(err), Some(fallback)) => {
+                warn!("check, addr: {}, error: {}, fallback: {}", addr, err, fallback);
+                (fallback, bind_std(fallback, options))
+            },
+            (result, _) => (addr, result),
+        })
+        .collect();
+    results
+        .iter()
+        .filter(|(addr, result)| match result {
+            Ok(_) => {
+                info!("check, addr: {}, ok", addr);
+                false
+            },
+            Err(err) => {
+                error!("check, addr: {}, error: {}", addr, err);
+                true
+            },
+        })
+        .count() + range_failures
+}
+
+/// Try to bind the preferred address of a listener on its fallback every so often, and hand it
+/// over once that works.
+async fn promote(
+    BindSpec { addr, options, .. }: BindSpec,
+    fallback: SocketAddr,
+    promote: oneshot::Sender<TcpListener>,
+) {
+    loop {
+        delay_for(PROMOTE_INTERVAL).await;
+        match bind(addr, &options) {
+            Ok(listener) => {
+                info!("promote, addr: {}, fallback: {}", addr, fallback);
+                let _ = promote.send(listener);
+                return;
+            },
+            Err(err) => debug!("promote, addr: {}, error: {}", addr, err),
+        }
+    }
+}
+
+/// Bind a listener anew, once its address is back, waiting longer after every failure.
+async fn rebind(
+    addr: SocketAddr,
+    options: SocketOptions,
+) -> TcpListener {
+    let (mut wait, max_wait) = REBIND_BACKOFF;
+    loop {
+        match bind(addr, &options) {
+            Ok(listener) => {
+                info!("rebind, addr: {}, ok", addr);
+                return listener;
+            },
+            Err(err) => {
+                warn!("rebind, addr: {}, error: {}, wait: {:?}", addr, err, wait);
+                delay_for(wait).await;
+                wait = (wait * 2).min(max_wait);
+            },
+        }
+    }
+}
+
+/// Measure how late the executor of the accept loops wakes up, as a proxy of its accept latency.
+async fn probe_acceptor(
+    metrics: Arc<Metrics>,
+) {
+    let interval = Duration::from_secs(1);
+    loop {
+        let expected = Instant::now() + interval;
+        delay_until(expected.into()).await;
+        metrics.accept_latency(Instant::now().saturating_duration_since(expected));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_listen_specs() {
+        let listen: ListenSpec = "0.0.0.0:22,fallback=0.0.0.0:2222,delay=5,timeout=60,overlimit=busy"
+            .parse()
+            .unwrap();
+        assert_eq!(listen.addr, "0.0.0.0:22".parse().unwrap());
+        assert_eq!(listen.fallback, Some("0.0.0.0:2222".parse().unwrap()));
+        assert_eq!(listen.delay, Some(Duration::from_secs(5)));
+        assert_eq!(listen.timeout, Some(Duration::from_secs(60)));
+        assert_eq!(listen.over_limit, Some(OverLimit::Busy));
+        let listen: ListenSpec = "[::]:22,v6only=true,transparent=true,defer=3,profile=dropbear,message=motd.txt:3"
+            .parse()
+            .unwrap();
+        assert_eq!(listen.v6only, Some(true));
+        assert!(listen.transparent);
+        assert_eq!(listen.defer, Some(Duration::from_secs(3)));
+        assert_eq!(listen.profile.map(|profile| profile.name), Some("dropbear"));
+        assert_eq!(listen.messages.len(), 1);
+    }
+
+    #[test]
+    fn splits_port_ranges() {
+        let listen: ListenSpec = "127.0.0.1:2000-2002,delay=1".parse().unwrap();
+        assert_eq!(listen.range(), "127.0.0.1:2000-2002");
+        let ports: Vec<_> = listen.ports().into_iter().map(|listen| (listen.addr.port(), listen.delay)).collect();
+        let delay = Some(Duration::from_secs(1));
+        assert_eq!(ports, [(2000, delay), (2001, delay), (2002, delay)]);
+        let listen: ListenSpec = "[::1]:2222-2222".parse().unwrap();
+        assert_eq!(listen.ports().len(), 1);
+    }
+
+    #[test]
+    fn rejects_listen_specs() {
+        for spec in &[
+            "",
+            "localhost:22",
+            "0.0.0.0",
+            "0.0.0.0:22,",
+            "0.0.0.0:22,bogus=1",
+            "0.0.0.0:22,delay",
+            "0.0.0.0:22,delay=soon",
+            "0.0.0.0:22,fallback=nowhere",
+            "0.0.0.0:22,v6only=yes",
+            "0.0.0.0:22,overlimit=slow",
+            "0.0.0.0:22,profile=telnet",
+            "0.0.0.0:22,message=:3",
+            "0.0.0.0:2100-2000",
+            "0.0.0.0:2000-70000",
+            "0.0.0.0:2000-2100,fallback=0.0.0.0:3000",
+        ] {
+            assert!(spec.parse::<ListenSpec>().is_err(), "accepted \"{}\"", spec);
+        }
+    }
+
+    #[test]
+    fn parses_over_limit_modes() {
+        assert_eq!("drop".parse(), Ok(OverLimit::Drop));
+        assert_eq!("rst".parse(), Ok(OverLimit::Rst));
+        assert_eq!("busy".parse(), Ok(OverLimit::Busy));
+        assert_eq!("hold:30".parse(), Ok(OverLimit::Hold(Duration::from_secs(30))));
+        assert_eq!(OverLimit::Hold(Duration::from_secs(30)).name(), "hold");
+        for spec in &["", "DROP", "hold", "hold:", "hold:-1", "hold:30s", "reset"] {
+            assert!(spec.parse::<OverLimit>().is_err(), "accepted \"{}\"", spec);
+        }
+    }
+
+    #[test]
+    fn parses_samples() {
+        for (spec, share) in &[("0", 0.0), ("1", 1.0), ("0.01", 0.01), ("1%", 0.01), ("100%", 1.0), ("12.5 %", 0.125)] {
+            let sample: Sample = spec.parse().unwrap();
+            assert!((sample.0 - share).abs() < 1e-9, "\"{}\" is {} rather than {}", spec, sample.0, share);
+        }
+        assert_eq!("25%".parse::<Sample>().unwrap().to_string(), "25%");
+        assert!("100%".parse::<Sample>().unwrap().pick());
+        assert!(!"0%".parse::<Sample>().unwrap().pick());
+    }
+
+    #[test]
+    fn rejects_samples() {
+        for spec in &["", "%", "-1%", "101%", "1.5", "-0.1", "half", "NaN"] {
+            assert!(spec.parse::<Sample>().is_err(), "accepted \"{}\"", spec);
+        }
+    }
+
+    #[test]
+    fn turns_banned_peers_away() {
+        use crate::{bans::{Bans, Change}, metrics::History, runtime::Runtime, shared::Shared};
+        let bans = Bans::load(None).unwrap();
+        bans.change(Change::Add("203.0.113.0/24".parse().unwrap())).unwrap();
+        let shared = Arc::new(Shared { geoip: None, asn_db: None, bans });
+        let metrics = Metrics::new(&Runtime::new(None, None), History { peers: 0, sessions: 0 }, "", shared, Duration::default());
+        let bound = "[::]:2222".parse().unwrap();
+        assert!(admit(&metrics, "203.0.113.9:50000".parse().unwrap(), bound).is_none());
+        assert_eq!(metrics.connections(), 0);
+        assert!(admit(&metrics, "198.51.100.9:50000".parse().unwrap(), bound).unwrap().is_ok());
+        assert_eq!(metrics.connections(), 1);
     }
 }
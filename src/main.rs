@@ -22,44 +22,158 @@
 #![cfg_attr(feature = "nightly", feature(external_doc))]
 #![cfg_attr(feature = "nightly", doc(include = "../README.md"))]
 
+// Cargo turns on the features a feature takes; builds passing features as cfgs by hand have to.
+#[cfg(all(
+    any(
+        feature = "aggregate_only",
+        feature = "control",
+        feature = "profiling",
+        feature = "tls",
+        feature = "top",
+    ),
+    not(feature = "exporters"),
+))]
+compile_error!("the aggregate_only, control, profiling, tls and top features take the exporters feature");
+
+/// Take commands on a local unix socket.
+#[cfg(unix)]
+mod admin;
+/// Raise the alarm about what clients send.
+#[cfg(feature = "alerts")]
+mod alerts;
+/// Turn away networks banned at runtime.
+mod bans;
+/// Banners to waffle with.
+mod banner;
+/// Share a bandwidth budget between connections.
+mod budget;
+/// Recommend delays against the timeouts of common clients.
+mod calibrate;
+/// Check everything sent before the version string against RFC 4253.
+#[cfg(test)]
+mod compliance;
+/// Control a running tarssh through its exporter.
+#[cfg(feature = "control")]
+mod control;
+/// Ways to fail.
+mod failure;
+/// Tell the configurations of several sensors apart.
+mod fingerprint;
+/// Drop the packets of some networks in the kernel.
+mod filter;
 /// Export some statistics.
 #[cfg(feature = "exporters")]
 mod exporters;
+/// Push the metrics to an OpenTelemetry collector.
+#[cfg(feature = "exporters")]
+mod otlp;
+/// Push the metrics to Graphite.
+#[cfg(feature = "exporters")]
+mod graphite;
+/// Look up where peers are in a MaxMind database.
+mod geoip;
+/// Write JSON by hand.
+#[cfg(any(feature = "alerts", feature = "exporters"))]
+mod json;
+/// Hand the listeners over to a new tarssh on restart.
+mod handover;
+/// Named tarpits from a config file.
+mod instances;
+/// Tarpit a connection handed over by inetd.
+#[cfg(unix)]
+mod inetd;
 /// Listen to ssh-connections.
 mod listeners;
 /// Everything to do with keeping track what happend.
 mod logging;
 /// Collect some statistics.
 mod metrics;
+/// Tell systemd how tarssh is doing.
+#[cfg(unix)]
+mod notify;
+/// Write metrics in the OpenMetrics format.
+#[cfg(feature = "exporters")]
+mod openmetrics;
+/// Write metrics in the Prometheus text format.
+#[cfg(feature = "exporters")]
+mod registry;
+/// Tell how the process itself is doing, for performance investigations.
+#[cfg(feature = "profiling")]
+mod profiling;
+/// Realistic-looking kinds of servers.
+mod profiles;
 /// Drop privileges.
 #[cfg(all(unix, feature = "drop_privs"))]
 mod privilege_dropper;
+/// Reload the config and message files on `SIGHUP` or through the exporter.
+mod reload;
 /// Parallel execution of tasks.
 mod runtime;
+/// What the instances share.
+mod shared;
+/// Set the options of sockets nothing else has a setter for.
+#[cfg(unix)]
+mod sockopt;
+/// Sum up what happened, now and then in the log or when asked.
+mod summary;
+/// Serve the exporter over TLS.
+#[cfg(feature = "tls")]
+mod tls;
+/// Lock mutexes whatever a panicking task left behind.
+mod sync;
 /// The actual ssh-tarpit.
 mod tarpit;
+/// Fun content now and then.
+mod surprises;
+/// What tarpitted clients are connected over.
+mod transport;
+/// Answer UDP scanners slowly.
+mod udp;
+/// Expand placeholders in banners.
+mod template;
+/// Sign connections to trace leaked banners back to them.
+mod watermark;
+/// Watch a running tarssh from the terminal.
+#[cfg(feature = "top")]
+mod top;
 
-use listeners::Listeners;
-use log::{error, info, warn};
-#[cfg(not(feature = "exporters"))]
-use metrics::Metrics;
-#[cfg(not(feature = "exporters"))]
-use std::sync::Arc;
+#[cfg(feature = "alerts")]
+use alerts::{Alerts, Thresholds};
+use banner::{BannerPool, MessageSpec};
+use bans::Bans;
+use budget::Budget;
+use shared::Shared;
+#[cfg(feature = "exporters")]
+use listeners::BindSpec;
+use listeners::{until_stopped, Defaults, ListenSpec, Listeners, OverLimit, Sample};
+use failure::Failure;
+use filter::Prefix;
+use fingerprint::Fingerprint;
+use geoip::GeoIp;
+use log::info;
+use metrics::{History, Metrics};
+use profiles::Profile;
 #[cfg(feature = "exporters")]
 use exporters::Exporter;
+#[cfg(feature = "exporters")]
+use graphite::Graphite;
+#[cfg(feature = "exporters")]
+use otlp::Otlp;
 #[cfg(all(unix, feature = "drop_privs"))]
 use privilege_dropper::PrivDropConfig;
-use runtime::Runtime;
+use runtime::{Runtime, Scheduler, Threads};
+use surprises::Surprises;
+use tarpit::{Backoff, ChunkSize, Disconnect, Keepalive, TarpitConfig, Tiers};
+use udp::UdpTarpit;
 use std::{
-    io::{
-        BufReader,
-        prelude::*,
-    },
-    fs::File,
-    net::SocketAddr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
     time::Duration,
 };
 use structopt::StructOpt;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
 
 #[cfg(all(unix, feature = "sandbox"))]
 use rusty_sandbox::Sandbox;
@@ -67,25 +181,127 @@ use rusty_sandbox::Sandbox;
 #[derive(Debug, StructOpt)]
 #[structopt(name = "tarssh", about = "A SSH tarpit server")]
 struct Config {
-    /// Listen address(es) to bind to of the tarpit.
-    #[structopt(short = "l", long = "listen", default_value = "0.0.0.0:2222")]
-    listen: Vec<SocketAddr>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+    /// Listen address(es) or port ranges to bind to of the tarpit, optionally with overrides
+    /// (e.g. "0.0.0.0:22,fallback=0.0.0.0:2222,v6only=true,transparent=true,defer=10,overlimit=rst,delay=5,timeout=10,message=telnet.txt"
+    /// or "0.0.0.0:2000-2100"), "0.0.0.0:2222" if neither this nor --listen-all is given.
+    #[structopt(short = "l", long = "listen")]
+    listen: Vec<ListenSpec>,
+    /// Port(s) to listen to on all addresses, of IPv4 and IPv6 alike.
+    #[structopt(long = "listen-all")]
+    listen_all: Vec<u16>,
+    /// Already bound listening socket(s) passed on by whoever starts tarssh, by file descriptor
+    /// (e.g. "3").
+    #[cfg(unix)]
+    #[structopt(long = "listen-fd")]
+    listen_fd: Vec<RawFd>,
+    /// UDP address(es) to answer datagrams on, late and with random bytes (e.g. "0.0.0.0:161").
+    #[structopt(long = "udp")]
+    udp: Vec<SocketAddr>,
+    /// Bytes per second all UDP replies together may send.
+    #[structopt(long = "udp-bandwidth", default_value = "4096", parse(try_from_str = budget::parse_rate))]
+    udp_bandwidth: usize,
+    /// Network(s) whose packets the kernel drops before they reach the listeners, SYNs included
+    /// (e.g. "203.0.113.0/24"), on Linux.
+    #[structopt(long = "drop-from")]
+    drop_from: Vec<Prefix>,
+    /// File to keep the networks banned through the exporter in, to be banned again on
+    /// startup; none unless given, and bans are gone with tarssh.
+    #[structopt(long = "bans", parse(from_os_str))]
+    bans: Option<PathBuf>,
+    /// Whether IPv6 listeners take IPv6 connections only, instead of the default of the system.
+    #[structopt(long = "v6only")]
+    v6only: Option<bool>,
+    /// Bind listen addresses the host doesn't have (yet), like floating ones of keepalived, with
+    /// IP_FREEBIND on Linux or IP_BINDANY on FreeBSD.
+    #[structopt(long = "freebind")]
+    freebind: bool,
     /// Best-effort connection limit.
     #[structopt(short = "c", long = "max-clients", default_value = "4096")]
     max_clients: u32,
+    /// What clients past the limit get: drop (a clean close), rst, busy (a line saying so) or
+    /// hold:<secs> (held open without a byte), unless a listener says otherwise.
+    #[structopt(long = "over-limit", default_value = "drop")]
+    over_limit: OverLimit,
     /// Seconds between responses.
     #[structopt(short = "d", long = "delay", default_value = "10")]
     delay: u64,
+    /// Bytes per response, fixed or a random range (e.g. "8-32").
+    #[structopt(long = "chunk-size", default_value = "16")]
+    chunk_size: ChunkSize,
     /// Socket write timeout.
     #[structopt(short = "t", long = "timeout", default_value = "30")]
     timeout: u64,
+    /// Retries of a chunk after transient write errors.
+    #[structopt(long = "write-retries", default_value = "0")]
+    write_retries: u32,
+    /// Maximum duration of a connection (e.g. "12h", "3days").
+    #[structopt(long = "max-session", parse(try_from_str = humantime::parse_duration))]
+    max_session: Option<Duration>,
+    /// Double the delay of a connection every so often (e.g. "10min").
+    #[structopt(long = "backoff", parse(try_from_str = humantime::parse_duration))]
+    backoff: Option<Duration>,
+    /// Delays by age of a connection instead of --delay (e.g. "5min=2s,1h=10s,60s").
+    #[structopt(long = "tiers")]
+    tiers: Option<Tiers>,
+    /// Probe connections idle for so long with TCP keepalive, to drop peers gone (e.g. "5min").
+    #[structopt(long = "keepalive", parse(try_from_str = humantime::parse_duration))]
+    keepalive: Option<Duration>,
+    /// Time between unanswered keepalive probes, instead of the default of the system.
+    #[structopt(long = "keepalive-interval", parse(try_from_str = humantime::parse_duration))]
+    keepalive_interval: Option<Duration>,
+    /// Unanswered keepalive probes after which a peer is gone, instead of the default of the system.
+    #[structopt(long = "keepalive-count")]
+    keepalive_count: Option<u32>,
+    /// Seconds the delay may grow to with --backoff.
+    #[structopt(long = "max-delay", default_value = "300")]
+    max_delay: u64,
+    /// Stay silent until the client sent its SSH identification, waiting at most so long (e.g. "10s").
+    #[structopt(long = "strict", parse(try_from_str = humantime::parse_duration))]
+    strict: Option<Duration>,
+    /// How to drop a client: fin, rst or linger (stop sending, wait for the client to close).
+    #[structopt(long = "disconnect", default_value = "fin")]
+    disconnect: Disconnect,
+    /// Bytes per second all connections together may send; new clients go first.
+    #[structopt(long = "max-bandwidth", alias = "byte-budget", parse(try_from_str = budget::parse_rate))]
+    max_bandwidth: Option<usize>,
+    /// Bytes per second a single connection may send.
+    #[structopt(long = "max-connection-bandwidth", parse(try_from_str = budget::parse_rate))]
+    max_connection_bandwidth: Option<usize>,
+    /// Drop the oldest so many connections whenever accepting runs out of file descriptors.
+    #[structopt(long = "shed-on-emfile")]
+    shed_on_emfile: Option<usize>,
+    /// Share of the connections to inspect the payload of and echo, e.g. "1%" on busy sensors.
+    #[structopt(long = "sample", default_value = "100%")]
+    sample: Sample,
+    /// Number of peers to remember the former connections of.
+    #[structopt(long = "peer-history", default_value = "65536")]
+    peer_history: usize,
+    /// Number of the latest connections ended to keep, for the exporter to hand out as CSV or
+    /// JSON lines.
+    #[structopt(long = "session-archive", default_value = "1024")]
+    session_archive: usize,
+    /// Config file of named tarpit instances, sections like "[dmz]" of long options like
+    /// "listen = 10.0.0.1:22".
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Only check whether all addresses can be bound, then exit.
+    #[structopt(long = "check")]
+    check: bool,
+    /// Tarpit the single connection on stdin, as started by inetd, then exit.
+    #[cfg(unix)]
+    #[structopt(long = "inetd")]
+    inetd: bool,
     /// Verbose level (repeat for more verbosity).
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: u8,
-    /// Use threads, with optional thread count.
+    /// Scheduler to run on: basic or threaded.
+    #[structopt(long = "scheduler")]
+    scheduler: Option<Scheduler>,
+    /// Worker threads of the threaded scheduler: "auto" or a count.
     #[structopt(long = "threads")]
-    #[allow(clippy::option_option)]
-    threads: Option<Option<usize>>,
+    threads: Option<Threads>,
     /// Disable timestamps in logs.
     #[structopt(long)]
     disable_log_timestamps: bool,
@@ -95,27 +311,309 @@ struct Config {
     /// Disable log level in logs (e.g. "info").
     #[structopt(long)]
     disable_log_level: bool,
+    /// Time between summaries of the clients and connections logged (e.g. "10m"); none unless
+    /// given.
+    #[structopt(long = "summary", parse(try_from_str = humantime::parse_duration))]
+    summary: Option<Duration>,
+    /// Unix socket to take commands like "show sessions" or "drain" on (e.g.
+    /// "/run/tarssh/control.sock"); none unless given.
+    #[cfg(unix)]
+    #[structopt(long = "control-socket", parse(from_os_str))]
+    control_socket: Option<PathBuf>,
+    /// Time a drain on SIGUSR1 waits for the clients left before tarssh ends anyway (e.g.
+    /// "1h"); until they all gave up unless given.
+    #[cfg(unix)]
+    #[structopt(long = "drain-deadline", parse(try_from_str = humantime::parse_duration))]
+    drain_deadline: Option<Duration>,
     #[cfg(all(unix, feature = "drop_privs"))]
     #[structopt(flatten)]
     #[cfg(all(unix, feature = "drop_privs"))]
     privdrop: PrivDropConfig,
-    /// Filename of the tarpit-message.
-    #[structopt(short = "m", long = "message", default_value = "")]
-    message: String,
-    /// Listen address(es) to bind to of the exporter.
-    #[structopt(short = "e", long = "exporter", default_value = "0.0.0.0:8080")]
+    /// Filename(s) of the tarpit-message, optionally weighted (e.g. "file.txt:3").
+    #[structopt(short = "m", long = "message")]
+    message: Vec<MessageSpec>,
+    /// Pretend to be a certain kind of server: openssh-debian, dropbear or cisco.
+    #[structopt(short = "p", long = "profile", parse(try_from_str = profiles::find))]
+    profile: Option<&'static Profile>,
+    /// Number of realistic-looking lines to send before the message, picked at random.
+    #[structopt(long = "decoy-lines", default_value = "0")]
+    decoy_lines: usize,
+    /// Shuffle the lines of the message, pad them and slip in filler lines, for every connection.
+    #[structopt(long = "disguise")]
+    disguise: bool,
+    /// Slowly send back whatever clients send, interleaved with the message.
+    #[structopt(long = "echo")]
+    echo: bool,
+    /// Secret to sign every connection with, in a line traceable back to it.
+    #[structopt(long = "watermark-key")]
+    watermark_key: Option<watermark::Watermark>,
+    /// Listen address(es) to bind to of the exporter (e.g. "127.0.0.1:8080"), or unix socket(s)
+    /// (e.g. "unix:/run/tarssh/metrics.sock"); none unless given, as with "disabled".
+    #[structopt(short = "e", long = "exporter", parse(try_from_str = exporters::parse_listen))]
+    #[cfg(feature = "exporters")]
+    exporter: Vec<Option<exporters::Listen>>,
+    /// Credentials scrapers of the exporter have to send, as "bearer:<token>" or
+    /// "basic:<user>:<password>".
+    #[structopt(long = "exporter-auth")]
+    #[cfg(feature = "exporters")]
+    exporter_auth: Option<exporters::Auth>,
+    /// Credentials of the exporter to read with but not change anything, e.g. for dashboards,
+    /// as "bearer:<token>" or "basic:<user>:<password>".
+    #[structopt(long = "exporter-read-auth")]
+    #[cfg(feature = "exporters")]
+    exporter_read_auth: Vec<exporters::Auth>,
+    /// PEM certificate, followed by its intermediates, to serve the exporter over TLS with on
+    /// its TCP addresses; read once at start.
+    #[structopt(long = "exporter-cert", parse(from_os_str))]
+    #[cfg(feature = "tls")]
+    exporter_cert: Option<PathBuf>,
+    /// PEM private key of the certificate of `--exporter-cert`.
+    #[structopt(long = "exporter-key", parse(from_os_str))]
+    #[cfg(feature = "tls")]
+    exporter_key: Option<PathBuf>,
+    /// OTLP/HTTP endpoint of an OpenTelemetry collector to push the metrics to, as JSON (e.g.
+    /// "http://127.0.0.1:4318/v1/metrics").
+    #[structopt(long = "otlp")]
     #[cfg(feature = "exporters")]
-    exporter: Vec<SocketAddr>,
+    otlp: Option<hyper::Uri>,
+    /// Time between pushes to the OpenTelemetry collector.
+    #[structopt(long = "otlp-interval", default_value = "60s", parse(try_from_str = humantime::parse_duration))]
+    #[cfg(feature = "exporters")]
+    otlp_interval: Duration,
+    /// Host and port of a Graphite carbon to push the metrics to in the plaintext protocol
+    /// (e.g. "graphite.example:2003").
+    #[structopt(long = "graphite")]
+    #[cfg(feature = "exporters")]
+    graphite: Option<String>,
+    /// Time between pushes to Graphite.
+    #[structopt(long = "graphite-interval", default_value = "60s", parse(try_from_str = humantime::parse_duration))]
+    #[cfg(feature = "exporters")]
+    graphite_interval: Duration,
+    /// What the paths pushed to Graphite start with.
+    #[structopt(long = "graphite-prefix", default_value = "tarssh")]
+    #[cfg(feature = "exporters")]
+    graphite_prefix: String,
+    /// Serve the metrics made for an earlier scrape up to this long (e.g. "10s"), instead of
+    /// making them anew for every one.
+    #[structopt(long = "metrics-cache", default_value = "0s", parse(try_from_str = humantime::parse_duration))]
+    #[cfg(feature = "exporters")]
+    metrics_cache: Duration,
+    /// Address(es) of monitoring which may scrape the metrics on the tarpit listeners with an
+    /// HTTP GET, and are never tarpitted.
+    #[structopt(long = "monitor")]
+    #[cfg(feature = "exporters")]
+    monitor: Vec<IpAddr>,
+    /// MaxMind database (e.g. "GeoLite2-Country.mmdb") to count connections and the time they
+    /// were trapped by the country of their peers with.
+    #[structopt(long = "geoip")]
+    geoip: Option<PathBuf>,
+    /// MaxMind database of autonomous systems (e.g. "GeoLite2-ASN.mmdb") to count connections
+    /// and the time they were trapped by the networks of their peers with, and log them.
+    #[structopt(long = "geoip-asn")]
+    geoip_asn: Option<PathBuf>,
+    /// Pattern(s) to warn about in the identification string of clients (e.g. "libssh_0\.8").
+    #[structopt(long = "alert-version")]
+    #[cfg(feature = "alerts")]
+    alert_version: Vec<regex::bytes::Regex>,
+    /// Pattern(s) to warn about in whatever clients send.
+    #[structopt(long = "alert-payload")]
+    #[cfg(feature = "alerts")]
+    alert_payload: Vec<regex::bytes::Regex>,
+    /// Post alerts as JSON to this http-URL.
+    #[structopt(long = "alert-webhook")]
+    #[cfg(feature = "alerts")]
+    alert_webhook: Option<hyper::Uri>,
+    /// Warn when more connections than this are accepted within a minute.
+    #[structopt(long = "alert-connections")]
+    #[cfg(feature = "alerts")]
+    alert_connections: Option<usize>,
+    /// Warn when more connections than this are rejected within a minute.
+    #[structopt(long = "alert-rejects")]
+    #[cfg(feature = "alerts")]
+    alert_rejects: Option<usize>,
+    /// Warn when more distinct peers than this connect within an hour.
+    #[structopt(long = "alert-peers")]
+    #[cfg(feature = "alerts")]
+    alert_peers: Option<usize>,
 }
 
-pub(crate) fn errx<M: AsRef<str>>(code: i32, message: M) -> ! {
-    error!("{}", message.as_ref());
-    std::process::exit(code);
+// Something else to do than running a tarpit (not a doc comment, which structopt would take
+// for the about of tarssh itself).
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Recommend delays holding common clients longest, optionally probing a running tarssh.
+    Calibrate(calibrate::CalibrateConfig),
+    /// Show a live view of a running tarssh, scraped from its exporter.
+    #[cfg(feature = "top")]
+    Top(top::TopConfig),
+    /// Hang up on a client or on all clients of a network of a running tarssh, through its
+    /// exporter.
+    #[cfg(feature = "control")]
+    Kick(control::KickConfig),
+    /// Tell how a running tarssh is doing: its uptime, clients, the time they were trapped
+    /// and the longest connection, through its control socket or exporter.
+    #[cfg(feature = "control")]
+    Stats(control::StatsConfig),
+    /// List the clients connected to a running tarssh by its control socket, trapped the longest
+    /// first, with how long, how many chunks they got and the listener they came in by.
+    #[cfg(all(unix, feature = "control"))]
+    Sessions(control::SessionsConfig),
 }
 
-fn main() -> std::io::Result<()> {
-    let opt = Config::from_args();
+/// Options which apply to the whole process, and so not to a single instance.
+const PROCESS_WIDE: &[&str] = &[
+    "verbose", "scheduler", "threads", "disable_log_timestamps", "disable_log_ident",
+    "disable_log_level", "user", "group", "chroot", "exporter", "alert_version",
+    "alert_payload", "alert_webhook", "alert_connections", "alert_rejects", "alert_peers",
+    "check", "inetd", "config", "listen_fd", "geoip", "geoip_asn", "otlp", "otlp_interval",
+    "metrics_cache", "exporter_auth", "exporter_read_auth", "exporter_cert", "exporter_key",
+    "graphite", "graphite_interval",
+    "graphite_prefix", "summary", "bans", "control_socket", "drain_deadline",
+];
 
+/// A tarpit of its own, with its listeners, limits and banners, among others in one process.
+struct Instance {
+    /// Label of the metrics of the instance, if there are several.
+    name:           Option<String>,
+    /// Whether it is a section of the config file, to reload the listeners of.
+    configured:     bool,
+    listen:         Vec<ListenSpec>,
+    udp:            Vec<SocketAddr>,
+    udp_bandwidth:  usize,
+    max_clients:    usize,
+    history:        History,
+    defaults:       Defaults,
+}
+
+impl Config {
+    /// Parse the options of an instance of a config file.
+    fn of_instance(
+        name: &str,
+        args: Vec<String>,
+    ) -> Result<Self, String> {
+        let fail = |error: String| format!("config, instance: {}, error: {}", name, error);
+        let matches = Self::clap()
+            .get_matches_from_safe(std::iter::once("tarssh".to_owned()).chain(args))
+            .map_err(|err| fail(err.message.trim_start_matches("error: ").to_owned()))?;
+        if let Some(option) = PROCESS_WIDE.iter().find(|option| matches.occurrences_of(option) > 0) {
+            return Err(fail(format!("--{} applies to the whole process", option.replace('_', "-"))));
+        }
+        Ok(Self::from_clap(&matches))
+    }
+
+    /// Every message file the options name, of the listeners included.
+    fn messages(
+        &self,
+    ) -> impl Iterator<Item = &MessageSpec> {
+        self.message.iter().chain(self.listen.iter().flat_map(ListenSpec::messages))
+    }
+
+    /// Every address to listen on, the default one if none is given.
+    fn listen(
+        &mut self,
+    ) -> Vec<ListenSpec> {
+        let mut listen = std::mem::take(&mut self.listen);
+        for port in &self.listen_all {
+            listen.push(ListenSpec::everywhere(IpAddr::V6(Ipv6Addr::UNSPECIFIED), *port));
+            listen.push(ListenSpec::everywhere(IpAddr::V4(Ipv4Addr::UNSPECIFIED), *port));
+        }
+        if listen.is_empty() {
+            listen.push(ListenSpec::everywhere(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 2222));
+        }
+        let v6only = self.v6only;
+        let freebind = self.freebind;
+        let over_limit = self.over_limit;
+        let drop_from: Arc<[Prefix]> = std::mem::take(&mut self.drop_from).into();
+        listen
+            .into_iter()
+            .map(|listen| listen
+                .or_v6only(v6only)
+                .or_freebind(freebind)
+                .or_over_limit(over_limit)
+                .drop_from(drop_from.clone())
+            )
+            .collect()
+    }
+
+    /// The tarpit the options describe, unless its message files can't be read.
+    fn instance(
+        &mut self,
+        name: Option<String>,
+    ) -> Result<Instance, String> {
+        let config = TarpitConfig {
+            delay:          Duration::from_secs(self.delay),
+            timeout:        Duration::from_secs(self.timeout),
+            write_retries:  self.write_retries,
+            max_session:    self.max_session,
+            backoff:        self.backoff.map(|every| Backoff {
+                every,
+                max_delay:  Duration::from_secs(self.max_delay),
+            }),
+            tiers:          self.tiers.take().map(Arc::new),
+            chunk_size:     self.chunk_size,
+            budget:         self.max_bandwidth.map(Budget::new),
+            bandwidth:      self.max_connection_bandwidth,
+            disconnect:     self.disconnect,
+            strict:         self.strict,
+            surprises:      Surprises::builtin(),
+            keepalive:      self.keepalive.map(|idle| Keepalive {
+                idle,
+                interval:   self.keepalive_interval,
+                count:      self.keepalive_count,
+            }),
+        };
+        let config = match self.profile {
+            Some(profile) => profile.config(config),
+            None => config,
+        };
+        let defaults = Defaults {
+            config,
+            banners:   Arc::new(match self.profile {
+                Some(profile) if self.message.is_empty() => profile.banners(),
+                _ => BannerPool::load(&self.message).map_err(|err| format!("banner, error: {}", err))?,
+            }),
+            profile:   self.profile,
+            decoys:    self.decoy_lines,
+            disguise:  self.disguise,
+            echo:      self.echo,
+            watermark: self.watermark_key.take().map(Arc::new),
+            shed:      self.shed_on_emfile,
+            sample:    self.sample,
+            #[cfg(feature = "exporters")]
+            monitors:  std::mem::take(&mut self.monitor).into(),
+        };
+
+        Ok(Instance {
+            name,
+            configured:     false,
+            listen:         self.listen(),
+            udp:            std::mem::take(&mut self.udp),
+            udp_bandwidth:  self.udp_bandwidth,
+            max_clients:    self.max_clients as usize,
+            history:        History {
+                peers:    self.peer_history,
+                sessions: self.session_archive,
+            },
+            defaults,
+        })
+    }
+}
+
+fn main() {
+    let mut opt = Config::from_args();
+
+    match opt.command {
+        Some(Command::Calibrate(config)) => return calibrate::run(config),
+        #[cfg(feature = "top")]
+        Some(Command::Top(config)) => return top::run(config),
+        #[cfg(feature = "control")]
+        Some(Command::Kick(config)) => return control::kick(config),
+        #[cfg(feature = "control")]
+        Some(Command::Stats(config)) => return control::stats(config),
+        #[cfg(all(unix, feature = "control"))]
+        Some(Command::Sessions(config)) => return control::sessions(config),
+        None => {},
+    }
     logging::init(
         opt.verbose,
         !opt.disable_log_timestamps,
@@ -123,65 +621,290 @@ fn main() -> std::io::Result<()> {
         !opt.disable_log_level,
     );
 
-    let mut runtime = Runtime::new(opt.threads);
+    let mut fingerprint = Fingerprint::default();
+    fingerprint.add("", &opt, opt.messages());
 
-    let listeners = Listeners::new(
-        &mut runtime,
-        opt.listen,
-    );
+    #[cfg(feature = "alerts")]
+    let alerts = Arc::new(Alerts::new(
+        std::mem::take(&mut opt.alert_version),
+        std::mem::take(&mut opt.alert_payload),
+        opt.alert_webhook.take(),
+        Thresholds {
+            connections:    opt.alert_connections,
+            rejects:        opt.alert_rejects,
+            peers:          opt.alert_peers,
+        },
+    ));
+
+    // Read before any chroot, for all instances.
+    let open = |path: &Path| {
+        let geoip = GeoIp::open(path).unwrap_or_else(|err| Failure::Config.exit(err));
+        info!("geoip, path: {}", path.display());
+        geoip
+    };
+    let shared = Arc::new(Shared {
+        geoip:  opt.geoip.as_deref().map(open),
+        asn_db: opt.geoip_asn.as_deref().map(open),
+        bans:   Bans::load(opt.bans.take()).unwrap_or_else(|err| Failure::Config.exit(err)),
+    });
+    #[cfg(feature = "exporters")]
+    let metrics_cache = opt.metrics_cache;
+    #[cfg(not(feature = "exporters"))]
+    let metrics_cache = Duration::default();
+
+    #[cfg(unix)]
+    if opt.inetd {
+        let instance = opt.instance(None).unwrap_or_else(|err| Failure::Config.exit(err));
+        let mut runtime = Runtime::new(opt.scheduler, opt.threads);
+        if let Some(budget) = &instance.defaults.config.budget {
+            runtime.spawn(budget.clone().refill());
+        }
+        let metrics = Arc::new(Metrics::new(&runtime, instance.history, &fingerprint.finish(), shared, metrics_cache));
+        return inetd::serve(
+            &mut runtime,
+            instance.defaults,
+            metrics,
+            #[cfg(feature = "alerts")]
+            alerts,
+        );
+    }
+
+    let handover = handover::Handover::inherit();
+    #[cfg(unix)]
+    for fd in std::mem::take(&mut opt.listen_fd) {
+        let addr = handover
+            .adopt(fd)
+            .unwrap_or_else(|err| Failure::Bind.exit(format!("listen, fd: {}, error: {}", fd, err)));
+        opt.listen.push(ListenSpec::addr(addr));
+    }
+
+    // The command line is an instance of its own, unless it leaves listening to the config file.
+    let mut instances = Vec::new();
+    let configured = match &opt.config {
+        Some(path) => instances::load(path)
+            .unwrap_or_else(|err| Failure::Config.exit(format!("config, error: {}", err))),
+        None => Vec::new(),
+    };
+    if configured.is_empty() || !opt.listen.is_empty() || !opt.listen_all.is_empty() {
+        let name = opt.config.as_ref().map(|_| "default".to_owned());
+        instances.push(opt.instance(name).unwrap_or_else(|err| Failure::Config.exit(err)));
+    }
+    for (name, args) in configured {
+        let mut config = Config::of_instance(&name, args).unwrap_or_else(|err| Failure::Config.exit(err));
+        fingerprint.add(&name, &config, config.messages());
+        instances.push(Instance {
+            configured: true,
+            ..config.instance(Some(name)).unwrap_or_else(|err| Failure::Config.exit(err))
+        });
+    }
+
+    let fingerprint = fingerprint.finish();
+    info!("fingerprint, config: {}", fingerprint);
+
+    // Read before dropping privileges, as the key is readable by root alone.
+    #[cfg(feature = "tls")]
+    let acceptor = match (opt.exporter_cert.take(), opt.exporter_key.take()) {
+        (Some(cert), Some(key)) => Some(tls::Acceptor::new(&cert, &key).unwrap_or_else(|err| {
+            Failure::Config.exit(format!("tls, cert: {}, key: {}, error: {}", cert.display(), key.display(), err))
+        })),
+        (None, None) => None,
+        _ => Failure::Config.exit("tls, error: --exporter-cert and --exporter-key go together"),
+    };
+
+    // Tell about every address which can't be bound at once, before anything is set up.
+    let addrs: Vec<_> = instances
+        .iter()
+        .flat_map(|instance| instance.listen.iter().map(ListenSpec::bind_spec))
+        .collect();
+    #[cfg(feature = "exporters")]
+    let addrs: Vec<_> = addrs.into_iter().chain(opt.exporter.iter().flatten().filter_map(exporters::Listen::tcp).map(BindSpec::addr)).collect();
+    let failures = listeners::preflight(&addrs, handover);
+    if failures > 0 {
+        Failure::Bind.exit(format!("check, addrs: {}, failures: {}", addrs.len(), failures));
+    }
+    if opt.check {
+        info!("check, addrs: {}, failures: 0", addrs.len());
+        return;
+    }
+
+    let mut runtime = Runtime::new(opt.scheduler, opt.threads);
+    for instance in &instances {
+        if let Some(budget) = &instance.defaults.config.budget {
+            runtime.spawn(budget.clone().refill());
+        }
+    }
+
+    let instances: Vec<_> = instances
+        .into_iter()
+        .map(|mut instance| {
+            let listen = std::mem::take(&mut instance.listen);
+            let listeners = Listeners::new(&mut runtime, listen, handover);
+            (listeners, UdpTarpit::bind(&instance.udp, instance.udp_bandwidth), instance)
+        })
+        .collect();
 
     #[cfg(feature = "exporters")]
     let exporters = Exporter::new(
         &mut runtime,
-        opt.exporter,
+        opt.exporter.iter().flatten().cloned().collect(),
+        exporters::Access::new(opt.exporter_auth.take(), std::mem::take(&mut opt.exporter_read_auth)),
+        #[cfg(feature = "tls")]
+        acceptor,
+        handover,
     );
 
+    #[cfg(unix)]
+    let control_socket = opt.control_socket.take().map(|path| admin::ControlSocket::bind(&mut runtime, path));
+
+    #[cfg(unix)]
+    let notifier = notify::Notifier::connect(handover.took_over());
+
+    // Taking over, this process already runs with whatever privileges the former one kept.
     #[cfg(all(unix, feature = "drop_privs"))]
-    opt.privdrop.drop();
+    if handover.took_over() {
+        info!("privdrop, inherited: true");
+    } else {
+        opt.privdrop.drop();
+    }
 
     #[cfg(all(unix, feature = "sandbox"))]
     {
         let sandboxed = Sandbox::new().sandbox_this_process().is_ok();
         info!("sandbox, enabled: {}", sandboxed);
     }
+    #[cfg(not(all(unix, feature = "sandbox")))]
+    info!("sandbox, enabled: false");
+
+    let instances: Vec<_> = instances
+        .into_iter()
+        .map(|(listeners, udp, instance)| {
+            let metrics = Arc::new(Metrics::new(&runtime, instance.history, &fingerprint, shared.clone(), metrics_cache));
+            (listeners, udp, instance, metrics)
+        })
+        .collect();
+
+    #[cfg(feature = "alerts")]
+    if alerts.watches_rates() {
+        runtime.spawn(alerts.clone().watch_rates());
+    }
 
+    // Served once the listeners are there to reload.
     #[cfg(feature = "exporters")]
-    let metrics = exporters.spawn(&runtime);
-    #[cfg(not(feature = "exporters"))]
-    let metrics = std::sync::Arc::new(metrics::Metrics::new(runtime.start()));
+    let exported: Vec<_> = instances
+        .iter()
+        .map(|(_, _, instance, metrics)| (instance.name.clone(), metrics.clone()))
+        .collect();
+
+    #[cfg(feature = "exporters")]
+    if let Some(uri) = opt.otlp.take() {
+        let otlp = Otlp::new(uri, opt.otlp_interval);
+        runtime.spawn(until_stopped(
+            handover,
+            otlp.push(
+                instances
+                    .iter()
+                    .map(|(_, _, instance, metrics)| (instance.name.clone(), metrics.clone()))
+                    .collect(),
+            ),
+        ));
+    }
+
+    #[cfg(feature = "exporters")]
+    if let Some(addr) = opt.graphite.take() {
+        let graphite = Graphite::new(addr, opt.graphite_interval, opt.graphite_prefix.clone());
+        runtime.spawn(until_stopped(
+            handover,
+            graphite.push(
+                instances
+                    .iter()
+                    .map(|(_, _, instance, metrics)| (instance.name.clone(), metrics.clone()))
+                    .collect(),
+            ),
+        ));
+    }
+
+    if let Some(interval) = opt.summary {
+        runtime.spawn(until_stopped(
+            handover,
+            summary::log(
+                interval,
+                instances
+                    .iter()
+                    .map(|(_, _, instance, metrics)| (instance.name.clone(), metrics.clone()))
+                    .collect(),
+            ),
+        ));
+    }
+
+    let mut metrics = Vec::with_capacity(instances.len());
+    let mut reloader = reload::Reloader::new(opt.config.clone());
+    #[cfg(unix)]
+    let mut controlled = Vec::new();
+    for (listeners, udp, instance, instance_metrics) in instances {
+        if let Some(name) = &instance.name {
+            info!(
+                "instance, name: {}, listeners: {}, max_clients: {}",
+                name,
+                listeners.len(),
+                instance.max_clients,
+            );
+        }
+        udp.spawn(&runtime, instance.defaults.config.delay, instance_metrics.clone(), handover);
+        let acceptors = listeners.spawn(
+            &runtime,
+            instance.max_clients,
+            instance.defaults,
+            instance_metrics.clone(),
+            #[cfg(feature = "alerts")]
+            alerts.clone(),
+            handover,
+        ).unwrap_or_else(|err| Failure::Config.exit(format!("banner, error: {}", err)));
+        #[cfg(unix)]
+        controlled.push(admin::Instance {
+            name:      instance.name.clone(),
+            metrics:   instance_metrics.clone(),
+            acceptors: acceptors.clone(),
+        });
+        metrics.push(instance_metrics);
+        match (instance.configured, instance.name) {
+            (true, Some(name)) => reloader.configured(name, acceptors),
+            (_, name) => reloader.command_line(name, opt.message.clone(), acceptors),
+        }
+    }
+    let reloader = Arc::new(reloader);
 
-    listeners.spawn(
+    #[cfg(feature = "exporters")]
+    exporters.spawn(
         &runtime,
-        opt.max_clients as usize,
-        Duration::from_secs(opt.delay),
-        Duration::from_secs(opt.timeout),
-        metrics.clone(),
-        if opt.message.is_empty() {
-            format!(
-                "{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n",
-                "My name is Yon Yonson",
-                "I live in Wisconsin.",
-                "There, the people I meet",
-                "As I walk down the street",
-                "Say “Hey, what’s your name?”",
-                "And I say:",
-            )
-        } else {
-            BufReader::new(File::open(opt.message)?)
-            .lines()
-            .try_fold(
-                String::new(),
-                |mut result, line| if let Ok(line) = line {
-                    result.push_str(&line);
-                    result.push_str("\r\n");
-                    Ok(result)
-                } else {
-                    line
-                },
-            )?
-        },
+        exported,
+        handover,
+        shared,
+        reloader.clone(),
     );
 
-    runtime.wait(metrics);
-    Ok(())
+    #[cfg(unix)]
+    if let Some(control_socket) = control_socket {
+        runtime.spawn(control_socket.serve(controlled, handover, reloader.clone()));
+    }
+
+    // Without a config file, SIGHUP keeps ending tarssh as ever.
+    #[cfg(unix)]
+    if opt.config.is_some() {
+        runtime.spawn(reload::serve(reloader, handover));
+    }
+    #[cfg(unix)]
+    runtime.spawn(handover.pause_on_signals());
+    handover.ready();
+    #[cfg(unix)]
+    if let Some(notifier) = notifier {
+        runtime.spawn(notifier.serve(metrics.clone(), handover));
+    }
+
+    runtime.wait(
+        &metrics,
+        #[cfg(unix)]
+        handover,
+        #[cfg(unix)]
+        opt.drain_deadline,
+    );
 }
@@ -1,62 +1,259 @@
-macro_rules! metric_bucket {
-    ($Name:ident ($Bucket:expr): $($Attributes:expr),* $(,)?)
-    => {concat!(stringify!($Name), "{{", $($Attributes),*, "}} {", stringify!($Bucket), "}\n",)};
+use super::shared::Shared;
+use super::filter::Prefix;
+use super::geoip::Country;
+use super::runtime::Runtime;
+use super::sync::lock;
+#[cfg(feature = "exporters")]
+use super::runtime::Scheduler;
+#[cfg(feature = "exporters")]
+use super::registry::{Kind, Registry};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
+};
+#[cfg(all(feature = "exporters", not(feature = "aggregate_only")))]
+use tokio::sync::broadcast;
+use tokio::sync::Notify;
 
+pub(crate) struct Client {
+    /// Unique number of the connection since startup.
+    #[cfg_attr(not(all(feature = "exporters", not(feature = "aggregate_only"))), allow(dead_code))]
+    id:               usize,
+    peer:             IpAddr,
+    /// Address of the listener it was accepted by.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    listener:         SocketAddr,
+    start:            Instant,
+    sent_chunks:      u64,
+    /// Bytes of all chunks written.
+    sent_bytes:       u64,
+    sent_eastereggs:  u64,
+    sent_banners:     u64,
+    /// To be dropped at the next chunk, to get its file descriptor back.
+    shed:             bool,
+    /// Where the peer is, as far as the GeoIP database knows.
+    country:          Option<Country>,
+    /// What the peer counts towards by its autonomous system, if looked up.
+    asn:              Option<String>,
+    /// Woken to hang up on the client at once.
+    kick:             Arc<Notify>,
+    /// Delay set at runtime for the connection, instead of the one it was accepted with.
+    delay:            Option<Duration>,
+    /// Bytes the client sent.
+    #[cfg_attr(not(all(feature = "exporters", not(feature = "aggregate_only"))), allow(dead_code))]
+    received_bytes:   u64,
+    /// The first line the client sent, if it sent one, as printable characters.
+    #[cfg_attr(not(all(feature = "exporters", not(feature = "aggregate_only"))), allow(dead_code))]
+    version:          Option<String>,
+    /// Lines an operator said to the client, written after the next line ending it is sent.
+    said:             Vec<u8>,
 }
 
-macro_rules! metric_type {
-    (counter)   => {"counter"};
-    (gauge)     => {"gauge"};
-    (histogram) => {"histogram"};
-    (summary)   => {"summary"};
-    (untyped)   => {"untyped"};
+/// Clients to kick: a single one by the id of its connection, or all of a network.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(all(feature = "exporters", not(feature = "aggregate_only"))), allow(dead_code))]
+pub(crate) enum Kick {
+    Id(usize),
+    Peers(Prefix),
 }
 
-macro_rules! metric_header {
-  (
-      $Name:ident:
-      $Type:ident,
-      $Description:expr$(,)?
-  ) => {
-      concat!(
-          "# HELP ", stringify!($Name), " ", $Description, "\n",
-          "# TYPE ", stringify!($Name), " ", metric_type!($Type), "\n"
-      )
-  };
+impl fmt::Display for Kick {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Id(id)         => write!(f, "id: {}", id),
+            Self::Peers(prefix)  => write!(f, "peers: {:?}", prefix),
+        }
+    }
 }
 
-macro_rules! metric {
-    (
-        $Name:ident:
-        $Type:ident,
-        $Description:expr$(,)?
-    ) => {
-        concat!(
-            metric_header!($Name: $Type, $Description),
-            stringify!($Name), " {", stringify!($Name), "}\n\n",
-        );
-    };
+/// Why something couldn't be done for a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MetricsError {
+    /// The client of the token is gone already.
+    AlreadyDisconnected,
+    /// The token doesn't belong to any client.
+    InvalidToken,
+    /// There are too many clients already, this one included.
+    OverLimit {
+        count: usize,
+    },
 }
 
-use std::{
-    borrow::Cow,
-    sync::{atomic::{AtomicUsize, Ordering}, Mutex},
-    time::Instant,
-};
+impl fmt::Display for MetricsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyDisconnected   => f.write_str("Already Disconnected"),
+            Self::InvalidToken          => f.write_str("Invalid Token"),
+            Self::OverLimit { count }   => write!(f, "Over Limit ({} clients)", count),
+        }
+    }
+}
 
-pub(crate) struct Client {
-    start:            Instant,
-    sent_chunks:      u64,
-    sent_eastereggs:  u64,
-    sent_banners:     u64,
+/// A place for a client, reused once the client is gone.
+struct Slot {
+    /// Bumped whenever the slot is reused, so tokens of earlier clients no longer fit.
+    generation: u64,
+    client:     Option<Client>,
+}
+
+impl Slot {
+    /// The client the token was issued to, unless it is gone.
+    fn client(
+        &mut self,
+        generation: u64,
+    ) -> Option<&mut Client> {
+        if self.generation == generation {
+            self.client.as_mut()
+        } else {
+            None
+        }
+    }
+}
+
+/// Some of the clients, in slots reused as they become free.
+#[derive(Default)]
+struct Shard {
+    slots: Vec<Slot>,
+    /// Slots without a client.
+    free:  Vec<usize>,
+}
+
+/// What is known about a peer from its former connections.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct PeerHistory {
+    /// Number of former connections.
+    pub(crate) seen:    u64,
+    /// Sum of seconds these connections were trapped.
+    pub(crate) trapped: u64,
+}
+
+/// Connections of an autonomous system, and the seconds they were trapped.
+#[derive(Default)]
+struct AsnHistory {
+    organization:   Option<String>,
+    connections:    usize,
+    trapped:        u64,
+}
+
+struct Peer {
+    history:    PeerHistory,
+    last_seen:  Instant,
 }
 
+/// How much of the former connections to remember.
+#[derive(Clone, Copy)]
+pub(crate) struct History {
+    /// Number of peers to remember the connections of.
+    pub(crate) peers:    usize,
+    /// Number of the latest connections to keep in the archive.
+    pub(crate) sessions: usize,
+}
+
+/// A former connection, as kept in the archive.
+#[derive(Clone)]
+#[cfg_attr(not(all(feature = "exporters", not(feature = "aggregate_only"))), allow(dead_code))]
+pub(crate) struct Archived {
+    pub(crate) id:          usize,
+    pub(crate) peer:        IpAddr,
+    pub(crate) listener:    SocketAddr,
+    pub(crate) start:       SystemTime,
+    pub(crate) duration:    Duration,
+    pub(crate) sent_chunks: u64,
+    pub(crate) sent_bytes:  u64,
+}
+
+/// Outcome of a disconnect.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Disconnected {
+    /// Unique number of the connection, unless it was gone already.
+    pub(crate) id:              Option<usize>,
+    /// Number of remaining connections.
+    pub(crate) clients:         usize,
+    /// Length of the connection.
+    pub(crate) duration:        Duration,
+    /// The peer before this connection.
+    pub(crate) history:         PeerHistory,
+}
+
+/// Something that happened to a client, as streamed to whoever listens.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(all(feature = "exporters", not(feature = "aggregate_only"))), allow(dead_code))]
+pub(crate) enum Event {
+    Connect {
+        id:       usize,
+        peer:     SocketAddr,
+        clients:  usize,
+    },
+    Disconnect {
+        id:       usize,
+        peer:     SocketAddr,
+        duration: Duration,
+        reason:   String,
+    },
+    Easteregg {
+        id:       usize,
+        peer:     SocketAddr,
+    },
+}
+
+/// A client connected now, as listed.
+#[cfg(unix)]
+pub(crate) struct Session {
+    pub(crate) id:          usize,
+    pub(crate) peer:        IpAddr,
+    /// How long it is trapped already.
+    pub(crate) duration:    Duration,
+    pub(crate) sent_chunks: u64,
+    pub(crate) sent_bytes:  u64,
+    pub(crate) listener:    SocketAddr,
+}
+
+/// Everything known about a client connected now.
+#[cfg(all(feature = "exporters", not(feature = "aggregate_only")))]
+pub(crate) struct Detail {
+    pub(crate) id:              usize,
+    pub(crate) peer:            IpAddr,
+    pub(crate) listener:        SocketAddr,
+    /// When it connected, by the wall clock.
+    pub(crate) start:           SystemTime,
+    /// How long it is trapped already.
+    pub(crate) duration:        Duration,
+    pub(crate) sent_chunks:     u64,
+    pub(crate) sent_bytes:      u64,
+    pub(crate) sent_eastereggs: u64,
+    pub(crate) sent_banners:    u64,
+    pub(crate) received_bytes:  u64,
+    /// Delay set at runtime for the connection, if one was.
+    pub(crate) delay:           Option<Duration>,
+    pub(crate) version:         Option<String>,
+    pub(crate) country:         Option<Country>,
+    pub(crate) asn:             Option<String>,
+}
+
+/// What the connections of an instance added up to so far.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Totals {
+    /// Connections accepted, those turned away included.
+    pub(crate) accepted: usize,
+    /// Connections turned away past the limit of clients.
+    pub(crate) rejected: usize,
+    /// Connections closed.
+    pub(crate) closed:   usize,
+    /// Sum of seconds the closed connections were trapped.
+    pub(crate) trapped:  u64,
+}
+
+#[derive(Clone)]
 pub(crate) struct ClientMetrics {
     maximum_connection_time:  u64,
     minimum_connection_time:  u64,
     connection_time_till:     [usize; 32],
     connection_time:          u64,
     sent_chunks_sum:          u64,
+    sent_bytes_sum:           u64,
     sent_eastereggs_sum:      u64,
     sent_banners_sum:         u64,
 }
@@ -65,82 +262,413 @@ impl ClientMetrics {
     pub(crate) fn new() -> Self {
         Self {
             maximum_connection_time:  0,
-            minimum_connection_time:  std::u64::MAX,
+            minimum_connection_time:  u64::MAX,
             connection_time_till:     [0usize; 32],
             connection_time:          0,
             sent_chunks_sum:          0,
+            sent_bytes_sum:           0,
             sent_eastereggs_sum:      0,
             sent_banners_sum:         0,
         }
     }
+
+    /// Count a client in, as connected so far.
+    fn add(
+        &mut self,
+        client: &Client,
+    ) {
+        let connection_time = client.start.elapsed().as_secs();
+        self.maximum_connection_time = self.maximum_connection_time.max(connection_time);
+        self.minimum_connection_time = self.minimum_connection_time.min(connection_time);
+        let bucket = (64 - connection_time.leading_zeros() as usize).min(31);
+        self.connection_time_till[bucket] += 1;
+        self.connection_time     += connection_time;
+        self.sent_chunks_sum     += client.sent_chunks;
+        self.sent_bytes_sum      += client.sent_bytes;
+        self.sent_eastereggs_sum += client.sent_eastereggs;
+        self.sent_banners_sum    += client.sent_banners;
+    }
+
+    /// The clients of both counted together.
+    #[cfg(feature = "exporters")]
+    fn combined(
+        &self,
+        other: &Self,
+    ) -> Self {
+        let mut connection_time_till = self.connection_time_till;
+        for (bucket, count) in connection_time_till.iter_mut().zip(other.connection_time_till.iter()) {
+            *bucket += count;
+        }
+        Self {
+            maximum_connection_time:  self.maximum_connection_time.max(other.maximum_connection_time),
+            minimum_connection_time:  self.minimum_connection_time.min(other.minimum_connection_time),
+            connection_time_till,
+            connection_time:          self.connection_time + other.connection_time,
+            sent_chunks_sum:          self.sent_chunks_sum + other.sent_chunks_sum,
+            sent_bytes_sum:           self.sent_bytes_sum + other.sent_bytes_sum,
+            sent_eastereggs_sum:      self.sent_eastereggs_sum + other.sent_eastereggs_sum,
+            sent_banners_sum:         self.sent_banners_sum + other.sent_banners_sum,
+        }
+    }
+}
+
+/// Why accepting a connection failed.
+#[derive(Clone, Copy)]
+pub(crate) enum AcceptError {
+    /// Refused before it was accepted.
+    Refused,
+    /// Reset or aborted by the peer before it was accepted.
+    Reset,
+    /// Out of file descriptors, of the process or the system.
+    Emfile,
+    Other,
+}
+
+impl AcceptError {
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    const ALL: [AcceptError; 4] = [AcceptError::Refused, AcceptError::Reset, AcceptError::Emfile, AcceptError::Other];
+
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    fn name(self) -> &'static str {
+        match self {
+            AcceptError::Refused => "refused",
+            AcceptError::Reset   => "reset",
+            AcceptError::Emfile  => "emfile",
+            AcceptError::Other   => "other",
+        }
+    }
+}
+
+/// Seconds over which the accept rate of a listener is averaged.
+const ACCEPT_WINDOW: usize = 10;
+
+/// Distinct autonomous systems counted, all others counted together as `other`.
+const MAX_ASNS: usize = 1024;
+
+/// Events kept for a slow listener, before it misses some.
+#[cfg(all(feature = "exporters", not(feature = "aggregate_only")))]
+const EVENTS_BUFFER: usize = 1024;
+
+/// Bytes said to a client that may wait for a line ending to be written after, four lines
+/// of the most a client reads.
+const MAX_SAID: usize = 1024;
+
+/// Distinct client versions counted, all others counted together as `other`.
+const MAX_VERSIONS: usize = 64;
+
+/// Shards the clients are spread over by connection, so a connect, disconnect or chunk only
+/// ever locks one of them, and an export walking them all holds up no accept for long.
+const CLIENT_SHARDS: usize = 16;
+
+/// Upper bounds of the buckets of connection times, in seconds: 0, 1, 3, 7 and so on to
+/// 2^30 - 1, and then infinity.
+#[cfg(feature = "exporters")]
+const CONNECTION_TIME_BOUNDS: [f64; 32] = {
+    let mut bounds = [f64::INFINITY; 32];
+    let mut bucket = 0;
+    while bucket < 31 {
+        bounds[bucket] = ((1u64 << bucket) - 1) as f64;
+        bucket += 1;
+    }
+    bounds
+};
+
+/// Connections accepted by a single listener, in buckets of a second.
+pub(crate) struct AcceptRate {
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    addr:       SocketAddr,
+    startup:    Instant,
+    /// The second since startup of the latest bucket, and the buckets.
+    window:     Mutex<(u64, [usize; ACCEPT_WINDOW])>,
+    /// Packets the kernel dropped for the listener, as last counted.
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    dropped:    AtomicUsize,
+    /// How clients past the limit are turned away, and how many were.
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    over_limit: (&'static str, AtomicUsize),
+    /// Times the listener was bound anew, accepting having failed for too long.
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    rebinds:    AtomicUsize,
+    /// Accepts which failed, by `AcceptError`.
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    errors:     [AtomicUsize; 4],
+}
+
+impl AcceptRate {
+    /// Lock the window, with the buckets of the seconds passed since the last access emptied.
+    fn window(&self) -> (MutexGuard<'_, (u64, [usize; ACCEPT_WINDOW])>, u64) {
+        let now = self.startup.elapsed().as_secs();
+        let mut guard = lock(&self.window);
+        let (last, buckets) = &mut *guard;
+        if now >= *last + ACCEPT_WINDOW as u64 {
+            *buckets = [0; ACCEPT_WINDOW];
+        } else {
+            for second in *last + 1..=now {
+                buckets[second as usize % ACCEPT_WINDOW] = 0;
+            }
+        }
+        *last = now.max(*last);
+        (guard, now)
+    }
+
+    pub(crate) fn accepted(&self) {
+        let (mut guard, now) = self.window();
+        guard.1[now as usize % ACCEPT_WINDOW] += 1;
+    }
+
+    pub(crate) fn rebound(&self) {
+        self.rebinds.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn accept_error(&self, error: AcceptError) {
+        self.errors[error as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn over_limit(&self) {
+        self.over_limit.1.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn dropped(&self, count: usize) {
+        self.dropped.store(count, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "exporters")]
+    fn per_second(&self) -> f64 {
+        let (guard, _) = self.window();
+        guard.1.iter().sum::<usize>() as f64 / ACCEPT_WINDOW as f64
+    }
 }
 
 pub(crate) struct Metrics {
-    startup:            Instant,
-    clients:            Mutex<Vec<Option<Client>>>,
-    former_metrics:     Mutex<ClientMetrics>,
-    connections_count:  AtomicUsize,
-    connections_total:  AtomicUsize,
+    startup:              Instant,
+    #[cfg(feature = "exporters")]
+    scheduler:            Scheduler,
+    #[cfg(feature = "exporters")]
+    cores:                usize,
+    #[cfg(feature = "exporters")]
+    workers:              usize,
+    #[cfg(feature = "exporters")]
+    fingerprint:          String,
+    /// How long an export is served again instead of made anew.
+    #[cfg(feature = "exporters")]
+    export_cache:         Duration,
+    /// The latest export made, and when.
+    #[cfg(feature = "exporters")]
+    cached_export:        Mutex<Option<(Instant, String)>>,
+    clients:              Vec<Mutex<Shard>>,
+    peers:                Mutex<HashMap<IpAddr, Peer>>,
+    history:              History,
+    /// The latest connections ended, the oldest first.
+    archive:              Mutex<VecDeque<Archived>>,
+    former_metrics:       Mutex<ClientMetrics>,
+    connections_count:    AtomicUsize,
+    /// Clients held at once at most, changed at runtime; any number until limited.
+    max_clients:          AtomicUsize,
+    connections_total:    AtomicUsize,
+    /// Sum of when the current clients connected, in milliseconds since startup, so how long
+    /// they were trapped so far is known without walking them.
+    connected_since_sum:  AtomicU64,
+    /// Connections turned away past the limit of clients.
+    connections_rejected: AtomicUsize,
+    write_retries:        AtomicUsize,
+    max_sessions:         AtomicUsize,
+    shed:                 AtomicUsize,
+    kicked:               AtomicUsize,
+    /// Bans and databases of the whole process.
+    shared:               Arc<Shared>,
+    banned:               AtomicUsize,
+    paused:               AtomicUsize,
+    sampled:              AtomicUsize,
+    received_bytes:       AtomicUsize,
+    udp_datagrams:        AtomicUsize,
+    udp_replies:          AtomicUsize,
+    accept_latency_sum:   AtomicUsize,
+    accept_latency_max:   AtomicUsize,
+    accept_latency_count: AtomicUsize,
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    listeners:            Mutex<Vec<Arc<AcceptRate>>>,
+    /// Connections by the address they were meant for, of transparent listeners.
+    services:             Mutex<BTreeMap<SocketAddr, usize>>,
+    /// Connections and seconds trapped of the connections closed, by country, if looked up.
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    countries:            Mutex<BTreeMap<Option<Country>, (usize, u64)>>,
+    /// Connections and seconds trapped of the connections closed, by autonomous system, if
+    /// looked up.
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    asns:                 Mutex<BTreeMap<String, AsnHistory>>,
+    /// Clients by the software of their identification string.
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    versions:             Mutex<BTreeMap<String, usize>>,
+    /// What happens to the clients, for anyone streaming it.
+    #[cfg(all(feature = "exporters", not(feature = "aggregate_only")))]
+    events:               broadcast::Sender<Event>,
 }
 
 impl Metrics {
+    #[cfg_attr(not(feature = "exporters"), allow(unused_variables))]
     pub(crate) fn new(
-        startup: Instant,
+        runtime: &Runtime,
+        history: History,
+        fingerprint: &str,
+        shared: Arc<Shared>,
+        export_cache: Duration,
     ) -> Self {
         Self {
-            startup,
-            clients:            Mutex::new(Vec::new()),
-            former_metrics:     Mutex::new(ClientMetrics::new()),
-            connections_count:  AtomicUsize::new(0),
-            connections_total:  AtomicUsize::new(0),
+            startup:              runtime.start(),
+            #[cfg(feature = "exporters")]
+            scheduler:            runtime.scheduler(),
+            #[cfg(feature = "exporters")]
+            cores:                runtime.cores(),
+            #[cfg(feature = "exporters")]
+            workers:              runtime.workers(),
+            #[cfg(feature = "exporters")]
+            fingerprint:          fingerprint.to_owned(),
+            #[cfg(feature = "exporters")]
+            export_cache,
+            #[cfg(feature = "exporters")]
+            cached_export:        Mutex::new(None),
+            clients:              (0..CLIENT_SHARDS).map(|_| Mutex::default()).collect(),
+            peers:                Mutex::new(HashMap::new()),
+            history,
+            archive:              Mutex::new(VecDeque::new()),
+            former_metrics:       Mutex::new(ClientMetrics::new()),
+            connections_count:    AtomicUsize::new(0),
+            max_clients:          AtomicUsize::new(usize::MAX),
+            connections_total:    AtomicUsize::new(0),
+            connected_since_sum:  AtomicU64::new(0),
+            connections_rejected: AtomicUsize::new(0),
+            write_retries:        AtomicUsize::new(0),
+            max_sessions:         AtomicUsize::new(0),
+            shed:                 AtomicUsize::new(0),
+            kicked:               AtomicUsize::new(0),
+            shared,
+            banned:               AtomicUsize::new(0),
+            paused:               AtomicUsize::new(0),
+            sampled:              AtomicUsize::new(0),
+            received_bytes:       AtomicUsize::new(0),
+            udp_datagrams:        AtomicUsize::new(0),
+            udp_replies:          AtomicUsize::new(0),
+            accept_latency_sum:   AtomicUsize::new(0),
+            accept_latency_max:   AtomicUsize::new(0),
+            accept_latency_count: AtomicUsize::new(0),
+            listeners:            Mutex::new(Vec::new()),
+            services:             Mutex::new(BTreeMap::new()),
+            countries:            Mutex::new(BTreeMap::new()),
+            asns:                 Mutex::new(BTreeMap::new()),
+            versions:             Mutex::new(BTreeMap::new()),
+            #[cfg(all(feature = "exporters", not(feature = "aggregate_only")))]
+            events:               broadcast::channel(EVENTS_BUFFER).0,
         }
     }
 
+    /// Start counting the accepts of a listener.
+    pub(crate) fn listener(
+        &self,
+        addr: SocketAddr,
+        over_limit: &'static str,
+    ) -> Arc<AcceptRate> {
+        let rate = Arc::new(AcceptRate {
+            addr,
+            startup:    self.startup,
+            window:     Mutex::new((self.startup.elapsed().as_secs(), [0; ACCEPT_WINDOW])),
+            dropped:    AtomicUsize::new(0),
+            over_limit: (over_limit, AtomicUsize::new(0)),
+            rebinds:    AtomicUsize::new(0),
+            errors:     Default::default(),
+        });
+        lock(&self.listeners).push(rate.clone());
+        rate
+    }
+
+    pub(crate) fn uptime(&self) -> Duration {
+        self.startup.elapsed()
+    }
+
     pub(crate) fn connections(&self) -> usize {
         self.connections_count.load(Ordering::Relaxed)
     }
 
-    pub(crate) fn connect(
+    pub(crate) fn max_clients(&self) -> usize {
+        self.max_clients.load(Ordering::Relaxed)
+    }
+
+    /// Hold this many clients at once at most from now on; none of those connected is dropped
+    /// over a limit lowered below how many there are.
+    pub(crate) fn limit(
         &self,
         max_clients: usize,
+    ) {
+        self.max_clients.store(max_clients, Ordering::Relaxed);
+    }
+
+    pub(crate) fn totals(&self) -> Totals {
+        let former = lock(&self.former_metrics);
+        Totals {
+            accepted: self.connections_total.load(Ordering::Relaxed),
+            rejected: self.connections_rejected.load(Ordering::Relaxed),
+            closed:   former.connection_time_till.iter().sum(),
+            trapped:  former.connection_time,
+        }
+    }
+
+    pub(crate) fn connect(
+        &self,
+        peer: IpAddr,
+        listener: SocketAddr,
         start: Instant,
-    ) -> Result<(usize, Token), usize> {
-        self.connections_total.fetch_add(1, Ordering::Relaxed);
+    ) -> Result<(usize, Token), MetricsError> {
+        let id = self.connections_total.fetch_add(1, Ordering::Relaxed);
         let connected = self.connections_count.fetch_add(1, Ordering::Relaxed) + 1;
-        if connected > max_clients {
+        if connected > self.max_clients() {
             self.connections_count.fetch_sub(1, Ordering::Relaxed);
-            Err(connected)
+            self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+            Err(MetricsError::OverLimit { count: connected })
         } else {
             let client = Client {
+                id,
+                peer,
+                listener,
                 start,
                 sent_chunks:      0,
+                sent_bytes:       0,
                 sent_eastereggs:  0,
                 sent_banners:     0,
+                shed:             false,
+                country:          self.shared.geoip.as_ref().and_then(|geoip| geoip.country(peer)),
+                asn:              self.shared.asn_db.as_ref().map(|_| self.count_asn(peer)),
+                kick:             Arc::new(Notify::new()),
+                delay:            None,
+                received_bytes:   0,
+                version:          None,
+                said:             Vec::new(),
             };
-            let mut guard = match self.clients.lock() {
-                Ok(guard) => guard,
-                Err(poisoned) => poisoned.into_inner(),
+            if self.shared.geoip.is_some() {
+                let mut countries = lock(&self.countries);
+                countries.entry(client.country).or_default().0 += 1;
+            }
+            self.connected_since_sum.fetch_add(self.since_startup(start), Ordering::Relaxed);
+            let shard = id % CLIENT_SHARDS;
+            let mut guard = lock(&self.clients[shard]);
+            let (uid, generation) = if let Some(index) = guard.free.pop() {
+                let slot = &mut guard.slots[index];
+                slot.generation += 1;
+                slot.client = Some(client);
+                (index, slot.generation)
+            } else {
+                guard.slots.push(Slot {
+                    generation: 0,
+                    client:     Some(client),
+                });
+                (guard.slots.len() - 1, 0)
             };
             Ok((
                 connected,
                 Token {
-                    uid: if let Some(index) = guard
-                        .iter()
-                        .enumerate()
-                        .find_map(|(index, value)|
-                            if value.is_none() {
-                                Some(index)
-                            }
-                            else {
-                                None
-                            }
-                        ) {
-                        guard [ index ] = Some(client);
-                        index
-                    } else {
-                        guard.push(Some(client));
-                        guard.len() - 1
-                    }
+                    id,
+                    shard,
+                    uid,
+                    generation,
                 },
             ))
         }
@@ -149,354 +677,773 @@ impl Metrics {
     pub(crate) fn disconnect(
         &self,
         token: Token,
-    ) -> Result<(usize, u64), Cow<'static, str>> {
-      let mut guard = match self.clients.lock() {
-          Ok(guard) => guard,
-          Err(poisoned) => poisoned.into_inner(),
-      };
-      let mut metrics_guard = match self.former_metrics.lock() {
-          Ok(guard) => guard,
-          Err(poisoned) => poisoned.into_inner(),
+    ) -> Result<Disconnected, MetricsError> {
+      let mut guard = match self.clients.get(token.shard) {
+          Some(shard) => lock(shard),
+          None => return Err(MetricsError::InvalidToken),
       };
-      if guard.len() > token.uid {
-          if let Some(ref client) = guard[token.uid] {
+      let mut metrics_guard = lock(&self.former_metrics);
+      if guard.slots.len() > token.uid {
+          if let Some(client) = guard.slots[token.uid].client(token.generation) {
               let connected = self.connections_count.fetch_sub(1, Ordering::Relaxed);
-              let connection_time = client.start.elapsed().as_secs();
-              metrics_guard.maximum_connection_time = metrics_guard.maximum_connection_time.max(connection_time);
-              metrics_guard.minimum_connection_time = metrics_guard.minimum_connection_time.min(connection_time);
-              let bucket = 63-connection_time.leading_zeros() as usize;
-              metrics_guard.connection_time_till[bucket] += 1;
-              metrics_guard.connection_time     += connection_time;
-              metrics_guard.sent_chunks_sum     += client.sent_chunks;
-              metrics_guard.sent_eastereggs_sum += client.sent_eastereggs;
-              metrics_guard.sent_banners_sum    += client.sent_banners;
-              guard[token.uid] = None;
-              Ok((connected-1, connection_time))
+              let duration = client.start.elapsed();
+              let connection_time = duration.as_secs();
+              self.connected_since_sum.fetch_sub(self.since_startup(client.start), Ordering::Relaxed);
+              metrics_guard.add(client);
+              let history = self.remember(client.peer, connection_time);
+              if self.history.sessions > 0 {
+                  let mut archive = lock(&self.archive);
+                  if archive.len() >= self.history.sessions {
+                      archive.pop_front();
+                  }
+                  archive.push_back(Archived {
+                      id:          token.id,
+                      peer:        client.peer,
+                      listener:    client.listener,
+                      start:       SystemTime::now() - duration,
+                      duration,
+                      sent_chunks: client.sent_chunks,
+                      sent_bytes:  client.sent_bytes,
+                  });
+              }
+              if self.shared.geoip.is_some() {
+                  let mut countries = lock(&self.countries);
+                  countries.entry(client.country).or_default().1 += connection_time;
+              }
+              if let Some(asn) = &client.asn {
+                  if let Some(history) = lock(&self.asns).get_mut(asn) {
+                      history.trapped += connection_time;
+                  }
+              }
+              guard.slots[token.uid].client = None;
+              guard.free.push(token.uid);
+              Ok(Disconnected {
+                  id:      Some(token.id),
+                  clients: connected-1,
+                  duration,
+                  history,
+              })
           } else {
-              Err(Cow::Borrowed("Already Disconnected"))
+              Err(MetricsError::AlreadyDisconnected)
           }
       } else {
-          Err(Cow::Borrowed("Invalid Token"))
+          Err(MetricsError::InvalidToken)
       }
     }
 
-    pub(crate) fn export(&self) -> String {
-        let client_guard = match self.clients.lock() {
-            Ok(guard) => guard,
-            Err(poisoned) => poisoned.into_inner(),
+    /// Clients connected now, by the shard they are kept in.
+    #[cfg(feature = "profiling")]
+    pub(crate) fn shards(&self) -> Vec<usize> {
+        self.clients
+            .iter()
+            .map(|shard| {
+                let shard = lock(shard);
+                shard.slots.len() - shard.free.len()
+            })
+            .collect()
+    }
+
+    /// Number of listeners counted the accepts of, since startup.
+    #[cfg(feature = "profiling")]
+    pub(crate) fn listening(&self) -> usize {
+        lock(&self.listeners).len()
+    }
+
+    /// The latest connections ended, the oldest first.
+    #[cfg(all(feature = "exporters", not(feature = "aggregate_only")))]
+    pub(crate) fn archive(&self) -> Vec<Archived> {
+        lock(&self.archive).iter().cloned().collect()
+    }
+
+    /// How long all clients were trapped, the current ones so far and the former ones
+    /// entirely, without walking the clients.
+    #[cfg_attr(not(any(unix, feature = "exporters")), allow(dead_code))]
+    pub(crate) fn wasted(&self) -> Duration {
+        let former = lock(&self.former_metrics).connection_time;
+        let connected = self.connections_count.load(Ordering::Relaxed) as u64;
+        let current = (connected * self.since_startup(Instant::now()))
+            .saturating_sub(self.connected_since_sum.load(Ordering::Relaxed));
+        Duration::from_secs(former) + Duration::from_millis(current)
+    }
+
+    /// How long the longest connection lasted, or lasts already.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn record(&self) -> Duration {
+        let mut longest = Duration::from_secs(lock(&self.former_metrics).maximum_connection_time);
+        self.each_client(|client| longest = longest.max(client.start.elapsed()));
+        longest
+    }
+
+    /// Milliseconds from startup to an instant.
+    fn since_startup(
+        &self,
+        instant: Instant,
+    ) -> u64 {
+        instant.saturating_duration_since(self.startup).as_millis() as u64
+    }
+
+    /// The autonomous system of a peer, by its number, with its organization if known.
+    pub(crate) fn asn(
+        &self,
+        peer: IpAddr,
+    ) -> Option<(u32, Option<&str>)> {
+        self.shared.asn_db.as_ref()?.asn(peer)
+    }
+
+    /// Count a connection towards the autonomous system of its peer, returning what it was
+    /// counted as: the number, `unknown`, or past `MAX_ASNS` of them, `other`.
+    fn count_asn(
+        &self,
+        peer: IpAddr,
+    ) -> String {
+        let (mut key, organization) = match self.asn(peer) {
+            Some((number, organization)) => (number.to_string(), organization),
+            None => ("unknown".to_owned(), None),
         };
-        let client_metrics = client_guard
+        let mut asns = lock(&self.asns);
+        if !asns.contains_key(&key) && asns.len() >= MAX_ASNS {
+            key = "other".to_owned();
+        }
+        let history = asns.entry(key.clone()).or_default();
+        if history.organization.is_none() {
+            history.organization = organization.map(str::to_owned);
+        }
+        history.connections += 1;
+        key
+    }
+
+    /// Add a finished connection to the history of its peer, returning the history before it.
+    ///
+    /// The table is bounded: once full, the peer seen least recently is forgotten.
+    fn remember(
+        &self,
+        peer: IpAddr,
+        connection_time: u64,
+    ) -> PeerHistory {
+        if self.history.peers == 0 {
+            return PeerHistory::default();
+        }
+        let mut peers = lock(&self.peers);
+        if !peers.contains_key(&peer) && peers.len() >= self.history.peers {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_seen)
+                .map(|(address, _)| *address);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        let entry = peers.entry(peer).or_insert(Peer {
+            history:    PeerHistory::default(),
+            last_seen:  Instant::now(),
+        });
+        let history = entry.history;
+        entry.history.seen += 1;
+        entry.history.trapped += connection_time;
+        entry.last_seen = Instant::now();
+        history
+    }
+
+    /// Every peer remembered, with its connections still open counted in, and how long ago
+    /// it was last seen.
+    #[cfg(all(feature = "exporters", not(feature = "aggregate_only")))]
+    pub(crate) fn peers(&self) -> HashMap<IpAddr, (PeerHistory, Duration)> {
+        let mut stats: HashMap<_, _> = lock(&self.peers)
             .iter()
-            .fold(
-                ClientMetrics::new(),
-                |mut metrics, client| {
-                    if let Some(client) = client {
-                        let connection_time = client.start.elapsed().as_secs();
-                        metrics.maximum_connection_time = metrics.maximum_connection_time.max(connection_time);
-                        metrics.minimum_connection_time = metrics.minimum_connection_time.min(connection_time);
-                        let bucket = 63-connection_time.leading_zeros() as usize;
-                        metrics.connection_time_till[bucket] += 1;
-                        metrics.connection_time     += connection_time;
-                        metrics.sent_chunks_sum     += client.sent_chunks;
-                        metrics.sent_eastereggs_sum += client.sent_eastereggs;
-                        metrics.sent_banners_sum    += client.sent_banners;
-                    }
-                    metrics
-                }
+            .map(|(&peer, entry)| (peer, (entry.history, entry.last_seen.elapsed())))
+            .collect();
+        self.each_client(|client| {
+            let (history, last_seen) = stats
+                .entry(client.peer)
+                .or_insert((PeerHistory::default(), Duration::default()));
+            history.seen += 1;
+            history.trapped += client.start.elapsed().as_secs();
+            *last_seen = Duration::default();
+        });
+        stats
+    }
+
+    /// Every client connected now, trapped the longest first.
+    #[cfg(unix)]
+    pub(crate) fn sessions(&self) -> Vec<Session> {
+        let mut sessions = Vec::new();
+        self.each_client(|client| sessions.push(Session {
+            id:          client.id,
+            peer:        client.peer,
+            duration:    client.start.elapsed(),
+            sent_chunks: client.sent_chunks,
+            sent_bytes:  client.sent_bytes,
+            listener:    client.listener,
+        }));
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.duration));
+        sessions
+    }
+
+    /// Everything known about the client of a connection, if it is still connected.
+    #[cfg(all(feature = "exporters", not(feature = "aggregate_only")))]
+    pub(crate) fn detail(
+        &self,
+        id: usize,
+    ) -> Option<Detail> {
+        self.details().into_iter().find(|detail| detail.id == id)
+    }
+
+    /// Everything known about every client connected now, trapped the longest first.
+    #[cfg(all(feature = "exporters", not(feature = "aggregate_only")))]
+    pub(crate) fn details(&self) -> Vec<Detail> {
+        let now = SystemTime::now();
+        let mut details = Vec::new();
+        self.each_client(|client| {
+            let duration = client.start.elapsed();
+            details.push(Detail {
+                id:              client.id,
+                peer:            client.peer,
+                listener:        client.listener,
+                start:           now - duration,
+                duration,
+                sent_chunks:     client.sent_chunks,
+                sent_bytes:      client.sent_bytes,
+                sent_eastereggs: client.sent_eastereggs,
+                sent_banners:    client.sent_banners,
+                received_bytes:  client.received_bytes,
+                delay:           client.delay,
+                version:         client.version.clone(),
+                country:         client.country,
+                asn:             client.asn.clone(),
+            });
+        });
+        details.sort_by_key(|detail| std::cmp::Reverse(detail.duration));
+        details
+    }
+
+    /// The metrics in the text format, as made for an earlier scrape if that was no longer ago
+    /// than the export cache, so aggressive scrapers can't keep walking all the clients.
+    #[cfg(feature = "exporters")]
+    pub(crate) fn export(&self) -> String {
+        if self.export_cache == Duration::default() {
+            return self.render();
+        }
+        // Held while rendering, so concurrent scrapes wait for the one export made.
+        let mut cached = lock(&self.cached_export);
+        if let Some((made, export)) = &*cached {
+            if made.elapsed() < self.export_cache {
+                return export.clone();
+            }
+        }
+        let export = self.render();
+        *cached = Some((Instant::now(), export.clone()));
+        export
+    }
+
+    #[cfg(feature = "exporters")]
+    fn render(&self) -> String {
+        let mut client_metrics = ClientMetrics::new();
+        self.each_client(|client| client_metrics.add(client));
+        let former_metrics = lock(&self.former_metrics).clone();
+        let total_metrics = client_metrics.combined(&former_metrics);
+
+        let mut registry = Registry::default();
+        registry.metric("uptime_seconds", Kind::Gauge, "Number of seconds since startup.", self.startup.elapsed().as_secs());
+        registry.family("runtime_scheduler_info", Kind::Gauge, "Scheduler the runtime is running on.", Some((vec![("scheduler", self.scheduler.to_string())], 1)));
+        registry.family("config_fingerprint_info", Kind::Gauge, "Fingerprint of the options and message files.", Some((vec![("fingerprint", self.fingerprint.clone())], 1)));
+        registry.metric("runtime_cores", Kind::Gauge, "Number of detected cores.", self.cores);
+        registry.metric("runtime_worker_threads", Kind::Gauge, "Number of worker threads of the runtime.", self.workers);
+        registry.metric("exporter_aggregate_only", Kind::Gauge, "Whether the exporter was built to never expose single peers.", cfg!(feature = "aggregate_only") as u8);
+        registry.metric("connections_count", Kind::Gauge, "Number of current connections.", self.connections_count.load(Ordering::Relaxed));
+        registry.metric("max_clients", Kind::Gauge, "Number of connections held at once at most.", self.max_clients());
+        registry.metric("connections_total", Kind::Counter, "Total number of connections.", self.connections_total.load(Ordering::Relaxed));
+        registry.metric("connections_rejected_total", Kind::Counter, "Total number of connections turned away past the limit of clients.", self.connections_rejected.load(Ordering::Relaxed));
+        registry.metric("write_retries_total", Kind::Counter, "Total number of retried writes after transient errors.", self.write_retries.load(Ordering::Relaxed));
+        registry.metric("max_session_disconnects_total", Kind::Counter, "Total number of connections dropped for their session length.", self.max_sessions.load(Ordering::Relaxed));
+        registry.metric("shed_disconnects_total", Kind::Counter, "Total number of connections shed for file descriptors.", self.shed.load(Ordering::Relaxed));
+        registry.metric("kicked_disconnects_total", Kind::Counter, "Total number of connections kicked through the exporter.", self.kicked.load(Ordering::Relaxed));
+        registry.metric("banned_connections_total", Kind::Counter, "Total number of connections hung up on at once, their networks banned.", self.banned.load(Ordering::Relaxed));
+        registry.metric("paused_connections_total", Kind::Counter, "Total number of connections hung up on at once, accepting paused.", self.paused.load(Ordering::Relaxed));
+        registry.metric("sampled_connections_total", Kind::Counter, "Total number of connections sampled for inspection.", self.sampled.load(Ordering::Relaxed));
+        registry.metric("received_bytes_total", Kind::Counter, "Total number of bytes received from clients.", self.received_bytes.load(Ordering::Relaxed));
+        registry.metric("udp_datagrams_total", Kind::Counter, "Total number of datagrams received on UDP ports.", self.udp_datagrams.load(Ordering::Relaxed));
+        registry.metric("udp_replies_total", Kind::Counter, "Total number of datagrams answered on UDP ports.", self.udp_replies.load(Ordering::Relaxed));
+        registry.metric("wasted_seconds", Kind::Gauge, "Number of seconds all clients were trapped, current ones so far and former ones entirely.", self.wasted().as_secs_f64());
+        registry.summary(
+            "accept_latency_seconds",
+            "Delay of the accept executor in waking up.",
+            self.accept_latency_count.load(Ordering::Relaxed),
+            self.accept_latency_sum.load(Ordering::Relaxed) as f64 / 1e6,
+        );
+        registry.metric("accept_latency_max_seconds", Kind::Gauge, "Longest delay of the accept executor in waking up.", self.accept_latency_max.load(Ordering::Relaxed) as f64 / 1e6);
+
+        // Current clients come and go, so only what former clients add up to keeps growing.
+        for (scope, whose, sums, metrics) in [
+            ("client", "current clients", Kind::Gauge, &client_metrics),
+            ("former", "former clients", Kind::Counter, &former_metrics),
+            ("total", "all clients", Kind::Counter, &total_metrics),
+        ] {
+            registry.metric(&format!("{}_maximum_connection_time_seconds", scope), Kind::Gauge, &format!("Length in seconds of longest connection by {}.", whose), metrics.maximum_connection_time);
+            registry.metric(&format!("{}_minimum_connection_time_seconds", scope), Kind::Gauge, &format!("Length in seconds of shortest connection by {}.", whose), metrics.minimum_connection_time);
+            registry.metric(&format!("{}_sent_chunks_sum", scope), sums, &format!("Sum of sent chunks by {}.", whose), metrics.sent_chunks_sum);
+            registry.metric(&format!("{}_sent_bytes_sum", scope), sums, &format!("Sum of bytes sent to {}.", whose), metrics.sent_bytes_sum);
+            registry.metric(&format!("{}_sent_eastereggs_sum", scope), sums, &format!("Sum of sent eastereggs by {}.", whose), metrics.sent_eastereggs_sum);
+            registry.metric(&format!("{}_sent_banners_sum", scope), sums, &format!("Sum of sent banners by {}.", whose), metrics.sent_banners_sum);
+            registry.histogram(
+                &format!("{}_connection_time_seconds", scope),
+                &format!("A histogram of the connection time of {}.", whose),
+                CONNECTION_TIME_BOUNDS.iter().copied().zip(metrics.connection_time_till.iter().copied()),
+                metrics.connection_time,
             );
-        let former_metrics = match self.former_metrics.lock() {
-            Ok(guard) => guard,
-            Err(poisoned) => poisoned.into_inner(),
-        };
-        format!(
-            concat!(
-                metric!       (uptime_seconds:                          gauge,      "Number of seconds since startup."                              ),
-                metric!       (connections_count:                       counter,    "Number of current connections."                                ),
-                metric!       (connections_total:                       counter,    "Total number of connections."                                  ),
-                metric!       (client_maximum_connection_time_seconds:  counter,    "Length in seconds of longest connection by current clients."   ),
-                metric!       (client_minimum_connection_time_seconds:  counter,    "Length in seconds of shortest connection by current clients."  ),
-                metric!       (client_sent_chunks_sum:                  counter,    "Sum of sent chunks by current clients."                        ),
-                metric!       (client_sent_eastereggs_sum:              counter,    "Sum of sent sent_eastereggs by current clients."               ),
-                metric!       (client_sent_banners_sum:                 counter,    "Sum of sent banners by current clients."                       ),
-                metric!       (client_connection_time_seconds_sum:      counter,    "Sum of connection time of current clients."                    ),
-                metric_header!(client_connection_time_seconds_bucket:   histogram,  "A histogram of the connection time of current clients."        ),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket00):  "le=\"0\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket01):  "le=\"1\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket02):  "le=\"3\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket03):  "le=\"7\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket04):  "le=\"15\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket05):  "le=\"31\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket06):  "le=\"63\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket07):  "le=\"127\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket08):  "le=\"255\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket09):  "le=\"511\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket0a):  "le=\"1023\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket0b):  "le=\"2047\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket0c):  "le=\"4095\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket0d):  "le=\"8191\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket0e):  "le=\"16383\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket0f):  "le=\"32767\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket10):  "le=\"65535\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket11):  "le=\"131071\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket12):  "le=\"262143\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket13):  "le=\"524287\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket14):  "le=\"1048575\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket15):  "le=\"2097151\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket16):  "le=\"4194303\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket17):  "le=\"8388607\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket18):  "le=\"16777215\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket19):  "le=\"33554431\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket1a):  "le=\"67108863\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket1b):  "le=\"134217727\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket1c):  "le=\"268435455\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket1d):  "le=\"536870911\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket1e):  "le=\"1073741823\"",),
-                metric_bucket!(client_connection_time_seconds_bucket (client_connection_time_bucket1f):  "le=\"+Inf\"",),
-                "\n",
-                metric!       (former_maximum_connection_time_seconds:  counter,    "Length in seconds of longest connection by former clients."  ),
-                metric!       (former_minimum_connection_time_seconds:  counter,    "Length in seconds of shortest connection by former clients." ),
-                metric!       (former_sent_chunks_sum:                  counter,    "Sum of sent chunks by former clients."                       ),
-                metric!       (former_sent_eastereggs_sum:              counter,    "Sum of sent sent_eastereggs by former clients."              ),
-                metric!       (former_sent_banners_sum:                 counter,    "Sum of sent banners by former clients."                      ),
-                metric!       (former_connection_time_seconds_sum:      counter,    "Sum of connection time of former clients."                    ),
-                metric_header!(former_connection_time_seconds_bucket:   histogram,  "A histogram of the connection time of former clients."       ),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket00):  "le=\"0\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket01):  "le=\"1\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket02):  "le=\"3\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket03):  "le=\"7\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket04):  "le=\"15\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket05):  "le=\"31\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket06):  "le=\"63\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket07):  "le=\"127\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket08):  "le=\"255\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket09):  "le=\"511\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket0a):  "le=\"1023\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket0b):  "le=\"2047\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket0c):  "le=\"4095\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket0d):  "le=\"8191\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket0e):  "le=\"16383\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket0f):  "le=\"32767\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket10):  "le=\"65535\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket11):  "le=\"131071\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket12):  "le=\"262143\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket13):  "le=\"524287\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket14):  "le=\"1048575\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket15):  "le=\"2097151\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket16):  "le=\"4194303\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket17):  "le=\"8388607\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket18):  "le=\"16777215\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket19):  "le=\"33554431\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket1a):  "le=\"67108863\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket1b):  "le=\"134217727\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket1c):  "le=\"268435455\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket1d):  "le=\"536870911\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket1e):  "le=\"1073741823\"",),
-                metric_bucket!(former_connection_time_seconds_bucket (former_connection_time_bucket1f):  "le=\"+Inf\"",),
-                "\n",
-                metric!       (total_maximum_connection_time_seconds:  counter,    "Length in seconds of longest connection overall."   ),
-                metric!       (total_minimum_connection_time_seconds:  counter,    "Length in seconds of shortest connection overall."  ),
-                metric!       (total_sent_chunks_sum:                  counter,    "Sum of sent chunks overall."                        ),
-                metric!       (total_sent_eastereggs_sum:              counter,    "Sum of sent sent_eastereggs overall."               ),
-                metric!       (total_sent_banners_sum:                 counter,    "Sum of sent banners overall."                       ),
-                metric!       (total_connection_time_seconds_sum:      counter,    "Sum of connection time overall."                    ),
-                metric_header!(total_connection_time_seconds_bucket:   histogram,  "A histogram of the connection time overall."        ),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket00):  "le=\"0\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket01):  "le=\"1\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket02):  "le=\"3\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket03):  "le=\"7\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket04):  "le=\"15\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket05):  "le=\"31\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket06):  "le=\"63\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket07):  "le=\"127\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket08):  "le=\"255\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket09):  "le=\"511\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket0a):  "le=\"1023\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket0b):  "le=\"2047\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket0c):  "le=\"4095\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket0d):  "le=\"8191\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket0e):  "le=\"16383\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket0f):  "le=\"32767\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket10):  "le=\"65535\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket11):  "le=\"131071\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket12):  "le=\"262143\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket13):  "le=\"524287\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket14):  "le=\"1048575\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket15):  "le=\"2097151\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket16):  "le=\"4194303\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket17):  "le=\"8388607\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket18):  "le=\"16777215\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket19):  "le=\"33554431\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket1a):  "le=\"67108863\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket1b):  "le=\"134217727\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket1c):  "le=\"268435455\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket1d):  "le=\"536870911\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket1e):  "le=\"1073741823\"",),
-                metric_bucket!(total_connection_time_seconds_bucket (total_connection_time_bucket1f):  "le=\"+Inf\"",),
-            ),
-            uptime_seconds                          = self.startup.elapsed().as_secs(),
-            connections_count                       = self.connections_count.load(Ordering::Relaxed),
-            connections_total                       = self.connections_total.load(Ordering::Relaxed),
-            client_maximum_connection_time_seconds  = client_metrics.maximum_connection_time,
-            client_minimum_connection_time_seconds  = client_metrics.minimum_connection_time,
-            client_sent_chunks_sum                  = client_metrics.sent_chunks_sum,
-            client_sent_eastereggs_sum              = client_metrics.sent_eastereggs_sum,
-            client_sent_banners_sum                 = client_metrics.sent_banners_sum,
-            client_connection_time_seconds_sum      = client_metrics.connection_time,
-            client_connection_time_bucket00         = client_metrics.connection_time_till[0x00],
-            client_connection_time_bucket01         = &client_metrics.connection_time_till[0x00..0x01].iter().sum::<usize>(),
-            client_connection_time_bucket02         = &client_metrics.connection_time_till[0x00..0x02].iter().sum::<usize>(),
-            client_connection_time_bucket03         = &client_metrics.connection_time_till[0x00..0x03].iter().sum::<usize>(),
-            client_connection_time_bucket04         = &client_metrics.connection_time_till[0x00..0x04].iter().sum::<usize>(),
-            client_connection_time_bucket05         = &client_metrics.connection_time_till[0x00..0x05].iter().sum::<usize>(),
-            client_connection_time_bucket06         = &client_metrics.connection_time_till[0x00..0x06].iter().sum::<usize>(),
-            client_connection_time_bucket07         = &client_metrics.connection_time_till[0x00..0x07].iter().sum::<usize>(),
-            client_connection_time_bucket08         = &client_metrics.connection_time_till[0x00..0x08].iter().sum::<usize>(),
-            client_connection_time_bucket09         = &client_metrics.connection_time_till[0x00..0x09].iter().sum::<usize>(),
-            client_connection_time_bucket0a         = &client_metrics.connection_time_till[0x00..0x0a].iter().sum::<usize>(),
-            client_connection_time_bucket0b         = &client_metrics.connection_time_till[0x00..0x0b].iter().sum::<usize>(),
-            client_connection_time_bucket0c         = &client_metrics.connection_time_till[0x00..0x0c].iter().sum::<usize>(),
-            client_connection_time_bucket0d         = &client_metrics.connection_time_till[0x00..0x0d].iter().sum::<usize>(),
-            client_connection_time_bucket0e         = &client_metrics.connection_time_till[0x00..0x0e].iter().sum::<usize>(),
-            client_connection_time_bucket0f         = &client_metrics.connection_time_till[0x00..0x0f].iter().sum::<usize>(),
-            client_connection_time_bucket10         = &client_metrics.connection_time_till[0x00..0x10].iter().sum::<usize>(),
-            client_connection_time_bucket11         = &client_metrics.connection_time_till[0x00..0x11].iter().sum::<usize>(),
-            client_connection_time_bucket12         = &client_metrics.connection_time_till[0x00..0x12].iter().sum::<usize>(),
-            client_connection_time_bucket13         = &client_metrics.connection_time_till[0x00..0x13].iter().sum::<usize>(),
-            client_connection_time_bucket14         = &client_metrics.connection_time_till[0x00..0x14].iter().sum::<usize>(),
-            client_connection_time_bucket15         = &client_metrics.connection_time_till[0x00..0x15].iter().sum::<usize>(),
-            client_connection_time_bucket16         = &client_metrics.connection_time_till[0x00..0x16].iter().sum::<usize>(),
-            client_connection_time_bucket17         = &client_metrics.connection_time_till[0x00..0x17].iter().sum::<usize>(),
-            client_connection_time_bucket18         = &client_metrics.connection_time_till[0x00..0x18].iter().sum::<usize>(),
-            client_connection_time_bucket19         = &client_metrics.connection_time_till[0x00..0x19].iter().sum::<usize>(),
-            client_connection_time_bucket1a         = &client_metrics.connection_time_till[0x00..0x1a].iter().sum::<usize>(),
-            client_connection_time_bucket1b         = &client_metrics.connection_time_till[0x00..0x1b].iter().sum::<usize>(),
-            client_connection_time_bucket1c         = &client_metrics.connection_time_till[0x00..0x1c].iter().sum::<usize>(),
-            client_connection_time_bucket1d         = &client_metrics.connection_time_till[0x00..0x1d].iter().sum::<usize>(),
-            client_connection_time_bucket1e         = &client_metrics.connection_time_till[0x00..0x1e].iter().sum::<usize>(),
-            client_connection_time_bucket1f         = &client_metrics.connection_time_till[0x00..0x1f].iter().sum::<usize>(),
-            former_maximum_connection_time_seconds  = former_metrics.maximum_connection_time,
-            former_minimum_connection_time_seconds  = former_metrics.minimum_connection_time,
-            former_sent_chunks_sum                  = former_metrics.sent_chunks_sum,
-            former_sent_eastereggs_sum              = former_metrics.sent_eastereggs_sum,
-            former_sent_banners_sum                 = former_metrics.sent_banners_sum,
-            former_connection_time_seconds_sum      = former_metrics.connection_time,
-            former_connection_time_bucket00         = former_metrics.connection_time_till[0x00],
-            former_connection_time_bucket01         = &former_metrics.connection_time_till[0x00..0x01].iter().sum::<usize>(),
-            former_connection_time_bucket02         = &former_metrics.connection_time_till[0x00..0x02].iter().sum::<usize>(),
-            former_connection_time_bucket03         = &former_metrics.connection_time_till[0x00..0x03].iter().sum::<usize>(),
-            former_connection_time_bucket04         = &former_metrics.connection_time_till[0x00..0x04].iter().sum::<usize>(),
-            former_connection_time_bucket05         = &former_metrics.connection_time_till[0x00..0x05].iter().sum::<usize>(),
-            former_connection_time_bucket06         = &former_metrics.connection_time_till[0x00..0x06].iter().sum::<usize>(),
-            former_connection_time_bucket07         = &former_metrics.connection_time_till[0x00..0x07].iter().sum::<usize>(),
-            former_connection_time_bucket08         = &former_metrics.connection_time_till[0x00..0x08].iter().sum::<usize>(),
-            former_connection_time_bucket09         = &former_metrics.connection_time_till[0x00..0x09].iter().sum::<usize>(),
-            former_connection_time_bucket0a         = &former_metrics.connection_time_till[0x00..0x0a].iter().sum::<usize>(),
-            former_connection_time_bucket0b         = &former_metrics.connection_time_till[0x00..0x0b].iter().sum::<usize>(),
-            former_connection_time_bucket0c         = &former_metrics.connection_time_till[0x00..0x0c].iter().sum::<usize>(),
-            former_connection_time_bucket0d         = &former_metrics.connection_time_till[0x00..0x0d].iter().sum::<usize>(),
-            former_connection_time_bucket0e         = &former_metrics.connection_time_till[0x00..0x0e].iter().sum::<usize>(),
-            former_connection_time_bucket0f         = &former_metrics.connection_time_till[0x00..0x0f].iter().sum::<usize>(),
-            former_connection_time_bucket10         = &former_metrics.connection_time_till[0x00..0x10].iter().sum::<usize>(),
-            former_connection_time_bucket11         = &former_metrics.connection_time_till[0x00..0x11].iter().sum::<usize>(),
-            former_connection_time_bucket12         = &former_metrics.connection_time_till[0x00..0x12].iter().sum::<usize>(),
-            former_connection_time_bucket13         = &former_metrics.connection_time_till[0x00..0x13].iter().sum::<usize>(),
-            former_connection_time_bucket14         = &former_metrics.connection_time_till[0x00..0x14].iter().sum::<usize>(),
-            former_connection_time_bucket15         = &former_metrics.connection_time_till[0x00..0x15].iter().sum::<usize>(),
-            former_connection_time_bucket16         = &former_metrics.connection_time_till[0x00..0x16].iter().sum::<usize>(),
-            former_connection_time_bucket17         = &former_metrics.connection_time_till[0x00..0x17].iter().sum::<usize>(),
-            former_connection_time_bucket18         = &former_metrics.connection_time_till[0x00..0x18].iter().sum::<usize>(),
-            former_connection_time_bucket19         = &former_metrics.connection_time_till[0x00..0x19].iter().sum::<usize>(),
-            former_connection_time_bucket1a         = &former_metrics.connection_time_till[0x00..0x1a].iter().sum::<usize>(),
-            former_connection_time_bucket1b         = &former_metrics.connection_time_till[0x00..0x1b].iter().sum::<usize>(),
-            former_connection_time_bucket1c         = &former_metrics.connection_time_till[0x00..0x1c].iter().sum::<usize>(),
-            former_connection_time_bucket1d         = &former_metrics.connection_time_till[0x00..0x1d].iter().sum::<usize>(),
-            former_connection_time_bucket1e         = &former_metrics.connection_time_till[0x00..0x1e].iter().sum::<usize>(),
-            former_connection_time_bucket1f         = &former_metrics.connection_time_till[0x00..0x1f].iter().sum::<usize>(),
-            total_maximum_connection_time_seconds   = client_metrics.maximum_connection_time.max(former_metrics.maximum_connection_time),
-            total_minimum_connection_time_seconds   = client_metrics.minimum_connection_time.min(former_metrics.maximum_connection_time),
-            total_sent_chunks_sum                   = client_metrics.sent_chunks_sum      + former_metrics.sent_chunks_sum,
-            total_sent_eastereggs_sum               = client_metrics.sent_eastereggs_sum  + former_metrics.sent_eastereggs_sum,
-            total_sent_banners_sum                  = client_metrics.sent_banners_sum     + former_metrics.sent_banners_sum,
-            total_connection_time_seconds_sum       = client_metrics.connection_time      + former_metrics.connection_time,
-            total_connection_time_bucket00          = client_metrics.connection_time_till[0x00] + former_metrics.connection_time_till[0x00],
-            total_connection_time_bucket01          = &client_metrics.connection_time_till[0x00..0x01].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x01].iter().sum::<usize>(),
-            total_connection_time_bucket02          = &client_metrics.connection_time_till[0x00..0x02].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x02].iter().sum::<usize>(),
-            total_connection_time_bucket03          = &client_metrics.connection_time_till[0x00..0x03].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x03].iter().sum::<usize>(),
-            total_connection_time_bucket04          = &client_metrics.connection_time_till[0x00..0x04].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x04].iter().sum::<usize>(),
-            total_connection_time_bucket05          = &client_metrics.connection_time_till[0x00..0x05].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x05].iter().sum::<usize>(),
-            total_connection_time_bucket06          = &client_metrics.connection_time_till[0x00..0x06].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x06].iter().sum::<usize>(),
-            total_connection_time_bucket07          = &client_metrics.connection_time_till[0x00..0x07].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x07].iter().sum::<usize>(),
-            total_connection_time_bucket08          = &client_metrics.connection_time_till[0x00..0x08].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x08].iter().sum::<usize>(),
-            total_connection_time_bucket09          = &client_metrics.connection_time_till[0x00..0x09].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x09].iter().sum::<usize>(),
-            total_connection_time_bucket0a          = &client_metrics.connection_time_till[0x00..0x0a].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x0a].iter().sum::<usize>(),
-            total_connection_time_bucket0b          = &client_metrics.connection_time_till[0x00..0x0b].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x0b].iter().sum::<usize>(),
-            total_connection_time_bucket0c          = &client_metrics.connection_time_till[0x00..0x0c].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x0c].iter().sum::<usize>(),
-            total_connection_time_bucket0d          = &client_metrics.connection_time_till[0x00..0x0d].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x0d].iter().sum::<usize>(),
-            total_connection_time_bucket0e          = &client_metrics.connection_time_till[0x00..0x0e].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x0e].iter().sum::<usize>(),
-            total_connection_time_bucket0f          = &client_metrics.connection_time_till[0x00..0x0f].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x0f].iter().sum::<usize>(),
-            total_connection_time_bucket10          = &client_metrics.connection_time_till[0x00..0x10].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x10].iter().sum::<usize>(),
-            total_connection_time_bucket11          = &client_metrics.connection_time_till[0x00..0x11].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x11].iter().sum::<usize>(),
-            total_connection_time_bucket12          = &client_metrics.connection_time_till[0x00..0x12].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x12].iter().sum::<usize>(),
-            total_connection_time_bucket13          = &client_metrics.connection_time_till[0x00..0x13].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x13].iter().sum::<usize>(),
-            total_connection_time_bucket14          = &client_metrics.connection_time_till[0x00..0x14].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x14].iter().sum::<usize>(),
-            total_connection_time_bucket15          = &client_metrics.connection_time_till[0x00..0x15].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x15].iter().sum::<usize>(),
-            total_connection_time_bucket16          = &client_metrics.connection_time_till[0x00..0x16].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x16].iter().sum::<usize>(),
-            total_connection_time_bucket17          = &client_metrics.connection_time_till[0x00..0x17].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x17].iter().sum::<usize>(),
-            total_connection_time_bucket18          = &client_metrics.connection_time_till[0x00..0x18].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x18].iter().sum::<usize>(),
-            total_connection_time_bucket19          = &client_metrics.connection_time_till[0x00..0x19].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x19].iter().sum::<usize>(),
-            total_connection_time_bucket1a          = &client_metrics.connection_time_till[0x00..0x1a].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x1a].iter().sum::<usize>(),
-            total_connection_time_bucket1b          = &client_metrics.connection_time_till[0x00..0x1b].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x1b].iter().sum::<usize>(),
-            total_connection_time_bucket1c          = &client_metrics.connection_time_till[0x00..0x1c].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x1c].iter().sum::<usize>(),
-            total_connection_time_bucket1d          = &client_metrics.connection_time_till[0x00..0x1d].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x1d].iter().sum::<usize>(),
-            total_connection_time_bucket1e          = &client_metrics.connection_time_till[0x00..0x1e].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x1e].iter().sum::<usize>(),
-            total_connection_time_bucket1f          = &client_metrics.connection_time_till[0x00..0x1f].iter().sum::<usize>() + &former_metrics.connection_time_till[0x00..0x1f].iter().sum::<usize>(),
-        )
+        }
+
+        let listeners = lock(&self.listeners);
+        let listener = |listener: &AcceptRate| vec![("listener", listener.addr.to_string())];
+        registry.family(
+            "listener_accepts_per_second",
+            Kind::Gauge,
+            "Connections accepted per second by a listener, averaged over 10 seconds.",
+            listeners.iter().map(|rate| (listener(rate), rate.per_second())),
+        );
+        registry.family(
+            "listener_dropped_packets_total",
+            Kind::Counter,
+            "Total number of packets the kernel dropped for a listener, as filtered or for lack of room.",
+            listeners.iter().map(|rate| (listener(rate), rate.dropped.load(Ordering::Relaxed))),
+        );
+        registry.family(
+            "listener_over_limit_total",
+            Kind::Counter,
+            "Total number of clients a listener turned away past the connection limit, by how.",
+            listeners.iter().map(|rate| {
+                let mut labels = listener(rate);
+                labels.push(("mode", rate.over_limit.0.to_owned()));
+                (labels, rate.over_limit.1.load(Ordering::Relaxed))
+            }),
+        );
+        registry.family(
+            "listener_rebinds_total",
+            Kind::Counter,
+            "Total number of times a listener was bound anew, accepting having failed for too long.",
+            listeners.iter().map(|rate| (listener(rate), rate.rebinds.load(Ordering::Relaxed))),
+        );
+        registry.family(
+            "accept_errors_total",
+            Kind::Counter,
+            "Total number of failed accepts of a listener, by kind.",
+            listeners.iter().flat_map(|rate| AcceptError::ALL.iter().map(move |&error| {
+                let mut labels = listener(rate);
+                labels.push(("kind", error.name().to_owned()));
+                (labels, rate.errors[error as usize].load(Ordering::Relaxed))
+            })),
+        );
+        drop(listeners);
+
+        let services = lock(&self.services);
+        registry.family(
+            "service_connections_total",
+            Kind::Counter,
+            "Total number of connections to a service, as diverted to a transparent listener.",
+            services.iter().map(|(service, connections)| (vec![("service", service.to_string())], connections)),
+        );
+        drop(services);
+
+        let versions = lock(&self.versions);
+        registry.family(
+            "client_version_total",
+            Kind::Counter,
+            "Total number of clients by the protocol and software version they identified as.",
+            versions.iter().map(|(version, clients)| (vec![("version", version.clone())], clients)),
+        );
+        drop(versions);
+
+        if self.shared.geoip.is_some() {
+            let countries = lock(&self.countries);
+            let country = |country: &Option<Country>| vec![("country", country.map_or_else(|| "unknown".to_owned(), |country| country.to_string()))];
+            registry.family(
+                "country_connections_total",
+                Kind::Counter,
+                "Total number of connections by the country of the peer, as the GeoIP database has it.",
+                countries.iter().map(|(code, (connections, _))| (country(code), connections)),
+            );
+            registry.family(
+                "country_trapped_seconds_total",
+                Kind::Counter,
+                "Total number of seconds closed connections were trapped, by the country of the peer.",
+                countries.iter().map(|(code, (_, trapped))| (country(code), trapped)),
+            );
+        }
+
+        if self.shared.asn_db.is_some() {
+            let asns = lock(&self.asns);
+            let asn = |asn: &String, history: &AsnHistory| vec![
+                ("asn", asn.clone()),
+                ("organization", history.organization.clone().unwrap_or_default()),
+            ];
+            registry.family(
+                "asn_connections_total",
+                Kind::Counter,
+                "Total number of connections by the autonomous system of the peer, as the ASN database has it.",
+                asns.iter().map(|(number, history)| (asn(number, history), history.connections)),
+            );
+            registry.family(
+                "asn_trapped_seconds_total",
+                Kind::Counter,
+                "Total number of seconds closed connections were trapped, by the autonomous system of the peer.",
+                asns.iter().map(|(number, history)| (asn(number, history), history.trapped)),
+            );
+        }
+        registry.finish()
     }
 
     fn in_client<Func>(
         &self,
         token: &Token,
         action:  Func,
-    ) -> Result<(), &'static str>
+    ) -> Result<(), MetricsError>
     where Func: FnOnce(&mut Client) {
-        let mut guard = match self.clients.lock() {
-            Ok(guard) => guard,
-            Err(poisoned) => poisoned.into_inner(),
+        let mut guard = match self.clients.get(token.shard) {
+            Some(shard) => lock(shard),
+            None => return Err(MetricsError::InvalidToken),
         };
-        if guard.len() > token.uid {
-            if let Some(ref mut entry) = guard[token.uid] {
+        if guard.slots.len() > token.uid {
+            if let Some(entry) = guard.slots[token.uid].client(token.generation) {
                 action(entry);
                 Ok(())
             } else {
-                Err("Already Disconnected")
+                Err(MetricsError::AlreadyDisconnected)
             }
         } else {
-            Err("Invalid Token")
+            Err(MetricsError::InvalidToken)
+        }
+    }
+
+    /// Do something with every current client, a shard locked at a time.
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    fn each_client<Func>(
+        &self,
+        mut action: Func,
+    )
+    where Func: FnMut(&mut Client) {
+        for shard in &self.clients {
+            lock(shard)
+                .slots
+                .iter_mut()
+                .filter_map(|slot| slot.client.as_mut())
+                .for_each(&mut action);
+        }
+    }
+
+    pub(crate) fn retried_write(&self) {
+        self.write_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reached_max_session(&self) {
+        self.max_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Mark the oldest clients to be dropped at their next chunk, to get their file descriptors
+    /// back; returns how many were marked.
+    pub(crate) fn shed(
+        &self,
+        count: usize,
+    ) -> usize {
+        let mut clients = Vec::new();
+        for (shard, guard) in self.clients.iter().enumerate() {
+            for (uid, slot) in lock(guard).slots.iter().enumerate() {
+                match &slot.client {
+                    Some(client) if !client.shed => clients.push((client.start, shard, uid, slot.generation)),
+                    _ => (),
+                }
+            }
+        }
+        clients.sort_unstable_by_key(|&(start, ..)| start);
+        clients
+            .into_iter()
+            .filter(|&(_, shard, uid, generation)| {
+                // Gone in the meantime, a client is no longer shed.
+                let mut guard = lock(&self.clients[shard]);
+                match guard.slots[uid].client(generation) {
+                    Some(client) => {
+                        client.shed = true;
+                        true
+                    },
+                    None => false,
+                }
+            })
+            .take(count)
+            .count()
+    }
+
+    /// Whether the client is to be dropped now, counting it as shed if so.
+    pub(crate) fn shedding(
+        &self,
+        token: &Token,
+    ) -> bool {
+        let mut shed = false;
+        let _ = self.in_client(token, |client: &mut Client| shed = client.shed);
+        if shed {
+            self.shed.fetch_add(1, Ordering::Relaxed);
         }
+        shed
+    }
+
+    /// What wakes the tarpit of a client to hang up on it; never woken for a client gone.
+    pub(crate) fn kicked(
+        &self,
+        token: &Token,
+    ) -> Arc<Notify> {
+        let mut kick = None;
+        let _ = self.in_client(token, |client: &mut Client| kick = Some(client.kick.clone()));
+        kick.unwrap_or_default()
+    }
+
+    /// The delay set at runtime for a client, if any.
+    pub(crate) fn retuned(
+        &self,
+        token: &Token,
+    ) -> Option<Duration> {
+        let mut delay = None;
+        let _ = self.in_client(token, |client: &mut Client| delay = client.delay);
+        delay
+    }
+
+    /// The lines said to a client since they were last taken, to be written after a line ending.
+    pub(crate) fn said(
+        &self,
+        token: &Token,
+    ) -> Vec<u8> {
+        let mut said = Vec::new();
+        let _ = self.in_client(token, |client: &mut Client| said = std::mem::take(&mut client.said));
+        said
+    }
+
+    /// Have the client of a connection write a line after a line ending of what it sends next;
+    /// returns whether it is connected, and if so, whether the line found room among those
+    /// waiting.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn say(
+        &self,
+        id: usize,
+        line: &[u8],
+    ) -> Option<bool> {
+        let mut told = None;
+        self.each_client(|client| if client.id == id {
+            let room = client.said.len() + line.len() <= MAX_SAID;
+            if room {
+                client.said.extend_from_slice(line);
+            }
+            told = Some(room);
+        });
+        told
+    }
+
+    /// Have every client connected now wait this long between chunks from its next one on,
+    /// whatever it was accepted with; returns how many.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn retune(
+        &self,
+        delay: Duration,
+    ) -> usize {
+        let mut retuned = 0;
+        self.each_client(|client| {
+            client.delay = Some(delay);
+            retuned += 1;
+        });
+        retuned
+    }
+
+    /// Hang up on the clients at once; returns how many.
+    #[cfg_attr(not(all(feature = "exporters", not(feature = "aggregate_only"))), allow(dead_code))]
+    pub(crate) fn kick(
+        &self,
+        kick: Kick,
+    ) -> usize {
+        let mut kicked = 0;
+        self.each_client(|client| {
+            let matches = match kick {
+                Kick::Id(id) => client.id == id,
+                Kick::Peers(prefix) => prefix.contains(client.peer),
+            };
+            if matches {
+                client.kick.notify();
+                kicked += 1;
+            }
+        });
+        self.kicked.fetch_add(kicked, Ordering::Relaxed);
+        kicked
+    }
+
+    /// The ban a peer falls under, if any, counting it as hung up on.
+    pub(crate) fn banned(
+        &self,
+        ip: IpAddr,
+    ) -> Option<Prefix> {
+        let ban = self.shared.bans.find(ip);
+        if ban.is_some() {
+            self.banned.fetch_add(1, Ordering::Relaxed);
+        }
+        ban
+    }
+
+    /// Count a connection hung up on as accepting is paused.
+    pub(crate) fn paused(&self) {
+        self.paused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Stream an event to whoever listens, made only if anyone does.
+    #[cfg_attr(not(all(feature = "exporters", not(feature = "aggregate_only"))), allow(unused_variables))]
+    pub(crate) fn event(
+        &self,
+        event: impl FnOnce() -> Event,
+    ) {
+        #[cfg(all(feature = "exporters", not(feature = "aggregate_only")))]
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(event());
+        }
+    }
+
+    /// Listen to the events from now on.
+    #[cfg(all(feature = "exporters", not(feature = "aggregate_only")))]
+    pub(crate) fn events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Count a connection to a service, diverted to a transparent listener.
+    pub(crate) fn service(
+        &self,
+        service: SocketAddr,
+    ) {
+        let mut services = lock(&self.services);
+        *services.entry(service).or_default() += 1;
+    }
+
+    pub(crate) fn sampled(&self) {
+        self.sampled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a datagram of a UDP port, and whether it gets a reply.
+    pub(crate) fn datagram(&self, replied: bool) {
+        self.udp_datagrams.fetch_add(1, Ordering::Relaxed);
+        if replied {
+            self.udp_replies.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count the identification string of a client, by its protocol and software version,
+    /// without the comments and cut at 64 characters; past `MAX_VERSIONS` of them or not SSH
+    /// at all, as `other`.
+    pub(crate) fn version(
+        &self,
+        token: &Token,
+        line: &[u8],
+    ) {
+        let identified: String = String::from_utf8_lossy(line)
+            .chars()
+            .map(|character| if character == ' ' || character.is_ascii_graphic() { character } else { '?' })
+            .collect();
+        let _ = self.in_client(token, |client: &mut Client| client.version = Some(identified));
+        let software = line.split(|&byte| byte == b' ').next().unwrap_or_default();
+        let mut version: String = String::from_utf8_lossy(software)
+            .chars()
+            .map(|character| if character.is_ascii_graphic() { character } else { '?' })
+            .take(64)
+            .collect();
+        if !version.starts_with("SSH-") {
+            version = "other".to_owned();
+        }
+        let mut versions = lock(&self.versions);
+        if !versions.contains_key(&version) && versions.len() >= MAX_VERSIONS {
+            version = "other".to_owned();
+        }
+        *versions.entry(version).or_default() += 1;
+    }
+
+    pub(crate) fn received_bytes(
+        &self,
+        token: &Token,
+        count: usize,
+    ) {
+        self.received_bytes.fetch_add(count, Ordering::Relaxed);
+        let _ = self.in_client(token, |client: &mut Client| client.received_bytes += count as u64);
+    }
+
+    pub(crate) fn accept_latency(&self, latency: Duration) {
+        let micros = latency.as_micros() as usize;
+        self.accept_latency_sum.fetch_add(micros, Ordering::Relaxed);
+        self.accept_latency_max.fetch_max(micros, Ordering::Relaxed);
+        self.accept_latency_count.fetch_add(1, Ordering::Relaxed);
     }
 
     pub(crate) fn sent_chunk(
         &self,
         token: &Token,
-    ) -> Result<(), &'static str> {
-        self.in_client(token, |client: &mut Client| client.sent_chunks += 1)
+        bytes: usize,
+    ) -> Result<(), MetricsError> {
+        self.in_client(token, |client: &mut Client| {
+            client.sent_chunks += 1;
+            client.sent_bytes += bytes as u64;
+        })
     }
 
     pub(crate) fn sent_easteregg(
         &self,
         token: &Token,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(), MetricsError> {
         self.in_client(token, |client: &mut Client| client.sent_eastereggs += 1)
     }
 
     pub(crate) fn sent_banner(
         &self,
         token: &Token,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(), MetricsError> {
         self.in_client(token, |client: &mut Client| client.sent_banners += 1)
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct Token {
-    uid: usize,
+    id:         usize,
+    /// Index of the shard of the client.
+    shard:      usize,
+    /// Index of the slot of the client in its shard.
+    uid:        usize,
+    /// Generation of the slot when the client got it.
+    generation: u64,
+}
+
+impl Token {
+    /// Unique number of this connection since startup.
+    pub(crate) fn id(&self) -> usize {
+        self.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bans::Bans;
+
+    fn metrics() -> Metrics {
+        let shared = Arc::new(Shared { geoip: None, asn_db: None, bans: Bans::load(None).unwrap() });
+        Metrics::new(&Runtime::new(None, None), History { peers: 16, sessions: 16 }, "", shared, Duration::default())
+    }
+
+    #[test]
+    fn refuses_tokens_of_former_clients() {
+        let metrics = metrics();
+        let peer = "192.0.2.7".parse().unwrap();
+        let listener = "[::]:2222".parse().unwrap();
+        let (_, former) = metrics.connect(peer, listener, Instant::now()).unwrap();
+        assert!(metrics.disconnect(former.clone()).is_ok());
+        // The next client of the same shard gets the slot freed.
+        for _ in 1..CLIENT_SHARDS {
+            metrics.connect(peer, listener, Instant::now()).unwrap();
+        }
+        let (_, token) = metrics.connect(peer, listener, Instant::now()).unwrap();
+        assert_eq!((token.shard, token.uid), (former.shard, former.uid));
+        assert_eq!(metrics.sent_chunk(&former, 1), Err(MetricsError::AlreadyDisconnected));
+        assert_eq!(metrics.disconnect(former.clone()).err(), Some(MetricsError::AlreadyDisconnected));
+        assert_eq!(metrics.sent_chunk(&token, 1), Ok(()));
+        assert!(metrics.disconnect(token.clone()).is_ok());
+        let unknown = Token { uid: 1, ..former.clone() };
+        assert_eq!(metrics.sent_chunk(&unknown, 1), Err(MetricsError::InvalidToken));
+        let unknown = Token { shard: CLIENT_SHARDS, ..former };
+        assert_eq!(metrics.disconnect(unknown).err(), Some(MetricsError::InvalidToken));
+    }
 }
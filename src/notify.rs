@@ -0,0 +1,114 @@
+use log::{debug, info, warn};
+use std::{
+    env,
+    os::unix::net::UnixDatagram,
+    sync::Arc,
+    time::Duration,
+};
+#[cfg(target_os = "linux")]
+use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+use super::{handover::Handover, metrics::Metrics, summary::Stats};
+use futures::{future::select, pin_mut};
+use tokio::time::delay_for;
+
+/// Time between updates of the status, unless the watchdog wants to hear more often.
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tells systemd how tarssh is doing over the socket of `NOTIFY_SOCKET`, for units of
+/// `Type=notify`: ready once listening, the clients held as its status, and still alive to
+/// the watchdog of `WatchdogSec=`.
+pub(crate) struct Notifier {
+    socket:   UnixDatagram,
+    /// How often systemd wants to hear from tarssh at least, if it watches it.
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Connect to the socket systemd passed on, if any, while neither a chroot nor the sandbox
+    /// can keep it out of reach.
+    ///
+    /// Taking over from a former tarssh, this one becomes the main process of the unit, so the
+    /// watchdog passed on for that one is for this one too.
+    pub(crate) fn connect(
+        took_over: bool,
+    ) -> Option<Self> {
+        let path = env::var_os("NOTIFY_SOCKET")?;
+        let socket = UnixDatagram::unbound().and_then(|socket| {
+            let path = path.to_string_lossy();
+            // Starting with `@`, the socket is in the abstract namespace of Linux.
+            #[cfg(target_os = "linux")]
+            if let Some(name) = path.strip_prefix('@') {
+                socket.connect_addr(&SocketAddr::from_abstract_name(name)?)?;
+                return Ok(socket);
+            }
+            socket.connect(&*path)?;
+            Ok(socket)
+        });
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(err) => {
+                warn!("notify, socket: {}, error: {}", path.to_string_lossy(), err);
+                return None;
+            },
+        };
+        let ours = env::var("WATCHDOG_PID")
+            .map_or(true, |pid| took_over || pid.parse() == Ok(std::process::id()));
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|&usec| usec > 0 && ours)
+            .map(Duration::from_micros);
+        info!(
+            "notify, socket: {}, watchdog: {}",
+            path.to_string_lossy(),
+            watchdog.map_or_else(|| "none".to_owned(), |watchdog| format!("{:?}", watchdog)),
+        );
+        Some(Self { socket, watchdog })
+    }
+
+    /// Send a state, lines of `NAME=value`; systemd not listening is no reason to stop.
+    fn notify(
+        &self,
+        state: &str,
+    ) {
+        if let Err(err) = self.socket.send(state.as_bytes()) {
+            debug!("notify, error: {}", err);
+        }
+    }
+
+    /// Tell systemd tarssh is ready, then how many clients it holds and that it is alive,
+    /// until it hands its listeners over to a new tarssh, which tells from then on; drained, it
+    /// tells it is stopping, and keeps the watchdog fed for the clients left.
+    pub(crate) async fn serve(
+        self,
+        metrics: Vec<Arc<Metrics>>,
+        handover: &'static Handover,
+    ) {
+        let status = |state: &str| format!("STATUS={}clients: {}\n", state, Stats::of(&metrics).clients);
+        let mut ready = String::from("READY=1\n");
+        if handover.took_over() {
+            ready.push_str(&format!("MAINPID={}\n", std::process::id()));
+        }
+        self.notify(&(ready + &status("")));
+        // Twice as often as asked, so a late wake-up doesn't get tarssh restarted.
+        let interval = self.watchdog.map_or(STATUS_INTERVAL, |watchdog| STATUS_INTERVAL.min(watchdog / 2));
+        let alive = if self.watchdog.is_some() { "WATCHDOG=1\n" } else { "" };
+        let running = async {
+            loop {
+                delay_for(interval).await;
+                self.notify(&(status("") + alive));
+            }
+        };
+        let stopped = handover.stopped();
+        pin_mut!(running, stopped);
+        select(running, stopped).await;
+        if handover.handed_over() {
+            return;
+        }
+        self.notify(&(String::from("STOPPING=1\n") + &status("draining, ")));
+        loop {
+            delay_for(interval).await;
+            self.notify(&(status("draining, ") + alive));
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use super::{
+    exporters::{families, Family},
+    registry::escape,
+};
+use std::fmt::Write;
+
+/// Media type of the OpenMetrics text format.
+pub(crate) const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Whether an `Accept` header asks for OpenMetrics, with whatever quality above zero.
+pub(crate) fn accepted(
+    accept: &str,
+) -> bool {
+    accept.split(',').any(|range| {
+        let mut parameters = range.split(';').map(str::trim);
+        parameters.next().is_some_and(|media| media.eq_ignore_ascii_case("application/openmetrics-text"))
+            && parameters
+                .filter_map(|parameter| parameter.strip_prefix("q="))
+                .all(|quality| quality.parse::<f64>().is_ok_and(|quality| quality > 0.0))
+    })
+}
+
+/// An export of the text format in the OpenMetrics text format: counters named without their
+/// `_total` and their samples with it, `_info` gauges as infos, without blank lines and with
+/// `# EOF` at the end.  No exemplars, none of the metrics observe single events.
+pub(crate) fn encode(
+    export: &str,
+) -> String {
+    let mut metrics = String::new();
+    for family in families(export) {
+        let (name, kind) = match family.kind {
+            "counter" => (family.name.strip_suffix("_total").unwrap_or(family.name), "counter"),
+            "gauge" if is_info(&family) => (family.name.strip_suffix("_info").unwrap_or(family.name), "info"),
+            "untyped" => (family.name, "unknown"),
+            kind => (family.name, kind),
+        };
+        let _ = writeln!(metrics, "# TYPE {} {}", name, kind);
+        let _ = writeln!(metrics, "# HELP {} {}", name, family.description.replace('"', "\\\""));
+        for (sample, labels, value) in &family.samples {
+            metrics.push_str(sample);
+            if kind == "counter" && !sample.ends_with("_total") {
+                metrics.push_str("_total");
+            }
+            if !labels.is_empty() {
+                let labels: Vec<_> = labels
+                    .iter()
+                    .map(|(key, value)| match *key {
+                        "le" => format!("le=\"{}\"", canonical(value)),
+                        key => format!("{}=\"{}\"", key, escape(value)),
+                    })
+                    .collect();
+                let _ = write!(metrics, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(metrics, " {}", number(*value));
+        }
+    }
+    metrics.push_str("# EOF\n");
+    metrics
+}
+
+/// Whether a gauge is an info, its samples all `1` and named so.
+fn is_info(
+    family: &Family<'_>,
+) -> bool {
+    family.name.ends_with("_info") && family.samples.iter().all(|(_, _, value)| *value == 1.0)
+}
+
+/// A bucket bound as OpenMetrics has it, always with a fraction: `1.0` rather than `1`.
+fn canonical(
+    bound: &str,
+) -> String {
+    match bound.parse::<f64>() {
+        Ok(bound) if bound.is_finite() && bound.fract() == 0.0 => format!("{:.1}", bound),
+        _ => bound.to_owned(),
+    }
+}
+
+/// A value as OpenMetrics has it, infinities and all.
+fn number(
+    value: f64,
+) -> String {
+    match value {
+        value if value == f64::INFINITY => "+Inf".to_owned(),
+        value if value == f64::NEG_INFINITY => "-Inf".to_owned(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::registry::{Kind, Registry};
+
+    #[test]
+    fn accepts_openmetrics() {
+        for accept in &[
+            "application/openmetrics-text",
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            "text/plain;q=0.5, Application/OpenMetrics-Text;q=0.9",
+            "application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1",
+        ] {
+            assert!(accepted(accept), "refused \"{}\"", accept);
+        }
+    }
+
+    #[test]
+    fn refuses_anything_else() {
+        for accept in &[
+            "",
+            "*/*",
+            "text/plain; version=0.0.4",
+            "application/openmetrics-text; q=0",
+            "application/openmetrics-text;q=none",
+            "application/openmetrics-textual",
+        ] {
+            assert!(!accepted(accept), "accepted \"{}\"", accept);
+        }
+    }
+
+    #[test]
+    fn encodes_the_text_format() {
+        let mut registry = Registry::default();
+        registry.metric("connections_total", Kind::Counter, "Connections \"accepted\".", 5);
+        registry.family("build_info", Kind::Gauge, "Build.", vec![(vec![("version", "0.4.0".to_owned())], 1)]);
+        registry.family("peers_info", Kind::Gauge, "Not an info.", vec![(Vec::new(), 2)]);
+        registry.histogram("trapped_seconds", "Time trapped.", vec![(1.0, 2), (2.5, 1), (f64::INFINITY, 0)], 4);
+        assert_eq!(encode(&registry.finish()), concat!(
+            "# TYPE connections counter\n",
+            "# HELP connections Connections \\\"accepted\\\".\n",
+            "connections_total 5\n",
+            "# TYPE build info\n",
+            "# HELP build Build.\n",
+            "build_info{version=\"0.4.0\"} 1\n",
+            "# TYPE peers_info gauge\n",
+            "# HELP peers_info Not an info.\n",
+            "peers_info 2\n",
+            "# TYPE trapped_seconds histogram\n",
+            "# HELP trapped_seconds Time trapped.\n",
+            "trapped_seconds_bucket{le=\"1.0\"} 2\n",
+            "trapped_seconds_bucket{le=\"2.5\"} 3\n",
+            "trapped_seconds_bucket{le=\"+Inf\"} 3\n",
+            "trapped_seconds_sum 4\n",
+            "trapped_seconds_count 3\n",
+            "# EOF\n",
+        ));
+        assert_eq!(encode(""), "# EOF\n");
+    }
+}
@@ -0,0 +1,212 @@
+use hyper::{
+    client::HttpConnector,
+    header::CONTENT_TYPE,
+    Body,
+    Client,
+    Method,
+    Request,
+    Uri,
+};
+use log::{debug, info, warn};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use super::{
+    exporters::{self, families, Family},
+    failure::Failure,
+    json::json,
+    metrics::Metrics,
+};
+use tokio::time::delay_for;
+
+/// Push the metrics of all instances to an OpenTelemetry collector, over OTLP/HTTP with the
+/// JSON encoding, instead of or besides having them scraped.
+pub(crate) struct Otlp {
+    uri:      Uri,
+    interval: Duration,
+    client:   Client<HttpConnector>,
+}
+
+impl Otlp {
+    pub(crate) fn new(
+        uri: Uri,
+        interval: Duration,
+    ) -> Self {
+        if uri.scheme_str() != Some("http") {
+            Failure::Config.exit(format!("otlp, uri: {}, error: only http is supported", uri));
+        }
+        info!("otlp, uri: {}, interval: {:?}", uri, interval);
+        Self {
+            uri,
+            interval,
+            client: Client::new(),
+        }
+    }
+
+    /// Push the metrics every interval, cumulative since startup.
+    pub(crate) async fn push(
+        self,
+        instances: Vec<(Option<String>, Arc<Metrics>)>,
+    ) {
+        let uptime = instances.first().map_or_else(Duration::default, |(_, metrics)| metrics.uptime());
+        let start = nanos(SystemTime::now() - uptime);
+        loop {
+            delay_for(self.interval).await;
+            let body = encode(&exporters::export(&instances), start, nanos(SystemTime::now()));
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(self.uri.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body));
+            let request = match request {
+                Ok(request) => request,
+                Err(err) => return warn!("otlp, error: {}", err),
+            };
+            match self.client.request(request).await {
+                Ok(response) if response.status().is_success() => debug!("otlp, status: {}", response.status()),
+                Ok(response) => warn!("otlp, status: {}", response.status()),
+                Err(err) => warn!("otlp, error: {}", err),
+            }
+        }
+    }
+}
+
+/// Nanoseconds since the epoch.
+fn nanos(
+    time: SystemTime,
+) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+/// An export of the text format as an OTLP request: counters as cumulative monotonic sums,
+/// gauges as gauges, histograms with their buckets and summaries with their count and sum.
+fn encode(
+    export: &str,
+    start: u128,
+    now: u128,
+) -> String {
+    let families = families(export);
+    let times = format!("\"startTimeUnixNano\":\"{}\",\"timeUnixNano\":\"{}\"", start, now);
+    let metrics: Vec<_> = families
+        .iter()
+        .map(|family| {
+            let data = match family.kind {
+                "counter" => format!(
+                    "\"sum\":{{\"aggregationTemporality\":2,\"isMonotonic\":true,\"dataPoints\":[{}]}}",
+                    numbers(family, &times),
+                ),
+                "histogram" => format!(
+                    "\"histogram\":{{\"aggregationTemporality\":2,\"dataPoints\":[{}]}}",
+                    histograms(family, &times),
+                ),
+                "summary" => format!("\"summary\":{{\"dataPoints\":[{}]}}", summaries(family, &times)),
+                _ => format!("\"gauge\":{{\"dataPoints\":[{}]}}", numbers(family, &times)),
+            };
+            let name = match family.kind {
+                "histogram" => family.name.strip_suffix("_bucket").unwrap_or(family.name),
+                _ => family.name,
+            };
+            format!("{{\"name\":{},\"description\":{},{}}}", json(name), json(family.description), data)
+        })
+        .collect();
+    format!(
+        concat!(
+            "{{\"resourceMetrics\":[{{",
+            "\"resource\":{{\"attributes\":[{{\"key\":\"service.name\",\"value\":{{\"stringValue\":\"tarssh\"}}}}]}},",
+            "\"scopeMetrics\":[{{\"scope\":{{\"name\":\"tarssh\",\"version\":{}}},\"metrics\":[{}]}}]",
+            "}}]}}",
+        ),
+        json(env!("CARGO_PKG_VERSION")),
+        metrics.join(","),
+    )
+}
+
+/// Labels as OTLP attributes.
+fn attributes(
+    labels: &[(&str, String)],
+) -> String {
+    let attributes: Vec<_> = labels
+        .iter()
+        .map(|(key, value)| format!("{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}", json(key), json(value)))
+        .collect();
+    format!("\"attributes\":[{}]", attributes.join(","))
+}
+
+/// A data point for every sample of a counter or gauge, but those which aren't finite.
+fn numbers(
+    family: &Family<'_>,
+    times: &str,
+) -> String {
+    let points: Vec<_> = family
+        .samples
+        .iter()
+        .filter(|(_, _, value)| value.is_finite())
+        .map(|(_, labels, value)| format!("{{{},{},\"asDouble\":{}}}", attributes(labels), times, value))
+        .collect();
+    points.join(",")
+}
+
+/// A data point for every series of a histogram, its cumulative buckets taken apart.
+fn histograms(
+    family: &Family<'_>,
+    times: &str,
+) -> String {
+    let points: Vec<_> = family.series()
+        .into_iter()
+        .map(|(labels, samples)| {
+            let mut bounds = Vec::new();
+            let mut counts = Vec::new();
+            let mut below = 0.0;
+            let mut sum = None;
+            let mut count = None;
+            for (name, sample_labels, value) in samples {
+                if name.ends_with("_sum") {
+                    sum = Some(*value);
+                } else if name.ends_with("_count") {
+                    count = Some(*value);
+                } else if let Some((_, le)) = sample_labels.iter().find(|(key, _)| *key == "le") {
+                    if let Ok(bound) = le.parse::<f64>().map(|bound| bound.is_finite().then_some(bound)) {
+                        bounds.extend(bound);
+                        counts.push(format!("\"{}\"", (value - below).max(0.0) as u64));
+                        below = *value;
+                    }
+                }
+            }
+            format!(
+                "{{{},{},\"count\":\"{}\",{}\"explicitBounds\":[{}],\"bucketCounts\":[{}]}}",
+                attributes(&labels),
+                times,
+                count.unwrap_or(below) as u64,
+                sum.map_or_else(String::new, |sum| format!("\"sum\":{},", sum)),
+                bounds.iter().map(f64::to_string).collect::<Vec<_>>().join(","),
+                counts.join(","),
+            )
+        })
+        .collect();
+    points.join(",")
+}
+
+/// A data point for every series of a summary, with its count and sum.
+fn summaries(
+    family: &Family<'_>,
+    times: &str,
+) -> String {
+    let points: Vec<_> = family.series()
+        .into_iter()
+        .map(|(labels, samples)| {
+            let value = |suffix: &str| samples
+                .iter()
+                .find(|(name, ..)| name.ends_with(suffix))
+                .map_or(0.0, |(_, _, value)| *value);
+            format!(
+                "{{{},{},\"count\":\"{}\",\"sum\":{},\"quantileValues\":[]}}",
+                attributes(&labels),
+                times,
+                value("_count") as u64,
+                value("_sum"),
+            )
+        })
+        .collect();
+    points.join(",")
+}
@@ -5,7 +5,7 @@ use std::{
   path::PathBuf,
 };
 use structopt::StructOpt;
-use super::errx;
+use super::failure::Failure;
 
 #[derive(Debug, StructOpt)]
 pub(crate) struct PrivDropConfig {
@@ -45,7 +45,7 @@ impl PrivDropConfig {
             }
 
             pd.apply()
-                .unwrap_or_else(|err| errx(exitcode::OSERR, format!("privdrop, error: {}", err)));
+                .unwrap_or_else(|err| Failure::Privdrop.exit(format!("privdrop, error: {}", err)));
 
             info!("privdrop, enabled: true");
         } else {
@@ -0,0 +1,161 @@
+use rand::seq::SliceRandom;
+use std::time::Duration;
+use super::{
+    banner::BannerPool,
+    tarpit::{ChunkSize, TarpitConfig},
+};
+
+/// A realistic-looking kind of server, bundling what it sends and how fast.
+#[derive(Debug)]
+pub(crate) struct Profile {
+    pub(crate) name:  &'static str,
+    delay:            u64,
+    chunk_size:       (usize, usize),
+    /// Sent once, before the banner.
+    preamble:         &'static [&'static str],
+    /// Sent over and over again.
+    banner:           &'static [&'static str],
+}
+
+pub(crate) static PROFILES: &[Profile] = &[
+    Profile {
+        name:       "openssh-debian",
+        delay:      10,
+        chunk_size: (8, 24),
+        preamble:   &[
+            "Linux bastion 5.10.0-21-amd64 #1 SMP Debian 5.10.162-1 (2023-01-21) x86_64",
+            "",
+        ],
+        banner:     &[
+            "The programs included with the Debian GNU/Linux system are free software;",
+            "the exact distribution terms for each program are described in the",
+            "individual files in /usr/share/doc/*/copyright.",
+            "",
+            "Debian GNU/Linux comes with ABSOLUTELY NO WARRANTY, to the extent",
+            "permitted by applicable law.",
+        ],
+    },
+    Profile {
+        name:       "dropbear",
+        delay:      5,
+        chunk_size: (4, 16),
+        preamble:   &[
+            "dropbear: loading host keys",
+        ],
+        banner:     &[
+            "Authorized access only!",
+            "All connections are logged.",
+        ],
+    },
+    Profile {
+        name:       "cisco",
+        delay:      15,
+        chunk_size: (16, 32),
+        preamble:   &[
+            "",
+            "User Access Verification",
+            "",
+        ],
+        banner:     &[
+            "**************************************************************************",
+            "* UNAUTHORIZED ACCESS TO THIS DEVICE IS PROHIBITED                       *",
+            "* You must have explicit, authorized permission to access or configure  *",
+            "* this device. All activities performed on this device are logged and   *",
+            "* monitored.                                                             *",
+            "**************************************************************************",
+        ],
+    },
+];
+
+/// Plausible lines real servers send before their banner, to pick `--decoy-lines` from.
+static DECOYS: &[&str] = &[
+    "debug1: server_input_channel_open: ctype session rchan 0 win 1048576 max 16384",
+    "debug1: input_session_request",
+    "debug1: Forked child 318637.",
+    "debug1: permanently_set_uid: 0/0",
+    "debug1: list_hostkey_types: rsa-sha2-512,rsa-sha2-256,ecdsa-sha2-nistp256,ssh-ed25519",
+    "debug1: SELinux support disabled",
+    "debug1: PAM: initializing for \"root\"",
+    "debug1: PAM: setting PAM_RHOST to \"unknown\"",
+    "debug1: monitor_read_log: child log fd closed",
+    "WARNING: This system is for the use of authorized users only.",
+    "Individuals using this computer system without authority, or in excess of",
+    "their authority, are subject to having all of their activities on this",
+    "system monitored and recorded by system personnel.",
+    "Unauthorized access to this system is forbidden and will be prosecuted by law.",
+    "By accessing this system, you agree that your actions may be monitored.",
+    "This computer system is the property of its owner. It is for authorized use only.",
+    "All activity may be monitored and reported to law enforcement.",
+    "Disconnect IMMEDIATELY if you are not an authorized user!",
+    "Last login: Tue Mar  7 09:12:44 2023 from 10.0.12.7",
+    "Welcome to Ubuntu 22.04.2 LTS (GNU/Linux 5.15.0-67-generic x86_64)",
+    " * Documentation:  https://help.ubuntu.com",
+    " * Management:     https://landscape.canonical.com",
+    " * Support:        https://ubuntu.com/advantage",
+    "  System load:  0.08               Processes:             121",
+    "  Usage of /:   41.7% of 19.20GB   Users logged in:       0",
+    "  Memory usage: 23%                IPv4 address for eth0: 10.0.12.4",
+    "0 updates can be applied immediately.",
+    "*** System restart required ***",
+    "CentOS Linux release 7.9.2009 (Core)",
+    "FreeBSD 13.1-RELEASE-p7 GENERIC",
+    "Connection from 10.0.12.7 port 52114 on 10.0.12.4 port 22 rdomain \"\"",
+    "Host key verification in progress, please wait...",
+];
+
+/// Look up a profile by name.
+pub(crate) fn find(
+    name: &str,
+) -> Result<&'static Profile, String> {
+    PROFILES
+        .iter()
+        .find(|profile| profile.name == name)
+        .ok_or_else(|| format!(
+            "unknown profile \"{}\", expected one of: {}",
+            name,
+            PROFILES.iter().map(|profile| profile.name).collect::<Vec<_>>().join(", "),
+        ))
+}
+
+fn lines(
+    lines: &[&str],
+) -> String {
+    lines
+        .iter()
+        .map(|line| format!("{}\r\n", line))
+        .collect()
+}
+
+/// A few of the decoy lines, in random order and never the same one twice.
+pub(crate) fn decoys(
+    count: usize,
+) -> Vec<u8> {
+    let decoys: Vec<_> = DECOYS
+        .choose_multiple(&mut rand::thread_rng(), count)
+        .copied()
+        .collect();
+    lines(&decoys).into_bytes()
+}
+
+impl Profile {
+    /// The pacing of this profile on top of the given one.
+    pub(crate) fn config(
+        &self,
+        config: TarpitConfig,
+    ) -> TarpitConfig {
+        TarpitConfig {
+            delay:      Duration::from_secs(self.delay),
+            // Fixed with the profile, the range is a valid one.
+            chunk_size: ChunkSize::new(self.chunk_size.0, self.chunk_size.1).expect("chunk sizes of a profile"),
+            ..config
+        }
+    }
+
+    pub(crate) fn preamble(&self) -> Vec<u8> {
+        lines(self.preamble).into_bytes()
+    }
+
+    pub(crate) fn banners(&self) -> BannerPool {
+        BannerPool::builtin(self.name, lines(self.banner))
+    }
+}
@@ -0,0 +1,165 @@
+use std::{sync::Arc, time::Duration};
+use super::metrics::Metrics;
+use tokio::time::delay_for;
+
+/// Seconds `/debug/cpu` samples the threads over unless asked for another number.
+const CPU_SECONDS: u64 = 10;
+
+/// Seconds `/debug/cpu` samples the threads over at most, as a request waits for all of them.
+const MAX_CPU_SECONDS: u64 = 60;
+
+/// What the process used so far: CPU time, memory, the heap where the allocator tells,
+/// threads and file descriptors, one `name: value` per line.
+pub(crate) fn process() -> String {
+    let mut process = String::new();
+    #[cfg(unix)]
+    if let Some(usage) = usage() {
+        let seconds = |time: libc::timeval| time.tv_sec as f64 + time.tv_usec as f64 / 1e6;
+        // Linux and the BSDs tell in KiB, macOS in bytes.
+        let max_resident = if cfg!(target_os = "macos") { usage.ru_maxrss as u64 } else { usage.ru_maxrss as u64 * 1024 };
+        process.push_str(&format!("user_cpu_seconds: {:.3}\n", seconds(usage.ru_utime)));
+        process.push_str(&format!("system_cpu_seconds: {:.3}\n", seconds(usage.ru_stime)));
+        process.push_str(&format!("max_resident_bytes: {}\n", max_resident));
+        process.push_str(&format!("voluntary_context_switches: {}\n", usage.ru_nvcsw));
+        process.push_str(&format!("involuntary_context_switches: {}\n", usage.ru_nivcsw));
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+        let field = |name: &str| status
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .map(|value| value.trim().trim_end_matches(" kB").to_owned());
+        if let Some(resident) = field("VmRSS").and_then(|kib| kib.parse::<u64>().ok()) {
+            process.push_str(&format!("resident_bytes: {}\n", resident * 1024));
+        }
+        if let Some(threads) = field("Threads") {
+            process.push_str(&format!("threads: {}\n", threads));
+        }
+        if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+            process.push_str(&format!("open_fds: {}\n", fds.count()));
+        }
+    }
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    {
+        // SAFETY: mallinfo2 only reads the statistics of the allocator.
+        let heap = unsafe { libc::mallinfo2() };
+        process.push_str(&format!("heap_in_use_bytes: {}\n", heap.uordblks));
+        process.push_str(&format!("heap_free_bytes: {}\n", heap.fordblks));
+        process.push_str(&format!("heap_mmapped_bytes: {}\n", heap.hblkhd));
+    }
+    process
+}
+
+#[cfg(unix)]
+fn usage() -> Option<libc::rusage> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage fills the struct in whole when it succeeds.
+    unsafe {
+        if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) == 0 {
+            Some(usage.assume_init())
+        } else {
+            None
+        }
+    }
+}
+
+/// The CPU time each thread used over `?seconds=`, ten by default, the busiest first, with
+/// its share of the time sampled; where there are no threads to look at, the process as a
+/// whole.
+pub(crate) async fn cpu(
+    query: Option<&str>,
+) -> Result<String, String> {
+    let mut seconds = CPU_SECONDS;
+    for pair in query.unwrap_or_default().split('&').filter(|pair| !pair.is_empty()) {
+        match pair.split_once('=') {
+            Some(("seconds", value)) => seconds = value
+                .parse()
+                .ok()
+                .filter(|seconds| (1..=MAX_CPU_SECONDS).contains(seconds))
+                .ok_or_else(|| format!("invalid seconds \"{}\", expected 1 to {}", value, MAX_CPU_SECONDS))?,
+            _ => return Err(format!("unknown parameter \"{}\", expected seconds", pair)),
+        }
+    }
+    let before = threads();
+    delay_for(Duration::from_secs(seconds)).await;
+    let after = threads();
+    let mut used: Vec<_> = after
+        .into_iter()
+        .map(|(tid, name, user, system)| {
+            let (_, _, user_before, system_before) = before
+                .iter()
+                .find(|(known, ..)| *known == tid)
+                .cloned()
+                .unwrap_or_default();
+            (tid, name, user - user_before, system - system_before)
+        })
+        .collect();
+    used.sort_by(|a, b| (b.2 + b.3).total_cmp(&(a.2 + a.3)));
+    let mut cpu = format!("# seconds: {}\n# tid name user_seconds system_seconds share\n", seconds);
+    for (tid, name, user, system) in used {
+        cpu.push_str(&format!(
+            "{} {} {:.3} {:.3} {:.1}%\n",
+            tid,
+            name,
+            user,
+            system,
+            100.0 * (user + system) / seconds as f64,
+        ));
+    }
+    Ok(cpu)
+}
+
+/// Every thread with the CPU seconds it used so far, in user and system mode.
+#[cfg(target_os = "linux")]
+fn threads() -> Vec<(u64, String, f64, f64)> {
+    // SAFETY: sysconf only reads the configuration.
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+    let tasks = match std::fs::read_dir("/proc/self/task") {
+        Ok(tasks) => tasks,
+        Err(_) => return Vec::new(),
+    };
+    tasks
+        .filter_map(|task| {
+            let task = task.ok()?;
+            let tid = task.file_name().to_str()?.parse().ok()?;
+            let stat = std::fs::read_to_string(task.path().join("stat")).ok()?;
+            // The name is in parentheses and may hold anything, spaces and parentheses too.
+            let name = stat.get(stat.find('(')? + 1..stat.rfind(')')?)?.replace(' ', "_");
+            let fields: Vec<_> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+            let user: f64 = fields.get(11)?.parse().ok()?;
+            let system: f64 = fields.get(12)?.parse().ok()?;
+            Some((tid, name, user / ticks, system / ticks))
+        })
+        .collect()
+}
+
+/// The process as a single thread, with the CPU seconds it used so far.
+#[cfg(not(target_os = "linux"))]
+fn threads() -> Vec<(u64, String, f64, f64)> {
+    #[cfg(unix)]
+    if let Some(usage) = usage() {
+        let seconds = |time: libc::timeval| time.tv_sec as f64 + time.tv_usec as f64 / 1e6;
+        return vec![(std::process::id() as u64, "process".to_owned(), seconds(usage.ru_utime), seconds(usage.ru_stime))];
+    }
+    Vec::new()
+}
+
+/// The tasks of every instance tarssh keeps an eye on: a connection each, by the shard it is
+/// kept in, and the listeners accepting them.
+pub(crate) fn tasks(
+    instances: &[(Option<String>, Arc<Metrics>)],
+) -> String {
+    let mut tasks = String::from("# instance connections listeners connections_by_shard\n");
+    for (name, metrics) in instances {
+        let shards = metrics.shards();
+        tasks.push_str(&format!(
+            "{} {} {} {}\n",
+            name.as_deref().unwrap_or("default"),
+            shards.iter().sum::<usize>(),
+            metrics.listening(),
+            shards.iter().map(usize::to_string).collect::<Vec<_>>().join(","),
+        ));
+    }
+    tasks
+}
@@ -0,0 +1,195 @@
+use std::fmt::{Display, Write};
+
+/// What a metric is, as the text format has it.
+#[derive(Clone, Copy)]
+pub(crate) enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Counter   => "counter",
+            Kind::Gauge     => "gauge",
+            Kind::Histogram => "histogram",
+            Kind::Summary   => "summary",
+        }
+    }
+}
+
+/// Metrics registered one after another, written out in the Prometheus text format as they
+/// come: every metric with its help and type, its samples labelled and the labels escaped.
+#[derive(Default)]
+pub(crate) struct Registry {
+    export: String,
+}
+
+impl Registry {
+    /// A metric of a single sample.
+    pub(crate) fn metric(
+        &mut self,
+        name: &str,
+        kind: Kind,
+        help: &str,
+        value: impl Display,
+    ) {
+        self.family(name, kind, help, Some((Vec::new(), value)));
+    }
+
+    /// A metric with a sample for every set of labels.
+    pub(crate) fn family<V: Display>(
+        &mut self,
+        name: &str,
+        kind: Kind,
+        help: &str,
+        samples: impl IntoIterator<Item = (Vec<(&'static str, String)>, V)>,
+    ) {
+        self.header(name, kind, help);
+        for (labels, value) in samples {
+            self.sample(name, &labels, value);
+        }
+        self.export.push('\n');
+    }
+
+    /// A histogram of how many observations fell into every bucket, by its upper bound, the
+    /// last of them infinite, and what they add up to; the buckets as cumulative as the text
+    /// format has them, and the count of all observations with them.
+    pub(crate) fn histogram(
+        &mut self,
+        name: &str,
+        help: &str,
+        buckets: impl IntoIterator<Item = (f64, usize)>,
+        sum: impl Display,
+    ) {
+        self.header(name, Kind::Histogram, help);
+        let mut count = 0;
+        for (bound, observations) in buckets {
+            count += observations;
+            let le = if bound.is_infinite() { "+Inf".to_owned() } else { bound.to_string() };
+            self.sample(&format!("{}_bucket", name), &[("le", le)], count);
+        }
+        self.sample(&format!("{}_sum", name), &[], sum);
+        self.sample(&format!("{}_count", name), &[], count);
+        self.export.push('\n');
+    }
+
+    /// A summary of just the count and sum of the observations, without quantiles.
+    pub(crate) fn summary(
+        &mut self,
+        name: &str,
+        help: &str,
+        count: impl Display,
+        sum: impl Display,
+    ) {
+        self.header(name, Kind::Summary, help);
+        self.sample(&format!("{}_sum", name), &[], sum);
+        self.sample(&format!("{}_count", name), &[], count);
+        self.export.push('\n');
+    }
+
+    /// The export of all metrics registered.
+    pub(crate) fn finish(self) -> String {
+        self.export
+    }
+
+    fn header(
+        &mut self,
+        name: &str,
+        kind: Kind,
+        help: &str,
+    ) {
+        let _ = writeln!(self.export, "# HELP {} {}", name, help.replace('\\', "\\\\").replace('\n', "\\n"));
+        let _ = writeln!(self.export, "# TYPE {} {}", name, kind.name());
+    }
+
+    fn sample(
+        &mut self,
+        name: &str,
+        labels: &[(&str, String)],
+        value: impl Display,
+    ) {
+        self.export.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels.iter().map(|(key, value)| format!("{}=\"{}\"", key, escape(value))).collect();
+            let _ = write!(self.export, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.export, " {}", value);
+    }
+}
+
+/// A label value escaped for the text format.
+pub(crate) fn escape(
+    value: &str,
+) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_metrics() {
+        let mut registry = Registry::default();
+        registry.metric("clients", Kind::Gauge, "Clients held.", 3);
+        registry.family("connections_total", Kind::Counter, "Connections by listener.", vec![
+            (vec![("listener", "0.0.0.0:22".to_owned())], 5),
+            (vec![("listener", "[::]:22".to_owned()), ("profile", "dropbear".to_owned())], 2),
+        ]);
+        registry.family("empty", Kind::Gauge, "No samples.", Vec::<(_, u32)>::new());
+        assert_eq!(registry.finish(), concat!(
+            "# HELP clients Clients held.\n",
+            "# TYPE clients gauge\n",
+            "clients 3\n",
+            "\n",
+            "# HELP connections_total Connections by listener.\n",
+            "# TYPE connections_total counter\n",
+            "connections_total{listener=\"0.0.0.0:22\"} 5\n",
+            "connections_total{listener=\"[::]:22\",profile=\"dropbear\"} 2\n",
+            "\n",
+            "# HELP empty No samples.\n",
+            "# TYPE empty gauge\n",
+            "\n",
+        ));
+    }
+
+    #[test]
+    fn accumulates_histograms() {
+        let mut registry = Registry::default();
+        registry.histogram("trapped_seconds", "Time trapped.", vec![(1.0, 2), (0.5e1, 0), (f64::INFINITY, 1)], 12.5);
+        registry.summary("chunk_bytes", "Bytes of chunks.", 4, 64);
+        assert_eq!(registry.finish(), concat!(
+            "# HELP trapped_seconds Time trapped.\n",
+            "# TYPE trapped_seconds histogram\n",
+            "trapped_seconds_bucket{le=\"1\"} 2\n",
+            "trapped_seconds_bucket{le=\"5\"} 2\n",
+            "trapped_seconds_bucket{le=\"+Inf\"} 3\n",
+            "trapped_seconds_sum 12.5\n",
+            "trapped_seconds_count 3\n",
+            "\n",
+            "# HELP chunk_bytes Bytes of chunks.\n",
+            "# TYPE chunk_bytes summary\n",
+            "chunk_bytes_sum 64\n",
+            "chunk_bytes_count 4\n",
+            "\n",
+        ));
+    }
+
+    #[test]
+    fn escapes_help_and_labels() {
+        let mut registry = Registry::default();
+        registry.family("banner_info", Kind::Gauge, "A \\ banner,\nof two lines.", vec![
+            (vec![("text", "say \"hi\"\nC:\\".to_owned())], 1),
+        ]);
+        assert_eq!(registry.finish(), concat!(
+            "# HELP banner_info A \\\\ banner,\\nof two lines.\n",
+            "# TYPE banner_info gauge\n",
+            "banner_info{text=\"say \\\"hi\\\"\\nC:\\\\\"} 1\n",
+            "\n",
+        ));
+        assert_eq!(escape("plain"), "plain");
+    }
+}
@@ -0,0 +1,207 @@
+#[cfg(unix)]
+use futures::{future::select, pin_mut};
+use log::{error, info, warn};
+use std::{fmt, path::PathBuf, sync::Arc};
+use super::{
+    banner::{BannerPool, MessageSpec},
+    instances,
+    listeners::Acceptors,
+    Config,
+};
+#[cfg(unix)]
+use super::handover::Handover;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+
+/// The instances running, to reload on `SIGHUP` or through the exporter: those of the config
+/// file from their sections, the command line with its message files re-read.
+pub(crate) struct Reloader {
+    config:  Option<PathBuf>,
+    running: Vec<Running>,
+}
+
+/// An instance running, and where its options come from.
+struct Running {
+    name:      Option<String>,
+    source:    Source,
+    acceptors: Arc<Acceptors>,
+}
+
+enum Source {
+    /// The options of the command line, which stay as they are, and its message files.
+    CommandLine(Vec<MessageSpec>),
+    /// A section of the config file.
+    Configured(String),
+}
+
+impl Reloader {
+    pub(crate) fn new(
+        config: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            config,
+            running: Vec::new(),
+        }
+    }
+
+    /// Reload an instance of the config file, by the name of its section.
+    pub(crate) fn configured(
+        &mut self,
+        name: String,
+        acceptors: Arc<Acceptors>,
+    ) {
+        self.running.push(Running {
+            name:   Some(name.clone()),
+            source: Source::Configured(name),
+            acceptors,
+        });
+    }
+
+    /// Reload the instance of the command line, by its message files.
+    pub(crate) fn command_line(
+        &mut self,
+        name: Option<String>,
+        messages: Vec<MessageSpec>,
+        acceptors: Arc<Acceptors>,
+    ) {
+        self.running.push(Running {
+            name,
+            source: Source::CommandLine(messages),
+            acceptors,
+        });
+    }
+
+    /// Re-read the config file and the message files, and apply their listeners, limits,
+    /// delays and timeouts to the instances running, for the connections to come; unless the
+    /// config file doesn't parse, when nothing changes.  The listeners of an instance change
+    /// all at once or not at all, told with every address bound or closed.
+    pub(crate) fn reload(&self) -> Reloaded {
+        let mut listeners = Vec::new();
+        let error = self.apply(&mut listeners).err();
+        if let Some(err) = &error {
+            error!("{}", err);
+        }
+        Reloaded { listeners, error }
+    }
+
+    fn apply(
+        &self,
+        listeners: &mut Vec<String>,
+    ) -> Result<(), String> {
+        let configured = match &self.config {
+            Some(path) => {
+                info!("reload, config: {}", path.display());
+                instances::load(path).map_err(|err| format!("reload, error: {}", err))?
+            },
+            None => {
+                info!("reload, config: none");
+                Vec::new()
+            },
+        };
+        let mut reloads = Vec::with_capacity(self.running.len());
+        for running in &self.running {
+            let (max_clients, mut defaults) = running.acceptors.defaults();
+            let section = match &running.source {
+                Source::CommandLine(messages) => {
+                    if !messages.is_empty() {
+                        defaults.banners = Arc::new(
+                            BannerPool::load(messages).map_err(|err| format!("reload, error: banner, error: {}", err))?
+                        );
+                    }
+                    reloads.push((running, max_clients, defaults, None));
+                    continue;
+                },
+                Source::Configured(name) => name,
+            };
+            match configured.iter().find(|(configured, _)| configured == section) {
+                Some((name, args)) => {
+                    let mut config = Config::of_instance(name, args.clone()).map_err(|err| format!("reload, error: {}", err))?;
+                    // A budget is refilled by a task of its own, started once: the one running stays.
+                    config.max_bandwidth = None;
+                    let mut instance = config.instance(Some(name.clone())).map_err(|err| format!("reload, instance: {}, error: {}", name, err))?;
+                    instance.defaults.config.budget = defaults.config.budget;
+                    let listen = std::mem::take(&mut instance.listen);
+                    reloads.push((running, instance.max_clients, instance.defaults, Some(listen)));
+                },
+                None => warn!("reload, instance: {}, error: removed instances take a restart", section),
+            }
+        }
+        for (name, _) in &configured {
+            if !self.running.iter().any(|running| running.name.as_ref() == Some(name)) {
+                warn!("reload, instance: {}, error: new instances take a restart", name);
+            }
+        }
+        for (running, max_clients, defaults, listen) in reloads {
+            let instance = running.name.as_deref().unwrap_or("default");
+            info!(
+                "reload, instance: {}, max_clients: {}, delay: {}s, timeout: {}s, banners: {}",
+                instance,
+                max_clients,
+                defaults.config.delay.as_secs(),
+                defaults.config.timeout.as_secs(),
+                defaults.banners.len(),
+            );
+            running.acceptors
+                .reconfigure(max_clients, defaults)
+                .map_err(|err| format!("reload, instance: {}, error: banner, error: {}", instance, err))?;
+            // Listeners bound from now on already accept with what was just reloaded.
+            if let Some(listen) = listen {
+                info!("reload, instance: {}, listen: {}", instance, listen.len());
+                let updated = running.acceptors.update(listen);
+                let reports = match &updated {
+                    Ok(reports) | Err(reports) => reports,
+                };
+                for report in reports {
+                    report.log(&format!("reload, instance: {}", instance));
+                    listeners.push(format!("instance: {}, {}", instance, report));
+                }
+                if let Err(reports) = updated {
+                    return Err(format!(
+                        "reload, instance: {}, error: listeners, failures: {}",
+                        instance,
+                        reports.iter().filter(|report| report.failed()).count(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a reload did to the listeners, an address a line, and what failed, if anything did.
+pub(crate) struct Reloaded {
+    pub(crate) listeners: Vec<String>,
+    pub(crate) error:     Option<String>,
+}
+
+impl fmt::Display for Reloaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for listener in &self.listeners {
+            writeln!(f, "{}", listener)?;
+        }
+        match &self.error {
+            Some(err) => writeln!(f, "error: {}", err),
+            None => writeln!(f, "reloaded"),
+        }
+    }
+}
+
+/// Reload whenever `SIGHUP` asks to, until the listeners are handed over.
+#[cfg(unix)]
+pub(crate) async fn serve(
+    reloader: Arc<Reloader>,
+    handover: &'static Handover,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => return error!("reload, error: {}", err),
+    };
+    let reloads = async {
+        while hangup.recv().await.is_some() {
+            let _ = reloader.reload();
+        }
+    };
+    let stopped = handover.stopped();
+    pin_mut!(reloads, stopped);
+    select(reloads, stopped).await;
+}
@@ -1,65 +1,227 @@
-use exitcode;
 use futures::stream::StreamExt;
 use futures_util::future::FutureExt;
 use log::info;
 use std::{
+    fmt,
+    future::Future,
     ops::{Deref, DerefMut},
+    str::FromStr,
     sync::Arc,
     time::Instant,
 };
-use super::{errx, metrics::Metrics};
+#[cfg(unix)]
+use std::time::Duration;
+use super::{failure::Failure, metrics::Metrics};
+use tokio::runtime::Handle;
 
+#[cfg(unix)]
+use super::handover::Handover;
 #[cfg(unix)]
 use tokio::signal::unix::{signal, SignalKind};
 
+/// Which flavour of tokio scheduler to run on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Scheduler {
+    /// Everything on the main thread.
+    Basic,
+    /// A pool of worker threads.
+    Threaded,
+}
+
+impl FromStr for Scheduler {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "basic"     => Ok(Self::Basic),
+            "threaded"  => Ok(Self::Threaded),
+            _ => Err(format!("unknown scheduler \"{}\", expected basic or threaded", name)),
+        }
+    }
+}
+
+impl fmt::Display for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Basic     => "basic",
+            Self::Threaded  => "threaded",
+        })
+    }
+}
+
+/// Number of worker threads of the threaded scheduler.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Threads {
+    /// One per core.
+    Auto,
+    /// A fixed number.
+    Count(usize),
+}
+
+impl FromStr for Threads {
+    type Err = String;
+
+    fn from_str(threads: &str) -> Result<Self, Self::Err> {
+        if threads == "auto" {
+            Ok(Self::Auto)
+        } else {
+            threads
+                .parse::<usize>()
+                .map(Self::Count)
+                .map_err(|err| format!("invalid thread count \"{}\": {}", threads, err))
+        }
+    }
+}
+
 pub(crate) struct Runtime {
-    runtime: tokio::runtime::Runtime,
-    startup: Instant,
+    runtime:    tokio::runtime::Runtime,
+    startup:    Instant,
+    // Only reported by the exporter.
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    scheduler:  Scheduler,
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    cores:      usize,
+    #[cfg_attr(not(feature = "exporters"), allow(dead_code))]
+    workers:    usize,
+    acceptor:   Option<Handle>,
 }
 
 impl Runtime {
+    /// Set up the scheduler; giving a thread count implies the threaded one.
     pub(crate) fn new(
-        threads: Option<Option<usize>>,
+        scheduler: Option<Scheduler>,
+        threads: Option<Threads>,
     ) -> Self {
-        let mut runtime = tokio::runtime::Builder::new();
-        let scheduler = if let Some(threaded) = threads {
-            runtime.threaded_scheduler();
-            if let Some(threads) = threaded {
-                let threads = threads.min(512).max(1);
-                runtime.core_threads(threads);
-                format!("threaded, threads: {}", threads)
-            } else {
-                "threaded".to_owned()
-            }
+        let scheduler = scheduler.unwrap_or(if threads.is_some() {
+            Scheduler::Threaded
         } else {
-            runtime.basic_scheduler();
-            "basic".to_owned()
+            Scheduler::Basic
+        });
+        let cores = num_cpus::get();
+
+        let mut runtime = tokio::runtime::Builder::new();
+        let workers = match scheduler {
+            Scheduler::Basic => {
+                runtime.basic_scheduler();
+                1
+            },
+            Scheduler::Threaded => {
+                let workers = match threads.unwrap_or(Threads::Auto) {
+                    Threads::Auto => cores,
+                    Threads::Count(threads) => threads.clamp(1, 512),
+                };
+                runtime.threaded_scheduler().core_threads(workers);
+                workers
+            },
         };
 
         info!(
-            "init, version: {}, scheduler: {}",
+            "init, version: {}, scheduler: {}, cores: {}, workers: {}",
             env!("CARGO_PKG_VERSION"),
             scheduler,
+            cores,
+            workers,
         );
 
         let runtime = runtime
             .enable_all()
             .build()
-            .unwrap_or_else(|err| errx(exitcode::UNAVAILABLE, format!("tokio, error: {:?}", err)));
+            .unwrap_or_else(|err| Failure::Runtime.exit(format!("tokio, error: {:?}", err)));
+
+        // With many workers busy dripping, accepting still deserves a thread of its own.
+        let acceptor = if scheduler == Scheduler::Threaded {
+            Some(Self::acceptor_thread())
+        } else {
+            None
+        };
 
         Self {
             runtime,
-            startup: Instant::now()
+            startup: Instant::now(),
+            scheduler,
+            cores,
+            workers,
+            acceptor,
         }
     }
 
+    /// Run a basic scheduler in a thread of its own, forever.
+    fn acceptor_thread() -> Handle {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("acceptor".to_owned())
+            .spawn(move || {
+                let mut runtime = tokio::runtime::Builder::new()
+                    .basic_scheduler()
+                    .enable_all()
+                    .build()
+                    .unwrap_or_else(|err| Failure::Runtime.exit(format!("tokio, acceptor, error: {:?}", err)));
+                let _ = sender.send(runtime.handle().clone());
+                runtime.block_on(futures::future::pending::<()>());
+            })
+            .unwrap_or_else(|err| Failure::Runtime.exit(format!("thread, acceptor, error: {}", err)));
+        receiver
+            .recv()
+            .unwrap_or_else(|err| Failure::Runtime.exit(format!("tokio, acceptor, error: {}", err)))
+    }
+
+    /// Run a future on the executor of the accept loops, which also drives the sockets it creates.
+    pub(crate) fn block_on_acceptor<F: Future>(
+        &mut self,
+        future: F,
+    ) -> F::Output {
+        match &self.acceptor {
+            Some(acceptor) => acceptor.block_on(future),
+            None => self.runtime.block_on(future),
+        }
+    }
+
+    /// Spawn a task on the executor of the accept loops.
+    pub(crate) fn spawn_acceptor<F>(
+        &self,
+        future: F,
+    )
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match &self.acceptor {
+            Some(acceptor) => acceptor.spawn(future),
+            None => self.runtime.spawn(future),
+        };
+    }
+
+    /// The executor of the accept loops, to spawn on later.
+    pub(crate) fn acceptor(&self) -> Handle {
+        self.acceptor.clone().unwrap_or_else(|| self.runtime.handle().clone())
+    }
+
+    #[cfg(feature = "exporters")]
+    pub(crate) fn scheduler(&self) -> Scheduler {
+        self.scheduler
+    }
+
+    #[cfg(feature = "exporters")]
+    pub(crate) fn cores(&self) -> usize {
+        self.cores
+    }
+
+    #[cfg(feature = "exporters")]
+    pub(crate) fn workers(&self) -> usize {
+        self.workers
+    }
+
     pub(crate) fn start(&self) -> Instant {
         self.startup
     }
 
     pub(crate) fn wait(
         &mut self,
-        metrics: Arc<Metrics>,
+        metrics: &[Arc<Metrics>],
+        #[cfg(unix)]
+        handover: &'static Handover,
+        #[cfg(unix)]
+        drain_deadline: Option<Duration>,
     ) {
         self.block_on(
             async {
@@ -67,7 +229,7 @@ impl Runtime {
 
                 #[cfg(unix)]
                 let mut term = signal(SignalKind::terminate()).unwrap_or_else(|error| {
-                    errx(exitcode::UNAVAILABLE, format!("signal(), error: {}", error))
+                    Failure::Runtime.exit(format!("signal(), error: {}", error))
                 });
 
                 #[cfg(unix)]
@@ -76,6 +238,13 @@ impl Runtime {
                     term.recv().into_stream().map(|_| "terminated")
                 );
 
+                // Once handed over or drained, the clients left are all there is to wait for.
+                #[cfg(unix)]
+                let interrupt = futures_util::stream::select(
+                    interrupt,
+                    handover.serve(metrics, drain_deadline).into_stream()
+                );
+
                 if let Some(signal) = interrupt.boxed().next().await {
                     info!("{}", signal);
                 };
@@ -85,7 +254,7 @@ impl Runtime {
         info!(
             "shutdown, uptime: {:.2?}, clients: {}",
             self.startup.elapsed(),
-            metrics.connections(),
+            metrics.iter().map(|metrics| metrics.connections()).sum::<usize>(),
         )
     }
 }
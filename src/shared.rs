@@ -0,0 +1,12 @@
+use super::{bans::Bans, geoip::GeoIp};
+
+/// What the instances share for the whole process, read once before any chroot and dropped
+/// along with the last task using it.
+pub(crate) struct Shared {
+    /// Country database, to tell where peers are.
+    pub(crate) geoip:  Option<GeoIp>,
+    /// Autonomous system database, to tell whose networks peers are in.
+    pub(crate) asn_db: Option<GeoIp>,
+    /// Networks to hang up on.
+    pub(crate) bans:   Bans,
+}
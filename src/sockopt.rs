@@ -0,0 +1,40 @@
+use std::{io, os::unix::io::RawFd};
+
+/// Set an option of a socket that neither the standard library nor socket2 has a setter for.
+///
+/// # Safety
+///
+/// The value has to be of the type the kernel expects for the option, along with anything it
+/// points to for the length of the call.
+#[cfg_attr(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos")), allow(dead_code))]
+pub(crate) unsafe fn set_option<T>(
+    fd:    RawFd,
+    level: libc::c_int,
+    name:  libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    let result = libc::setsockopt(
+        fd,
+        level,
+        name,
+        value as *const T as *const libc::c_void,
+        std::mem::size_of_val(value) as libc::socklen_t,
+    );
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Set an int option of a socket, which most options are.
+#[cfg_attr(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos")), allow(dead_code))]
+pub(crate) fn set_int_option(
+    fd:    RawFd,
+    level: libc::c_int,
+    name:  libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: the option is an int.
+    unsafe { set_option(fd, level, name, &value) }
+}
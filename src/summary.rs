@@ -0,0 +1,71 @@
+use log::info;
+use std::{fmt, sync::Arc, time::Duration};
+use super::metrics::{Metrics, Totals};
+use tokio::time::delay_for;
+
+/// How a tarssh is doing, all instances together, as `tarssh stats` tells.
+#[cfg_attr(not(any(unix, feature = "control")), allow(dead_code))]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Stats {
+    pub(crate) uptime:  Duration,
+    pub(crate) clients: usize,
+    /// How long all clients were trapped, the current ones so far.
+    pub(crate) trapped: Duration,
+    /// How long the longest connection lasted.
+    pub(crate) record:  Duration,
+}
+
+impl Stats {
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn of<'a>(
+        instances: impl IntoIterator<Item = &'a Arc<Metrics>>,
+    ) -> Self {
+        instances.into_iter().fold(Self::default(), |stats, metrics| Self {
+            uptime:  stats.uptime.max(metrics.uptime()),
+            clients: stats.clients + metrics.connections(),
+            trapped: stats.trapped + metrics.wasted(),
+            record:  stats.record.max(metrics.record()),
+        })
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let human = |duration: Duration| humantime::format_duration(Duration::from_secs(duration.as_secs()));
+        writeln!(f, "uptime: {}", human(self.uptime))?;
+        writeln!(f, "clients: {}", self.clients)?;
+        writeln!(f, "trapped: {}", human(self.trapped))?;
+        writeln!(f, "record: {}", human(self.record))
+    }
+}
+
+/// Log a line for every instance every interval, of its clients now and its connections since
+/// the last one: how many were accepted, turned away and closed, and how long those closed
+/// were trapped on average.
+pub(crate) async fn log(
+    interval: Duration,
+    instances: Vec<(Option<String>, Arc<Metrics>)>,
+) {
+    let mut last: Vec<Totals> = instances.iter().map(|(_, metrics)| metrics.totals()).collect();
+    loop {
+        delay_for(interval).await;
+        for ((name, metrics), last) in instances.iter().zip(last.iter_mut()) {
+            let totals = metrics.totals();
+            let closed = totals.closed - last.closed;
+            let mean_trapped = match closed {
+                0 => 0.0,
+                closed => (totals.trapped - last.trapped) as f64 / closed as f64,
+            };
+            info!(
+                "summary, {}clients: {}, accepted: {}, rejected: {}, closed: {}, mean_trapped: {:.1}s",
+                name.as_ref().map_or_else(String::new, |name| format!("instance: {}, ", name)),
+                metrics.connections(),
+                totals.accepted - last.accepted,
+                totals.rejected - last.rejected,
+                closed,
+                mean_trapped,
+            );
+            *last = totals;
+        }
+    }
+}
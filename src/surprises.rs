@@ -0,0 +1,111 @@
+use log::debug;
+use rand::{seq::SliceRandom, Rng};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use super::sync::lock;
+
+/// Chance of a surprise instead of another round of the banner, one in so many.
+const CHANCE: u32 = 256;
+
+/// Peers remembered to hold back surprises they got recently; when full, nobody is surprised
+/// until some of them cooled down.
+const MAX_PEERS: usize = 65536;
+
+/// Some fun content, now and then sent instead of the banner.
+#[derive(Debug)]
+pub(crate) struct Surprise {
+    pub(crate) name:    &'static str,
+    pub(crate) content: &'static [u8],
+    /// Relative to the weights of the other surprises.
+    weight:             u32,
+    /// Time before the same peer gets this surprise again.
+    cooldown:           Duration,
+}
+
+pub(crate) static SURPRISES: &[Surprise] = &[
+    Surprise {
+        name:       "meow",
+        content:    b"Meow Meow Meow, but anymeow:\r\n",
+        weight:     4,
+        cooldown:   Duration::from_secs(60),
+    },
+    Surprise {
+        name:       "grue",
+        content:    b"It is pitch black. You are likely to be eaten by a grue.\r\n",
+        weight:     2,
+        cooldown:   Duration::from_secs(3600),
+    },
+    Surprise {
+        name:       "xyzzy",
+        content:    b"A hollow voice says \"Fool.\"\r\n",
+        weight:     1,
+        cooldown:   Duration::from_secs(24 * 3600),
+    },
+];
+
+/// All the surprises there are, and who got which one when.
+#[derive(Debug)]
+pub(crate) struct Surprises {
+    surprises:  Vec<&'static Surprise>,
+    recent:     Mutex<HashMap<(IpAddr, &'static str), Instant>>,
+}
+
+impl Surprises {
+    /// The built-in surprises.
+    pub(crate) fn builtin() -> Arc<Self> {
+        let mut surprises = Self {
+            surprises:  Vec::new(),
+            recent:     Mutex::default(),
+        };
+        for surprise in SURPRISES {
+            surprises.register(surprise);
+        }
+        Arc::new(surprises)
+    }
+
+    pub(crate) fn register(
+        &mut self,
+        surprise: &'static Surprise,
+    ) {
+        self.surprises.push(surprise);
+    }
+
+    /// Maybe a surprise for a peer, one it didn't get recently.
+    pub(crate) fn pick(
+        &self,
+        peer: IpAddr,
+    ) -> Option<&'static Surprise> {
+        let mut rng = rand::thread_rng();
+        if !rng.gen_ratio(1, CHANCE) {
+            return None;
+        }
+        let mut recent = lock(&self.recent);
+        let now = Instant::now();
+        if recent.len() >= MAX_PEERS {
+            recent.retain(|(_, name), &mut at| {
+                self.surprises
+                    .iter()
+                    .find(|surprise| surprise.name == *name)
+                    .is_some_and(|surprise| now.duration_since(at) < surprise.cooldown)
+            });
+            if recent.len() >= MAX_PEERS {
+                return None;
+            }
+        }
+        let ready: Vec<_> = self.surprises
+            .iter()
+            .filter(|surprise| recent
+                .get(&(peer, surprise.name))
+                .is_none_or(|&at| now.duration_since(at) >= surprise.cooldown)
+            )
+            .collect();
+        let surprise = **ready.choose_weighted(&mut rng, |surprise| surprise.weight).ok()?;
+        recent.insert((peer, surprise.name), now);
+        debug!("surprise, peer: {}, surprise: {}", peer, surprise.name);
+        Some(surprise)
+    }
+}
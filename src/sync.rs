@@ -0,0 +1,13 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// Lock a mutex, even one a panicking task left poisoned: the state behind every mutex of
+/// tarssh stays consistent between statements, and one client gone wrong is no reason to stop
+/// serving the others.
+pub(crate) fn lock<T>(
+    mutex: &Mutex<T>,
+) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
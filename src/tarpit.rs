@@ -1,142 +1,740 @@
-use log::info;
+use log::{debug, info};
 use std::{
     borrow::Cow,
+    collections::VecDeque,
+    io::ErrorKind,
     net::SocketAddr,
-    sync::Arc,
-    time::Duration,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use log::warn;
-use tokio::io::AsyncWriteExt;
+use futures::{
+    future::{select, Either},
+    pin_mut,
+};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot;
 use tokio::time::{delay_for, timeout};
 
-use super::metrics::{Metrics, Token};
+#[cfg(feature = "alerts")]
+use super::alerts::Inspection;
+use super::banner::Banner;
+use super::budget::Budget;
+use super::metrics::{Disconnected, Event, Metrics, MetricsError, Token};
+use super::surprises::Surprises;
+use super::sync::lock;
+use super::transport::Transport;
+
+/// Longest identification string a client may send, as per RFC 4253.
+const MAX_VERSION: usize = 255;
+
+/// Bytes of the input of a connection waiting to be echoed, the rest is dropped.
+const MAX_ECHO: usize = 1024;
+
+/// How every tarpitted connection is paced.
+#[derive(Clone, Debug)]
+pub(crate) struct TarpitConfig {
+    /// Time between chunks.
+    pub(crate) delay:         Duration,
+    /// Time a single chunk may take to be written.
+    pub(crate) timeout:       Duration,
+    /// How often a chunk is retried after a transient write error.
+    pub(crate) write_retries: u32,
+    /// Time after which a connection is dropped, however patient the client is.
+    pub(crate) max_session:   Option<Duration>,
+    /// Let the delay of a connection grow with its age.
+    pub(crate) backoff:       Option<Backoff>,
+    /// Delays by the age of a connection, instead of the fixed one.
+    pub(crate) tiers:         Option<Arc<Tiers>>,
+    /// Number of bytes written at once.
+    pub(crate) chunk_size:    ChunkSize,
+    /// Bytes per second shared by all connections.
+    pub(crate) budget:        Option<Arc<Budget>>,
+    /// Bytes per second of a single connection.
+    pub(crate) bandwidth:     Option<usize>,
+    /// How to let go of a client.
+    pub(crate) disconnect:    Disconnect,
+    /// Time to wait for the client to identify itself as SSH before sending anything at all.
+    pub(crate) strict:        Option<Duration>,
+    /// Fun content to send now and then, instead of the banner.
+    pub(crate) surprises:     Arc<Surprises>,
+    /// Probe idle connections, to find peers gone for good.
+    pub(crate) keepalive:     Option<Keepalive>,
+}
+
+/// What dropping a client looks like from its end.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Disconnect {
+    /// A clean close.
+    Fin,
+    /// An immediate reset, with `SO_LINGER` set to 0.
+    Rst,
+    /// Stop sending but keep the connection open, until the client closes it or the timeout.
+    Linger,
+}
+
+impl FromStr for Disconnect {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "fin"       => Ok(Self::Fin),
+            "rst"       => Ok(Self::Rst),
+            "linger"    => Ok(Self::Linger),
+            _ => Err(format!("unknown disconnect \"{}\", expected fin, rst or linger", name)),
+        }
+    }
+}
+
+/// Size of the chunks, either fixed (`16`) or drawn at random from a range (`8-32`).
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ChunkSize {
+    min: usize,
+    max: usize,
+}
+
+impl ChunkSize {
+    /// Chunks of `min` to `max` bytes, unless that range is one of empty chunks or runs
+    /// backward.
+    pub(crate) fn new(
+        min: usize,
+        max: usize,
+    ) -> Result<Self, String> {
+        if min == 0 || min > max {
+            return Err(format!("invalid chunk size range {}-{}", min, max));
+        }
+        Ok(Self { min, max })
+    }
+
+    pub(crate) fn max(&self) -> usize {
+        self.max
+    }
+
+    fn pick(&self) -> usize {
+        if self.min == self.max {
+            self.min
+        } else {
+            rand::thread_rng().gen_range(self.min, self.max + 1)
+        }
+    }
+
+    /// Cut the banner into chunks of (possibly random) size, never in the middle of a
+    /// UTF-8 code point or a line break.
+    pub(crate) fn chunks<'a>(
+        self,
+        banner: &'a [u8],
+    ) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let mut rest = banner;
+        std::iter::from_fn(move || {
+            if rest.is_empty() {
+                None
+            } else {
+                let end = self.pick().min(rest.len());
+                let inside = |cut: usize| rest[cut] & 0xc0 == 0x80 || rest[cut - 1..=cut] == *b"\r\n";
+                // Back off to the start of the code point (or line break), unless the chunk
+                // can't even hold that, in which case it gets all of it.
+                let mut cut = end;
+                while cut > 0 && cut < rest.len() && inside(cut) {
+                    cut -= 1;
+                }
+                if cut == 0 {
+                    cut = end;
+                    while cut < rest.len() && inside(cut) {
+                        cut += 1;
+                    }
+                }
+                let (chunk, tail) = rest.split_at(cut);
+                rest = tail;
+                Some(chunk)
+            }
+        })
+    }
+}
+
+impl FromStr for ChunkSize {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let parse = |size: &str| size
+            .trim()
+            .parse::<usize>()
+            .map_err(|err| format!("invalid chunk size \"{}\": {}", size, err));
+        let (min, max) = match spec.find('-') {
+            Some(index) => (parse(&spec[..index])?, parse(&spec[index + 1..])?),
+            None => {
+                let size = parse(spec)?;
+                (size, size)
+            },
+        };
+        Self::new(min, max).map_err(|_| format!("invalid chunk size range \"{}\"", spec))
+    }
+}
+
+/// What a connection gets to read: the preamble once, then the banner over and over again.
+pub(crate) struct Script {
+    pub(crate) preamble:  Arc<Vec<u8>>,
+    pub(crate) banner:    Banner,
+    /// Input of the client to send back to it, between the chunks of the banner.
+    pub(crate) echo:      Option<Arc<Echo>>,
+    /// Whether the connection is sampled for inspection.
+    #[cfg_attr(not(feature = "alerts"), allow(dead_code))]
+    pub(crate) sampled:   bool,
+}
+
+/// Whatever a client sent which wasn't sent back to it yet, up to `MAX_ECHO` bytes.
+#[derive(Default)]
+pub(crate) struct Echo {
+    pending: Mutex<VecDeque<u8>>,
+}
+
+impl Echo {
+    /// Keep what the client sent, as far as there is room.
+    pub(crate) fn push(
+        &self,
+        input: &[u8],
+    ) {
+        let mut pending = lock(&self.pending);
+        let room = MAX_ECHO.saturating_sub(pending.len());
+        pending.extend(&input[..input.len().min(room)]);
+    }
+
+    /// The next line to send back, made of at most so many bytes of input, if there is any.
+    ///
+    /// Lines are quoted with `> ` so they never begin with "SSH-", and anything but printable
+    /// ASCII is replaced by a dot.
+    pub(crate) fn line(
+        &self,
+        max: usize,
+    ) -> Option<Vec<u8>> {
+        let mut pending = lock(&self.pending);
+        if pending.is_empty() {
+            return None;
+        }
+        let end = pending
+            .iter()
+            .take(max)
+            .position(|&byte| byte == b'\n')
+            .map_or(pending.len().min(max), |end| end + 1);
+        let mut line = b"> ".to_vec();
+        line.extend(
+            pending
+                .drain(..end)
+                .filter(|&byte| byte != b'\r' && byte != b'\n')
+                .map(|byte| if byte == b' ' || byte.is_ascii_graphic() { byte } else { b'.' }),
+        );
+        line.extend_from_slice(b"\r\n");
+        Some(line)
+    }
+}
+
+/// Doubling of the delay, every so often, up to a cap.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Backoff {
+    /// Age of a connection after which its delay doubles.
+    pub(crate) every:     Duration,
+    /// The delay will never grow beyond this.
+    pub(crate) max_delay: Duration,
+}
+
+/// TCP keepalive probes, so that peers gone behind a NAT are found and dropped instead of
+/// counting as ever so long sessions.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Keepalive {
+    /// Time a connection is idle before the first probe.
+    pub(crate) idle:     Duration,
+    /// Time between unanswered probes, the default of the system if `None`.
+    pub(crate) interval: Option<Duration>,
+    /// Unanswered probes after which the peer is gone, the default of the system if `None`.
+    pub(crate) count:    Option<u32>,
+}
+
+impl Keepalive {
+    /// Turn keepalive on for a connection.
+    pub(crate) fn apply(
+        &self,
+        sock: &tokio::net::TcpStream,
+    ) -> std::io::Result<()> {
+        sock.set_keepalive(Some(self.idle))?;
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos"))]
+        {
+            use std::os::unix::io::AsRawFd;
+            use super::sockopt::set_int_option;
+            if let Some(interval) = self.interval {
+                set_int_option(sock.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, interval.as_secs().clamp(1, i32::MAX as u64) as libc::c_int)?;
+            }
+            if let Some(count) = self.count {
+                set_int_option(sock.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_KEEPCNT, count.min(i32::MAX as u32) as libc::c_int)?;
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos")))]
+        if self.interval.is_some() || self.count.is_some() {
+            return Err(std::io::Error::new(ErrorKind::Other, "keepalive intervals and counts are not supported here"));
+        }
+        Ok(())
+    }
+}
+
+/// Cadence by age of a connection, e.g. `5min=2s,1h=10s,60s`: every tier holds until the
+/// connection is that old, and the last delay without an age applies afterwards.
+#[derive(Debug)]
+pub(crate) struct Tiers {
+    /// Age up to which a delay applies, ascending.
+    tiers:  Vec<(Duration, Duration)>,
+    after:  Duration,
+}
+
+impl Tiers {
+    fn delay(
+        &self,
+        age: Duration,
+    ) -> Duration {
+        self.tiers
+            .iter()
+            .find(|(until, _)| age < *until)
+            .map_or(self.after, |(_, delay)| *delay)
+    }
+}
+
+impl FromStr for Tiers {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let duration = |value: &str| humantime::parse_duration(value.trim())
+            .map_err(|err| format!("invalid duration \"{}\": {}", value, err));
+        let mut tiers: Vec<(Duration, Duration)> = Vec::new();
+        let mut after = None;
+        for tier in spec.split(',') {
+            if after.is_some() {
+                return Err(format!("tier \"{}\" after the last delay in \"{}\"", tier, spec));
+            }
+            match tier.find('=') {
+                Some(index) => {
+                    let until = duration(&tier[..index])?;
+                    if tiers.last().is_some_and(|(last, _)| *last >= until) {
+                        return Err(format!("ages of tiers must ascend in \"{}\"", spec));
+                    }
+                    tiers.push((until, duration(&tier[index + 1..])?));
+                },
+                None => after = Some(duration(tier)?),
+            }
+        }
+        let after = after
+            .or_else(|| tiers.last().map(|(_, delay)| *delay))
+            .ok_or_else(|| format!("no tiers in \"{}\"", spec))?;
+        Ok(Self { tiers, after })
+    }
+}
+
+impl TarpitConfig {
+    /// Delay between chunks for a connection of the given age.
+    fn delay_after(
+        &self,
+        age: Duration,
+    ) -> Duration {
+        let delay = self.tiers.as_ref().map_or(self.delay, |tiers| tiers.delay(age));
+        match self.backoff {
+            Some(backoff) if backoff.every > Duration::from_secs(0) => {
+                let doublings = (age.as_secs() / backoff.every.as_secs().max(1)).min(31) as u32;
+                delay
+                    .checked_mul(1 << doublings)
+                    .unwrap_or(backoff.max_delay)
+                    .min(backoff.max_delay)
+                    .max(delay)
+            },
+            _ => delay,
+        }
+    }
+}
+
+/// Whether a write error is worth another try instead of giving up on the client.
+fn is_transient(
+    kind: ErrorKind,
+) -> bool {
+    matches!(
+        kind,
+        ErrorKind::WouldBlock
+        | ErrorKind::Interrupted
+        | ErrorKind::TimedOut
+    )
+}
+
+async fn write_chunk(
+    sock: &mut (impl AsyncWrite + Unpin),
+    config: &TarpitConfig,
+    metrics: &Arc<Metrics>,
+    chunk: &[u8],
+) -> std::io::Result<()> {
+    let mut written = 0;
+    let mut retries = 0;
+    while written < chunk.len() {
+        match sock.write(&chunk[written..]).await {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(count) => written += count,
+            Err(error) if retries < config.write_retries && is_transient(error.kind()) => {
+                retries += 1;
+                metrics.retried_write();
+                debug!("retry, error: \"{}\", retries: {}", error, retries);
+            },
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// The chunk with the lines said by an operator, taken only then, right after its first line
+/// ending, so as not to land amid a line; without one, they wait for a chunk with.
+fn interjected(
+    chunk: &[u8],
+    said: impl FnOnce() -> Vec<u8>,
+) -> Cow<'_, [u8]> {
+    let end = match chunk.iter().position(|&byte| byte == b'\n') {
+        Some(end) => end,
+        None => return Cow::Borrowed(chunk),
+    };
+    let said = said();
+    if said.is_empty() {
+        return Cow::Borrowed(chunk);
+    }
+    Cow::Owned([&chunk[..=end], &said[..], &chunk[end + 1..]].concat())
+}
 
 async fn send_chunk(
-    sock: &mut tokio::net::TcpStream,
-    delay: &Duration,
-    time_out: &Duration,
+    sock: &mut (impl AsyncWrite + Unpin),
+    config: &TarpitConfig,
+    start: Instant,
+    fresh: bool,
     token: Token,
     metrics: &Arc<Metrics>,
     chunk: &[u8],
-) -> Result<Token, (usize, u64, Cow<'static, str>)> {
-    delay_for(*delay).await;
+) -> Result<Token, (Disconnected, Cow<'static, str>)> {
+    let delay = metrics.retuned(&token).unwrap_or_else(|| config.delay_after(start.elapsed()));
+    // Only one chunk is sent per delay, so stretching it keeps the connection under its cap.
+    let delay = config.bandwidth.map_or(delay, |bandwidth| {
+        delay.max(Duration::from_secs_f64(chunk.len() as f64 / bandwidth as f64))
+    });
+    if let Some(max_session) = config.max_session {
+        let remaining = (start + max_session).saturating_duration_since(Instant::now());
+        if remaining <= delay {
+            delay_for(remaining).await;
+            metrics.reached_max_session();
+            return Err(drop_client(metrics, token, Cow::Borrowed("max session")));
+        }
+    }
+    delay_for(delay).await;
+    if metrics.shedding(&token) {
+        return Err(drop_client(metrics, token, Cow::Borrowed("shed")));
+    }
+    let chunk = interjected(chunk, || metrics.said(&token));
+    if let Some(budget) = &config.budget {
+        budget.spend(chunk.len(), fresh).await;
+    }
     match timeout(
-        *time_out,
-        sock.write_all(chunk)
+        config.timeout,
+        write_chunk(sock, config, metrics, &chunk),
     )
     .await {
-        Ok(Ok(_)) => if let Err(error) = metrics.sent_chunk(&token) {
-            Err(match metrics.disconnect(token) {
-                Ok((connections, connection_time)) => (
-                    connections,
-                    connection_time,
-                    Cow::Borrowed(error),
-                ),
-                Err(failure) => (
-                    0usize,
-                    0u64,
-                    Cow::Owned(format!("{}\", \"{}", error, failure)),
-                ),
-            })
-        } else {
-            Ok(token)
-        },
-        Err(error) => {
-          Err(match metrics.disconnect(token) {
-              Ok((connections, connection_time)) => (
-                  connections,
-                  connection_time,
-                  Cow::Borrowed("time out"),
-              ),
-              Err(failure) => (
-                  0usize,
-                  0u64,
-                  Cow::Owned(format!("{}\", \"{}", error, failure)),
-              ),
-          })
+        // The client isn't known anymore, so there is nothing left to disconnect either.
+        Ok(Ok(_)) => match metrics.sent_chunk(&token, chunk.len()) {
+            Ok(()) => Ok(token),
+            Err(error) => Err((Disconnected::default(), Cow::Owned(error.to_string()))),
         },
-        Ok(Err(error)) => {
-          Err(match metrics.disconnect(token) {
-              Ok((connections, connection_time)) => (
-                  connections,
-                  connection_time,
-                  Cow::Owned(format!("{}", error)),
-              ),
-              Err(failure) => (
-                  0usize,
-                  0u64,
-                  Cow::Owned(format!("{}\", \"{}", error, failure)),
-              ),
-          })
+        Err(_) => Err(drop_client(metrics, token, Cow::Borrowed("time out"))),
+        Ok(Err(error)) => Err(drop_client(metrics, token, Cow::Owned(error.to_string()))),
+    }
+}
+
+/// Forget about a client, for the given reason and whatever went wrong on top of it.
+fn drop_client(
+    metrics:    &Metrics,
+    token:      Token,
+    reason:     Cow<'static, str>,
+) -> (Disconnected, Cow<'static, str>) {
+    match metrics.disconnect(token) {
+        Ok(disconnected) => (disconnected, reason),
+        Err(error) => (
+            Disconnected::default(),
+            Cow::Owned(format!("{}\", \"{}", reason, error)),
+        ),
+    }
+}
+
+pub(crate) async fn tarpit_connection<T: Transport>(
+    sock:       T,
+    peer:       SocketAddr,
+    config:     TarpitConfig,
+    token:      Token,
+    metrics:    Arc<Metrics>,
+    script:     Script,
+    #[cfg(feature = "alerts")]
+    inspection: Inspection,
+) -> Result<(), MetricsError> {
+    sock.tune(&config);
+
+    let (identify, identified) = match config.strict {
+        Some(_) => {
+            let (identify, identified) = oneshot::channel();
+            (Some(identify), Some(identified))
         },
+        None => (None, None),
+    };
+    let (reader, mut writer) = sock.split();
+    let reader = read_input(
+        reader,
+        peer,
+        token.clone(),
+        metrics.clone(),
+        #[cfg(feature = "alerts")]
+        inspection,
+        identify,
+        script.echo.clone(),
+    );
+    // Kicked, the client is hung up on as if it had closed the connection itself.
+    let kick = metrics.kicked(&token);
+    let reader = async move {
+        let kicked = kick.notified();
+        pin_mut!(reader, kicked);
+        match select(reader, kicked).await {
+            Either::Left((reason, _)) => reason,
+            Either::Right(_) => Cow::Borrowed("kicked"),
+        }
+    };
+    pin_mut!(reader);
+
+    // Whoever notices first that the connection is over ends it; if it is the reader, the
+    // writer is dropped mid-drip and never gets to disconnect with its own token.
+    let (result, reader) = {
+        let dripping = drip(&mut writer, peer, &config, token.clone(), metrics.clone(), script, identified);
+        pin_mut!(dripping);
+        match select(dripping, reader).await {
+            Either::Left((result, reader)) => (result, Some(reader)),
+            Either::Right((reason, _)) => {
+                hang_up(peer, &metrics, token, &reason);
+                (Ok(()), None)
+            },
+        }
+    };
+
+    T::hang_up(writer, config.disconnect);
+    if let (Disconnect::Linger, Some(reader)) = (config.disconnect, reader) {
+        let _ = timeout(config.timeout, reader).await;
+    }
+    result
+}
+
+/// Read whatever the client sends, note its identification string and keep the input to
+/// echo, until the client closes the connection; returns why.
+async fn read_input(
+    mut sock:   impl AsyncRead + Unpin,
+    peer:       SocketAddr,
+    token:      Token,
+    metrics:    Arc<Metrics>,
+    #[cfg(feature = "alerts")]
+    mut inspection: Inspection,
+    mut identified: Option<oneshot::Sender<bool>>,
+    echo:       Option<Arc<Echo>>,
+) -> Cow<'static, str> {
+    let mut buffer = [0u8; 64];
+    let mut version = Some(Vec::new());
+    loop {
+        let count = match sock.read(&mut buffer).await {
+            Ok(0) => return Cow::Borrowed("eof"),
+            Ok(count) => count,
+            Err(error) => return Cow::Owned(error.to_string()),
+        };
+        metrics.received_bytes(&token, count);
+        let input = &buffer[..count];
+
+        if let Some(line) = version.as_mut() {
+            let end = input.iter().position(|&byte| byte == b'\n');
+            line.extend_from_slice(&input[..end.unwrap_or(count)]);
+            if end.is_some() || line.len() >= MAX_VERSION {
+                let mut line = version.take().unwrap_or_default();
+                line.truncate(MAX_VERSION);
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                info!("version, peer: {}, version: {:?}", peer, String::from_utf8_lossy(&line));
+                metrics.version(&token, &line);
+                if let Some(identified) = identified.take() {
+                    let _ = identified.send(line.starts_with(b"SSH-"));
+                }
+                #[cfg(feature = "alerts")]
+                inspection.version(&line);
+            }
+        }
+
+        #[cfg(feature = "alerts")]
+        inspection.payload(input);
+
+        if let Some(echo) = &echo {
+            echo.push(input);
+        }
+    }
+}
+
+fn log_disconnect(
+    peer:           SocketAddr,
+    metrics:        &Metrics,
+    disconnected:   &Disconnected,
+    error:          &str,
+) {
+    if let Some(id) = disconnected.id {
+        metrics.event(|| Event::Disconnect {
+            id,
+            peer,
+            duration: disconnected.duration,
+            reason:   error.to_owned(),
+        });
     }
+    info!(
+        "disconnect, peer: {}, duration: {:.2?}, error: \"{}\", clients: {}, seen: {}, trapped: {}s",
+        peer,
+        disconnected.duration,
+        error,
+        disconnected.clients,
+        disconnected.history.seen,
+        disconnected.history.trapped,
+    );
 }
 
-pub(crate) async fn tarpit_connection(
-    mut sock:   tokio::net::TcpStream,
+/// Disconnect a client for a reason of our own and log it.
+fn hang_up(
     peer:       SocketAddr,
-    delay:      Duration,
-    time_out:   Duration,
+    metrics:    &Metrics,
+    token:      Token,
+    reason:     &str,
+) {
+    let (disconnected, reason) = drop_client(metrics, token, Cow::Owned(reason.to_owned()));
+    log_disconnect(peer, metrics, &disconnected, &reason);
+}
+
+/// Drip the preamble and then the banner into the connection, until the client gives up.
+///
+/// In strict mode, nothing is sent before the client identified itself as SSH. With echo,
+/// a line of whatever the client sent follows each line of the banner.
+async fn drip(
+    sock:       &mut (impl AsyncWrite + Unpin),
+    peer:       SocketAddr,
+    config:     &TarpitConfig,
     mut token:  Token,
     metrics:    Arc<Metrics>,
-    banner:     Arc<Vec<u8>>,
-) -> Result<(), &'static str> {
-    sock.set_recv_buffer_size(1)
-        .unwrap_or_else(|err| warn!("set_recv_buffer_size(), error: {}", err));
+    script:     Script,
+    identified: Option<oneshot::Receiver<bool>>,
+) -> Result<(), MetricsError> {
+    if let (Some(wait), Some(identified)) = (config.strict, identified) {
+        let reason = match timeout(wait, identified).await {
+            Ok(Ok(true)) => None,
+            Ok(Ok(false)) => Some("not ssh"),
+            Ok(Err(_)) | Err(_) => Some("silent"),
+        };
+        if let Some(reason) = reason {
+            hang_up(peer, &metrics, token, reason);
+            return Ok(());
+        }
+    }
 
-    sock.set_send_buffer_size(16)
-        .unwrap_or_else(|err| warn!("set_send_buffer_size(), error: {}", err));
+    let start = Instant::now();
+    let mut fresh = true;
+
+    for chunk in config.chunk_size.chunks(&script.preamble) {
+        match send_chunk(
+            sock,
+            config,
+            start,
+            fresh,
+            token,
+            &metrics,
+            chunk,
+        ).await {
+            Ok(the_token) => {
+                token = the_token;
+                fresh = false;
+            },
+            Err((disconnected, error)) => {
+                log_disconnect(peer, &metrics, &disconnected, &error);
+                return Ok(());
+            },
+        }
+    }
 
     'otter: loop {
-        if rand::random::<u8>() == 0x42 {
+        if let Some(surprise) = config.surprises.pick(peer.ip()) {
             match send_chunk(
-                &mut sock,
-                &delay,
-                &time_out,
+                sock,
+                config,
+                start,
+                fresh,
                 token,
                 &metrics,
-                b"Meow Meow Meow, but anymeow:\r\n",
+                surprise.content,
             ).await {
                 Ok(the_token) => {
                     token = the_token;
+                    fresh = false;
                     metrics.sent_easteregg(&token)?;
+                    metrics.event(|| Event::Easteregg { id: token.id(), peer });
                 },
-                Err((connected, connection_time, error)) => {
-                    info!(
-                        "disconnect, peer: {}, duration: {:.2?}, error: \"{}\", clients: {}",
-                        peer,
-                        connection_time,
-                        error,
-                        connected,
-                    );
+                Err((disconnected, error)) => {
+                    log_disconnect(peer, &metrics, &disconnected, &error);
                     break 'otter;
                 },
             }
         }
 
-        for chunk in banner.chunks(16) {
-            match send_chunk(
-                &mut sock,
-                &delay,
-                &time_out,
-                token,
-                &metrics,
-                chunk,
-            ).await {
-                Ok(the_token) => {
-                    token = the_token;
-                },
-                Err((connected, connection_time, error)) => {
-                    info!(
-                        "disconnect, peer: {}, duration: {:.2?}, error: \"{}\", clients: {}",
-                        peer,
-                        connection_time,
-                        error,
-                        connected,
-                    );
+        let mut windows = script.banner.windows();
+        loop {
+            let window = match windows.next().await {
+                Ok(Some(window)) => window,
+                Ok(None) => break,
+                Err(error) => {
+                    hang_up(peer, &metrics, token, &error.to_string());
                     break 'otter;
                 },
+            };
+            for chunk in config.chunk_size.chunks(&window) {
+                match send_chunk(
+                    sock,
+                    config,
+                    start,
+                    fresh,
+                    token,
+                    &metrics,
+                    chunk,
+                ).await {
+                    Ok(the_token) => {
+                        token = the_token;
+                        fresh = false;
+                    },
+                    Err((disconnected, error)) => {
+                        log_disconnect(peer, &metrics, &disconnected, &error);
+                        break 'otter;
+                    },
+                }
+
+                // Echoed lines go between the lines of the banner, not into the middle of one.
+                let line = match &script.echo {
+                    Some(echo) if chunk.ends_with(b"\n") => echo.line(config.chunk_size.max()),
+                    _ => None,
+                };
+                for chunk in config.chunk_size.chunks(line.as_deref().unwrap_or_default()) {
+                    match send_chunk(
+                        sock,
+                        config,
+                        start,
+                        fresh,
+                        token,
+                        &metrics,
+                        chunk,
+                    ).await {
+                        Ok(the_token) => token = the_token,
+                        Err((disconnected, error)) => {
+                            log_disconnect(peer, &metrics, &disconnected, &error);
+                            break 'otter;
+                        },
+                    }
+                }
             }
         }
 
@@ -144,3 +742,81 @@ pub(crate) async fn tarpit_connection(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chunk_sizes() {
+        let size: ChunkSize = "16".parse().unwrap();
+        assert_eq!((size.min, size.max), (16, 16));
+        let size: ChunkSize = " 8 - 32 ".parse().unwrap();
+        assert_eq!((size.min, size.max), (8, 32));
+        let size: ChunkSize = "1-1".parse().unwrap();
+        assert_eq!((size.min, size.max), (1, 1));
+    }
+
+    #[test]
+    fn rejects_chunk_sizes() {
+        for spec in &["", "0", "0-8", "32-8", "-8", "8-", "8-16-32", "eight", "-1"] {
+            assert!(spec.parse::<ChunkSize>().is_err(), "accepted \"{}\"", spec);
+        }
+    }
+
+    #[test]
+    fn refuses_empty_chunks() {
+        assert_eq!(ChunkSize::new(0, 4).err().unwrap(), "invalid chunk size range 0-4");
+        assert_eq!(ChunkSize::new(0, 0).err().unwrap(), "invalid chunk size range 0-0");
+    }
+
+    #[test]
+    fn refuses_backward_ranges() {
+        assert_eq!(ChunkSize::new(8, 4).err().unwrap(), "invalid chunk size range 8-4");
+        assert!(ChunkSize::new(4, 4).is_ok());
+    }
+
+    #[test]
+    fn cuts_between_code_points_and_line_breaks() {
+        let banner = "aä\r\nb€\r\n".as_bytes();
+        let chunks: Vec<_> = ChunkSize::new(1, 1).unwrap().chunks(banner).collect();
+        assert_eq!(chunks.concat(), banner);
+        for chunk in &chunks {
+            assert!(std::str::from_utf8(chunk).is_ok(), "cut a code point: {:?}", chunk);
+            assert!(!chunk.ends_with(b"\r"), "cut a line break: {:?}", chunk);
+        }
+        assert_eq!(ChunkSize::new(64, 64).unwrap().chunks(banner).count(), 1);
+        assert_eq!(ChunkSize::new(1, 4).unwrap().chunks(b"").count(), 0);
+    }
+
+    #[test]
+    fn parses_tiers() {
+        let tiers: Tiers = "5min=2s, 1h=10s, 60s".parse().unwrap();
+        assert_eq!(tiers.delay(Duration::from_secs(0)), Duration::from_secs(2));
+        assert_eq!(tiers.delay(Duration::from_secs(299)), Duration::from_secs(2));
+        assert_eq!(tiers.delay(Duration::from_secs(300)), Duration::from_secs(10));
+        assert_eq!(tiers.delay(Duration::from_secs(3600)), Duration::from_secs(60));
+        // Without a last delay, the last tier holds on.
+        let tiers: Tiers = "1min=1s,10min=5s".parse().unwrap();
+        assert_eq!(tiers.delay(Duration::from_secs(86400)), Duration::from_secs(5));
+        let tiers: Tiers = "30s".parse().unwrap();
+        assert_eq!(tiers.delay(Duration::from_secs(0)), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn rejects_tiers() {
+        for spec in &["", "1h=10s,5min=2s", "5min=2s,5min=3s", "60s,5min=2s", "10s,20s", "5min=", "=2s", "soon=2s"] {
+            assert!(spec.parse::<Tiers>().is_err(), "accepted \"{}\"", spec);
+        }
+    }
+
+    #[test]
+    fn interjects_after_line_endings() {
+        let said = || b"hi\r\n".to_vec();
+        assert_eq!(&interjected(b"ab\r\ncd", said)[..], b"ab\r\nhi\r\ncd");
+        assert_eq!(&interjected(b"\ncd\r\n", said)[..], b"\nhi\r\ncd\r\n");
+        assert_eq!(&interjected(b"ab\r\n", Vec::new)[..], b"ab\r\n");
+        // Amid a line, nothing is taken to wait.
+        assert_eq!(&interjected(b"abcd\r", || unreachable!())[..], b"abcd\r");
+    }
+}
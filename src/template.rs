@@ -0,0 +1,154 @@
+use rand::Rng;
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// Everything a placeholder might be expanded to.
+pub(crate) struct Context {
+    pub(crate) peer:          SocketAddr,
+    pub(crate) connection_id: usize,
+    pub(crate) uptime:        Duration,
+    /// Unique to the connection, if the banner asks for one.
+    pub(crate) canary:        Option<String>,
+}
+
+/// A fresh canary, 16 random hex digits.
+pub(crate) fn canary() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+enum Part {
+    Literal(String),
+    Peer,
+    Timestamp,
+    ConnectionId,
+    Uptime,
+    Canary,
+}
+
+/// A banner with placeholders like `{peer}`, `{timestamp}`, `{connection_id}`, `{uptime}` and `{canary}`.
+pub(crate) struct Template {
+    parts:  Vec<Part>,
+    cached: Option<Arc<Vec<u8>>>,
+}
+
+impl Template {
+    pub(crate) fn parse(
+        text: &str,
+    ) -> Self {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find('{') {
+            literal.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let part = rest.find('}').and_then(|end| {
+                match &rest[1..end] {
+                    "peer"          => Some(Part::Peer),
+                    "timestamp"     => Some(Part::Timestamp),
+                    "connection_id" => Some(Part::ConnectionId),
+                    "uptime"        => Some(Part::Uptime),
+                    "canary"        => Some(Part::Canary),
+                    _ => None,
+                }.map(|part| (part, end))
+            });
+            if let Some((part, end)) = part {
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(part);
+                rest = &rest[end + 1..];
+            } else {
+                literal.push('{');
+                rest = &rest[1..];
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        let cached = match parts.as_slice() {
+            [] => Some(Arc::new(Vec::new())),
+            [Part::Literal(text)] => Some(Arc::new(text.clone().into_bytes())),
+            _ => None,
+        };
+        Self { parts, cached }
+    }
+
+    /// Whether the connection needs a canary of its own.
+    pub(crate) fn has_canary(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, Part::Canary))
+    }
+
+    /// Expand all placeholders for a single connection.
+    pub(crate) fn render(
+        &self,
+        context: &Context,
+    ) -> Arc<Vec<u8>> {
+        if let Some(ref banner) = self.cached {
+            return banner.clone();
+        }
+        let mut banner = String::new();
+        for part in &self.parts {
+            // Writing into a String cannot fail.
+            let _ = match part {
+                Part::Literal(text)     => write!(banner, "{}", text),
+                Part::Peer              => write!(banner, "{}", context.peer.ip()),
+                Part::Timestamp         => write!(banner, "{}", humantime::format_rfc3339_seconds(SystemTime::now())),
+                Part::ConnectionId      => write!(banner, "{}", context.connection_id),
+                Part::Uptime            => write!(banner, "{:.0?}", context.uptime),
+                Part::Canary            => write!(banner, "{}", context.canary.as_deref().unwrap_or_default()),
+            };
+        }
+        Arc::new(banner.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> Context {
+        Context {
+            peer:          "192.0.2.1:50022".parse().unwrap(),
+            connection_id: 42,
+            uptime:        Duration::from_secs(90),
+            canary:        Some("0123456789abcdef".to_owned()),
+        }
+    }
+
+    fn render(
+        text: &str,
+    ) -> String {
+        String::from_utf8(Template::parse(text).render(&context()).to_vec()).unwrap()
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        assert_eq!(render("from {peer}, #{connection_id}"), "from 192.0.2.1, #42");
+        assert_eq!(render("up {uptime}"), "up 90s");
+        assert_eq!(render("{canary}{canary}"), "0123456789abcdef0123456789abcdef");
+        assert!(render("{timestamp}").ends_with('Z'));
+    }
+
+    #[test]
+    fn keeps_anything_else() {
+        assert_eq!(render(""), "");
+        assert_eq!(render("no placeholders"), "no placeholders");
+        assert_eq!(render("{unknown} {peer"), "{unknown} {peer");
+        assert_eq!(render("{{peer}}"), "{192.0.2.1}");
+        assert_eq!(render("}{"), "}{");
+    }
+
+    #[test]
+    fn caches_literals_only() {
+        assert!(Template::parse("plain").cached.is_some());
+        assert!(Template::parse("{peer}").cached.is_none());
+        assert!(Template::parse("{canary}").has_canary());
+        assert!(!Template::parse("{canary").has_canary());
+    }
+}
@@ -0,0 +1,242 @@
+use hyper::{body, client::HttpConnector, header::AUTHORIZATION, Body, Client, Request, StatusCode, Uri};
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+use super::{exporters, failure::Failure};
+
+/// Width of the bars of the listeners, in characters.
+const BAR_WIDTH: f64 = 40.0;
+
+/// Where to watch a running tarssh and how often.
+#[derive(Debug, StructOpt)]
+pub(crate) struct TopConfig {
+    /// Exporter of the tarssh to watch, its `/metrics` and `/top` scraped.
+    #[structopt(long = "url", default_value = "http://127.0.0.1:8080")]
+    url:      Uri,
+    /// Credentials the exporter asks for, as "bearer:<token>" or "basic:<user>:<password>".
+    #[structopt(long = "auth", parse(try_from_str = exporters::authorization))]
+    auth:     Option<String>,
+    /// Time between refreshes.
+    #[structopt(long = "interval", default_value = "1s", parse(try_from_str = interval))]
+    interval: Duration,
+    /// Peers with the most connections to show.
+    #[structopt(long = "peers", default_value = "10")]
+    peers:    usize,
+}
+
+/// A time between refreshes, which can't be none at all.
+fn interval(
+    interval: &str,
+) -> Result<Duration, String> {
+    match humantime::parse_duration(interval) {
+        Ok(interval) if interval == Duration::from_secs(0) => Err("interval must be more than 0s".to_owned()),
+        Ok(interval) => Ok(interval),
+        Err(err) => Err(format!("invalid interval \"{}\": {}", interval, err)),
+    }
+}
+
+impl TopConfig {
+    /// Fetch a path of the exporter, the body if it succeeded, else the status along.
+    async fn fetch(
+        &self,
+        client: &Client<HttpConnector>,
+        path:   &str,
+    ) -> Result<String, String> {
+        let uri = format!("{}{}", self.url.to_string().trim_end_matches('/'), path);
+        let mut request = Request::get(uri.as_str());
+        if let Some(auth) = &self.auth {
+            request = request.header(AUTHORIZATION, auth.as_str());
+        }
+        let request = request.body(Body::empty()).map_err(|err| format!("{}: {}", uri, err))?;
+        let fetched = async {
+            let response = client.request(request).await?;
+            let status = response.status();
+            body::to_bytes(response.into_body()).await.map(|body| (status, body))
+        };
+        match fetched.await {
+            Ok((status, body)) if status.is_success() => Ok(String::from_utf8_lossy(&body).into_owned()),
+            Ok((StatusCode::NOT_FOUND, _)) if path.starts_with("/top") => {
+                Err("none, the exporter only exports aggregates".to_owned())
+            },
+            Ok((status, body)) => Err(format!("{}: {}, {}", uri, status, String::from_utf8_lossy(&body).trim())),
+            Err(err) => Err(format!("{}: {}", uri, err)),
+        }
+    }
+}
+
+/// The metrics of one scrape, by name and labels.
+struct Sample {
+    taken:  Instant,
+    values: HashMap<String, f64>,
+}
+
+impl Sample {
+    fn parse(
+        text: &str,
+    ) -> Self {
+        let values = text
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| {
+                let index = line.rfind(' ')?;
+                let value = line[index + 1..].parse().ok()?;
+                Some((line[..index].to_owned(), value))
+            })
+            .collect();
+        Self {
+            taken: Instant::now(),
+            values,
+        }
+    }
+
+    fn get(
+        &self,
+        name: &str,
+    ) -> f64 {
+        self.values.get(name).copied().unwrap_or_default()
+    }
+
+    /// Change of a counter per second since an earlier sample.
+    fn rate(
+        &self,
+        earlier: Option<&Self>,
+        name: &str,
+    ) -> f64 {
+        match earlier {
+            Some(earlier) => {
+                let seconds = self.taken.duration_since(earlier.taken).as_secs_f64();
+                if seconds > 0.0 {
+                    (self.get(name) - earlier.get(name)).max(0.0) / seconds
+                } else {
+                    0.0
+                }
+            },
+            None => 0.0,
+        }
+    }
+
+    /// Accept rates of the listeners, by address.
+    fn listeners(&self) -> Vec<(&str, f64)> {
+        let mut listeners: Vec<_> = self.values
+            .iter()
+            .filter_map(|(key, value)| {
+                let addr = key
+                    .strip_prefix("listener_accepts_per_second{listener=\"")?
+                    .strip_suffix("\"}")?;
+                Some((addr, *value))
+            })
+            .collect();
+        listeners.sort_by(|a, b| a.0.cmp(b.0));
+        listeners
+    }
+}
+
+fn seconds(
+    seconds: f64,
+) -> String {
+    humantime::format_duration(Duration::from_secs(seconds.max(0.0) as u64)).to_string()
+}
+
+/// The peers of `/top`, one per line: address, connections, seconds trapped and seconds since
+/// last seen.
+fn peers(
+    top: &str,
+) -> String {
+    let mut screen = format!("{:<40} {:>11} {:>18} {}\n", "peer", "connections", "trapped", "last seen");
+    for line in top.lines().filter(|line| !line.starts_with('#')) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let [peer, connections, trapped, last_seen] = fields[..] {
+            let duration = |value: &str| value.parse().map_or_else(|_| value.to_owned(), seconds);
+            screen.push_str(&format!("{:<40} {:>11} {:>18} {} ago\n", peer, connections, duration(trapped), duration(last_seen)));
+        }
+    }
+    screen
+}
+
+/// The whole screen for a sample, and the peers the exporter lists if it does.
+fn render(
+    config:  &TopConfig,
+    sample:  &Sample,
+    earlier: Option<&Sample>,
+    top:     Result<String, String>,
+) -> String {
+    let mut screen = format!(
+        concat!(
+            "tarssh top, {url}, every {interval}\n",
+            "\n",
+            "uptime             {uptime}\n",
+            "connections        {connections}\n",
+            "connections total  {total}\n",
+            "connects/s         {connects:.2}\n",
+            "received bytes/s   {received:.2}\n",
+            "write retries/s    {retries:.2}\n",
+            "longest connection {longest}\n",
+            "accept latency max {latency:.6}s\n",
+            "\n",
+            "{listener:<24} accepts/s\n",
+        ),
+        url         = config.url,
+        interval    = humantime::format_duration(config.interval),
+        uptime      = seconds(sample.get("uptime_seconds")),
+        connections = sample.get("connections_count"),
+        total       = sample.get("connections_total"),
+        connects    = sample.rate(earlier, "connections_total"),
+        received    = sample.rate(earlier, "received_bytes_total"),
+        retries     = sample.rate(earlier, "write_retries_total"),
+        longest     = seconds(sample.get("client_maximum_connection_time_seconds")),
+        latency     = sample.get("accept_latency_max_seconds"),
+        listener    = "listener",
+    );
+    let listeners = sample.listeners();
+    let busiest = listeners.iter().map(|(_, rate)| *rate).fold(0.0, f64::max);
+    for (addr, rate) in listeners {
+        let bar = if busiest > 0.0 {
+            (rate / busiest * BAR_WIDTH).round() as usize
+        } else {
+            0
+        };
+        screen.push_str(&format!("{:<24} {:>9.2} {}\n", addr, rate, "#".repeat(bar)));
+    }
+    screen.push('\n');
+    match top {
+        Ok(top) => screen.push_str(&peers(&top)),
+        Err(err) => screen.push_str(&format!("peers: {}\n", err)),
+    }
+    screen
+}
+
+/// Scrape the exporter and redraw the terminal, until interrupted.
+pub(crate) fn run(
+    config: TopConfig,
+) {
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap_or_else(|err| Failure::Runtime.exit(format!("runtime, error: {}", err)));
+    runtime.block_on(async {
+        let client = Client::new();
+        let top = format!("/top?n={}", config.peers);
+        let mut earlier = None;
+        let mut ticks = tokio::time::interval(config.interval);
+        loop {
+            ticks.tick().await;
+            let screen = match config.fetch(&client, "/metrics").await {
+                Ok(text) => {
+                    let sample = Sample::parse(&text);
+                    let screen = render(&config, &sample, earlier.as_ref(), config.fetch(&client, &top).await);
+                    earlier = Some(sample);
+                    screen
+                },
+                Err(err) => format!("tarssh top, {}\n\nerror: {}\n", config.url, err),
+            };
+            // Clear the screen and start at the top left.
+            let mut stdout = std::io::stdout();
+            let _ = write!(stdout, "\x1b[2J\x1b[H{}", screen);
+            let _ = stdout.flush();
+        }
+    });
+}
@@ -0,0 +1,83 @@
+use log::warn;
+use std::time::Duration;
+use super::tarpit::{Disconnect, TarpitConfig};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+
+/// Whatever a client is connected over, to be tarpitted: a TCP connection as accepted, or
+/// any other stream with a reading and a writing half, like one of a proxy or an in-memory
+/// pipe.  The peer is told apart by the address it is tarpitted with.
+pub(crate) trait Transport: Send + 'static {
+    type Reader: AsyncRead + Send + Unpin + 'static;
+    type Writer: AsyncWrite + Send + Unpin + 'static;
+
+    /// Set the connection up to hold as little as possible of what is sent and received, as
+    /// far as the transport can.
+    fn tune(
+        &self,
+        _config: &TarpitConfig,
+    ) {
+    }
+
+    /// Read and write on their own.
+    fn split(
+        self,
+    ) -> (Self::Reader, Self::Writer);
+
+    /// Stop writing, the way asked to; the reading half lives on for `Disconnect::Linger`,
+    /// and the connection is closed once both are gone.
+    fn hang_up(
+        writer: Self::Writer,
+        _disconnect: Disconnect,
+    ) {
+        drop(writer);
+    }
+}
+
+impl Transport for TcpStream {
+    type Reader = OwnedReadHalf;
+    type Writer = OwnedWriteHalf;
+
+    fn tune(
+        &self,
+        config: &TarpitConfig,
+    ) {
+        self.set_recv_buffer_size(1)
+            .unwrap_or_else(|err| warn!("set_recv_buffer_size(), error: {}", err));
+
+        self.set_send_buffer_size(config.chunk_size.max())
+            .unwrap_or_else(|err| warn!("set_send_buffer_size(), error: {}", err));
+
+        if let Some(keepalive) = config.keepalive {
+            keepalive.apply(self)
+                .unwrap_or_else(|err| warn!("keepalive, error: {}", err));
+        }
+    }
+
+    fn split(
+        self,
+    ) -> (OwnedReadHalf, OwnedWriteHalf) {
+        self.into_split()
+    }
+
+    fn hang_up(
+        writer: OwnedWriteHalf,
+        disconnect: Disconnect,
+    ) {
+        match disconnect {
+            // Dropping the writing half sends a FIN.
+            Disconnect::Fin => (),
+            Disconnect::Rst => {
+                writer.as_ref().set_linger(Some(Duration::from_secs(0)))
+                    .unwrap_or_else(|err| warn!("set_linger(), error: {}", err));
+                writer.forget();
+            },
+            Disconnect::Linger => writer.forget(),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use log::{debug, error, info};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use super::{
+    budget::Budget,
+    failure::Failure,
+    handover::Handover,
+    listeners::{unmap, until_stopped},
+    metrics::Metrics,
+    runtime::Runtime,
+    sync::lock,
+};
+use tokio::{net::UdpSocket, time::delay_for};
+
+/// Bytes of a datagram read at once; a reply is never any longer.
+const MAX_DATAGRAM: usize = 1472;
+
+/// Peers waiting for a reply at most, of a port; anything beyond is dropped unanswered.
+const MAX_PENDING: usize = 1024;
+
+/// UDP ports answering every datagram with as many random bytes, late.
+///
+/// A reply is never larger than the datagram it answers, every peer gets one reply at a time,
+/// and all replies together stay within a bandwidth budget, so spoofed requests can't turn the
+/// tarpit into an amplifier.
+pub(crate) struct UdpTarpit {
+    sockets:   Vec<(SocketAddr, std::net::UdpSocket)>,
+    bandwidth: usize,
+}
+
+impl UdpTarpit {
+    /// Bind all addresses or exit.
+    pub(crate) fn bind(
+        addrs: &[SocketAddr],
+        bandwidth: usize,
+    ) -> Self {
+        let results: Vec<_> = addrs.iter().map(|&addr| (addr, bind(addr))).collect();
+        let failures = results.iter().filter(|(_, result)| result.is_err()).count();
+        let mut sockets = Vec::with_capacity(results.len());
+        for (addr, result) in results {
+            match result {
+                Ok(socket) => {
+                    info!("listen, udp: {}", addr);
+                    sockets.push((addr, socket));
+                },
+                Err(err) => error!("listen, udp: {}, error: {}", addr, err),
+            }
+        }
+        if failures > 0 {
+            Failure::Bind.exit(format!("listen, udps: {}, failures: {}", addrs.len(), failures));
+        }
+        Self {
+            sockets,
+            bandwidth,
+        }
+    }
+
+    /// Start answering on every port, until the listeners are handed over.
+    pub(crate) fn spawn(
+        self,
+        runtime: &Runtime,
+        delay: Duration,
+        metrics: Arc<Metrics>,
+        handover: &'static Handover,
+    ) {
+        if self.sockets.is_empty() {
+            return;
+        }
+        let budget = Budget::new(self.bandwidth);
+        runtime.spawn(budget.clone().refill());
+        for (addr, socket) in self.sockets {
+            runtime.spawn_acceptor(until_stopped(handover, serve(addr, socket, delay, budget.clone(), metrics.clone())));
+        }
+    }
+}
+
+/// Bind a UDP socket, shared with the next process on a handover.
+fn bind(
+    addr: SocketAddr,
+) -> io::Result<std::net::UdpSocket> {
+    let domain = if addr.is_ipv6() { Domain::ipv6() } else { Domain::ipv4() };
+    let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into_udp_socket())
+}
+
+/// Answer the datagrams of a port.
+async fn serve(
+    addr: SocketAddr,
+    socket: std::net::UdpSocket,
+    delay: Duration,
+    budget: Arc<Budget>,
+    metrics: Arc<Metrics>,
+) {
+    let socket = match UdpSocket::from_std(socket) {
+        Ok(socket) => socket,
+        Err(err) => return error!("udp, addr: {}, error: {}", addr, err),
+    };
+    let (mut receive, send) = socket.split();
+    let send = Arc::new(tokio::sync::Mutex::new(send));
+    let pending: Arc<Mutex<HashSet<IpAddr>>> = Arc::default();
+    let mut buffer = [0u8; MAX_DATAGRAM];
+    loop {
+        let (length, peer) = match receive.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            // Mostly the ICMP errors of former replies.
+            Err(err) => {
+                debug!("udp, addr: {}, error: {}", addr, err);
+                continue;
+            },
+        };
+        let replying = {
+            let mut pending = lock(&pending);
+            pending.len() < MAX_PENDING && pending.insert(peer.ip())
+        };
+        metrics.datagram(replying);
+        debug!("udp, addr: {}, peer: {}, bytes: {}, reply: {}", addr, unmap(peer), length, replying);
+        if !replying {
+            continue;
+        }
+        let send = send.clone();
+        let pending = pending.clone();
+        let budget = budget.clone();
+        tokio::spawn(async move {
+            delay_for(delay).await;
+            budget.spend(length, true).await;
+            let reply: Vec<u8> = (0..length).map(|_| rand::random()).collect();
+            if let Err(err) = send.lock().await.send_to(&reply, &peer).await {
+                debug!("udp, peer: {}, error: {}", unmap(peer), err);
+            }
+            lock(&pending).remove(&peer.ip());
+        });
+    }
+}
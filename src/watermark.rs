@@ -0,0 +1,151 @@
+use log::info;
+use rand::Rng;
+use std::{
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+};
+
+/// Bytes per block of SHA-256, and so of the key of HMAC-SHA256.
+const BLOCK: usize = 64;
+
+/// Bytes of the HMAC kept in a watermark.
+const TAG: usize = 8;
+
+/// Round constants of SHA-256.
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// A secret to sign connections with, so that a banner showing up somewhere else can be traced
+/// back to the connection it was sent to, and can't be forged by whoever finds it.
+pub(crate) struct Watermark {
+    /// The key padded (or hashed) to a block.
+    key: [u8; BLOCK],
+}
+
+impl Watermark {
+    /// A fresh watermark for a connection, logged together with it: 16 hex digits of a random
+    /// nonce, followed by 16 of the HMAC-SHA256 of `nonce|peer|connection_id`.
+    pub(crate) fn mark(
+        &self,
+        peer: SocketAddr,
+        connection_id: usize,
+    ) -> String {
+        let nonce = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let tag = self.hmac(format!("{}|{}|{}", nonce, peer, connection_id).as_bytes());
+        let mut watermark = nonce;
+        for byte in &tag[..TAG] {
+            watermark.push_str(&format!("{:02x}", byte));
+        }
+        info!("watermark, peer: {}, connection_id: {}, watermark: {}", peer, connection_id, watermark);
+        watermark
+    }
+
+    /// HMAC-SHA256 as per RFC 2104.
+    fn hmac(
+        &self,
+        message: &[u8],
+    ) -> [u8; 32] {
+        let pad = |byte: u8| {
+            let mut pad = self.key;
+            pad.iter_mut().for_each(|key| *key ^= byte);
+            pad
+        };
+        let inner = sha256(&[&pad(0x36), message]);
+        sha256(&[&pad(0x5c), &inner])
+    }
+}
+
+impl FromStr for Watermark {
+    type Err = String;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        if key.is_empty() {
+            return Err("the watermark key must not be empty".to_owned());
+        }
+        let mut padded = [0u8; BLOCK];
+        if key.len() > BLOCK {
+            padded[..32].copy_from_slice(&sha256(&[key.as_bytes()]));
+        } else {
+            padded[..key.len()].copy_from_slice(key.as_bytes());
+        }
+        Ok(Self { key: padded })
+    }
+}
+
+impl fmt::Debug for Watermark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The key stays out of the logs.
+        f.write_str("Watermark")
+    }
+}
+
+/// SHA-256 of the concatenation of some slices, as per FIPS 180-4.
+pub(crate) fn sha256(
+    parts: &[&[u8]],
+) -> [u8; 32] {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    let length: usize = parts.iter().map(|part| part.len()).sum();
+    let mut message: Vec<u8> = parts.concat();
+    message.push(0x80);
+    while message.len() % BLOCK != BLOCK - 8 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((length as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(BLOCK) {
+        let mut w = [0u32; 64];
+        for (index, word) in block.chunks(4).enumerate() {
+            w[index] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for index in 16..64 {
+            let s0 = w[index - 15].rotate_right(7) ^ w[index - 15].rotate_right(18) ^ (w[index - 15] >> 3);
+            let s1 = w[index - 2].rotate_right(17) ^ w[index - 2].rotate_right(19) ^ (w[index - 2] >> 10);
+            w[index] = w[index - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[index - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for index in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(K[index])
+                .wrapping_add(w[index]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for (bytes, word) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}